@@ -0,0 +1,528 @@
+use assem::Instr;
+use codegen::{Codegen, ascii_literal};
+use frame::{Access, Frame, shift_view};
+use gc::{PointerMap, pointer_map_table};
+use ir::{Exp, Stm, BinOp, RelOp};
+use symbol::SymbolTable;
+use temp::{Temp, Label, TempGenerator};
+
+// The AArch64 back end, with the AAPCS64 calling convention: x0-x7 for the
+// first arguments, the rest on the stack, x29 as the frame pointer and x30
+// as the link register. Temp(i) is register xi; sp and the zero register
+// get the numbers 31 and 32.
+
+pub const X0: Temp = Temp(0);
+pub const X9: Temp = Temp(9);
+pub const X10: Temp = Temp(10);
+pub const X11: Temp = Temp(11);
+pub const X16: Temp = Temp(16);
+pub const FP: Temp = Temp(29);
+pub const LR: Temp = Temp(30);
+pub const SP: Temp = Temp(31);
+pub const XZR: Temp = Temp(32);
+
+pub const ARG_REGISTERS: [Temp; 8] = [Temp(0), Temp(1), Temp(2), Temp(3), Temp(4), Temp(5), Temp(6), Temp(7)];
+pub const CALLEE_SAVES: [Temp; 10] = [Temp(19), Temp(20), Temp(21), Temp(22), Temp(23), Temp(24), Temp(25),
+                                      Temp(26), Temp(27), Temp(28)];
+// x18 is the platform register, which we never touch
+pub const CALLER_SAVES: [Temp; 19] = [Temp(0), Temp(1), Temp(2), Temp(3), Temp(4), Temp(5), Temp(6), Temp(7),
+                                      Temp(8), X9, X10, X11, Temp(12), Temp(13), Temp(14), Temp(15), X16,
+                                      Temp(17), LR];
+
+const WORD: i32 = 8;
+
+// the caller's frame pointer and the link register, pushed by the prologue
+const SAVED_REGISTERS_SIZE: i32 = 2 * WORD;
+
+// the immediate of add and sub
+fn fits_imm12(i: i32) -> bool {
+    i >= 0 && i <= 4095
+}
+
+// the offset of ldr and str: signed and unscaled, or unsigned and scaled by
+// the word size
+fn fits_offset(i: i32) -> bool {
+    (i >= -256 && i <= 255) || (i >= 0 && i <= 4095 * WORD && i % WORD == 0)
+}
+
+// the instructions setting dst to i: mov takes any 16-bit value or its
+// complement, the others need a movk for the upper half
+fn load_immediate(dst: Temp, i: i32) -> Vec<Instr> {
+    if i >= -65536 && i <= 65535 {
+        return vec![Instr::oper(format!("mov `d0, #{}", i), vec![dst], vec![])];
+    }
+    let low = i as u32 & 0xffff;
+    let high = i as u32 >> 16;
+    let first = if i < 0 {
+        format!("movn `d0, #{}", !low & 0xffff)
+    } else {
+        format!("mov `d0, #{}", low)
+    };
+    vec![Instr::oper(first, vec![dst], vec![]),
+         Instr::oper(format!("movk `d0, #{}, lsl #16", high), vec![dst], vec![dst])]
+}
+
+#[derive(Clone)]
+pub struct AArch64Frame {
+    name: Label,
+    formals: Vec<Access>,
+    locals: i32,
+}
+
+impl AArch64Frame {
+    // the size of the locals area, keeping sp 16-byte aligned
+    pub fn frame_size(&self) -> i32 {
+        (self.locals * WORD + 15) / 16 * 16
+    }
+}
+
+// arguments after the eighth are above the saved frame pointer and link
+// register
+fn stack_arg_offset(i: usize) -> i32 {
+    SAVED_REGISTERS_SIZE + (i - ARG_REGISTERS.len()) as i32 * WORD
+}
+
+impl Frame for AArch64Frame {
+    fn new_frame(name: Label, formals_escape: Vec<bool>, gen: &mut TempGenerator) -> AArch64Frame {
+        let mut frame = AArch64Frame { name: name, formals: vec![], locals: 0 };
+        for (i, &escape) in formals_escape.iter().enumerate() {
+            let access = if escape && i >= ARG_REGISTERS.len() {
+                Access::InFrame(stack_arg_offset(i))
+            } else {
+                frame.alloc_local(escape, gen)
+            };
+            frame.formals.push(access);
+        }
+        frame
+    }
+
+    fn name(&self) -> Label {
+        self.name
+    }
+
+    fn formals(&self) -> &[Access] {
+        &self.formals
+    }
+
+    fn alloc_local(&mut self, escape: bool, gen: &mut TempGenerator) -> Access {
+        if escape {
+            self.locals += 1;
+            Access::InFrame(-self.locals * WORD)
+        } else {
+            Access::InReg(gen.new_temp())
+        }
+    }
+
+    fn fp() -> Temp {
+        FP
+    }
+
+    fn rv() -> Temp {
+        X0
+    }
+
+    fn word_size() -> i32 {
+        WORD
+    }
+
+    fn external_name(name: &str) -> String {
+        format!("tig_{}", name)
+    }
+
+    fn proc_entry_exit1(&self, body: Stm, gen: &mut TempGenerator) -> Stm {
+        shift_view(self, body, &ARG_REGISTERS, &|i| Access::InFrame(stack_arg_offset(i)), &CALLEE_SAVES, gen)
+    }
+}
+
+pub struct AArch64Codegen;
+
+fn condition(op: RelOp) -> &'static str {
+    match op {
+        RelOp::Eq => "eq",
+        RelOp::Ne => "ne",
+        RelOp::Lt => "lt",
+        RelOp::Gt => "gt",
+        RelOp::Le => "le",
+        RelOp::Ge => "ge",
+        RelOp::ULt => "lo",
+        RelOp::UGt => "hi",
+        RelOp::ULe => "ls",
+        RelOp::UGe => "hs",
+    }
+}
+
+struct Muncher<'a> {
+    gen: &'a mut TempGenerator,
+    symbol_table: &'a SymbolTable,
+    instrs: Vec<Instr>,
+}
+
+impl<'a> Muncher<'a> {
+    fn emit(&mut self, assem: String, dst: Vec<Temp>, src: Vec<Temp>) {
+        self.instrs.push(Instr::oper(assem, dst, src));
+    }
+
+    fn emit_move(&mut self, dst: Temp, src: Temp) {
+        self.instrs.push(Instr::Move { assem: String::from("mov `d0, `s0"), dst: dst, src: src });
+    }
+
+    fn label_name(&self, l: Label) -> String {
+        l.name(self.symbol_table).clone()
+    }
+
+    // the base temp and offset of a memory operand at address e
+    fn munch_address(&mut self, e: &Exp) -> (Temp, i32) {
+        match e {
+            &Exp::BinOp(BinOp::Plus, ref a, ref b) => match (a.as_ref(), b.as_ref()) {
+                (a, &Exp::Const(i)) | (&Exp::Const(i), a) if fits_offset(i) => (self.munch_exp(a), i),
+                _ => (self.munch_exp(e), 0),
+            },
+            &Exp::BinOp(BinOp::Minus, ref a, ref b) => match b.as_ref() {
+                &Exp::Const(i) if i != i32::min_value() && fits_offset(-i) => (self.munch_exp(a), -i),
+                _ => (self.munch_exp(e), 0),
+            },
+            e => (self.munch_exp(e), 0),
+        }
+    }
+
+    // a register holding the value of e, using the zero register for 0;
+    // only valid where xzr is not read as sp, as for the second operand of
+    // an arithmetic instruction or the source of a store
+    fn munch_operand(&mut self, e: &Exp) -> Temp {
+        match e {
+            &Exp::Const(0) => XZR,
+            e => self.munch_exp(e),
+        }
+    }
+
+    fn munch_stm(&mut self, stm: &Stm) {
+        match stm {
+            &Stm::Move(ref dst, ref src) => match (dst.as_ref(), src.as_ref()) {
+                (&Exp::Mem(ref address), src) => {
+                    let (base, offset) = self.munch_address(address);
+                    let src = self.munch_operand(src);
+                    self.emit(format!("str `s0, [`s1, #{}]", offset), vec![], vec![src, base]);
+                },
+                (&Exp::Temp(t), &Exp::Const(i)) => self.instrs.extend(load_immediate(t, i)),
+                (&Exp::Temp(t), &Exp::Mem(ref address)) => {
+                    let (base, offset) = self.munch_address(address);
+                    self.emit(format!("ldr `d0, [`s0, #{}]", offset), vec![t], vec![base]);
+                },
+                (&Exp::Temp(t), src) => {
+                    let src = self.munch_exp(src);
+                    self.emit_move(t, src);
+                },
+                _ => panic!("cannot move to {:?}", dst),
+            },
+            &Stm::Exp(ref e) => match e.as_ref() {
+                &Exp::Call(ref f, ref args) => self.munch_call(f, args),
+                e => {
+                    self.munch_exp(e);
+                },
+            },
+            &Stm::Jump(ref e, ref labels) => match e.as_ref() {
+                &Exp::Name(l) => {
+                    self.instrs.push(Instr::Oper {
+                        assem: String::from("b `j0"),
+                        dst: vec![],
+                        src: vec![],
+                        jump: Some(vec![l]),
+                    });
+                },
+                e => {
+                    let t = self.munch_exp(e);
+                    self.instrs.push(Instr::Oper {
+                        assem: String::from("br `s0"),
+                        dst: vec![],
+                        src: vec![t],
+                        jump: Some(labels.clone()),
+                    });
+                },
+            },
+            &Stm::CJump(op, ref a, ref b, t, f) => {
+                match (a.as_ref(), b.as_ref()) {
+                    (a, &Exp::Const(i)) if i != i32::min_value() && (fits_imm12(i) || fits_imm12(-i)) => {
+                        self.emit_compare_immediate(a, i);
+                        self.emit_jump(op, t, f);
+                    },
+                    (&Exp::Const(i), b) if i != i32::min_value() && (fits_imm12(i) || fits_imm12(-i)) => {
+                        self.emit_compare_immediate(b, i);
+                        self.emit_jump(op.commute(), t, f);
+                    },
+                    (a, b) => {
+                        let a = self.munch_exp(a);
+                        let b = self.munch_operand(b);
+                        self.emit(String::from("cmp `s0, `s1"), vec![], vec![a, b]);
+                        self.emit_jump(op, t, f);
+                    },
+                }
+            },
+            &Stm::Label(l) => {
+                let assem = format!("{}:", self.label_name(l));
+                self.instrs.push(Instr::Label { assem: assem, label: l });
+            },
+            &Stm::Seq(_, _) => panic!("SEQ in canonical IR"),
+        }
+    }
+
+    fn emit_compare_immediate(&mut self, a: &Exp, i: i32) {
+        let a = self.munch_exp(a);
+        if i >= 0 {
+            self.emit(format!("cmp `s0, #{}", i), vec![], vec![a]);
+        } else {
+            self.emit(format!("cmn `s0, #{}", -i), vec![], vec![a]);
+        }
+    }
+
+    // a conditional branch to t, falling through to f
+    fn emit_jump(&mut self, op: RelOp, t: Label, f: Label) {
+        let assem = format!("b.{} `j0", condition(op));
+        self.instrs.push(Instr::Oper { assem: assem, dst: vec![], src: vec![], jump: Some(vec![t, f]) });
+    }
+
+    fn munch_call(&mut self, f: &Exp, args: &[Exp]) {
+        let name = match f {
+            &Exp::Name(l) => self.label_name(l),
+            f => panic!("cannot call {:?}", f),
+        };
+
+        let temps: Vec<Temp> = args.iter().map(|a| self.munch_exp(a)).collect();
+        let in_registers = temps.len().min(ARG_REGISTERS.len());
+        // arguments after the eighth go to the bottom of the stack, which
+        // stays 16-byte aligned
+        let stack_size = ((temps.len() - in_registers) as i32 * WORD + 15) / 16 * 16;
+        if stack_size > 0 {
+            self.emit(format!("sub `d0, `s0, #{}", stack_size), vec![SP], vec![SP]);
+        }
+        for (i, &t) in temps[in_registers..].iter().enumerate() {
+            self.emit(format!("str `s0, [`s1, #{}]", i as i32 * WORD), vec![], vec![t, SP]);
+        }
+        for (&t, &r) in temps.iter().zip(ARG_REGISTERS.iter()) {
+            self.emit_move(r, t);
+        }
+
+        self.emit(format!("bl {}", name), CALLER_SAVES.to_vec(), ARG_REGISTERS[..in_registers].to_vec());
+        if stack_size > 0 {
+            self.emit(format!("add `d0, `s0, #{}", stack_size), vec![SP], vec![SP]);
+        }
+    }
+
+    fn munch_exp(&mut self, exp: &Exp) -> Temp {
+        match exp {
+            &Exp::Const(i) => {
+                let r = self.gen.new_temp();
+                self.instrs.extend(load_immediate(r, i));
+                r
+            },
+            &Exp::Temp(t) => t,
+            &Exp::Name(l) => {
+                let r = self.gen.new_temp();
+                let name = self.label_name(l);
+                self.emit(format!("adrp `d0, {}", name), vec![r], vec![]);
+                self.emit(format!("add `d0, `s0, :lo12:{}", name), vec![r], vec![r]);
+                r
+            },
+            &Exp::Mem(ref address) => {
+                let r = self.gen.new_temp();
+                let (base, offset) = self.munch_address(address);
+                self.emit(format!("ldr `d0, [`s0, #{}]", offset), vec![r], vec![base]);
+                r
+            },
+            &Exp::BinOp(op, ref a, ref b) => {
+                let mnemonic = match op {
+                    BinOp::Plus => "add",
+                    BinOp::Minus => "sub",
+                    BinOp::Mul => "mul",
+                    BinOp::Div => "sdiv",
+                    BinOp::And => "and",
+                    BinOp::Or => "orr",
+                    BinOp::Xor => "eor",
+                    BinOp::LShift => "lsl",
+                    BinOp::RShift => "lsr",
+                    BinOp::ARShift => "asr",
+                };
+                // the instruction with an immediate operand, and the
+                // immediate it needs for b; logical immediates are bit
+                // patterns, which we do not bother to encode
+                let immediate = |i: i32| match op {
+                    BinOp::Plus if fits_imm12(i) => Some(("add", i)),
+                    BinOp::Plus if i != i32::min_value() && fits_imm12(-i) => Some(("sub", -i)),
+                    BinOp::Minus if fits_imm12(i) => Some(("sub", i)),
+                    BinOp::Minus if i != i32::min_value() && fits_imm12(-i) => Some(("add", -i)),
+                    BinOp::LShift | BinOp::RShift | BinOp::ARShift if i >= 0 && i < 64 => Some((mnemonic, i)),
+                    _ => None,
+                };
+
+                let with_immediate = match (a.as_ref(), b.as_ref()) {
+                    (a, &Exp::Const(i)) => immediate(i).map(|imm| (a, imm)),
+                    (&Exp::Const(i), b) if op == BinOp::Plus => immediate(i).map(|imm| (b, imm)),
+                    _ => None,
+                };
+                let r = self.gen.new_temp();
+                match with_immediate {
+                    Some((a, (mnemonic, i))) => {
+                        let a = self.munch_exp(a);
+                        self.emit(format!("{} `d0, `s0, #{}", mnemonic, i), vec![r], vec![a]);
+                    },
+                    None => {
+                        let a = self.munch_exp(a);
+                        let b = self.munch_operand(b);
+                        self.emit(format!("{} `d0, `s0, `s1", mnemonic), vec![r], vec![a, b]);
+                    },
+                }
+                r
+            },
+            &Exp::Call(ref f, ref args) => {
+                self.munch_call(f, args);
+                let r = self.gen.new_temp();
+                self.emit_move(r, X0);
+                r
+            },
+            &Exp::ESeq(_, _) => panic!("ESEQ in canonical IR"),
+        }
+    }
+}
+
+impl Codegen for AArch64Codegen {
+    type Frame = AArch64Frame;
+
+    fn codegen(&self, _: &AArch64Frame, stm: Stm, gen: &mut TempGenerator, symbol_table: &SymbolTable)
+               -> Vec<Instr> {
+        let mut muncher = Muncher {
+            gen: gen,
+            symbol_table: symbol_table,
+            instrs: vec![],
+        };
+        muncher.munch_stm(&stm);
+        muncher.instrs
+    }
+
+    fn proc_entry_exit2(&self, _: &AArch64Frame, mut body: Vec<Instr>) -> Vec<Instr> {
+        let mut live = vec![XZR, X0, SP, FP];
+        live.extend(CALLEE_SAVES.iter());
+        body.push(Instr::oper(String::new(), vec![], live));
+        body
+    }
+
+    fn proc_entry_exit3(&self, frame: &AArch64Frame, symbol_table: &SymbolTable) -> (String, String) {
+        let name = frame.name.name(symbol_table);
+        let mut prologue = format!("\t.globl {}\n{}:\n\tstp x29, x30, [sp, #-{}]!\n\tmov x29, sp\n",
+                                   name, name, SAVED_REGISTERS_SIZE);
+        let size = frame.frame_size();
+        if fits_imm12(size) {
+            prologue.push_str(&format!("\tsub sp, sp, #{}\n", size));
+        } else {
+            let mut instrs = load_immediate(X16, size);
+            instrs.push(Instr::oper(String::from("sub `d0, `s0, `s1"), vec![SP], vec![SP, X16]));
+            for instr in instrs.iter() {
+                let text = instr.format(&|t| self.register_name(t), &|l| l.name(symbol_table).clone());
+                prologue.push_str(&format!("\t{}\n", text));
+            }
+        }
+        let epilogue = format!("\tmov sp, x29\n\tldp x29, x30, [sp], #{}\n\tret\n", SAVED_REGISTERS_SIZE);
+        (prologue, epilogue)
+    }
+
+    fn file_header(&self) -> String {
+        String::from("\t.section .note.GNU-stack,\"\",%progbits\n\t.text\n")
+    }
+
+    fn data_section(&self) -> String {
+        String::from("\t.data\n")
+    }
+
+    // strings are a length word followed by the characters
+    fn string(&self, label: Label, s: &str, symbol_table: &SymbolTable) -> String {
+        format!("\t.balign 8\n{}:\n\t.quad {}\n\t.ascii {}\n",
+                label.name(symbol_table), s.chars().count(), ascii_literal(s))
+    }
+
+    fn register_name(&self, t: Temp) -> String {
+        match t {
+            SP => String::from("sp"),
+            XZR => String::from("xzr"),
+            Temp(i) if i < 31 => format!("x{}", i),
+            Temp(i) => format!("t{}", i),
+        }
+    }
+
+    // frame offsets beyond an immediate go through x16, which instruction
+    // selection never uses
+    fn load(&self, dst: Temp, offset: i32) -> Vec<Instr> {
+        if fits_offset(offset) {
+            vec![Instr::oper(format!("ldr `d0, [`s0, #{}]", offset), vec![dst], vec![FP])]
+        } else {
+            let mut instrs = load_immediate(X16, offset);
+            instrs.push(Instr::oper(String::from("ldr `d0, [`s0, `s1]"), vec![dst], vec![FP, X16]));
+            instrs
+        }
+    }
+
+    fn store(&self, src: Temp, offset: i32) -> Vec<Instr> {
+        if fits_offset(offset) {
+            vec![Instr::oper(format!("str `s0, [`s1, #{}]", offset), vec![], vec![src, FP])]
+        } else {
+            let mut instrs = load_immediate(X16, offset);
+            instrs.push(Instr::oper(String::from("str `s0, [`s1, `s2]"), vec![], vec![src, FP, X16]));
+            instrs
+        }
+    }
+
+    // x16 is left for large immediates
+    fn registers(&self) -> Vec<Temp> {
+        CALLER_SAVES.iter().chain(CALLEE_SAVES.iter()).cloned().filter(|&r| r != X16).collect()
+    }
+
+    fn is_call(&self, instr: &Instr) -> bool {
+        match instr {
+            &Instr::Oper { ref assem, .. } => assem.starts_with("bl "),
+            _ => false,
+        }
+    }
+
+    fn pointer_map_table(&self, maps: &[PointerMap], symbol_table: &SymbolTable) -> String {
+        format!("\t.balign 8\n{}", pointer_map_table(maps, ".quad", symbol_table))
+    }
+}
+
+#[test]
+fn test_munch() {
+    let mut table = SymbolTable::new();
+    let frame = AArch64Frame::new_frame(Label(table.symbol("f")), vec![], &mut TempGenerator::new());
+    let (g, yes, no) = (Label(table.symbol("g")), Label(table.symbol("yes")), Label(table.symbol("no")));
+    let (t, a) = (Temp(200), Temp(201));
+    let codegen = AArch64Codegen;
+    let name = |t: Temp| codegen.register_name(t);
+    let label = |l: Label| l.name(&table).clone();
+    let format = |instrs: Vec<Instr>| -> Vec<String> { instrs.iter().map(|i| i.format(&name, &label)).collect() };
+    let munch = |stm: Stm| format(codegen.codegen(&frame, stm, &mut TempGenerator::new(), &table));
+    let temp = |t: Temp| Box::new(Exp::Temp(t));
+    let plus = |a: Box<Exp>, i: i32| Box::new(Exp::BinOp(BinOp::Plus, a, Box::new(Exp::Const(i))));
+
+    // mov takes 16 bits or their complement, the upper half needs a movk
+    assert_eq!(format(load_immediate(t, 65535)), vec!["mov t200, #65535"]);
+    assert_eq!(format(load_immediate(t, 65536)), vec!["mov t200, #0", "movk t200, #1, lsl #16"]);
+    assert_eq!(format(load_immediate(t, -100000)), vec!["movn t200, #34463", "movk t200, #65534, lsl #16"]);
+    // offsets are 9 signed bits, or 12 unsigned bits scaled by the word size
+    assert_eq!(munch(Stm::Move(Box::new(Exp::Mem(plus(temp(t), 32760))), temp(a))), vec!["str t201, [t200, #32760]"]);
+    assert_eq!(munch(Stm::Move(Box::new(Exp::Mem(plus(temp(t), -256))), Box::new(Exp::Const(0)))),
+               vec!["str xzr, [t200, #-256]"]);
+    assert_eq!(munch(Stm::Move(Box::new(Exp::Mem(plus(temp(t), 260))), temp(a))),
+               vec!["add t100, t200, #260", "str t201, [t100, #0]"]);
+    // compares take 12 bits, negated with cmn
+    assert_eq!(munch(Stm::CJump(RelOp::Lt, temp(t), Box::new(Exp::Const(-4095)), yes, no)),
+               vec!["cmn t200, #4095", "b.lt yes"]);
+    assert_eq!(munch(Stm::CJump(RelOp::Lt, temp(t), Box::new(Exp::Const(4096)), yes, no)),
+               vec!["mov t100, #4096", "cmp t200, t100", "b.lt yes"]);
+
+    // the ninth and tenth arguments go below a 16-byte aligned stack pointer
+    let args = (0..10).map(|i| Exp::Temp(Temp(210 + i))).collect();
+    let call = munch(Stm::Exp(Box::new(Exp::Call(Box::new(Exp::Name(g)), args))));
+    assert_eq!(&call[..3], &["sub sp, sp, #16", "str t218, [sp, #0]", "str t219, [sp, #8]"]);
+    assert_eq!(&call[11..], &["bl g", "add sp, sp, #16"]);
+    let callee = AArch64Frame::new_frame(g, vec![true; 10], &mut TempGenerator::new());
+    match (&callee.formals()[8], &callee.formals()[9]) {
+        (&Access::InFrame(16), &Access::InFrame(24)) => (),
+        _ => panic!("stack formals should be above the saved registers"),
+    }
+}
@@ -0,0 +1,135 @@
+use driver::{self, Mode, Target};
+use parser;
+use regalloc::Allocator;
+use type_check::type_check;
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{self, Command, Stdio};
+
+// The test programs from Appel's book, in tests/appel, with what each of
+// them should do: the valid ones are also run, by the interpreter and by
+// the bytecode machine, and their output compared.
+
+enum Expect {
+    // type checks, and given the input prints the output
+    Runs(&'static str, &'static str),
+    // type checks, but never stops when run
+    Checks,
+    SyntaxError,
+    // fails to type check with a message starting with this
+    TypeError(&'static str),
+}
+
+use self::Expect::*;
+
+const PROGRAMS: [(&'static str, Expect); 51] = [
+    ("test1", Runs("", "")),
+    ("test2", Runs("", "")),
+    ("test3", Runs("", "")),
+    ("test4", Runs("", "")),
+    ("test5", Runs("", "")),
+    ("test6", Checks),
+    ("test7", Checks),
+    ("test8", Runs("", "")),
+    ("test9", TypeError("then (int) and else (string) branch are not of the same type")),
+    ("test10", TypeError("Body of while loop must not return a value")),
+    ("test11", TypeError("Upper bound must be of type int")),
+    ("test12", Runs("", "")),
+    ("test13", TypeError("Integer or string operands required")),
+    ("test14", TypeError("Cannot compare")),
+    ("test15", TypeError("if-then without else must not return a value")),
+    ("test16", TypeError("Illegal cycle in declaration of type")),
+    ("test17", TypeError("Unknown type treelist")),
+    ("test18", TypeError("Unknown function do_nothing2")),
+    ("test19", TypeError("Unknown variable a")),
+    ("test20", TypeError("Unknown variable i")),
+    ("test21", TypeError("Integer required")),
+    ("test22", TypeError("Record of type {name: string, id: int} has no field named nam")),
+    ("test23", TypeError("Assigned value must be of type string, found int")),
+    ("test24", TypeError("Variable of type int is not an array")),
+    ("test25", TypeError("Variable of type int is not a record")),
+    ("test26", TypeError("Integer required")),
+    ("test27", Runs("", "")),
+    ("test28", TypeError("Initializer must be of type")),
+    ("test29", TypeError("Initializer must be of type")),
+    ("test30", Runs("", "")),
+    ("test31", TypeError("Initializer must be of type int, found string")),
+    ("test32", TypeError("Array initializer must be of type int, found string")),
+    ("test33", TypeError("Unknown type rectype")),
+    ("test34", TypeError("Argument must be of type int, found string")),
+    ("test35", TypeError("Function g expects 2 arguments, got 1")),
+    ("test36", TypeError("Function g expects 2 arguments, got 3")),
+    ("test37", Runs("", "")),
+    ("test38", TypeError("Type a declared twice in the same group")),
+    ("test39", TypeError("Function g declared twice in the same group")),
+    ("test40", TypeError("Procedure g returns a value")),
+    ("test41", Runs("", "")),
+    ("test42", Runs("", "")),
+    ("test43", TypeError("Integer required")),
+    ("test44", Runs("", "")),
+    ("test45", TypeError("Variable a initialized with nil needs a record type")),
+    ("test46", Runs("", "")),
+    ("test47", Runs("", "")),
+    ("test48", Runs("", "")),
+    ("test49", SyntaxError),
+    ("queens", Runs("", include_str!("../tests/appel/queens.out"))),
+    ("merge", Runs("1 3 5 9 .\n2 4 10 11 .", "1 2 3 4 5 9 10 11 \n")),
+];
+
+#[test]
+fn test_appel() {
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("appel");
+    for &(name, ref expect) in PROGRAMS.iter() {
+        let source = fs::read_to_string(directory.join(format!("{}.tig", name))).unwrap();
+        let checked = match parser::parse(&source) {
+            Ok((exp, mut symbol_table)) => Ok(type_check(&exp, &mut symbol_table)),
+            Err(e) => Err(parser::error_message(&e)),
+        };
+        match (expect, checked) {
+            (&Runs(input, output), Ok(Ok(_))) => {
+                for &mode in [Mode::Interpret, Mode::Bytecode].iter() {
+                    let mut out = vec![];
+                    let status = driver::run(&source, mode, &mut input.as_bytes(), &mut out);
+                    assert_eq!(status, Ok(0), "{} in {:?}", name, mode);
+                    assert_eq!(String::from_utf8(out).unwrap(), output, "{} in {:?}", name, mode);
+                }
+            },
+            (&Checks, Ok(Ok(_))) | (&SyntaxError, Err(_)) => (),
+            (&TypeError(message), Ok(Err(e))) => assert!(e.starts_with(message), "{}: {}", name, e),
+            (_, checked) => panic!("{}: unexpected {:?}", name, checked.map(|r| r.map(|_| ()))),
+        }
+    }
+}
+
+// the programs that run, compiled for MIPS and run by SPIM, both with and
+// without the simulation of delay slots
+#[test]
+fn test_appel_spim() {
+    if !driver::installed("spim") {
+        println!("skipped: needs spim");
+        return;
+    }
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("appel");
+    let asm = env::temp_dir().join(format!("tiger-spim-test-{}.s", process::id()));
+    for &(name, ref expect) in PROGRAMS.iter() {
+        let (input, output) = match expect {
+            &Runs(input, output) => (input, output),
+            _ => continue,
+        };
+        let source = fs::read_to_string(directory.join(format!("{}.tig", name))).unwrap();
+        driver::build(&source, Target::Mips, Allocator::Coloring, &asm).unwrap();
+        for flags in [&["-quiet"][..], &["-quiet", "-delayed_branches"][..]].iter() {
+            let mut spim = Command::new("spim").args(flags.iter()).arg("-file").arg(&asm)
+                .stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+            spim.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+            let out = spim.wait_with_output().unwrap();
+            // spim may first say where it loaded its exception handler from
+            let out = String::from_utf8_lossy(&out.stdout);
+            assert!(out.ends_with(output), "{} with {:?}: {}", name, flags, out);
+        }
+    }
+    fs::remove_file(&asm).unwrap();
+}
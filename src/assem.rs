@@ -0,0 +1,140 @@
+use temp::{Temp, Label};
+
+use std::collections::HashSet;
+
+// Machine instructions with their operands abstracted away (Appel chapter
+// 9). In an assembly template `s0, `s1, ... stand for the source temps,
+// `d0, `d1, ... for the destination temps and `j0, `j1, ... for the jump
+// targets; `` is a literal backquote.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instr {
+    Oper {
+        assem: String,
+        dst: Vec<Temp>,
+        src: Vec<Temp>,
+        // None if control falls through to the next instruction
+        jump: Option<Vec<Label>>,
+    },
+    Label {
+        assem: String,
+        label: Label,
+    },
+    Move {
+        assem: String,
+        dst: Temp,
+        src: Temp,
+    },
+}
+
+impl Instr {
+    pub fn oper(assem: String, dst: Vec<Temp>, src: Vec<Temp>) -> Instr {
+        Instr::Oper { assem: assem, dst: dst, src: src, jump: None }
+    }
+
+    pub fn defs(&self) -> Vec<Temp> {
+        match self {
+            &Instr::Oper { ref dst, .. } => dst.clone(),
+            &Instr::Label { .. } => vec![],
+            &Instr::Move { dst, .. } => vec![dst],
+        }
+    }
+
+    pub fn uses(&self) -> Vec<Temp> {
+        match self {
+            &Instr::Oper { ref src, .. } => src.clone(),
+            &Instr::Label { .. } => vec![],
+            &Instr::Move { src, .. } => vec![src],
+        }
+    }
+
+    pub fn jumps(&self) -> Option<&Vec<Label>> {
+        match self {
+            &Instr::Oper { ref jump, .. } => jump.as_ref(),
+            _ => None,
+        }
+    }
+
+    // every temp of the instruction, replaced by f(temp)
+    pub fn map_temps<F: Fn(Temp) -> Temp>(&self, f: F) -> Instr {
+        match self {
+            &Instr::Oper { ref assem, ref dst, ref src, ref jump } => Instr::Oper {
+                assem: assem.clone(),
+                dst: dst.iter().map(|&t| f(t)).collect(),
+                src: src.iter().map(|&t| f(t)).collect(),
+                jump: jump.clone(),
+            },
+            &Instr::Label { .. } => self.clone(),
+            &Instr::Move { ref assem, dst, src } => Instr::Move {
+                assem: assem.clone(),
+                dst: f(dst),
+                src: f(src),
+            },
+        }
+    }
+
+    // all temps mentioned by the instruction
+    pub fn temps(&self) -> HashSet<Temp> {
+        self.defs().into_iter().chain(self.uses().into_iter()).collect()
+    }
+
+    // fills in the template; temp_name gives the register (or temp) name
+    // and label_name the name of a jump target
+    pub fn format(&self, temp_name: &dyn Fn(Temp) -> String, label_name: &dyn Fn(Label) -> String) -> String {
+        let (assem, dst, src, jump) = match self {
+            &Instr::Oper { ref assem, ref dst, ref src, ref jump } =>
+                (assem, dst.clone(), src.clone(), jump.clone().unwrap_or(vec![])),
+            &Instr::Label { ref assem, .. } => return assem.clone(),
+            &Instr::Move { ref assem, dst, src } => (assem, vec![dst], vec![src], vec![]),
+        };
+
+        let mut res = String::new();
+        let mut chars = assem.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '`' {
+                res.push(c);
+                continue;
+            }
+            let kind = chars.next();
+            if kind == Some('`') {
+                res.push('`');
+                continue;
+            }
+            let mut index = 0;
+            while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+                index = index * 10 + d as usize;
+                chars.next();
+            }
+            match kind {
+                Some('s') => res.push_str(&temp_name(src[index])),
+                Some('d') => res.push_str(&temp_name(dst[index])),
+                Some('j') => res.push_str(&label_name(jump[index])),
+                k => panic!("bad assembly template {:?} at {:?}", assem, k),
+            }
+        }
+        res
+    }
+
+    // a move whose source and destination ended up in the same register
+    pub fn is_redundant_move(&self, register: &dyn Fn(Temp) -> Temp) -> bool {
+        match self {
+            &Instr::Move { dst, src, .. } => register(dst) == register(src),
+            _ => false,
+        }
+    }
+}
+
+#[test]
+fn test_format() {
+    let i = Instr::Oper {
+        assem: String::from("addq `s0, `d0 # ``"),
+        dst: vec![Temp(101)],
+        src: vec![Temp(100), Temp(101)],
+        jump: None,
+    };
+    let name = |t: Temp| format!("t{}", t.0);
+    let label = |_: Label| String::new();
+    assert_eq!(i.format(&name, &label), "addq t100, t101 # `");
+    assert_eq!(i.defs(), vec![Temp(101)]);
+    assert_eq!(i.map_temps(|t| Temp(t.0 + 1)).uses(), vec![Temp(101), Temp(102)]);
+}
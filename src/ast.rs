@@ -1,25 +1,39 @@
 use symbol;
 
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 pub type Symbol = symbol::SymbolId;
 pub type Position = usize;
 
+// Tells the nodes of syntax trees apart, so that what the type checker
+// records about a node stays with it when the tree is cloned or moved.
+pub type NodeId = usize;
+
+static NODE_IDS: AtomicUsize = AtomicUsize::new(0);
+
+// an id no other node has
+pub fn node_id() -> NodeId {
+    NODE_IDS.fetch_add(1, Ordering::Relaxed)
+}
+
 #[derive(Debug,Clone,PartialEq)]
 pub enum Var {
-    SimpleVar(Symbol, Position),
-    FieldVar(Box<Var>, Symbol, Position),
-    SubscriptVar(Box<Var>, Box<Exp>, Position),
+    SimpleVar(Symbol, Position, NodeId),
+    FieldVar(Box<Var>, Symbol, Position, NodeId),
+    SubscriptVar(Box<Var>, Box<Exp>, Position, NodeId),
 }
 
 #[derive(Debug,Clone,PartialEq)]
 pub enum Exp {
-    VarExp(Box<Var>),
-    NilExp,
-    IntExp(i32),
-    StringExp(String, Position),
+    VarExp(Box<Var>, NodeId),
+    NilExp(NodeId),
+    IntExp(i32, NodeId),
+    StringExp(String, Position, NodeId),
     CallExp {
         func: Symbol,
         args: Vec<Box<Exp>>,
         pos: Position,
+        id: NodeId,
     },
 //    NewExp(Symbol, Position),
     OpExp {
@@ -27,28 +41,33 @@ pub enum Exp {
         op: Oper,
         right: Box<Exp>,
         pos: Position,
+        id: NodeId,
     },
     RecordExp {
         fields: Vec<(Symbol, Box<Exp>, Position)>,
         typ: Symbol,
         pos: Position,
+        id: NodeId,
     },
-    SeqExp(Vec<Box<Exp>>),
+    SeqExp(Vec<Box<Exp>>, NodeId),
     AssignExp {
         var: Box<Var>,
         exp: Box<Exp>,
         pos: Position,
+        id: NodeId,
     },
     IfExp {
         test: Box<Exp>,
         then_: Box<Exp>,
         else_: Option<Box<Exp>>,
         pos: Position,
+        id: NodeId,
     },
     WhileExp {
         test: Box<Exp>,
         body: Box<Exp>,
         pos: Position,
+        id: NodeId,
     },
     ForExp {
         var: Symbol,
@@ -56,19 +75,22 @@ pub enum Exp {
         lo: Box<Exp>,
         hi: Box<Exp>,
         body: Box<Exp>,
-        pos: Position
+        pos: Position,
+        id: NodeId,
     },
-    BreakExp(Position),
+    BreakExp(Position, NodeId),
     LetExp {
         decs: Vec<Box<Dec>>,
         body: Box<Exp>,
         pos: Position,
+        id: NodeId,
     },
     ArrayExp {
         typ: Symbol,
         size: Box<Exp>,
         init: Box<Exp>,
         pos: Position,
+        id: NodeId,
     },
 }
 
@@ -80,6 +102,7 @@ pub enum Dec {
         result: Option<(Symbol,Position)>,
         body: Box<Exp>,
         pos: Position,
+        id: NodeId,
     },
 
     VarDec {
@@ -88,11 +111,41 @@ pub enum Dec {
         typ: Option<(Symbol, Position)>,
         init: Box<Exp>,
         pos: Position,
+        id: NodeId,
     },
     TypeDec {
         name: Symbol,
         ty: Box<Ty>,
         pos: Position,
+        id: NodeId,
+    }
+}
+
+impl Var {
+    pub fn id(&self) -> NodeId {
+        match self {
+            &Var::SimpleVar(_, _, id) | &Var::FieldVar(_, _, _, id) | &Var::SubscriptVar(_, _, _, id) => id,
+        }
+    }
+}
+
+impl Exp {
+    pub fn id(&self) -> NodeId {
+        match self {
+            &Exp::VarExp(_, id) | &Exp::NilExp(id) | &Exp::IntExp(_, id) | &Exp::StringExp(_, _, id) |
+            &Exp::SeqExp(_, id) | &Exp::BreakExp(_, id) => id,
+            &Exp::CallExp { id, .. } | &Exp::OpExp { id, .. } | &Exp::RecordExp { id, .. } |
+            &Exp::AssignExp { id, .. } | &Exp::IfExp { id, .. } | &Exp::WhileExp { id, .. } |
+            &Exp::ForExp { id, .. } | &Exp::LetExp { id, .. } | &Exp::ArrayExp { id, .. } => id,
+        }
+    }
+}
+
+impl Dec {
+    pub fn id(&self) -> NodeId {
+        match self {
+            &Dec::FunDec { id, .. } | &Dec::VarDec { id, .. } | &Dec::TypeDec { id, .. } => id,
+        }
     }
 }
 
@@ -102,6 +155,7 @@ pub struct Field {
     pub escape: bool,
     pub typ: Symbol,
     pub pos: Position,
+    pub id: NodeId,
 }
 
 #[derive(Debug,Clone,PartialEq)]
@@ -123,4 +177,15 @@ pub enum Oper {
     LeOp,
     GtOp,
     GeOp
-}
\ No newline at end of file
+}
+
+// converts a position (a byte offset) into a 1-based line and column
+pub fn line_col(source: &str, pos: Position) -> (usize, usize) {
+    let before = &source[..pos.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let col = match before.rfind('\n') {
+        Some(i) => before[i + 1..].chars().count() + 1,
+        None => before.chars().count() + 1,
+    };
+    (line, col)
+}
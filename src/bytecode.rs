@@ -0,0 +1,658 @@
+use ast;
+use symbol::SymbolTable;
+use type_check::TypeMap;
+use types::{Ty, Table, builtin_functions};
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::rc::Rc;
+
+// A compiler from the type checked AST (with escapes computed) to the
+// bytecode of a stack machine, run by vm.rs.
+//
+// Every function has a fixed number of local slots: slot 0 holds the static
+// link, slot 1 the function's environment, then the parameters and the
+// other non-escaping variables. A call pushes the static link, nil for the
+// environment and the arguments, which become the first slots of the callee. Escaping variables live in the environment,
+// a heap record whose field 0 is the static link, so that nested functions
+// can reach them; the static link of a function is the environment of the
+// one declaring it. Every expression leaves exactly one value on the
+// operand stack, 0 for expressions without a value.
+
+pub const LINK_SLOT: u16 = 0;
+pub const ENV_SLOT: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Builtin {
+    Print,
+    PrintI,
+    Flush,
+    GetChar,
+    Ord,
+    Chr,
+    Size,
+    Substring,
+    Concat,
+    Not,
+    Exit,
+}
+
+impl Builtin {
+    fn from_name(name: &str) -> Builtin {
+        match name {
+            "print" => Builtin::Print,
+            "printi" => Builtin::PrintI,
+            "flush" => Builtin::Flush,
+            "getchar" => Builtin::GetChar,
+            "ord" => Builtin::Ord,
+            "chr" => Builtin::Chr,
+            "size" => Builtin::Size,
+            "substring" => Builtin::Substring,
+            "concat" => Builtin::Concat,
+            "not" => Builtin::Not,
+            "exit" => Builtin::Exit,
+            _ => panic!("unknown builtin {}", name),
+        }
+    }
+
+    pub fn arity(self) -> usize {
+        match self {
+            Builtin::Flush | Builtin::GetChar => 0,
+            Builtin::Concat => 2,
+            Builtin::Substring => 3,
+            _ => 1,
+        }
+    }
+}
+
+// Environments are reached by hops: 0 is the environment of the current
+// function, n > 0 the one reached by following the static link n - 1 times
+// from the current static link.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Int(i32),
+    Nil,
+    // a string constant, by index
+    Str(u32),
+    Pop,
+    GetLocal(u16),
+    SetLocal(u16),
+    // hops, index
+    GetEnv(u16, u16),
+    SetEnv(u16, u16),
+    PushEnv(u16),
+    // creates the environment of the current function with this many
+    // variables; only the first instruction of a function
+    MakeEnv(u16),
+    Add,
+    Sub,
+    Mul,
+    Div(ast::Position),
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Jump(u32),
+    JumpIfFalse(u32),
+    // function index, number of values making up the first local slots
+    Call(u32, u16),
+    CallBuiltin(Builtin, ast::Position),
+    Return,
+    // a record of the given number of fields from the top of the stack
+    Record(u16),
+    GetField(u16, ast::Position),
+    SetField(u16, ast::Position),
+    // size and initial value
+    Array(ast::Position),
+    GetIndex(ast::Position),
+    SetIndex(ast::Position),
+    Nop,
+}
+
+impl Op {
+    // the change in the height of the operand stack
+    fn stack_effect(&self) -> isize {
+        match self {
+            &Op::Int(_) | &Op::Nil | &Op::Str(_) | &Op::GetLocal(_) | &Op::GetEnv(_, _) | &Op::PushEnv(_) => 1,
+            &Op::Pop | &Op::SetLocal(_) | &Op::SetEnv(_, _) | &Op::JumpIfFalse(_) => -1,
+            &Op::Add | &Op::Sub | &Op::Mul | &Op::Div(_) | &Op::Eq | &Op::Ne | &Op::Lt | &Op::Le |
+            &Op::Gt | &Op::Ge | &Op::Array(_) | &Op::GetIndex(_) => -1,
+            &Op::Call(_, args) => 1 - args as isize,
+            &Op::CallBuiltin(builtin, _) => 1 - builtin.arity() as isize,
+            &Op::Record(fields) => 1 - fields as isize,
+            &Op::SetField(_, _) => -2,
+            &Op::SetIndex(_) => -3,
+            &Op::Return => -1,
+            &Op::MakeEnv(_) | &Op::Jump(_) | &Op::GetField(_, _) | &Op::Nop => 0,
+        }
+    }
+}
+
+pub struct Function {
+    pub name: String,
+    // the number of values passed, including the static link and the
+    // environment slot
+    pub arity: usize,
+    pub locals: usize,
+    pub code: Vec<Op>,
+}
+
+// The main program is function 0, which takes a nil static link.
+pub struct Program {
+    pub functions: Vec<Function>,
+    pub strings: Vec<String>,
+}
+
+#[derive(Clone, Copy)]
+enum Slot {
+    Local(u16),
+    Env(u16),
+}
+
+enum Entry {
+    // the depth of the declaring function and where the variable lives
+    Var(usize, Slot),
+    // the depth of the function itself and its index
+    Fun(usize, u32),
+    Builtin(Builtin),
+}
+
+type Env<'a> = Table<'a, Entry>;
+
+struct FunctionState {
+    name: String,
+    arity: usize,
+    depth: usize,
+    code: Vec<Op>,
+    locals: usize,
+    env_size: usize,
+    // whether functions are declared inside, whose static link is the
+    // environment even if no variable escapes
+    declares_functions: bool,
+    // the height of the operand stack
+    height: usize,
+    // the height at the start of each enclosing loop and the jumps of its
+    // breaks, to be patched
+    loops: Vec<(usize, Vec<usize>)>,
+}
+
+struct Compiler<'a> {
+    symbol_table: &'a SymbolTable,
+    types: &'a TypeMap,
+    functions: Vec<Option<Function>>,
+    strings: Vec<String>,
+    string_indices: HashMap<String, u32>,
+    // the functions being compiled, innermost last
+    stack: Vec<FunctionState>,
+}
+
+impl<'a> Compiler<'a> {
+    fn function(&mut self) -> &mut FunctionState {
+        self.stack.last_mut().expect("no function being compiled")
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        let f = self.function();
+        f.height = (f.height as isize + op.stack_effect()) as usize;
+        f.code.push(op);
+        f.code.len() - 1
+    }
+
+    fn here(&mut self) -> u32 {
+        self.function().code.len() as u32
+    }
+
+    fn patch(&mut self, at: usize, target: u32) {
+        let f = self.function();
+        f.code[at] = match f.code[at] {
+            Op::Jump(_) => Op::Jump(target),
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(target),
+            _ => panic!("patching a non-jump"),
+        };
+    }
+
+    fn new_local(&mut self) -> u16 {
+        let f = self.function();
+        f.locals += 1;
+        (f.locals - 1) as u16
+    }
+
+    fn new_var(&mut self, escape: bool) -> Slot {
+        if escape {
+            let f = self.function();
+            f.env_size += 1;
+            Slot::Env(f.env_size as u16)
+        } else {
+            Slot::Local(self.new_local())
+        }
+    }
+
+    // the hops from the current function to the environment of the
+    // function at depth
+    fn hops(&mut self, depth: usize) -> u16 {
+        (self.function().depth - depth) as u16
+    }
+
+    fn get_var(&mut self, depth: usize, slot: Slot) {
+        match slot {
+            Slot::Local(i) => self.emit(Op::GetLocal(i)),
+            Slot::Env(i) => {
+                let hops = self.hops(depth);
+                self.emit(Op::GetEnv(hops, i))
+            },
+        };
+    }
+
+    fn set_var(&mut self, depth: usize, slot: Slot) {
+        match slot {
+            Slot::Local(i) => self.emit(Op::SetLocal(i)),
+            Slot::Env(i) => {
+                let hops = self.hops(depth);
+                self.emit(Op::SetEnv(hops, i))
+            },
+        };
+    }
+
+    fn string(&mut self, s: &str) -> u32 {
+        if let Some(&i) = self.string_indices.get(s) {
+            return i;
+        }
+        let i = self.strings.len() as u32;
+        self.strings.push(s.to_owned());
+        self.string_indices.insert(s.to_owned(), i);
+        i
+    }
+
+    fn field_index(&self, record: &ast::Var, field: ast::Symbol) -> u16 {
+        match self.types.var_ty(record).map(|t| t.as_ref()) {
+            Some(&Ty::Record { ref fields, .. }) =>
+                fields.iter().position(|&(s, _)| s == field).expect("unknown field") as u16,
+            _ => panic!("field access on a non-record"),
+        }
+    }
+
+    fn var(&mut self, var: &ast::Var, env: &Env) {
+        match var {
+            &ast::Var::SimpleVar(symbol, _, _) => match env.look(symbol).map(|e| e.as_ref()) {
+                Some(&Entry::Var(depth, slot)) => self.get_var(depth, slot),
+                _ => panic!("unknown variable {}", self.symbol_table.name(&symbol)),
+            },
+            &ast::Var::FieldVar(ref record, field, pos, _) => {
+                let index = self.field_index(record, field);
+                self.var(record, env);
+                self.emit(Op::GetField(index, pos));
+            },
+            &ast::Var::SubscriptVar(ref array, ref index, pos, _) => {
+                self.var(array, env);
+                self.exp(index, env);
+                self.emit(Op::GetIndex(pos));
+            },
+        }
+    }
+
+    fn exp(&mut self, exp: &ast::Exp, env: &Env) {
+        use ast::Oper::*;
+
+        match exp {
+            &ast::Exp::VarExp(ref var, _) => self.var(var, env),
+            &ast::Exp::NilExp(_) => {
+                self.emit(Op::Nil);
+            },
+            &ast::Exp::IntExp(i, _) => {
+                self.emit(Op::Int(i));
+            },
+            &ast::Exp::StringExp(ref s, _, _) => {
+                let i = self.string(s);
+                self.emit(Op::Str(i));
+            },
+            &ast::Exp::CallExp{ func, ref args, pos, .. } => {
+                match env.look(func).map(|e| e.as_ref()) {
+                    Some(&Entry::Fun(depth, index)) => {
+                        let hops = self.hops(depth - 1);
+                        self.emit(Op::PushEnv(hops));
+                        self.emit(Op::Nil);
+                        for arg in args.iter() {
+                            self.exp(arg, env);
+                        }
+                        self.emit(Op::Call(index, args.len() as u16 + 2));
+                    },
+                    Some(&Entry::Builtin(builtin)) => {
+                        for arg in args.iter() {
+                            self.exp(arg, env);
+                        }
+                        self.emit(Op::CallBuiltin(builtin, pos));
+                    },
+                    _ => panic!("unknown function {}", self.symbol_table.name(&func)),
+                }
+            },
+            &ast::Exp::OpExp{ ref left, op, ref right, pos, .. } => {
+                self.exp(left, env);
+                self.exp(right, env);
+                self.emit(match op {
+                    PlusOp => Op::Add,
+                    MinusOp => Op::Sub,
+                    TimesOp => Op::Mul,
+                    DivideOp => Op::Div(pos),
+                    EqOp => Op::Eq,
+                    NeqOp => Op::Ne,
+                    LtOp => Op::Lt,
+                    LeOp => Op::Le,
+                    GtOp => Op::Gt,
+                    GeOp => Op::Ge,
+                });
+            },
+            &ast::Exp::RecordExp { ref fields, .. } => {
+                // the type checker ensures the fields are in declaration order
+                for &(_, ref exp, _) in fields.iter() {
+                    self.exp(exp, env);
+                }
+                self.emit(Op::Record(fields.len() as u16));
+            },
+            &ast::Exp::SeqExp(ref exps, _) => {
+                if exps.is_empty() {
+                    self.emit(Op::Int(0));
+                }
+                for (i, e) in exps.iter().enumerate() {
+                    if i > 0 {
+                        self.emit(Op::Pop);
+                    }
+                    self.exp(e, env);
+                }
+            },
+            &ast::Exp::AssignExp { ref var, ref exp, .. } => {
+                match var.as_ref() {
+                    &ast::Var::SimpleVar(symbol, _, _) => {
+                        self.exp(exp, env);
+                        match env.look(symbol).map(|e| e.as_ref()) {
+                            Some(&Entry::Var(depth, slot)) => self.set_var(depth, slot),
+                            _ => panic!("unknown variable {}", self.symbol_table.name(&symbol)),
+                        }
+                    },
+                    &ast::Var::FieldVar(ref record, field, pos, _) => {
+                        let index = self.field_index(record, field);
+                        self.var(record, env);
+                        self.exp(exp, env);
+                        self.emit(Op::SetField(index, pos));
+                    },
+                    &ast::Var::SubscriptVar(ref array, ref index, pos, _) => {
+                        self.var(array, env);
+                        self.exp(index, env);
+                        self.exp(exp, env);
+                        self.emit(Op::SetIndex(pos));
+                    },
+                }
+                self.emit(Op::Int(0));
+            },
+            &ast::Exp::IfExp { ref test, ref then_, ref else_, .. } => {
+                self.exp(test, env);
+                let to_else = self.emit(Op::JumpIfFalse(0));
+                let height = self.function().height;
+                self.exp(then_, env);
+                match else_ {
+                    &Some(ref else_) => {
+                        let to_end = self.emit(Op::Jump(0));
+                        let else_start = self.here();
+                        self.patch(to_else, else_start);
+                        // only one of the branches leaves its value
+                        self.function().height = height;
+                        self.exp(else_, env);
+                        let end = self.here();
+                        self.patch(to_end, end);
+                    },
+                    &None => {
+                        self.emit(Op::Pop);
+                        let end = self.here();
+                        self.patch(to_else, end);
+                        self.emit(Op::Int(0));
+                    },
+                }
+            },
+            &ast::Exp::WhileExp { ref test, ref body, .. } => {
+                let start = self.here();
+                self.exp(test, env);
+                let to_end = self.emit(Op::JumpIfFalse(0));
+                self.begin_loop();
+                self.exp(body, env);
+                self.emit(Op::Pop);
+                self.emit(Op::Jump(start));
+                let end = self.here();
+                self.patch(to_end, end);
+                self.end_loop(end);
+                self.emit(Op::Int(0));
+            },
+            &ast::Exp::ForExp { var, escape, ref lo, ref hi, ref body, .. } => {
+                let depth = self.function().depth;
+                let slot = self.new_var(escape);
+                let limit = self.new_local();
+                self.exp(lo, env);
+                self.set_var(depth, slot);
+                self.exp(hi, env);
+                self.emit(Op::SetLocal(limit));
+
+                self.get_var(depth, slot);
+                self.emit(Op::GetLocal(limit));
+                self.emit(Op::Le);
+                let to_end = self.emit(Op::JumpIfFalse(0));
+                let start = self.here();
+                self.begin_loop();
+                let mut body_env = Env::new(Some(env));
+                body_env.enter(var, Rc::new(Entry::Var(depth, slot)));
+                self.exp(body, &body_env);
+                self.emit(Op::Pop);
+                // checked before incrementing, so that hi = maxint terminates
+                self.get_var(depth, slot);
+                self.emit(Op::GetLocal(limit));
+                self.emit(Op::Lt);
+                let to_end2 = self.emit(Op::JumpIfFalse(0));
+                self.get_var(depth, slot);
+                self.emit(Op::Int(1));
+                self.emit(Op::Add);
+                self.set_var(depth, slot);
+                self.emit(Op::Jump(start));
+                let end = self.here();
+                self.patch(to_end, end);
+                self.patch(to_end2, end);
+                self.end_loop(end);
+                self.emit(Op::Int(0));
+            },
+            &ast::Exp::BreakExp(_, _) => {
+                // leaves the operands of enclosing expressions behind
+                let (height, _) = *self.function().loops.last().expect("break outside a loop");
+                let current = self.function().height;
+                for _ in height..current {
+                    self.function().code.push(Op::Pop);
+                }
+                let jump = self.emit(Op::Jump(0));
+                self.function().loops.last_mut().unwrap().1.push(jump);
+                // the code after a break is unreachable, but still counts
+                // as leaving a value
+                self.emit(Op::Int(0));
+            },
+            &ast::Exp::LetExp { ref decs, ref body, .. } => {
+                let mut let_env = Env::new(Some(env));
+                let mut i = 0;
+                while i < decs.len() {
+                    let mut j = i + 1;
+                    match decs[i].as_ref() {
+                        &ast::Dec::FunDec { .. } => {
+                            while j < decs.len() {
+                                if let &ast::Dec::FunDec { .. } = decs[j].as_ref() { j += 1 } else { break }
+                            }
+                            self.fun_decs(&decs[i..j], &mut let_env);
+                        },
+                        &ast::Dec::VarDec { name, escape, ref init, .. } => {
+                            self.exp(init, &let_env);
+                            let depth = self.function().depth;
+                            let slot = self.new_var(escape);
+                            self.set_var(depth, slot);
+                            let_env.enter(name, Rc::new(Entry::Var(depth, slot)));
+                        },
+                        &ast::Dec::TypeDec { .. } => (),
+                    }
+                    i = j;
+                }
+                self.exp(body, &let_env);
+            },
+            &ast::Exp::ArrayExp { ref size, ref init, pos, .. } => {
+                self.exp(size, env);
+                self.exp(init, env);
+                self.emit(Op::Array(pos));
+            },
+        }
+    }
+
+    fn begin_loop(&mut self) {
+        let f = self.function();
+        let height = f.height;
+        f.loops.push((height, vec![]));
+    }
+
+    fn end_loop(&mut self, end: u32) {
+        let (_, breaks) = self.function().loops.pop().unwrap();
+        for jump in breaks.into_iter() {
+            self.patch(jump, end);
+        }
+    }
+
+    fn fun_decs(&mut self, decs: &[Box<ast::Dec>], env: &mut Env) {
+        let depth = self.function().depth + 1;
+        self.function().declares_functions = true;
+        let mut indices = vec![];
+        for dec in decs.iter() {
+            if let &ast::Dec::FunDec { name, .. } = dec.as_ref() {
+                let index = self.functions.len() as u32;
+                self.functions.push(None);
+                env.enter(name, Rc::new(Entry::Fun(depth, index)));
+                indices.push(index);
+            }
+        }
+
+        for (dec, index) in decs.iter().zip(indices.into_iter()) {
+            if let &ast::Dec::FunDec { name, ref params, ref body, .. } = dec.as_ref() {
+                self.stack.push(FunctionState {
+                    name: self.symbol_table.name(&name).clone(),
+                    arity: params.len() + 2,
+                    depth: depth,
+                    code: vec![],
+                    locals: 2,
+                    env_size: 0,
+                    declares_functions: false,
+                    height: 0,
+                    loops: vec![],
+                });
+                self.emit(Op::MakeEnv(0));
+                let mut fun_env = Env::new(Some(env));
+                for param in params.iter() {
+                    let local = self.new_local();
+                    let slot = if param.escape {
+                        self.emit(Op::GetLocal(local));
+                        let slot = self.new_var(true);
+                        self.set_var(depth, slot);
+                        slot
+                    } else {
+                        Slot::Local(local)
+                    };
+                    fun_env.enter(param.name, Rc::new(Entry::Var(depth, slot)));
+                }
+                self.exp(body, &fun_env);
+                self.functions[index as usize] = Some(self.end_function());
+            }
+        }
+    }
+
+    fn end_function(&mut self) -> Function {
+        self.emit(Op::Return);
+        let mut f = self.stack.pop().unwrap();
+        f.code[0] = if f.env_size > 0 || f.declares_functions { Op::MakeEnv(f.env_size as u16) } else { Op::Nop };
+        Function {
+            name: f.name,
+            arity: f.arity,
+            locals: f.locals,
+            code: f.code,
+        }
+    }
+}
+
+pub fn compile(exp: &ast::Exp, types: &TypeMap, symbol_table: &mut SymbolTable) -> Program {
+    let mut env = Env::new(None);
+    for (name, _, _) in builtin_functions().into_iter() {
+        env.enter(symbol_table.symbol(name), Rc::new(Entry::Builtin(Builtin::from_name(name))));
+    }
+    let mut compiler = Compiler {
+        symbol_table: symbol_table,
+        types: types,
+        functions: vec![None],
+        strings: vec![],
+        string_indices: HashMap::new(),
+        stack: vec![],
+    };
+    compiler.stack.push(FunctionState {
+        name: String::from("tigermain"),
+        arity: 2,
+        depth: 0,
+        code: vec![],
+        locals: 2,
+        env_size: 0,
+        declares_functions: false,
+        height: 0,
+        loops: vec![],
+    });
+    compiler.emit(Op::MakeEnv(0));
+    compiler.exp(exp, &env);
+    compiler.functions[0] = Some(compiler.end_function());
+    Program {
+        functions: compiler.functions.into_iter().map(|f| f.unwrap()).collect(),
+        strings: compiler.strings,
+    }
+}
+
+// a listing of the program, one instruction per line
+pub fn disassemble(program: &Program) -> String {
+    let mut out = String::new();
+    for (i, s) in program.strings.iter().enumerate() {
+        let _ = writeln!(out, "string {}: {:?}", i, s);
+    }
+    for (i, f) in program.functions.iter().enumerate() {
+        let _ = writeln!(out, "\nfunction {} {} (arity {}, {} locals):", i, f.name, f.arity, f.locals);
+        for (pc, op) in f.code.iter().enumerate() {
+            let _ = writeln!(out, "{:5}  {:?}", pc, op);
+        }
+    }
+    out
+}
+
+#[test]
+fn test_compile() {
+    use driver::front_end;
+
+    let (exp, mut symbol_table, types) = front_end("let var n := 0 \
+                                                        function f(x: int) = let function g() = n := n + x in g() end \
+                                                    in f(1); \
+                                                       while 1 do (n := n + (if n > 3 then break; 1)); \
+                                                       if n then 10 else 20 end").unwrap();
+    let program = compile(&exp, &types, &mut symbol_table);
+    let names: Vec<&str> = program.functions.iter().map(|f| f.name.as_str()).collect();
+    assert_eq!(names, ["tigermain", "f", "g"]);
+
+    // n and x escape to g, which reaches n two static links up and x one up
+    assert_eq!(program.functions[1].code, [Op::MakeEnv(1), Op::GetLocal(2), Op::SetEnv(0, 1),
+                                           Op::PushEnv(0), Op::Nil, Op::Call(2, 2), Op::Return]);
+    assert_eq!(program.functions[2].code, [Op::Nop, Op::GetEnv(2, 1), Op::GetEnv(1, 1), Op::Add,
+                                           Op::SetEnv(2, 1), Op::Int(0), Op::Return]);
+
+    // the exit of the while and its break both jump past the jump back to
+    // the test, the break popping the n it left for the addition first
+    let main = &program.functions[0].code;
+    let jumps: Vec<(usize, Op)> = main.iter().cloned().enumerate().filter(|&(_, op)| match op {
+        Op::Jump(_) | Op::JumpIfFalse(_) => true,
+        _ => false,
+    }).collect();
+    assert_eq!(jumps, [(9, Op::JumpIfFalse(27)), (14, Op::JumpIfFalse(19)), (16, Op::Jump(27)),
+                       (26, Op::Jump(8)), (30, Op::JumpIfFalse(33)), (32, Op::Jump(34))]);
+    assert_eq!(main[8], Op::Int(1));
+    assert_eq!(main[15], Op::Pop);
+    assert_eq!(&main[27..], [Op::Int(0), Op::Pop, Op::GetEnv(0, 1), Op::JumpIfFalse(33),
+                             Op::Int(10), Op::Jump(34), Op::Int(20), Op::Return]);
+}
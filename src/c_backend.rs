@@ -0,0 +1,720 @@
+use ast;
+use symbol::SymbolTable;
+use type_check::TypeMap;
+use types::{Ty, Table, builtin_functions};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
+use std::rc::Rc;
+
+// A back end emitting C from the type checked AST (with escapes computed).
+// Every Tiger function becomes a C function whose first parameter is the
+// static link: a pointer to the frame struct of the enclosing function,
+// which holds that function's escaping variables and its own static link.
+// Other variables are plain C locals, renamed apart.
+//
+// C leaves the evaluation order of operands and arguments unspecified, so
+// expressions are compiled to side-effect free C expressions plus the
+// statements computing them; a value is saved in a temporary whenever the
+// code of a later operand could change it.
+
+const RUNTIME: &'static str = include_str!("c_runtime.h");
+
+const C_KEYWORDS: [&'static str; 37] = [
+    "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else", "enum",
+    "extern", "float", "for", "goto", "if", "inline", "int", "long", "register", "restrict", "return",
+    "short", "signed", "sizeof", "static", "struct", "switch", "typedef", "union", "unsigned", "void",
+    "volatile", "while", "_Bool", "_Complex", "_Imaginary"];
+
+enum Entry {
+    // the depth of the declaring function, the C name and whether the
+    // variable lives in the frame struct
+    Var(usize, String, bool),
+    // the depth of the function itself, the C name and the result type
+    Fun(usize, String, Rc<Ty>),
+    Builtin(String, Rc<Ty>),
+}
+
+type Env<'a> = Table<'a, Entry>;
+
+struct Function {
+    name: String,
+    depth: usize,
+    header: String,
+    // the escaping parameters, copied to the frame on entry
+    escaping_params: Vec<String>,
+    frame_fields: Vec<String>,
+    locals: Vec<String>,
+    body: String,
+    indent: usize,
+    // whether the frame struct is needed, by escaping variables or as the
+    // static link of a nested function
+    uses_frame: bool,
+}
+
+struct Emitter<'a> {
+    symbol_table: &'a SymbolTable,
+    types: &'a TypeMap,
+    next_name: usize,
+    // the records and arrays used, by their unique tag
+    type_decls: BTreeMap<u32, Rc<Ty>>,
+    strings: Vec<String>,
+    // the functions being compiled, innermost last
+    stack: Vec<Function>,
+    // the finished functions and their frames, by order of declaration
+    functions: Vec<Option<(Function, Option<String>)>>,
+}
+
+// a declaration of name with C type ty, e.g. "long x" or "struct tig_string *s"
+fn declare(ty: &str, name: &str) -> String {
+    if ty.ends_with('*') {
+        format!("{}{}", ty, name)
+    } else {
+        format!("{} {}", ty, name)
+    }
+}
+
+// value as the parenthesized condition of an if
+fn condition(value: &str) -> String {
+    let mut depth = 0;
+    for (i, c) in value.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 && i + 1 < value.len() {
+                    break;
+                } else if depth == 0 {
+                    return value.to_owned();
+                }
+            },
+            _ if depth == 0 => break,
+            _ => (),
+        }
+    }
+    format!("({})", value)
+}
+
+// literals and temporaries, whose value cannot change
+fn is_constant(value: &str) -> bool {
+    value == "NULL" || value.starts_with("(struct tig_string *)&") ||
+        value.parse::<i64>().is_ok() || value.trim_matches(|c| c == '(' || c == ')').parse::<i64>().is_ok() ||
+        (value.starts_with('t') && value[1..].parse::<usize>().is_ok())
+}
+
+fn c_string_literal(s: &str) -> String {
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                res.push('\\');
+                res.push(c);
+            },
+            // avoids trigraphs
+            '?' => res.push_str("\\?"),
+            c if c >= ' ' && c <= '~' => res.push(c),
+            _ => res.push_str(&format!("\\{:03o}", c as u32 & 0xff)),
+        }
+    }
+    res.push('"');
+    res
+}
+
+impl<'a> Emitter<'a> {
+    fn function(&mut self) -> &mut Function {
+        self.stack.last_mut().expect("no function being compiled")
+    }
+
+    fn emit(&mut self, line: &str) {
+        let f = self.function();
+        for _ in 0..f.indent {
+            f.body.push_str("    ");
+        }
+        f.body.push_str(line);
+        f.body.push('\n');
+    }
+
+    // a fresh C name for the Tiger name symbol
+    fn c_name(&mut self, symbol: ast::Symbol) -> String {
+        self.next_name += 1;
+        format!("{}_{}", self.symbol_table.name(&symbol), self.next_name)
+    }
+
+    fn field_name(&self, symbol: ast::Symbol) -> String {
+        let name = self.symbol_table.name(&symbol);
+        if C_KEYWORDS.contains(&name.as_str()) {
+            format!("{}_", name)
+        } else {
+            name.clone()
+        }
+    }
+
+    fn ctype(&mut self, ty: &Rc<Ty>) -> String {
+        let ty = Ty::actual(ty);
+        match ty.as_ref() {
+            &Ty::Int | &Ty::Bool => String::from("long"),
+            &Ty::String => String::from("struct tig_string *"),
+            &Ty::Nil => String::from("void *"),
+            &Ty::Unit => String::from("void"),
+            &Ty::Record { unique, .. } => {
+                self.type_decls.insert(unique, ty.clone());
+                format!("struct record_{} *", unique)
+            },
+            &Ty::Array { unique, .. } => {
+                self.type_decls.insert(unique, ty.clone());
+                format!("struct array_{} *", unique)
+            },
+            &Ty::Name(s, _) => panic!("unresolved type {}", self.symbol_table.name(&s)),
+        }
+    }
+
+    fn exp_ty(&self, exp: &ast::Exp) -> Rc<Ty> {
+        self.types.exp_ty(exp).expect("expression without a type").clone()
+    }
+
+    fn temp(&mut self, ty: &Rc<Ty>) -> String {
+        self.next_name += 1;
+        let name = format!("t{}", self.next_name);
+        let ctype = self.ctype(ty);
+        let decl = format!("{};", declare(&ctype, &name));
+        self.function().locals.push(decl);
+        name
+    }
+
+    fn spill(&mut self, value: String, ty: &Rc<Ty>) -> String {
+        if is_constant(&value) {
+            return value;
+        }
+        let t = self.temp(ty);
+        self.emit(&format!("{} = {};", t, value));
+        t
+    }
+
+    // runs f, returning its result and the code it emitted instead of
+    // emitting it
+    fn capture<T, F: FnOnce(&mut Emitter<'a>) -> T>(&mut self, f: F) -> (T, String) {
+        let saved = mem::replace(&mut self.function().body, String::new());
+        let res = f(self);
+        let code = mem::replace(&mut self.function().body, saved);
+        (res, code)
+    }
+
+    // compiles exps from left to right
+    fn exps_in_order(&mut self, exps: &[&ast::Exp], env: &Env) -> Vec<String> {
+        let mut values: Vec<(String, Rc<Ty>)> = vec![];
+        for &exp in exps.iter() {
+            let (value, code) = self.capture(|e| e.exp(exp, env));
+            if !code.is_empty() {
+                for i in 0..values.len() {
+                    let (value, ty) = values[i].clone();
+                    values[i].0 = self.spill(value, &ty);
+                }
+                self.function().body.push_str(&code);
+            }
+            let ty = self.exp_ty(exp);
+            values.push((value, ty));
+        }
+        values.into_iter().map(|(v, _)| v).collect()
+    }
+
+    // the address of the frame of the function at depth
+    fn frame_address(&mut self, depth: usize) -> String {
+        let current = self.function().depth;
+        if depth == current {
+            self.function().uses_frame = true;
+            return String::from("&frame");
+        }
+        let mut res = String::from("sl");
+        for _ in depth + 1..current {
+            res.push_str("->sl");
+        }
+        res
+    }
+
+    fn var(&mut self, var: &ast::Var, env: &Env) -> String {
+        match var {
+            &ast::Var::SimpleVar(symbol, _, _) => match env.look(symbol).map(|e| e.as_ref()) {
+                Some(&Entry::Var(depth, ref name, escape)) => {
+                    if depth == self.function().depth {
+                        if escape {
+                            format!("frame.{}", name)
+                        } else {
+                            name.clone()
+                        }
+                    } else {
+                        format!("{}->{}", self.frame_address(depth), name)
+                    }
+                },
+                _ => panic!("unknown variable {}", self.symbol_table.name(&symbol)),
+            },
+            &ast::Var::FieldVar(ref record, field, pos, _) => {
+                let record = self.var(record, env);
+                self.emit(&format!("if ({} == NULL)", record));
+                self.emit(&format!("    tig_nilError({});", pos));
+                format!("{}->{}", record, self.field_name(field))
+            },
+            &ast::Var::SubscriptVar(ref array, ref index, pos, _) => {
+                let array_ty = self.types.var_ty(array).expect("variable without a type").clone();
+                let array = self.var(array, env);
+                let (index, code) = self.capture(|e| e.exp(index, env));
+                let array = if code.is_empty() { array } else { self.spill(array, &array_ty) };
+                self.function().body.push_str(&code);
+                let index = self.spill(index, &Rc::new(Ty::Int));
+                self.emit(&format!("if ((unsigned long){} >= (unsigned long){}->size)", index, array));
+                self.emit(&format!("    tig_boundsError({}, {});", pos, index));
+                format!("{}->elems[{}]", array, index)
+            },
+        }
+    }
+
+    // the C expression for the value of exp, emitting the statements it
+    // needs; unit values are 0
+    fn exp(&mut self, exp: &ast::Exp, env: &Env) -> String {
+        use ast::Oper::*;
+
+        match exp {
+            &ast::Exp::VarExp(ref var, _) => self.var(var, env),
+            &ast::Exp::NilExp(_) => String::from("NULL"),
+            &ast::Exp::IntExp(i, _) => if i < 0 { format!("({})", i) } else { i.to_string() },
+            &ast::Exp::StringExp(ref s, _, _) => {
+                let name = format!("string_{}", self.strings.len());
+                self.strings.push(format!("static struct {{ long length; unsigned char chars[{}]; }} {} = {{ {}, {} }};",
+                                          s.chars().count() + 1, name, s.chars().count(), c_string_literal(s)));
+                format!("(struct tig_string *)&{}", name)
+            },
+            &ast::Exp::CallExp { func, ref args, .. } => {
+                let args: Vec<&ast::Exp> = args.iter().map(|a| a.as_ref()).collect();
+                let mut values = self.exps_in_order(&args, env);
+                let (name, result) = match env.look(func).map(|e| e.as_ref()) {
+                    Some(&Entry::Fun(depth, ref name, ref result)) => {
+                        values.insert(0, self.frame_address(depth - 1));
+                        (name.clone(), result.clone())
+                    },
+                    Some(&Entry::Builtin(ref name, ref result)) => (name.clone(), result.clone()),
+                    _ => panic!("unknown function {}", self.symbol_table.name(&func)),
+                };
+                let call = format!("{}({})", name, values.join(", "));
+                if *Ty::actual(&result) == Ty::Unit {
+                    self.emit(&format!("{};", call));
+                    String::from("0")
+                } else {
+                    let t = self.temp(&result);
+                    self.emit(&format!("{} = {};", t, call));
+                    t
+                }
+            },
+            &ast::Exp::OpExp { ref left, op, ref right, .. } => {
+                let strings = *Ty::actual(&self.exp_ty(left)) == Ty::String;
+                let values = self.exps_in_order(&[left, right], env);
+                let (l, r) = (&values[0], &values[1]);
+                let operator = match op {
+                    PlusOp => "+",
+                    MinusOp => "-",
+                    TimesOp => "*",
+                    DivideOp => "/",
+                    EqOp => "==",
+                    NeqOp => "!=",
+                    LtOp => "<",
+                    LeOp => "<=",
+                    GtOp => ">",
+                    GeOp => ">=",
+                };
+                match op {
+                    EqOp if strings => format!("tig_stringEqual({}, {})", l, r),
+                    NeqOp if strings => format!("!tig_stringEqual({}, {})", l, r),
+                    _ if strings => format!("(tig_stringCompare({}, {}) {} 0)", l, r, operator),
+                    _ => format!("({} {} {})", l, operator, r),
+                }
+            },
+            &ast::Exp::RecordExp { ref fields, .. } => {
+                let ty = self.exp_ty(exp);
+                let exps: Vec<&ast::Exp> = fields.iter().map(|f| f.1.as_ref()).collect();
+                let values = self.exps_in_order(&exps, env);
+                let unique = match Ty::actual(&ty).as_ref() {
+                    &Ty::Record { unique, .. } => unique,
+                    _ => panic!("record expression of a non-record type"),
+                };
+                let t = self.temp(&ty);
+                self.emit(&format!("{} = new_record_{}({});", t, unique, values.join(", ")));
+                t
+            },
+            &ast::Exp::SeqExp(ref exps, _) => {
+                let mut value = String::from("0");
+                for e in exps.iter() {
+                    value = self.exp(e, env);
+                }
+                value
+            },
+            &ast::Exp::AssignExp { ref var, ref exp, .. } => {
+                let ty = self.types.var_ty(var).expect("variable without a type").clone();
+                let dst = self.var(var, env);
+                let (value, code) = self.capture(|e| e.exp(exp, env));
+                match var.as_ref() {
+                    &ast::Var::SimpleVar(.., _) => {
+                        self.function().body.push_str(&code);
+                        self.emit(&format!("{} = {};", dst, value));
+                    },
+                    _ if code.is_empty() => self.emit(&format!("{} = {};", dst, value)),
+                    // the code of exp may change the record or array
+                    _ => {
+                        self.next_name += 1;
+                        let p = format!("t{}", self.next_name);
+                        let ctype = self.ctype(&ty);
+                        let pointer = if ctype.ends_with('*') { format!("{}*", ctype) } else { format!("{} *", ctype) };
+                        let decl = format!("{};", declare(&pointer, &p));
+                        self.function().locals.push(decl);
+                        self.emit(&format!("{} = &{};", p, dst));
+                        self.function().body.push_str(&code);
+                        self.emit(&format!("*{} = {};", p, value));
+                    },
+                }
+                String::from("0")
+            },
+            &ast::Exp::IfExp { ref test, ref then_, ref else_, .. } => {
+                let test = self.exp(test, env);
+                let mut ty = self.exp_ty(exp);
+                if *Ty::actual(&ty) == Ty::Nil {
+                    if let &Some(ref else_) = else_ {
+                        ty = self.exp_ty(else_);
+                    }
+                }
+                let result = match (else_, Ty::actual(&ty).as_ref()) {
+                    (&Some(_), &Ty::Unit) | (&None, _) => None,
+                    _ => Some(self.temp(&ty)),
+                };
+
+                self.emit(&format!("if {} {{", condition(&test)));
+                self.branch(then_, &result, env);
+                if let &Some(ref else_) = else_ {
+                    self.emit("} else {");
+                    self.branch(else_, &result, env);
+                }
+                self.emit("}");
+                result.unwrap_or(String::from("0"))
+            },
+            &ast::Exp::WhileExp { ref test, ref body, .. } => {
+                self.emit("while (1) {");
+                self.function().indent += 1;
+                let test = self.exp(test, env);
+                self.emit(&format!("if (!{})", test));
+                self.emit("    break;");
+                self.exp(body, env);
+                self.function().indent -= 1;
+                self.emit("}");
+                String::from("0")
+            },
+            &ast::Exp::ForExp { var, escape, ref lo, ref hi, ref body, .. } => {
+                let name = self.c_name(var);
+                let depth = self.function().depth;
+                let i = self.declare_var(&name, &Rc::new(Ty::Int), escape);
+                let lo = self.exp(lo, env);
+                self.emit(&format!("{} = {};", i, lo));
+                let hi = self.exp(hi, env);
+                let limit = self.temp(&Rc::new(Ty::Int));
+                self.emit(&format!("{} = {};", limit, hi));
+
+                // the limit is tested before incrementing, so that a loop up
+                // to the largest integer does not overflow
+                let mut body_env = Env::new(Some(env));
+                body_env.enter(var, Rc::new(Entry::Var(depth, name, escape)));
+                self.emit(&format!("if ({} <= {}) {{", i, limit));
+                self.emit("    while (1) {");
+                self.function().indent += 2;
+                self.exp(body, &body_env);
+                self.emit(&format!("if ({} >= {})", i, limit));
+                self.emit("    break;");
+                self.emit(&format!("{} = {} + 1;", i, i));
+                self.function().indent -= 2;
+                self.emit("    }");
+                self.emit("}");
+                String::from("0")
+            },
+            &ast::Exp::BreakExp(_, _) => {
+                self.emit("break;");
+                String::from("0")
+            },
+            &ast::Exp::LetExp { ref decs, ref body, .. } => {
+                let mut let_env = Env::new(Some(env));
+                let mut i = 0;
+                while i < decs.len() {
+                    let mut j = i + 1;
+                    match decs[i].as_ref() {
+                        &ast::Dec::FunDec { .. } => {
+                            while j < decs.len() {
+                                if let &ast::Dec::FunDec { .. } = decs[j].as_ref() { j += 1 } else { break }
+                            }
+                            self.fun_decs(&decs[i..j], &mut let_env);
+                        },
+                        &ast::Dec::VarDec { name, escape, ref init, .. } => {
+                            let ty = self.types.dec_ty(&decs[i]).expect("declaration without a type").clone();
+                            let value = self.exp(init, &let_env);
+                            let c_name = self.c_name(name);
+                            let var = self.declare_var(&c_name, &ty, escape);
+                            self.emit(&format!("{} = {};", var, value));
+                            let depth = self.function().depth;
+                            let_env.enter(name, Rc::new(Entry::Var(depth, c_name, escape)));
+                        },
+                        &ast::Dec::TypeDec { .. } => (),
+                    }
+                    i = j;
+                }
+                self.exp(body, &let_env)
+            },
+            &ast::Exp::ArrayExp { ref size, ref init, .. } => {
+                let ty = self.exp_ty(exp);
+                let values = self.exps_in_order(&[size, init], env);
+                let unique = match Ty::actual(&ty).as_ref() {
+                    &Ty::Array { unique, .. } => unique,
+                    _ => panic!("array expression of a non-array type"),
+                };
+                let t = self.temp(&ty);
+                self.emit(&format!("{} = new_array_{}({}, {});", t, unique, values[0], values[1]));
+                t
+            },
+        }
+    }
+
+    // one branch of an if, assigning its value to result if there is one
+    fn branch(&mut self, exp: &ast::Exp, result: &Option<String>, env: &Env) {
+        self.function().indent += 1;
+        let value = self.exp(exp, env);
+        if let &Some(ref result) = result {
+            self.emit(&format!("{} = {};", result, value));
+        }
+        self.function().indent -= 1;
+    }
+
+    // declares a variable of the current function, returning how to access it
+    fn declare_var(&mut self, name: &str, ty: &Rc<Ty>, escape: bool) -> String {
+        let ctype = self.ctype(ty);
+        let decl = format!("{};", declare(&ctype, name));
+        let f = self.function();
+        if escape {
+            f.frame_fields.push(decl);
+            f.uses_frame = true;
+            format!("frame.{}", name)
+        } else {
+            f.locals.push(decl);
+            name.to_owned()
+        }
+    }
+
+    fn fun_decs(&mut self, decs: &[Box<ast::Dec>], env: &mut Env) {
+        let depth = self.function().depth + 1;
+        let mut names = vec![];
+        for dec in decs.iter() {
+            if let &ast::Dec::FunDec { name, .. } = dec.as_ref() {
+                let c_name = self.c_name(name);
+                let result = self.types.dec_ty(dec).expect("declaration without a type").clone();
+                env.enter(name, Rc::new(Entry::Fun(depth, c_name.clone(), result)));
+                names.push(c_name);
+            }
+        }
+
+        let parent_frame = {
+            let parent = self.function();
+            parent.uses_frame = true;
+            format!("struct frame_{}", parent.name)
+        };
+        for (dec, c_name) in decs.iter().zip(names.into_iter()) {
+            if let &ast::Dec::FunDec { ref params, ref body, .. } = dec.as_ref() {
+                let result = self.types.dec_ty(dec).expect("declaration without a type").clone();
+                let result_ctype = self.ctype(&result);
+                let mut fun_env = Env::new(Some(env));
+                let mut param_decls = vec![format!("{} *sl", parent_frame)];
+                let mut escaping_params = vec![];
+                let mut frame_fields = vec![];
+                for param in params.iter() {
+                    let ty = self.types.param_ty(param).expect("parameter without a type").clone();
+                    let ctype = self.ctype(&ty);
+                    let name = self.c_name(param.name);
+                    param_decls.push(declare(&ctype, &name));
+                    if param.escape {
+                        frame_fields.push(format!("{};", declare(&ctype, &name)));
+                        escaping_params.push(name.clone());
+                    }
+                    fun_env.enter(param.name, Rc::new(Entry::Var(depth, name, param.escape)));
+                }
+
+                let index = self.functions.len();
+                self.functions.push(None);
+                self.stack.push(Function {
+                    name: c_name.clone(),
+                    depth: depth,
+                    header: format!("{}({})", declare(&result_ctype, &c_name), param_decls.join(", ")),
+                    uses_frame: !escaping_params.is_empty(),
+                    escaping_params: escaping_params,
+                    frame_fields: frame_fields,
+                    locals: vec![],
+                    body: String::new(),
+                    indent: 1,
+                });
+                let value = self.exp(body, &fun_env);
+                if *Ty::actual(&result) != Ty::Unit {
+                    self.emit(&format!("return {};", value));
+                }
+                let function = self.stack.pop().unwrap();
+                self.functions[index] = Some((function, Some(parent_frame.clone())));
+            }
+        }
+    }
+
+    // the definitions of the record and array types used, with their
+    // constructors
+    fn type_decls(&mut self) -> (String, String) {
+        let mut done = BTreeSet::new();
+        let mut structs = String::new();
+        let mut constructors = String::new();
+        loop {
+            let pending: Vec<(u32, Rc<Ty>)> = self.type_decls.iter()
+                .filter(|&(u, _)| !done.contains(u))
+                .map(|(&u, ty)| (u, ty.clone()))
+                .collect();
+            if pending.is_empty() {
+                break;
+            }
+            for (unique, ty) in pending.into_iter() {
+                done.insert(unique);
+                match ty.as_ref() {
+                    &Ty::Record { ref fields, .. } => {
+                        let name = format!("record_{}", unique);
+                        let mut members = vec![];
+                        let mut params = vec![];
+                        for &(symbol, ref ty) in fields.iter() {
+                            let field = self.field_name(symbol);
+                            let ctype = self.ctype(ty);
+                            members.push(format!("    {};\n", declare(&ctype, &field)));
+                            params.push(declare(&ctype, &field));
+                        }
+                        if members.is_empty() {
+                            members.push(String::from("    char unused;\n"));
+                            params.push(String::from("void"));
+                        }
+                        structs.push_str(&format!("struct {} {{\n{}}};\n\n", name, members.concat()));
+                        constructors.push_str(&format!("struct {} *new_{}({})\n{{\n", name, name, params.join(", ")));
+                        constructors.push_str(&format!("    struct {} *_r = tig_alloc(sizeof(struct {}));\n", name, name));
+                        for &(symbol, _) in fields.iter() {
+                            let field = self.field_name(symbol);
+                            constructors.push_str(&format!("    _r->{} = {};\n", field, field));
+                        }
+                        constructors.push_str("    return _r;\n}\n\n");
+                    },
+                    &Ty::Array { ref typ, .. } => {
+                        let name = format!("array_{}", unique);
+                        let ctype = self.ctype(typ);
+                        structs.push_str(&format!("struct {} {{\n    long size;\n    {};\n}};\n\n",
+                                                  name, declare(&ctype, "elems[]")));
+                        constructors.push_str(&format!("struct {} *new_{}(long size, {})\n{{\n", name, name,
+                                                       declare(&ctype, "init")));
+                        constructors.push_str(&format!("    struct {} *a;\n    long i;\n", name));
+                        constructors.push_str("    tig_checkArraySize(size);\n");
+                        constructors.push_str(&format!("    a = tig_alloc(sizeof(struct {}) + size * sizeof(a->elems[0]));\n",
+                                                       name));
+                        constructors.push_str("    a->size = size;\n    for (i = 0; i < size; i++)\n");
+                        constructors.push_str("        a->elems[i] = init;\n    return a;\n}\n\n");
+                    },
+                    _ => unreachable!(),
+                }
+            }
+        }
+
+        let forward: Vec<String> = self.type_decls.iter().map(|(&u, ty)| match ty.as_ref() {
+            &Ty::Record { .. } => format!("struct record_{};\n", u),
+            _ => format!("struct array_{};\n", u),
+        }).collect();
+        (format!("{}\n{}", forward.concat(), structs), constructors)
+    }
+}
+
+// Compiles a type checked program (with escapes computed) to a C program.
+pub fn emit_c(exp: &ast::Exp, types: &TypeMap, symbol_table: &mut SymbolTable) -> String {
+    let mut env = Env::new(None);
+    for (name, _, result) in builtin_functions().into_iter() {
+        env.enter(symbol_table.symbol(name), Rc::new(Entry::Builtin(format!("tig_{}", name), Rc::new(result))));
+    }
+
+    let mut emitter = Emitter {
+        symbol_table: symbol_table,
+        types: types,
+        next_name: 0,
+        type_decls: BTreeMap::new(),
+        strings: vec![],
+        stack: vec![],
+        functions: vec![None],
+    };
+    emitter.stack.push(Function {
+        name: String::from("tigermain"),
+        depth: 0,
+        header: String::from("void tigermain(void)"),
+        escaping_params: vec![],
+        frame_fields: vec![],
+        locals: vec![],
+        body: String::new(),
+        indent: 1,
+        uses_frame: false,
+    });
+    emitter.exp(exp, &env);
+    let main = emitter.stack.pop().unwrap();
+    emitter.functions[0] = Some((main, None));
+
+    let (structs, constructors) = emitter.type_decls();
+    let mut out = format!("{}\n{}", RUNTIME, structs);
+    let functions: Vec<(Function, Option<String>)> = emitter.functions.into_iter().map(|f| f.unwrap()).collect();
+    for &(ref f, ref parent_frame) in functions.iter() {
+        if f.uses_frame {
+            let sl = match parent_frame {
+                &Some(ref parent_frame) => format!("{} *sl", parent_frame),
+                &None => String::from("void *sl"),
+            };
+            out.push_str(&format!("struct frame_{} {{\n    {};\n", f.name, sl));
+            for field in f.frame_fields.iter() {
+                out.push_str(&format!("    {}\n", field));
+            }
+            out.push_str("};\n\n");
+        }
+    }
+    out.push_str(&constructors);
+    for &(ref f, _) in functions.iter() {
+        out.push_str(&format!("{};\n", f.header));
+    }
+    out.push('\n');
+    for s in emitter.strings.iter() {
+        out.push_str(&format!("{}\n", s));
+    }
+    out.push('\n');
+
+    for &(ref f, ref parent_frame) in functions.iter() {
+        out.push_str(&format!("{}\n{{\n", f.header));
+        if f.uses_frame {
+            out.push_str(&format!("    struct frame_{} frame;\n", f.name));
+        }
+        for local in f.locals.iter() {
+            out.push_str(&format!("    {}\n", local));
+        }
+        if f.uses_frame {
+            if parent_frame.is_some() {
+                out.push_str("    frame.sl = sl;\n");
+            } else {
+                out.push_str("    frame.sl = NULL;\n");
+            }
+        }
+        for param in f.escaping_params.iter() {
+            out.push_str(&format!("    frame.{} = {};\n", param, param));
+        }
+        out.push_str(&f.body);
+        out.push_str("}\n\n");
+    }
+    out.push_str("int main(void)\n{\n    tigermain();\n    fflush(stdout);\n    return 0;\n}\n");
+    out
+}
+
+#[test]
+fn test_helpers() {
+    assert_eq!(c_string_literal("a\"b\\c?\n"), r#""a\"b\\c\?\012""#);
+    assert_eq!(declare("long", "x"), "long x");
+    assert_eq!(declare("struct tig_string *", "s"), "struct tig_string *s");
+    assert_eq!(condition("(a < b)"), "(a < b)");
+    assert_eq!(condition("(a) + (b)"), "((a) + (b))");
+    assert_eq!(condition("x"), "(x)");
+    assert!(is_constant("t3") && is_constant("(-1)") && !is_constant("x_3"));
+}
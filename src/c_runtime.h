@@ -0,0 +1,167 @@
+/* The runtime system of Tiger programs compiled to C. Strings are a length
+   followed by the characters, records and arrays are allocated on the heap
+   and never freed. Programs compiled to assembly share it, with TIG_GC
+   defined: their heap is garbage collected (see runtime.c). */
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+struct tig_string {
+    long length;
+    unsigned char chars[];
+};
+
+static struct tig_string tig_empty = { 0 };
+
+#ifdef TIG_GC
+/* Strings on the collected heap are tagged as such. A collection may move
+   the strings a function holds while it allocates: tig_gcRoot registers
+   the address of a variable holding one, until tig_gcUnroot(n) drops the
+   last n registered. */
+#define TIG_STRING 3
+void *tig_gcAlloc(long words, long header);
+void tig_gcRoot(void *root);
+void tig_gcUnroot(long n);
+#else
+#define tig_gcRoot(root) ((void)0)
+#define tig_gcUnroot(n) ((void)0)
+#endif
+
+void tig_fail(const char *message)
+{
+    fflush(stdout);
+    fprintf(stderr, "%s\n", message);
+    exit(1);
+}
+
+void *tig_alloc(size_t size)
+{
+    void *p = calloc(1, size ? size : 1);
+    if (!p)
+        tig_fail("out of memory");
+    return p;
+}
+
+void tig_checkArraySize(long size)
+{
+    if (size < 0)
+        tig_fail("negative array size");
+}
+
+void tig_nilError(long pos)
+{
+    fflush(stdout);
+    fprintf(stderr, "nil record dereference at position %ld\n", pos);
+    exit(1);
+}
+
+void tig_boundsError(long pos, long index)
+{
+    fflush(stdout);
+    fprintf(stderr, "index %ld out of bounds at position %ld\n", index, pos);
+    exit(1);
+}
+
+struct tig_string *tig_newString(long length)
+{
+#ifdef TIG_GC
+    struct tig_string *s = tig_gcAlloc(1 + (length + 7) / 8, TIG_STRING);
+#else
+    struct tig_string *s = tig_alloc(sizeof(struct tig_string) + length);
+#endif
+    s->length = length;
+    return s;
+}
+
+long tig_stringEqual(struct tig_string *a, struct tig_string *b)
+{
+    return a->length == b->length && memcmp(a->chars, b->chars, a->length) == 0;
+}
+
+long tig_stringCompare(struct tig_string *a, struct tig_string *b)
+{
+    long n = a->length < b->length ? a->length : b->length;
+    int c = memcmp(a->chars, b->chars, n);
+    if (c != 0)
+        return c < 0 ? -1 : 1;
+    return a->length < b->length ? -1 : a->length > b->length;
+}
+
+void tig_print(struct tig_string *s)
+{
+    fwrite(s->chars, 1, s->length, stdout);
+}
+
+void tig_printi(long i)
+{
+    printf("%ld", i);
+}
+
+void tig_flush(void)
+{
+    fflush(stdout);
+}
+
+struct tig_string *tig_chr(long i)
+{
+    struct tig_string *s;
+    if (i < 0 || i > 255)
+        tig_fail("chr out of range");
+    s = tig_newString(1);
+    s->chars[0] = (unsigned char)i;
+    return s;
+}
+
+struct tig_string *tig_getchar(void)
+{
+    int c = getchar();
+    if (c == EOF)
+        return &tig_empty;
+    return tig_chr(c);
+}
+
+long tig_ord(struct tig_string *s)
+{
+    return s->length ? s->chars[0] : -1;
+}
+
+long tig_size(struct tig_string *s)
+{
+    return s->length;
+}
+
+struct tig_string *tig_substring(struct tig_string *s, long first, long n)
+{
+    struct tig_string *res;
+    if (first < 0 || n < 0 || first > s->length - n)
+        tig_fail("substring out of range");
+    tig_gcRoot(&s);
+    res = tig_newString(n);
+    tig_gcUnroot(1);
+    memcpy(res->chars, s->chars + first, n);
+    return res;
+}
+
+struct tig_string *tig_concat(struct tig_string *a, struct tig_string *b)
+{
+    struct tig_string *res;
+    tig_gcRoot(&a);
+    tig_gcRoot(&b);
+    res = tig_newString(a->length + b->length);
+    tig_gcUnroot(2);
+    memcpy(res->chars, a->chars, a->length);
+    memcpy(res->chars + a->length, b->chars, b->length);
+    return res;
+}
+
+long tig_not(long i)
+{
+    return !i;
+}
+
+void tig_exit(long i)
+{
+    fflush(stdout);
+    exit((int)i);
+}
@@ -0,0 +1,521 @@
+use ir::{BinOp, Exp, Stm};
+use symbol::SymbolTable;
+use temp::{Label, Temp, TempGenerator, FIRST_FREE_TEMP};
+
+use std::collections::HashMap;
+
+// Canonical trees (Appel chapter 8): linearize, group into basic blocks and
+// schedule traces, so that the result is a list of statements without SEQ
+// or ESEQ, with CALLs only at the top of EXP(...) or MOVE(TEMP t, ...), and
+// with every CJUMP immediately followed by its false label.
+
+fn is_nop(stm: &Stm) -> bool {
+    match stm {
+        &Stm::Exp(ref e) => match e.as_ref() {
+            &Exp::Const(_) => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn nop() -> Stm {
+    Stm::Exp(Box::new(Exp::Const(0)))
+}
+
+// sequences two statements, dropping no-ops
+fn join(a: Stm, b: Stm) -> Stm {
+    if is_nop(&a) {
+        b
+    } else if is_nop(&b) {
+        a
+    } else {
+        Stm::Seq(Box::new(a), Box::new(b))
+    }
+}
+
+// whether a statement without ESEQs assigns temp t
+fn assigns(stm: &Stm, t: Temp) -> bool {
+    match stm {
+        &Stm::Seq(ref a, ref b) => assigns(a, t) || assigns(b, t),
+        &Stm::Move(ref dst, _) => **dst == Exp::Temp(t),
+        _ => false,
+    }
+}
+
+// a conservative approximation of whether stm and exp can be evaluated in
+// either order: exp is computed from constants and temps stm doesn't
+// assign (registers of the machine may be clobbered by calls, and a
+// division may trap). Keeping such an expression out of a new temp also
+// keeps addresses inside records and arrays out of temps live across
+// calls, which the garbage collector couldn't update.
+fn commute(stm: &Stm, exp: &Exp) -> bool {
+    if is_nop(stm) {
+        return true;
+    }
+    match exp {
+        &Exp::Name(_) | &Exp::Const(_) => true,
+        &Exp::Temp(t) => t.0 >= FIRST_FREE_TEMP && !assigns(stm, t),
+        &Exp::BinOp(op, ref a, ref b) => op != BinOp::Div && commute(stm, a) && commute(stm, b),
+        _ => false,
+    }
+}
+
+struct Canon<'a> {
+    gen: &'a mut TempGenerator,
+}
+
+impl<'a> Canon<'a> {
+    // pulls the statements out of a list of expressions, returning them
+    // together with the remaining side effect free expressions
+    fn reorder(&mut self, mut exps: Vec<Exp>) -> (Stm, Vec<Exp>) {
+        if exps.is_empty() {
+            return (nop(), vec![]);
+        }
+        let first = exps.remove(0);
+        let (stms, e) = self.do_exp(first);
+        // calls clobber the return value register, save the result at once
+        // (this also catches calls that are the value of an ESEQ)
+        let (stms, e) = if let Exp::Call(_, _) = e {
+            let t = self.gen.new_temp();
+            (join(stms, Stm::Move(Box::new(Exp::Temp(t)), Box::new(e))), Exp::Temp(t))
+        } else {
+            (stms, e)
+        };
+
+        let (stms2, mut rest) = self.reorder(exps);
+        if commute(&stms2, &e) {
+            rest.insert(0, e);
+            (join(stms, stms2), rest)
+        } else {
+            let t = self.gen.new_temp();
+            // a copy of a pointer is a pointer too
+            if let Exp::Temp(p) = e {
+                if self.gen.is_pointer(p) {
+                    self.gen.mark_pointer(t);
+                }
+            }
+            rest.insert(0, Exp::Temp(t));
+            (join(stms, join(Stm::Move(Box::new(Exp::Temp(t)), Box::new(e)), stms2)), rest)
+        }
+    }
+
+    fn do_exp(&mut self, exp: Exp) -> (Stm, Exp) {
+        match exp {
+            Exp::BinOp(op, a, b) => {
+                let (s, mut l) = self.reorder(vec![*a, *b]);
+                let b = l.pop().unwrap();
+                let a = l.pop().unwrap();
+                (s, Exp::BinOp(op, Box::new(a), Box::new(b)))
+            },
+            Exp::Mem(a) => {
+                let (s, mut l) = self.reorder(vec![*a]);
+                (s, Exp::Mem(Box::new(l.pop().unwrap())))
+            },
+            Exp::ESeq(s, e) => {
+                let stms = self.do_stm(*s);
+                let (stms2, e) = self.do_exp(*e);
+                (join(stms, stms2), e)
+            },
+            Exp::Call(f, args) => {
+                let mut exps = vec![*f];
+                exps.extend(args.into_iter());
+                let (s, mut l) = self.reorder(exps);
+                let f = l.remove(0);
+                (s, Exp::Call(Box::new(f), l))
+            },
+            e => (nop(), e),
+        }
+    }
+
+    fn do_stm(&mut self, stm: Stm) -> Stm {
+        match stm {
+            Stm::Seq(a, b) => {
+                let a = self.do_stm(*a);
+                let b = self.do_stm(*b);
+                join(a, b)
+            },
+            Stm::Jump(e, labels) => {
+                let (s, mut l) = self.reorder(vec![*e]);
+                join(s, Stm::Jump(Box::new(l.pop().unwrap()), labels))
+            },
+            Stm::CJump(op, a, b, t, f) => {
+                let (s, mut l) = self.reorder(vec![*a, *b]);
+                let b = l.pop().unwrap();
+                let a = l.pop().unwrap();
+                join(s, Stm::CJump(op, Box::new(a), Box::new(b), t, f))
+            },
+            Stm::Move(dst, src) => {
+                match (*dst, *src) {
+                    (Exp::Temp(t), Exp::Call(f, args)) => {
+                        let mut exps = vec![*f];
+                        exps.extend(args.into_iter());
+                        let (s, mut l) = self.reorder(exps);
+                        let f = l.remove(0);
+                        join(s, Stm::Move(Box::new(Exp::Temp(t)), Box::new(Exp::Call(Box::new(f), l))))
+                    },
+                    (Exp::Temp(t), src) => {
+                        let (s, mut l) = self.reorder(vec![src]);
+                        join(s, Stm::Move(Box::new(Exp::Temp(t)), Box::new(l.pop().unwrap())))
+                    },
+                    (Exp::Mem(addr), src) => {
+                        let (s, mut l) = self.reorder(vec![*addr, src]);
+                        let src = l.pop().unwrap();
+                        let addr = l.pop().unwrap();
+                        join(s, Stm::Move(Box::new(Exp::Mem(Box::new(addr))), Box::new(src)))
+                    },
+                    (Exp::ESeq(s, e), src) => {
+                        self.do_stm(Stm::Seq(s, Box::new(Stm::Move(e, Box::new(src)))))
+                    },
+                    (dst, _) => panic!("cannot move to {:?}", dst),
+                }
+            },
+            Stm::Exp(e) => {
+                match *e {
+                    Exp::Call(f, args) => {
+                        let mut exps = vec![*f];
+                        exps.extend(args.into_iter());
+                        let (s, mut l) = self.reorder(exps);
+                        let f = l.remove(0);
+                        join(s, Stm::Exp(Box::new(Exp::Call(Box::new(f), l))))
+                    },
+                    e => {
+                        let (s, mut l) = self.reorder(vec![e]);
+                        join(s, Stm::Exp(Box::new(l.pop().unwrap())))
+                    },
+                }
+            },
+            s => s,
+        }
+    }
+}
+
+fn flatten(stm: Stm, out: &mut Vec<Stm>) {
+    match stm {
+        Stm::Seq(a, b) => {
+            flatten(*a, out);
+            flatten(*b, out);
+        },
+        s => out.push(s),
+    }
+}
+
+// Removes all ESEQs and moves CALLs to the top level, returning a flat
+// list of statements without SEQs.
+pub fn linearize(stm: Stm, gen: &mut TempGenerator) -> Vec<Stm> {
+    let stm = Canon { gen: gen }.do_stm(stm);
+    let mut stms = vec![];
+    flatten(stm, &mut stms);
+    stms
+}
+
+// Splits a linearized statement list into basic blocks, each starting with
+// a LABEL and ending with a JUMP or CJUMP. The returned label is where the
+// last block jumps to at the end of the list.
+pub fn basic_blocks(stms: Vec<Stm>, gen: &mut TempGenerator, symbol_table: &mut SymbolTable)
+                    -> (Vec<Vec<Stm>>, Label) {
+    let done = gen.new_label(symbol_table);
+    let mut blocks: Vec<Vec<Stm>> = vec![];
+    let mut current: Vec<Stm> = vec![];
+
+    for stm in stms.into_iter() {
+        if let Stm::Label(l) = stm {
+            if !current.is_empty() {
+                // fall through into the new block
+                current.push(Stm::Jump(Box::new(Exp::Name(l)), vec![l]));
+                blocks.push(current);
+            }
+            current = vec![stm];
+            continue;
+        }
+
+        if current.is_empty() {
+            current.push(Stm::Label(gen.new_label(symbol_table)));
+        }
+        let ends_block = match stm {
+            Stm::Jump(_, _) | Stm::CJump(_, _, _, _, _) => true,
+            _ => false,
+        };
+        current.push(stm);
+        if ends_block {
+            blocks.push(current);
+            current = vec![];
+        }
+    }
+
+    if !current.is_empty() {
+        current.push(Stm::Jump(Box::new(Exp::Name(done)), vec![done]));
+        blocks.push(current);
+    }
+    (blocks, done)
+}
+
+fn block_label(block: &Vec<Stm>) -> Label {
+    match block[0] {
+        Stm::Label(l) => l,
+        _ => panic!("basic block does not start with a label"),
+    }
+}
+
+fn successors(block: &Vec<Stm>) -> Vec<Label> {
+    match block.last() {
+        Some(&Stm::Jump(_, ref labels)) => labels.clone(),
+        // the false label first, as it should follow the block
+        Some(&Stm::CJump(_, _, _, t, f)) => vec![f, t],
+        _ => panic!("basic block does not end with a jump"),
+    }
+}
+
+// Orders the basic blocks into traces so that every CJUMP is followed by
+// its false label, and removes jumps to the immediately following label.
+pub fn trace_schedule(blocks: Vec<Vec<Stm>>, done: Label, gen: &mut TempGenerator,
+                      symbol_table: &mut SymbolTable) -> Vec<Stm> {
+    let mut index: HashMap<Label, usize> = HashMap::new();
+    for (i, block) in blocks.iter().enumerate() {
+        index.insert(block_label(block), i);
+    }
+
+    let mut marked = vec![false; blocks.len()];
+    let mut order = vec![];
+    for start in 0..blocks.len() {
+        let mut i = start;
+        while !marked[i] {
+            marked[i] = true;
+            order.push(i);
+            let next = successors(&blocks[i]).into_iter()
+                .filter_map(|l| index.get(&l).cloned())
+                .find(|&j| !marked[j]);
+            match next {
+                Some(j) => i = j,
+                None => break,
+            }
+        }
+    }
+
+    let mut blocks: Vec<Option<Vec<Stm>>> = blocks.into_iter().map(Some).collect();
+    let mut stms: Vec<Stm> = vec![];
+    for (n, &i) in order.iter().enumerate() {
+        let next_label = order.get(n + 1).map(|&j| block_label(blocks[j].as_ref().unwrap()));
+        let mut block = blocks[i].take().unwrap();
+        let last = block.pop().unwrap();
+        stms.extend(block.into_iter());
+
+        match last {
+            Stm::CJump(op, a, b, t, f) => {
+                if Some(f) == next_label {
+                    stms.push(Stm::CJump(op, a, b, t, f));
+                } else if Some(t) == next_label {
+                    stms.push(Stm::CJump(op.not(), a, b, f, t));
+                } else {
+                    let f2 = gen.new_label(symbol_table);
+                    stms.push(Stm::CJump(op, a, b, t, f2));
+                    stms.push(Stm::Label(f2));
+                    stms.push(Stm::Jump(Box::new(Exp::Name(f)), vec![f]));
+                }
+            },
+            Stm::Jump(e, labels) => {
+                let falls_through = match *e {
+                    Exp::Name(l) => Some(l) == next_label,
+                    _ => false,
+                };
+                if !falls_through {
+                    stms.push(Stm::Jump(e, labels));
+                }
+            },
+            s => stms.push(s),
+        }
+    }
+    stms.push(Stm::Label(done));
+    stms
+}
+
+// All three phases in a row.
+pub fn canonicalize(stm: Stm, gen: &mut TempGenerator, symbol_table: &mut SymbolTable) -> Vec<Stm> {
+    let stms = linearize(stm, gen);
+    let (blocks, done) = basic_blocks(stms, gen, symbol_table);
+    trace_schedule(blocks, done, gen, symbol_table)
+}
+
+#[test]
+fn test_canonical_form() {
+    let mut table = SymbolTable::new();
+    let mut gen = TempGenerator::new();
+    let f = Label(table.symbol("f"));
+    let call = |arg: i32| Exp::Call(Box::new(Exp::Name(f)), vec![Exp::Const(arg)]);
+
+    // MOVE(t, f(1) + ESEQ(EXP(f(2)), f(3)))
+    let stm = Stm::Move(Box::new(Exp::Temp(Temp(100))),
+                        Box::new(Exp::BinOp(BinOp::Plus, Box::new(call(1)),
+                                            Box::new(Exp::ESeq(Box::new(Stm::Exp(Box::new(call(2)))),
+                                                               Box::new(call(3)))))));
+    let stms = canonicalize(stm, &mut gen, &mut table);
+
+    for stm in stms.iter() {
+        match stm {
+            &Stm::Seq(_, _) => panic!("SEQ left after canonicalization"),
+            &Stm::Move(ref dst, ref src) => {
+                if let &Exp::Call(_, _) = src.as_ref() {
+                    assert!(if let &Exp::Temp(_) = dst.as_ref() { true } else { false });
+                }
+            },
+            _ => (),
+        }
+    }
+    // f(1) is saved to a temp before f(2) and f(3) are called
+    let calls: Vec<i32> = stms.iter().filter_map(|s| match s {
+        &Stm::Move(_, ref src) | &Stm::Exp(ref src) => match src.as_ref() {
+            &Exp::Call(_, ref args) => match args[0] {
+                Exp::Const(i) => Some(i),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }).collect();
+    assert_eq!(calls, vec![1, 2, 3]);
+}
+
+#[test]
+fn test_canonicalize_preserves_semantics() {
+    use ir::RelOp;
+    use ir_interp::Interpreter;
+
+    // a small xorshift generator, so that failures are reproducible
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self, n: u32) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as u32
+        }
+    }
+
+    struct Generator<'a> {
+        random: Random,
+        table: &'a mut SymbolTable,
+        gen: &'a mut TempGenerator,
+        f: Label,
+    }
+
+    impl<'a> Generator<'a> {
+        fn temp(&mut self) -> Exp {
+            Exp::Temp(Temp(200 + self.random.next(4)))
+        }
+
+        fn address(&mut self) -> Exp {
+            Exp::Const(8 * self.random.next(4) as i32)
+        }
+
+        fn exp(&mut self, depth: u32) -> Exp {
+            let choice = if depth == 0 { self.random.next(2) } else { self.random.next(7) };
+            match choice {
+                0 => Exp::Const(self.random.next(10) as i32),
+                1 => self.temp(),
+                2 => {
+                    let ops = [BinOp::Plus, BinOp::Minus, BinOp::Mul];
+                    let op = ops[self.random.next(3) as usize];
+                    Exp::BinOp(op, Box::new(self.exp(depth - 1)), Box::new(self.exp(depth - 1)))
+                },
+                3 => Exp::Mem(Box::new(self.address())),
+                4 => {
+                    let n = self.random.next(3);
+                    let args = (0..n).map(|_| self.exp(depth - 1)).collect();
+                    Exp::Call(Box::new(Exp::Name(self.f)), args)
+                },
+                _ => Exp::ESeq(Box::new(self.stm(depth - 1)), Box::new(self.exp(depth - 1))),
+            }
+        }
+
+        fn stm(&mut self, depth: u32) -> Stm {
+            let choice = if depth == 0 { self.random.next(2) } else { self.random.next(6) };
+            match choice {
+                0 => Stm::Move(Box::new(self.temp()), Box::new(self.exp(depth))),
+                1 => Stm::Move(Box::new(Exp::Mem(Box::new(self.address()))), Box::new(self.exp(depth))),
+                2 => Stm::Exp(Box::new(self.exp(depth))),
+                3 => Stm::Seq(Box::new(self.stm(depth - 1)), Box::new(self.stm(depth - 1))),
+                _ => {
+                    // if a < b then s1 else s2
+                    let t = self.gen.new_label(self.table);
+                    let f = self.gen.new_label(self.table);
+                    let end = self.gen.new_label(self.table);
+                    let (a, b) = (self.exp(depth - 1), self.exp(depth - 1));
+                    let (s1, s2) = (self.stm(depth - 1), self.stm(depth - 1));
+                    ::ir::seq(vec![
+                        Stm::CJump(RelOp::Lt, Box::new(a), Box::new(b), t, f),
+                        Stm::Label(t), s1, Stm::Jump(Box::new(Exp::Name(end)), vec![end]),
+                        Stm::Label(f), s2,
+                        Stm::Label(end),
+                    ])
+                },
+            }
+        }
+    }
+
+    fn has_eseq_or_call(exp: &Exp) -> bool {
+        match exp {
+            &Exp::ESeq(_, _) | &Exp::Call(_, _) => true,
+            &Exp::BinOp(_, ref a, ref b) => has_eseq_or_call(a) || has_eseq_or_call(b),
+            &Exp::Mem(ref a) => has_eseq_or_call(a),
+            _ => false,
+        }
+    }
+
+    fn check_canonical(stms: &[Stm]) {
+        for (i, stm) in stms.iter().enumerate() {
+            match stm {
+                &Stm::Seq(_, _) => panic!("SEQ left after canonicalization"),
+                &Stm::Move(ref dst, ref src) => {
+                    assert!(!has_eseq_or_call(dst));
+                    match (dst.as_ref(), src.as_ref()) {
+                        (&Exp::Temp(_), &Exp::Call(ref f, ref args)) => {
+                            assert!(!has_eseq_or_call(f) && !args.iter().any(has_eseq_or_call))
+                        },
+                        (_, src) => assert!(!has_eseq_or_call(src)),
+                    }
+                },
+                &Stm::Exp(ref e) => match e.as_ref() {
+                    &Exp::Call(ref f, ref args) => assert!(!has_eseq_or_call(f) && !args.iter().any(has_eseq_or_call)),
+                    e => assert!(!has_eseq_or_call(e)),
+                },
+                &Stm::CJump(_, ref a, ref b, _, f) => {
+                    assert!(!has_eseq_or_call(a) && !has_eseq_or_call(b));
+                    assert!(stms.get(i + 1) == Some(&Stm::Label(f)), "CJUMP not followed by its false label");
+                },
+                _ => (),
+            }
+        }
+    }
+
+    let mut table = SymbolTable::new();
+    let f = Label(table.symbol("f"));
+    for seed in 1..200 {
+        let mut gen = TempGenerator::new();
+        let stm = {
+            let mut generator = Generator {
+                random: Random(seed * 7919),
+                table: &mut table,
+                gen: &mut gen,
+                f: f,
+            };
+            generator.stm(4)
+        };
+
+        let mut original = Interpreter::new();
+        original.run(&[stm.clone()]).unwrap();
+
+        let stms = canonicalize(stm, &mut gen, &mut table);
+        check_canonical(&stms);
+        let mut canonical = Interpreter::new();
+        canonical.run(&stms).unwrap();
+
+        for t in 200..204 {
+            assert_eq!(original.temp(Temp(t)), canonical.temp(Temp(t)), "seed {}", seed);
+        }
+        for a in 0..4 {
+            assert_eq!(original.load(8 * a), canonical.load(8 * a), "seed {}", seed);
+        }
+        assert_eq!(original.calls, canonical.calls, "seed {}", seed);
+    }
+}
@@ -0,0 +1,367 @@
+use bytecode;
+use dot;
+use driver::{self, Mode, Target};
+use fmt;
+use lsp;
+use regalloc::Allocator;
+use repl::{self, Repl};
+use serialize;
+use x86_64::Syntax;
+
+use std::fs;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+
+// The command line of the tiger binary.
+
+pub const USAGE: &'static str = "\
+usage: tiger <command> [options] [file]
+
+commands:
+  parse   print the syntax tree (--format json or sexp for a dump with names)
+  check   type check, printing nothing
+  dot     print the syntax tree as a Graphviz graph
+  fmt     print the program formatted
+  ir      print the IR trees of the fragments (--emit canon for canonical trees)
+  asm     print the assembly, C or wasm text module for the target
+  run     run the program
+  repl    start an interactive session, loading file first if there is one
+  lsp     serve the Language Server Protocol on the standard input and output
+  build   compile to an executable, or to the stage given with --emit
+  help    print this message
+
+options:
+  --target TARGET  x86_64 (the default), x86_64-intel, riscv64, mips, aarch64, c or wasm
+  -O LEVEL         0 allocates registers by linear scan, 1 and 2 (the default) by coloring
+  -o FILE          where the output goes, - for the standard output
+  --emit STAGE     ast, dot, ir, canon, bytecode, asm or exe
+  --mode MODE      how run runs the program: interp (the default) or bytecode
+  --format FORMAT  how parse prints the tree: debug (the default), json or sexp
+  --types          give the types of the nodes that dot, or parse in json or
+                   sexp, prints
+
+Other than for repl and lsp, the program is read from the standard input if
+file is absent or -. It may also be a syntax tree as parse --format json
+prints it.
+";
+
+// What a command produces from a program.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    Ast,
+    Dot,
+    Ir,
+    Canon,
+    Bytecode,
+    Asm,
+    Exe,
+}
+
+impl Stage {
+    pub fn from_name(name: &str) -> Option<Stage> {
+        match name {
+            "ast" | "parse" => Some(Stage::Ast),
+            "dot" => Some(Stage::Dot),
+            "ir" => Some(Stage::Ir),
+            "canon" => Some(Stage::Canon),
+            "bytecode" => Some(Stage::Bytecode),
+            "asm" => Some(Stage::Asm),
+            "exe" => Some(Stage::Exe),
+            _ => None,
+        }
+    }
+}
+
+// How parse prints the syntax tree.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Debug,
+    Json,
+    Sexp,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "debug" => Some(Format::Debug),
+            "json" => Some(Format::Json),
+            "sexp" => Some(Format::Sexp),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Check,
+    Fmt,
+    Emit(Stage),
+    Run(Mode),
+    Repl,
+    Lsp,
+    Help,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub command: Command,
+    pub input: Option<String>,
+    pub output: Option<String>,
+    pub target: Target,
+    pub allocator: Allocator,
+    pub format: Format,
+    pub types: bool,
+}
+
+// the value of the option at args[*i], given either after an = or as the
+// next argument, which is then consumed
+fn option_value(args: &[String], i: &mut usize, name: &str) -> Result<String, String> {
+    let arg = &args[*i];
+    if arg.len() > name.len() {
+        let value = &arg[name.len()..];
+        return Ok(value.trim_start_matches('=').to_owned());
+    }
+    *i += 1;
+    args.get(*i).cloned().ok_or_else(|| format!("{} needs a value", name))
+}
+
+// the options of a command line without the program name
+pub fn parse_args(args: &[String]) -> Result<Options, String> {
+    let command = match args.first().map(|a| a.as_str()) {
+        Some("parse") => Command::Emit(Stage::Ast),
+        Some("check") => Command::Check,
+        Some("dot") => Command::Emit(Stage::Dot),
+        Some("fmt") => Command::Fmt,
+        Some("ir") => Command::Emit(Stage::Ir),
+        Some("asm") => Command::Emit(Stage::Asm),
+        Some("run") => Command::Run(Mode::Interpret),
+        Some("build") => Command::Emit(Stage::Exe),
+        Some("repl") => Command::Repl,
+        Some("lsp") => Command::Lsp,
+        Some("help") | Some("-h") | Some("--help") => Command::Help,
+        Some(c) => return Err(format!("unknown command {}", c)),
+        None => return Err(String::from("no command given")),
+    };
+    let mut options = Options {
+        command: command,
+        input: None,
+        output: None,
+        target: Target::X86_64(Syntax::Att),
+        allocator: Allocator::Coloring,
+        format: Format::Debug,
+        types: false,
+    };
+
+    let mut i = 1;
+    while i < args.len() {
+        let arg = args[i].clone();
+        if arg.starts_with("--target") {
+            let name = option_value(args, &mut i, "--target")?;
+            options.target = Target::from_name(&name).ok_or_else(|| format!("unknown target {}", name))?;
+        } else if arg.starts_with("--emit") {
+            let name = option_value(args, &mut i, "--emit")?;
+            let stage = Stage::from_name(&name).ok_or_else(|| format!("unknown stage {}", name))?;
+            match options.command {
+                Command::Emit(_) => options.command = Command::Emit(stage),
+                _ => return Err(String::from("--emit only goes with parse, dot, ir, asm and build")),
+            }
+        } else if arg.starts_with("--mode") {
+            let name = option_value(args, &mut i, "--mode")?;
+            let mode = Mode::from_name(&name).ok_or_else(|| format!("unknown mode {}", name))?;
+            match options.command {
+                Command::Run(_) => options.command = Command::Run(mode),
+                _ => return Err(String::from("--mode only goes with run")),
+            }
+        } else if arg.starts_with("--format") {
+            let name = option_value(args, &mut i, "--format")?;
+            options.format = Format::from_name(&name).ok_or_else(|| format!("unknown format {}", name))?;
+        } else if arg == "--types" {
+            options.types = true;
+        } else if arg.starts_with("-O") {
+            let level = option_value(args, &mut i, "-O")?;
+            options.allocator = match level.as_str() {
+                "0" => Allocator::LinearScan,
+                "1" | "2" => Allocator::Coloring,
+                _ => return Err(format!("unknown optimization level {}", level)),
+            };
+        } else if arg.starts_with("-o") {
+            options.output = Some(option_value(args, &mut i, "-o")?);
+        } else if arg == "-" || !arg.starts_with('-') {
+            if options.input.is_some() {
+                return Err(String::from("more than one input file"));
+            }
+            options.input = Some(arg);
+        } else {
+            return Err(format!("unknown option {}", arg));
+        }
+        i += 1;
+    }
+    let dump = options.command == Command::Emit(Stage::Ast);
+    if options.format != Format::Debug && !dump {
+        return Err(String::from("--format only goes with parse"));
+    }
+    if options.types && options.command != Command::Emit(Stage::Dot) && !(dump && options.format != Format::Debug) {
+        return Err(String::from("--types only goes with dot and parse --format json or sexp"));
+    }
+    Ok(options)
+}
+
+fn read_source(options: &Options, stdin: &mut dyn Read) -> Result<String, String> {
+    let mut source = String::new();
+    match options.input.as_deref() {
+        None | Some("-") => stdin.read_to_string(&mut source).map(|_| source).map_err(|e| format!("<stdin>: {}", e)),
+        Some(file) => fs::read_to_string(file).map_err(|e| format!("{}: {}", file, e)),
+    }
+}
+
+// the text of a stage other than an executable
+fn emit_text(stage: Stage, source: &str, options: &Options) -> Result<String, String> {
+    match stage {
+        Stage::Ast => {
+            let (exp, symbol_table, types) = driver::front_end(source)?;
+            let types = if options.types { Some(&types) } else { None };
+            Ok(match options.format {
+                Format::Debug => format!("{:#?}\n", exp),
+                Format::Json => format!("{}\n", serialize::to_json(&exp, &symbol_table, types).pretty()),
+                Format::Sexp => format!("{}\n", serialize::to_sexp(&serialize::to_json(&exp, &symbol_table, types))),
+            })
+        },
+        Stage::Dot => {
+            let (exp, symbol_table, types) = driver::front_end(source)?;
+            let mut out = vec![];
+            let _ = writeln!(out, "digraph G {{");
+            dot::render_ast(&mut out, &exp, &symbol_table, if options.types { Some(&types) } else { None });
+            let _ = writeln!(out, "}}");
+            Ok(String::from_utf8(out).unwrap())
+        },
+        Stage::Ir => driver::ir(source, options.target, false),
+        Stage::Canon => driver::ir(source, options.target, true),
+        Stage::Bytecode => {
+            let (exp, mut symbol_table, types) = driver::front_end(source)?;
+            Ok(bytecode::disassemble(&bytecode::compile(&exp, &types, &mut symbol_table)))
+        },
+        Stage::Asm => driver::compile(source, options.target, options.allocator),
+        Stage::Exe => Err(String::from("an executable is not text")),
+    }
+}
+
+fn write_output(options: &Options, stdout: &mut dyn Write, text: &str) -> Result<(), String> {
+    match options.output.as_deref() {
+        None | Some("-") => stdout.write_all(text.as_bytes()).map_err(|e| e.to_string()),
+        Some(file) => fs::write(file, text).map_err(|e| format!("{}: {}", file, e)),
+    }
+}
+
+fn execute(options: &Options, stdin: &mut dyn Read, stdout: &mut dyn Write) -> Result<i32, String> {
+    if options.command == Command::Help {
+        let _ = write!(stdout, "{}", USAGE);
+        return Ok(0);
+    }
+    if options.command == Command::Repl {
+        let mut input = BufReader::new(stdin);
+        let mut session = Repl::new();
+        if let Some(ref file) = options.input {
+            if let Some(status) = session.eval(&format!(":load {}", file), &mut input, stdout) {
+                return Ok(status);
+            }
+        }
+        return Ok(repl::run(&mut session, &mut input, stdout));
+    }
+    if options.command == Command::Lsp {
+        return Ok(lsp::serve(&mut BufReader::new(stdin), stdout));
+    }
+    let source = read_source(options, stdin)?;
+    match options.command {
+        Command::Help | Command::Repl | Command::Lsp => Ok(0),
+        Command::Check => driver::front_end(&source).map(|_| 0),
+        Command::Fmt => write_output(options, stdout, &fmt::format(&source)?).map(|_| 0),
+        Command::Run(mode) => driver::run(&source, mode, stdin, stdout),
+        Command::Emit(Stage::Exe) => {
+            let output = match options.output {
+                Some(ref output) => output.clone(),
+                None => {
+                    let stem = options.input.as_ref()
+                        .and_then(|input| Path::new(input).file_stem())
+                        .map(|stem| stem.to_string_lossy().into_owned());
+                    options.target.output_name(&stem.unwrap_or(String::from("a.out")))
+                },
+            };
+            driver::build(&source, options.target, options.allocator, Path::new(&output)).map(|_| 0)
+        },
+        Command::Emit(stage) => {
+            let text = emit_text(stage, &source, options)?;
+            write_output(options, stdout, &text).map(|_| 0)
+        },
+    }
+}
+
+// Runs a command line (without the program name), returning the exit
+// status: that of the program for run, 1 for errors in the program or
+// while compiling it, and 2 for a bad command line.
+pub fn main(args: &[String], stdin: &mut dyn Read, stdout: &mut dyn Write, stderr: &mut dyn Write) -> i32 {
+    let options = match parse_args(args) {
+        Ok(options) => options,
+        Err(e) => {
+            let _ = write!(stderr, "tiger: {}\n\n{}", e, USAGE);
+            return 2;
+        },
+    };
+    match execute(&options, stdin, stdout) {
+        Ok(status) => status,
+        Err(e) => {
+            let _ = stdout.flush();
+            let _ = writeln!(stderr, "tiger: {}", e);
+            1
+        },
+    }
+}
+
+#[test]
+fn test_cli() {
+    let args = |s: &str| -> Vec<String> { s.split_whitespace().map(String::from).collect() };
+    let options = parse_args(&args("build -O0 --target=riscv64 -o out prog.tig")).unwrap();
+    assert_eq!(options.command, Command::Emit(Stage::Exe));
+    assert_eq!(options.allocator, Allocator::LinearScan);
+    assert_eq!(options.target, Target::RiscV64);
+    assert_eq!(options.output, Some(String::from("out")));
+    assert_eq!(options.input, Some(String::from("prog.tig")));
+    assert_eq!(parse_args(&args("asm --emit ir")).unwrap().command, Command::Emit(Stage::Ir));
+    assert!(parse_args(&args("run --emit asm")).is_err());
+    assert!(parse_args(&args("frobnicate")).is_err());
+
+    let run = |command: &str, source: &str| {
+        let (mut out, mut err) = (vec![], vec![]);
+        let status = main(&args(command), &mut source.as_bytes(), &mut out, &mut err);
+        (status, String::from_utf8(out).unwrap(), String::from_utf8(err).unwrap())
+    };
+    assert_eq!(run("run", "(print(\"hi\"); exit(3))"), (3, String::from("hi"), String::new()));
+    assert_eq!(run("run --mode bytecode -", "printi(6 * 7)").1, "42");
+    assert_eq!(run("check", "1 + \"a\"").0, 1);
+    // errors in lexing too
+    assert_eq!(run("check", "print(\"ab"), (1, String::new(), String::from("tiger: unterminated string at pos 6\n")));
+    assert_eq!(run("check", "1 # 2").2, "tiger: unexpected character # at pos 2\n");
+    assert_eq!(run("run", "printi(99999999999)").0, 1);
+    use std::env;
+    use std::process;
+    let file = env::temp_dir().join(format!("tiger-cli-test-{}.tig", process::id()));
+    fs::write(&file, "(printi(1);\n print(\"ab\n").unwrap();
+    assert_eq!(run(&format!("check {}", file.display()), ""), (1, String::new(), String::from("tiger: unterminated string at pos 19\n")));
+    fs::remove_file(&file).unwrap();
+    assert_eq!(run("check", "let var a := 1 in a end"), (0, String::new(), String::new()));
+    assert!(run("ir --emit canon", "print(\"x\")").1.contains("tig_print"));
+    assert!(run("asm --target mips", "printi(1)").1.contains("jal printi"));
+    assert!(run("dot", "1").1.starts_with("digraph G {"));
+    assert!(run("dot --types", "1").1.contains(r#"nd_0 [label="IntExp(1)\n: int"]"#));
+    assert!(parse_args(&args("run --types")).is_err());
+    let json = run("parse --format json", "let var a := 6 in printi(a * 7) end").1;
+    assert!(json.starts_with("{\n  \"kind\": \"LetExp\""));
+    assert_eq!(run("run", &json), (0, String::from("42"), String::new()));
+    assert!(run("parse --format sexp --types", "1").1.starts_with("(IntExp :value 1 :type \"int\")"));
+    assert!(parse_args(&args("check --format json")).is_err());
+    assert!(parse_args(&args("parse --types")).is_err());
+    assert_eq!(run("fmt", "let var a:=1 in a end").1, "let\n  var a := 1\nin\n  a\nend\n");
+    assert!(emit_text(Stage::Exe, "1", &parse_args(&args("build")).unwrap()).is_err());
+    let (status, _, err) = run("bogus", "");
+    assert_eq!(status, 2);
+    assert!(err.contains("usage: tiger"));
+}
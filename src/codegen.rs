@@ -0,0 +1,123 @@
+use assem::Instr;
+use canon::canonicalize;
+use frame::{Frame, Fragment};
+use gc::{PointerMap, pointer_maps};
+use ir::Stm;
+use regalloc::{Allocator, allocate};
+use symbol::SymbolTable;
+use temp::{Temp, Label, TempGenerator};
+
+use std::io;
+use std::io::Write;
+
+// What a back end provides besides its Frame: instruction selection and the
+// parts of the assembly file that depend on the target.
+pub trait Codegen {
+    type Frame: Frame;
+
+    // instruction selection for one statement of canonical IR
+    fn codegen(&self, frame: &Self::Frame, stm: Stm, gen: &mut TempGenerator,
+               symbol_table: &SymbolTable) -> Vec<Instr>;
+
+    // appends a sink instruction that uses the registers live at the end of
+    // the procedure, so that the register allocator keeps them
+    fn proc_entry_exit2(&self, frame: &Self::Frame, body: Vec<Instr>) -> Vec<Instr>;
+
+    // the prologue and epilogue of a procedure, once its frame size is known
+    fn proc_entry_exit3(&self, frame: &Self::Frame, symbol_table: &SymbolTable) -> (String, String);
+
+    fn file_header(&self) -> String;
+
+    fn data_section(&self) -> String;
+
+    // a string literal in the data section, as the runtime expects it
+    fn string(&self, label: Label, s: &str, symbol_table: &SymbolTable) -> String;
+
+    // the assembly name of a register; other temps are named after their number
+    fn register_name(&self, t: Temp) -> String;
+
+    // a load from and a store to the frame slot at offset from the frame pointer
+    fn load(&self, dst: Temp, offset: i32) -> Vec<Instr>;
+    fn store(&self, src: Temp, offset: i32) -> Vec<Instr>;
+
+    // the registers the register allocator may assign to temps, in order
+    // of preference
+    fn registers(&self) -> Vec<Temp>;
+
+    // whether an instruction calls a procedure
+    fn is_call(&self, instr: &Instr) -> bool;
+
+    // the table of the pointer maps of all calls in the data section, for
+    // the garbage collector
+    fn pointer_map_table(&self, maps: &[PointerMap], symbol_table: &SymbolTable) -> String;
+}
+
+// The characters of s as the operand of an .ascii directive; characters
+// are bytes in Tiger.
+pub fn ascii_literal(s: &str) -> String {
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                res.push('\\');
+                res.push(c);
+            },
+            c if c >= ' ' && c <= '~' => res.push(c),
+            _ => res.push_str(&format!("\\{:03o}", c as u32 & 0xff)),
+        }
+    }
+    res.push('"');
+    res
+}
+
+// Compiles the fragments of a translated program to an assembly file,
+// with the given register allocator, and the pointer maps of its calls.
+pub fn emit_program<C: Codegen, W: Write>(out: &mut W, codegen: &C, allocator: Allocator,
+                                          fragments: Vec<Fragment<C::Frame>>, gen: &mut TempGenerator,
+                                          symbol_table: &mut SymbolTable) -> io::Result<()> {
+    write!(out, "{}", codegen.file_header())?;
+    let mut strings = vec![];
+    let mut maps = vec![];
+    for fragment in fragments.into_iter() {
+        match fragment {
+            Fragment::Proc { body, mut frame } => {
+                let stms = canonicalize(body, gen, symbol_table);
+                let mut instrs = vec![];
+                for stm in stms.into_iter() {
+                    instrs.extend(codegen.codegen(&frame, stm, gen, symbol_table));
+                }
+                let instrs = codegen.proc_entry_exit2(&frame, instrs);
+                let (instrs, proc_maps) = pointer_maps(codegen, &mut frame, instrs, gen, symbol_table);
+                maps.extend(proc_maps.into_iter());
+                let (instrs, allocation) = allocate(allocator, codegen, &mut frame, instrs, gen);
+
+                let (prologue, epilogue) = codegen.proc_entry_exit3(&frame, symbol_table);
+                write!(out, "{}", prologue)?;
+                let temp_name = |t: Temp| codegen.register_name(allocation[&t]);
+                let label_name = |l: Label| l.name(symbol_table).clone();
+                for instr in instrs.iter() {
+                    if instr.is_redundant_move(&|t| allocation[&t]) {
+                        continue;
+                    }
+                    let text = instr.format(&temp_name, &label_name);
+                    if text.is_empty() {
+                        continue;
+                    }
+                    match instr {
+                        &Instr::Label { .. } => writeln!(out, "{}", text)?,
+                        _ => writeln!(out, "\t{}", text)?,
+                    }
+                }
+                write!(out, "{}", epilogue)?;
+            },
+            Fragment::String(label, s) => strings.push((label, s)),
+        }
+    }
+
+    write!(out, "{}", codegen.data_section())?;
+    for &(label, ref s) in strings.iter() {
+        write!(out, "{}", codegen.string(label, s, symbol_table))?;
+    }
+    write!(out, "{}", codegen.pointer_map_table(&maps, symbol_table))?;
+    Ok(())
+}
@@ -1,113 +1,457 @@
 use std::io::Write;
+use std::rc::Rc;
 
+use assem::Instr;
 use ast;
 use ast::Exp::*;
 use ast::Var::*;
+use flow::FlowGraph;
+use ir;
+use liveness::{InterferenceGraph, Liveness};
 use symbol;
+use temp::{Temp, Label, FIRST_FREE_TEMP};
+use type_check::TypeMap;
+use types::Ty;
 
-fn label_var(v: &ast::Var, symbol_table: &symbol::SymbolTable) -> String {
-    match v {
-        &SimpleVar(s, _) => format!("SimpleVar({})", symbol_table.name(&s)),
-        &FieldVar(_, s, _) => format!("FieldVar[{}]", symbol_table.name(&s)),
-        &SubscriptVar(_, ref e, _) => String::from("SubscriptVar"),
-    }
+// Renders a syntax tree with a node for every expression, variable,
+// declaration, type and field, numbered in the order they are visited, and
+// edges labelled with the part of its parent each child is. Given the types
+// from the checker, nodes also show the type of what they stand for.
+struct AstRenderer<'a, W: 'a + Write> {
+    out: &'a mut W,
+    symbol_table: &'a symbol::SymbolTable,
+    types: Option<&'a TypeMap>,
+    next_id: usize,
 }
 
-fn label_tree(tree: &ast::Exp, symbol_table: &symbol::SymbolTable) -> String {
-    match tree {
-        &VarExp(ref var) => label_var(&**var, symbol_table),
-        &NilExp => String::from("NilExp"),
-        &IntExp(i) => format!("IntExp({})", i),
-        &StringExp(ref s, _) => format!("StringExp({})", s),
-        &OpExp { op, .. } => format!("OpExp({:?})", op),
+impl<'a, W: Write> AstRenderer<'a, W> {
+    fn name(&self, symbol: ast::Symbol) -> &'a str {
+        self.symbol_table.name(&symbol)
+    }
+
+    fn node(&mut self, label: String, ty: Option<&Rc<Ty>>) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        let label = match ty {
+            Some(ty) => format!("{}\\n: {}", escape_label(&label), escape_label(&ty.name(self.symbol_table))),
+            None => escape_label(&label),
+        };
+        writeln!(self.out, r#"nd_{} [label="{}"]"#, id, label).unwrap();
+        id
+    }
+
+    fn edge(&mut self, from: usize, to: usize, label: &str) {
+        writeln!(self.out, r#"nd_{} -> nd_{} [label="{}"];"#, from, to, label).unwrap();
+    }
+
+    fn var(&mut self, var: &ast::Var) -> usize {
+        let ty = self.types.and_then(|types| types.var_ty(var));
+        match var {
+            &SimpleVar(s, _, _) => self.node(format!("SimpleVar({})", self.name(s)), ty),
+            &FieldVar(ref v, s, _, _) => {
+                let id = self.node(format!("FieldVar[{}]", self.name(s)), ty);
+                let v = self.var(v);
+                self.edge(id, v, "var");
+                id
+            },
+            &SubscriptVar(ref v, ref e, _, _) => {
+                let id = self.node(String::from("SubscriptVar"), ty);
+                let v = self.var(v);
+                self.edge(id, v, "var");
+                let e = self.exp(e);
+                self.edge(id, e, "index");
+                id
+            },
+        }
+    }
 
-        &IfExp { .. } => String::from("IfExp"),
+    fn exp(&mut self, exp: &ast::Exp) -> usize {
+        let ty = self.types.and_then(|types| types.exp_ty(exp));
+        let (label, children): (String, Vec<(String, &ast::Exp)>) = match exp {
+            // a variable is its own node
+            &VarExp(ref var, _) => return self.var(var),
+            &NilExp(_) => (String::from("NilExp"), vec![]),
+            &IntExp(i, _) => (format!("IntExp({})", i), vec![]),
+            &StringExp(ref s, _, _) => (format!("StringExp({:?})", s), vec![]),
+            &CallExp { func, ref args, .. } => (
+                format!("CallExp({})", self.name(func)),
+                args.iter().enumerate().map(|(i, a)| (format!("args[{}]", i), &**a)).collect(),
+            ),
+            &OpExp { ref left, op, ref right, .. } =>
+                (format!("OpExp({:?})", op), vec![(String::from("left"), &**left), (String::from("right"), &**right)]),
+            &RecordExp { ref fields, typ, .. } => (
+                format!("RecordExp({})", self.name(typ)),
+                fields.iter().map(|&(name, ref e, _)| (String::from(self.name(name)), &**e)).collect(),
+            ),
+            &SeqExp(ref exps, _) => (
+                String::from("SeqExp"),
+                exps.iter().enumerate().map(|(i, e)| (format!("exps[{}]", i), &**e)).collect(),
+            ),
+            &AssignExp { ref var, exp: ref e, .. } => {
+                let id = self.node(String::from("AssignExp"), ty);
+                let v = self.var(var);
+                self.edge(id, v, "var");
+                let e = self.exp(e);
+                self.edge(id, e, "exp");
+                return id;
+            },
+            &IfExp { ref test, ref then_, ref else_, .. } => {
+                let mut children = vec![(String::from("test"), &**test), (String::from("then"), &**then_)];
+                if let &Some(ref else_) = else_ {
+                    children.push((String::from("else"), &**else_));
+                }
+                (String::from("IfExp"), children)
+            },
+            &WhileExp { ref test, ref body, .. } =>
+                (String::from("WhileExp"), vec![(String::from("test"), &**test), (String::from("body"), &**body)]),
+            &ForExp { var, ref lo, ref hi, ref body, .. } => (
+                format!("ForExp({})", self.name(var)),
+                vec![(String::from("lo"), &**lo), (String::from("hi"), &**hi), (String::from("body"), &**body)],
+            ),
+            &BreakExp(_, _) => (String::from("BreakExp"), vec![]),
+            &LetExp { ref decs, ref body, .. } => {
+                let id = self.node(String::from("LetExp"), ty);
+                for (i, dec) in decs.iter().enumerate() {
+                    let d = self.dec(dec);
+                    self.edge(id, d, &format!("decs[{}]", i));
+                }
+                let b = self.exp(body);
+                self.edge(id, b, "body");
+                return id;
+            },
+            &ArrayExp { typ, ref size, ref init, .. } => (
+                format!("ArrayExp({})", self.name(typ)),
+                vec![(String::from("size"), &**size), (String::from("init"), &**init)],
+            ),
+        };
+        let id = self.node(label, ty);
+        for (edge, child) in children.into_iter() {
+            let c = self.exp(child);
+            self.edge(id, c, &edge);
+        }
+        id
+    }
+
+    fn field(&mut self, field: &ast::Field) -> usize {
+        let ty = self.types.and_then(|types| types.param_ty(field));
+        self.node(format!("Field({}: {})", self.name(field.name), self.name(field.typ)), ty)
+    }
+
+    fn fields(&mut self, id: usize, fields: &[Box<ast::Field>], edge: &str) {
+        for (i, field) in fields.iter().enumerate() {
+            let f = self.field(field);
+            self.edge(id, f, &format!("{}[{}]", edge, i));
+        }
+    }
+
+    fn dec(&mut self, dec: &ast::Dec) -> usize {
+        let ty = self.types.and_then(|types| types.dec_ty(dec));
+        match dec {
+            &ast::Dec::FunDec { name, ref params, ref result, ref body, .. } => {
+                let label = match result {
+                    &Some((result, _)) => format!("FunDec({}): {}", self.name(name), self.name(result)),
+                    &None => format!("FunDec({})", self.name(name)),
+                };
+                let id = self.node(label, ty);
+                self.fields(id, params, "params");
+                let b = self.exp(body);
+                self.edge(id, b, "body");
+                id
+            },
+            &ast::Dec::VarDec { name, ref typ, ref init, .. } => {
+                let label = match typ {
+                    &Some((typ, _)) => format!("VarDec({}: {})", self.name(name), self.name(typ)),
+                    &None => format!("VarDec({})", self.name(name)),
+                };
+                let id = self.node(label, ty);
+                let i = self.exp(init);
+                self.edge(id, i, "init");
+                id
+            },
+            &ast::Dec::TypeDec { name, ty: ref t, .. } => {
+                let id = self.node(format!("TypeDec({})", self.name(name)), ty);
+                let t = self.ty(t);
+                self.edge(id, t, "ty");
+                id
+            },
+        }
+    }
+
+    fn ty(&mut self, ty: &ast::Ty) -> usize {
+        match ty {
+            &ast::Ty::NameTy(name, _) => self.node(format!("NameTy({})", self.name(name)), None),
+            &ast::Ty::RecordTy(ref fields) => {
+                let id = self.node(String::from("RecordTy"), None);
+                self.fields(id, fields, "fields");
+                id
+            },
+            &ast::Ty::ArrayTy(name, _) => self.node(format!("ArrayTy({})", self.name(name)), None),
+        }
+    }
+}
+
+// the nodes and edges of a syntax tree, with the types of its parts if
+// there are any
+pub fn render_ast<W>(out: &mut W, tree: &ast::Exp, symbol_table: &symbol::SymbolTable, types: Option<&TypeMap>)
+    where W: Write
+{
+    let mut renderer = AstRenderer {
+        out: out,
+        symbol_table: symbol_table,
+        types: types,
+        next_id: 0,
+    };
+    renderer.exp(tree);
+}
 
-        _ => String::from("??"),
+fn label_ir_exp(exp: &ir::Exp, symbol_table: &symbol::SymbolTable) -> String {
+    match exp {
+        &ir::Exp::Const(i) => format!("CONST {}", i),
+        &ir::Exp::Name(l) => format!("NAME {}", l.name(symbol_table)),
+        &ir::Exp::Temp(t) => format!("TEMP t{}", t.0),
+        &ir::Exp::BinOp(op, _, _) => format!("BINOP({:?})", op),
+        &ir::Exp::Mem(_) => String::from("MEM"),
+        &ir::Exp::Call(_, _) => String::from("CALL"),
+        &ir::Exp::ESeq(_, _) => String::from("ESEQ"),
     }
 }
 
-type NodeId = u64;
+fn label_ir_stm(stm: &ir::Stm, symbol_table: &symbol::SymbolTable) -> String {
+    match stm {
+        &ir::Stm::Move(_, _) => String::from("MOVE"),
+        &ir::Stm::Exp(_) => String::from("EXP"),
+        &ir::Stm::Jump(_, _) => String::from("JUMP"),
+        &ir::Stm::CJump(op, _, _, t, f) => format!("CJUMP({:?}) {} {}", op,
+                                                   t.name(symbol_table), f.name(symbol_table)),
+        &ir::Stm::Seq(_, _) => String::from("SEQ"),
+        &ir::Stm::Label(l) => format!("LABEL {}", l.name(symbol_table)),
+    }
+}
 
-trait Node {
-    fn node_id(&self) -> NodeId;
-    fn neighbors(&self) -> Vec<NodeId>;
+// Renders an IR tree with a node for every expression and statement,
+// numbered in the order they are visited like the nodes of a syntax tree,
+// so that the same tree always gives the same graph.
+struct IrRenderer<'a, W: 'a + Write> {
+    out: &'a mut W,
+    symbol_table: &'a symbol::SymbolTable,
+    next_id: usize,
 }
 
-impl Node for ast::Exp {
-    fn node_id(&self) -> NodeId {
-        (self as *const ast::Exp) as NodeId
+impl<'a, W: Write> IrRenderer<'a, W> {
+    fn node(&mut self, label: String, shape: &str) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        writeln!(self.out, r#"nd_{} [{}label="{}"]"#, id, shape, escape_label(&label)).unwrap();
+        id
     }
 
-    fn neighbors(&self) -> Vec<NodeId> {
-        match self {
-            &VarExp(ref var) => var.neighbors(),
+    fn edge(&mut self, from: usize, to: usize) {
+        writeln!(self.out, r#"nd_{} -> nd_{};"#, from, to).unwrap();
+    }
 
-            &IfExp { ref test, ref then_, ref else_, .. } => {
-                let mut v = vec![test.node_id(), then_.node_id()];
-                if let &Some(ref else_) = else_ {
-                    v.push(else_.node_id());
+    fn exp(&mut self, exp: &ir::Exp) -> usize {
+        let id = self.node(label_ir_exp(exp, self.symbol_table), "");
+        let children = match exp {
+            &ir::Exp::BinOp(_, ref a, ref b) => vec![self.exp(a), self.exp(b)],
+            &ir::Exp::Mem(ref e) => vec![self.exp(e)],
+            &ir::Exp::Call(ref f, ref args) => {
+                let mut v = vec![self.exp(f)];
+                for a in args.iter() {
+                    v.push(self.exp(a));
                 }
                 v
-            }
-
+            },
+            &ir::Exp::ESeq(ref s, ref e) => vec![self.stm(s), self.exp(e)],
             _ => vec![],
+        };
+        for &child in children.iter() {
+            self.edge(id, child);
         }
-    }
-}
-
-impl Node for ast::Var {
-    fn node_id(&self) -> NodeId {
-        (self as *const ast::Var) as NodeId
+        id
     }
 
-    fn neighbors(&self) -> Vec<NodeId> {
-        match self {
-            &FieldVar(ref v, _, _) => vec![v.node_id()],
-            &SubscriptVar(ref v, ref e, _) => vec![v.node_id(), e.node_id()],
-            _ => vec![]
+    fn stm(&mut self, stm: &ir::Stm) -> usize {
+        let id = self.node(label_ir_stm(stm, self.symbol_table), "shape=box,");
+        let children = match stm {
+            &ir::Stm::Move(ref dst, ref src) => vec![self.exp(dst), self.exp(src)],
+            &ir::Stm::Exp(ref e) | &ir::Stm::Jump(ref e, _) => vec![self.exp(e)],
+            &ir::Stm::CJump(_, ref a, ref b, _, _) => vec![self.exp(a), self.exp(b)],
+            &ir::Stm::Seq(ref a, ref b) => vec![self.stm(a), self.stm(b)],
+            &ir::Stm::Label(_) => vec![],
+        };
+        for &child in children.iter() {
+            self.edge(id, child);
         }
+        id
     }
 }
 
-pub fn render_var<W>(out: &mut W, var: &ast::Var, symbol_table: &symbol::SymbolTable)
-    where W: Write {
-    let id = var.node_id();
-    writeln!(out, r#"nd_{:x} [label="{}"]"#, id,
-             label_var(var, symbol_table)).unwrap();
-    for &l in var.neighbors().iter() {
-        writeln!(out, r#"nd_{:x} -> nd_{:x};"#, id, l);
+pub fn render_ir_exp<W>(out: &mut W, exp: &ir::Exp, symbol_table: &symbol::SymbolTable)
+    where W: Write
+{
+    IrRenderer { out: out, symbol_table: symbol_table, next_id: 0 }.exp(exp);
+}
+
+pub fn render_ir_stm<W>(out: &mut W, stm: &ir::Stm, symbol_table: &symbol::SymbolTable)
+    where W: Write
+{
+    IrRenderer { out: out, symbol_table: symbol_table, next_id: 0 }.stm(stm);
+}
+
+fn escape_label(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// One node per instruction, labelled with its text and the temps live
+// after it.
+pub fn render_flow_graph<W>(out: &mut W, instrs: &[Instr], graph: &FlowGraph, live: &Liveness,
+                            temp_name: &dyn Fn(Temp) -> String, label_name: &dyn Fn(Label) -> String)
+    where W: Write
+{
+    for (i, instr) in instrs.iter().enumerate() {
+        let live_out: Vec<String> = live.live_out(i).into_iter().map(temp_name).collect();
+        let text = instr.format(temp_name, label_name);
+        writeln!(out, r#"instr_{} [shape=box,label="{}\nout: {}"]"#, i,
+                 escape_label(text.trim()), live_out.join(" ")).unwrap();
+        for &s in graph.succ[i].iter() {
+            writeln!(out, r#"instr_{} -> instr_{};"#, i, s).unwrap();
+        }
     }
 }
 
-pub fn render_ast<W>(out: &mut W, tree: &ast::Exp, symbol_table: &symbol::SymbolTable)
+// Machine registers are boxes; moves are dashed edges. Nodes are named
+// after temps, and flow graph nodes after instructions, so that both can
+// go in one graph.
+pub fn render_interference_graph<W>(out: &mut W, graph: &InterferenceGraph, temp_name: &dyn Fn(Temp) -> String)
     where W: Write
 {
-    writeln!(out, r#"nd_{:x} [label="{}"]"#,
-             tree.node_id(),
-             label_tree(tree, symbol_table)).unwrap();
-    let id = tree.node_id();
-    for &l in tree.neighbors().iter() {
-        writeln!(out, r#"nd_{:x} -> nd_{:x};"#, id, l);
-    }
-
-    match tree {
-        &VarExp(ref v) => match v.as_ref() {
-            &FieldVar(ref v, _, _) => render_var(out, v, symbol_table),
-            &SubscriptVar(ref v, ref e, _) => {
-                render_var(out, v, symbol_table);
-                render_ast(out, e, symbol_table);
-            },
-            _ => (),
-        },
-        &IfExp { ref test, ref then_, ref else_, .. } => {
-            render_ast(out, test.as_ref(), symbol_table);
-            render_ast(out, then_.as_ref(), symbol_table);
-            if let &Some(ref else_) = else_ {
-                render_ast(out, else_, symbol_table);
-            }
-        },
-        _ => ()
+    for (i, &t) in graph.temps.iter().enumerate() {
+        let shape = if t.0 < FIRST_FREE_TEMP { "box" } else { "ellipse" };
+        writeln!(out, r#"temp_{} [shape={},label="{}"]"#, t.0, shape, temp_name(t)).unwrap();
+        for &j in graph.adj_list[i].iter().filter(|&&j| j > i) {
+            writeln!(out, r#"temp_{} -> temp_{} [dir=none];"#, t.0, graph.temps[j].0).unwrap();
+        }
+    }
+    for &(dst, src) in graph.moves.iter() {
+        writeln!(out, r#"temp_{} -> temp_{} [dir=none,style=dashed];"#, src.0, dst.0).unwrap();
     }
 }
 
+#[test]
+fn test_render_ast() {
+    use parser::parse;
+    use type_check::type_check;
+
+    let (exp, mut table) = parse("let type point = {x: int, y: int} \
+                                  var p := point {x = 1, y = 2} \
+                                  function f(q: point): int = q.x \
+                                  in for i := 0 to 2 do p.y := f(p); p end").unwrap();
+    let types = type_check(&exp, &mut table).unwrap();
+    let render = |types: Option<&TypeMap>| {
+        let mut out = vec![];
+        render_ast(&mut out, &exp, &table, types);
+        String::from_utf8(out).unwrap()
+    };
+
+    let plain = render(None);
+    assert_eq!(plain, render(None));
+    // every node but the root has exactly one edge into it
+    let nodes = plain.lines().filter(|l| l.contains(" [label=") && !l.contains("->")).count();
+    let edges = plain.lines().filter(|l| l.contains("->")).count();
+    assert_eq!((nodes, edges), (23, 22));
+    for line in ["nd_0 [label=\"LetExp\"]",
+                 "nd_0 -> nd_1 [label=\"decs[0]\"];",
+                 "nd_2 [label=\"RecordTy\"]",
+                 "nd_2 -> nd_4 [label=\"fields[1]\"];",
+                 "nd_4 [label=\"Field(y: int)\"]",
+                 "nd_5 [label=\"VarDec(p)\"]",
+                 "nd_6 -> nd_8 [label=\"y\"];",
+                 "nd_9 [label=\"FunDec(f): int\"]",
+                 "nd_9 -> nd_10 [label=\"params[0]\"];",
+                 "nd_11 [label=\"FieldVar[x]\"]",
+                 "nd_13 [label=\"SeqExp\"]",
+                 "nd_14 [label=\"ForExp(i)\"]",
+                 "nd_14 -> nd_16 [label=\"hi\"];",
+                 "nd_14 -> nd_17 [label=\"body\"];",
+                 "nd_20 [label=\"CallExp(f)\"]",
+                 "nd_20 -> nd_21 [label=\"args[0]\"];",
+                 "nd_0 -> nd_13 [label=\"body\"];"].iter() {
+        assert!(plain.lines().any(|l| l == *line), "no {} in\n{}", line, plain);
+    }
+
+    let typed = render(Some(&types));
+    assert!(typed.contains("nd_0 [label=\"LetExp\\n: {x: int, y: int}\"]"));
+    assert!(typed.contains("nd_10 [label=\"Field(q: point)\\n: point\"]"));
+    assert!(typed.contains("nd_17 [label=\"AssignExp\\n: unit\"]"));
+    assert!(typed.contains("nd_16 [label=\"IntExp(2)\\n: int\"]"));
+}
+
+#[test]
+fn test_render_ir() {
+    use ir::{BinOp, Exp, Stm};
+    use symbol::SymbolTable;
+
+    let mut table = SymbolTable::new();
+    let f = Label(table.symbol("f"));
+    let stm = Stm::Seq(Box::new(Stm::Label(f)),
+                       Box::new(Stm::Move(Box::new(Exp::Temp(Temp(100))),
+                                          Box::new(Exp::Call(Box::new(Exp::Name(f)), vec![
+                                              Exp::BinOp(BinOp::Plus, Box::new(Exp::Const(1)), Box::new(Exp::Const(2))),
+                                          ])))));
+    let render = |stm: &Stm| {
+        let mut out = vec![];
+        render_ir_stm(&mut out, stm, &table);
+        String::from_utf8(out).unwrap()
+    };
+    // the same tree anywhere in memory gives the same graph
+    let graph = render(&stm);
+    assert_eq!(graph, render(&stm.clone()));
+    assert_eq!(graph, "nd_0 [shape=box,label=\"SEQ\"]\n\
+                       nd_1 [shape=box,label=\"LABEL f\"]\n\
+                       nd_2 [shape=box,label=\"MOVE\"]\n\
+                       nd_3 [label=\"TEMP t100\"]\n\
+                       nd_4 [label=\"CALL\"]\n\
+                       nd_5 [label=\"NAME f\"]\n\
+                       nd_6 [label=\"BINOP(Plus)\"]\n\
+                       nd_7 [label=\"CONST 1\"]\n\
+                       nd_8 [label=\"CONST 2\"]\n\
+                       nd_6 -> nd_7;\n\
+                       nd_6 -> nd_8;\n\
+                       nd_4 -> nd_5;\n\
+                       nd_4 -> nd_6;\n\
+                       nd_2 -> nd_3;\n\
+                       nd_2 -> nd_4;\n\
+                       nd_0 -> nd_1;\n\
+                       nd_0 -> nd_2;\n");
+}
+
+#[test]
+fn test_render_flow_and_interference() {
+    use flow::flow_graph;
+    use liveness::{interference_graph, liveness};
+
+    let (a, b) = (Temp(100), Temp(101));
+    let instrs = vec![Instr::oper(String::from("li `d0, 1"), vec![a], vec![]),
+                      Instr::Move { assem: String::from("mv `d0, `s0"), dst: b, src: a },
+                      Instr::oper(String::from("add `d0, `s0, `s1"), vec![a], vec![a, b]),
+                      Instr::oper(String::from("ret"), vec![], vec![a])];
+    let graph = flow_graph(&instrs);
+    let live = liveness(&graph);
+    let mut out = vec![];
+    let name = |t: Temp| format!("t{}", t.0);
+    render_flow_graph(&mut out, &instrs, &graph, &live, &name, &|_| String::new());
+    render_interference_graph(&mut out, &interference_graph(&graph, &live), &name);
+    let out = String::from_utf8(out).unwrap();
+    for line in ["instr_0 [shape=box,label=\"li t100, 1\\nout: t100\"]",
+                 "instr_1 -> instr_2;",
+                 "temp_100 [shape=ellipse,label=\"t100\"]",
+                 "temp_100 -> temp_101 [dir=none,style=dashed];"].iter() {
+        assert!(out.lines().any(|l| l == *line), "no {} in\n{}", line, out);
+    }
+    // no node is declared twice
+    let mut nodes: Vec<&str> = out.lines().filter(|l| !l.contains("->")).map(|l| l.split(' ').next().unwrap()).collect();
+    let count = nodes.len();
+    nodes.sort();
+    nodes.dedup();
+    assert_eq!(nodes.len(), count);
+}
@@ -0,0 +1,370 @@
+use aarch64::{AArch64Codegen, AArch64Frame};
+use ast;
+use bytecode;
+use c_backend::emit_c;
+use canon::canonicalize;
+use codegen::{Codegen, emit_program};
+use escape::find_escapes;
+use frame::{Frame, Fragment};
+use interp::{Control, Interpreter};
+use ir::print_stm;
+use json::Json;
+use mips::{MipsCodegen, MipsFrame};
+use parser;
+use regalloc::Allocator;
+use riscv64::{RiscVCodegen, RiscVFrame};
+use serialize;
+use symbol::SymbolTable;
+use temp::TempGenerator;
+use translate::translate;
+use type_check::{TypeMap, type_check};
+use vm::Vm;
+use wasm::{WasmFrame, emit_wat};
+use x86_64::{X86Codegen, X86Frame, Syntax};
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// The phases of the compiler chained together, for each target.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    X86_64(Syntax),
+    RiscV64,
+    Mips,
+    AArch64,
+    C,
+    Wasm,
+}
+
+impl Target {
+    pub fn from_name(name: &str) -> Option<Target> {
+        match name {
+            "x86_64" | "x86-64" => Some(Target::X86_64(Syntax::Att)),
+            "x86_64-intel" | "x86-64-intel" => Some(Target::X86_64(Syntax::Intel)),
+            "riscv64" | "rv64" => Some(Target::RiscV64),
+            "mips" | "spim" => Some(Target::Mips),
+            "aarch64" | "arm64" => Some(Target::AArch64),
+            "c" => Some(Target::C),
+            "wasm" | "wat" => Some(Target::Wasm),
+            _ => None,
+        }
+    }
+
+    // the C compiler, and its flags, that assembles and links a program
+    // for the target with the runtime system, or compiles the C target;
+    // None for targets whose output carries its own runtime
+    fn c_compiler(&self) -> Option<(&'static str, &'static [&'static str])> {
+        match self {
+            &Target::X86_64(_) => Some(("cc", &["-no-pie", "-fno-omit-frame-pointer"])),
+            &Target::RiscV64 => Some(("riscv64-linux-gnu-gcc", &["-static", "-fno-omit-frame-pointer"])),
+            &Target::AArch64 => Some(("aarch64-linux-gnu-gcc", &["-static", "-fno-omit-frame-pointer"])),
+            &Target::C => Some(("cc", &["-std=c99"])),
+            &Target::Mips | &Target::Wasm => None,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            &Target::C => "c",
+            &Target::Wasm => "wat",
+            _ => "s",
+        }
+    }
+
+    // the name of what build makes of a source file named stem.tig
+    pub fn output_name(&self, stem: &str) -> String {
+        match self.c_compiler() {
+            Some(_) => stem.to_owned(),
+            None => format!("{}.{}", stem, self.extension()),
+        }
+    }
+}
+
+// The runtime system linked with the assembly of the 64-bit targets: the
+// one of the C back end on a garbage collected heap, and what the C back
+// end does inline. The collector walks the stack by frame pointers.
+pub const NATIVE_RUNTIME: &'static str = concat!("#define TIG_GC\n", include_str!("c_runtime.h"),
+                                                 include_str!("runtime.c"));
+
+// How a program is run directly instead of being compiled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Interpret,
+    Bytecode,
+}
+
+impl Mode {
+    pub fn from_name(name: &str) -> Option<Mode> {
+        match name {
+            "interp" | "interpret" => Some(Mode::Interpret),
+            "bytecode" | "vm" => Some(Mode::Bytecode),
+            _ => None,
+        }
+    }
+}
+
+// parses a program given either as source or as a syntax tree in JSON,
+// which cannot be mistaken for source since no expression starts with {
+pub fn parse(source: &str) -> Result<(Box<ast::Exp>, Box<SymbolTable>), String> {
+    if source.trim_start().starts_with('{') {
+        let json = Json::parse(source)?;
+        let mut symbol_table = Box::new(SymbolTable::new());
+        let exp = serialize::from_json(&json, &mut symbol_table)?;
+        return Ok((exp, symbol_table));
+    }
+    parser::parse(source).map_err(|e| parser::error_message(&e))
+}
+
+// parses and type checks a program, with escapes computed
+pub fn front_end(source: &str) -> Result<(Box<ast::Exp>, Box<SymbolTable>, TypeMap), String> {
+    let (mut exp, mut symbol_table) = parse(source)?;
+    find_escapes(&mut exp);
+    let types = type_check(&exp, &mut symbol_table)?;
+    Ok((exp, symbol_table, types))
+}
+
+fn emit<C: Codegen>(codegen: &C, allocator: Allocator, source: &str) -> Result<String, String> {
+    let (exp, mut symbol_table, types) = front_end(source)?;
+    let mut gen = TempGenerator::new();
+    let fragments: Vec<Fragment<C::Frame>> = translate(&exp, &types, &mut symbol_table, &mut gen);
+    let mut out = vec![];
+    emit_program(&mut out, codegen, allocator, fragments, &mut gen, &mut symbol_table).map_err(|e| e.to_string())?;
+    Ok(String::from_utf8(out).unwrap())
+}
+
+// compiles a program to an assembly file (or a C file, or a wasm text
+// module) for target; allocator only matters for assembly
+pub fn compile(source: &str, target: Target, allocator: Allocator) -> Result<String, String> {
+    match target {
+        Target::X86_64(syntax) => emit(&X86Codegen { syntax: syntax }, allocator, source),
+        Target::RiscV64 => emit(&RiscVCodegen, allocator, source),
+        Target::Mips => emit(&MipsCodegen, allocator, source),
+        Target::AArch64 => emit(&AArch64Codegen, allocator, source),
+        Target::C => {
+            let (exp, mut symbol_table, types) = front_end(source)?;
+            Ok(emit_c(&exp, &types, &mut symbol_table))
+        },
+        Target::Wasm => {
+            let (exp, mut symbol_table, types) = front_end(source)?;
+            let mut gen = TempGenerator::new();
+            let fragments: Vec<Fragment<WasmFrame>> = translate(&exp, &types, &mut symbol_table, &mut gen);
+            Ok(emit_wat(fragments, &mut gen, &mut symbol_table))
+        },
+    }
+}
+
+fn print_ir<F: Frame>(source: &str, canonical: bool) -> Result<String, String> {
+    let (exp, mut symbol_table, types) = front_end(source)?;
+    let mut gen = TempGenerator::new();
+    let fragments: Vec<Fragment<F>> = translate(&exp, &types, &mut symbol_table, &mut gen);
+    let mut out = vec![];
+    for fragment in fragments.into_iter() {
+        match fragment {
+            Fragment::Proc { body, frame } => {
+                let _ = writeln!(out, "{}:", frame.name().name(&symbol_table));
+                if canonical {
+                    for stm in canonicalize(body, &mut gen, &mut symbol_table).iter() {
+                        print_stm(&mut out, stm, &symbol_table);
+                    }
+                } else {
+                    print_stm(&mut out, &body, &symbol_table);
+                }
+            },
+            Fragment::String(label, s) => {
+                let _ = writeln!(out, "{}: {:?}", label.name(&symbol_table), s);
+            },
+        }
+    }
+    Ok(String::from_utf8(out).unwrap())
+}
+
+// the IR trees of the fragments of a program, with the frames of target,
+// before or after canonicalization
+pub fn ir(source: &str, target: Target, canonical: bool) -> Result<String, String> {
+    match target {
+        Target::X86_64(_) => print_ir::<X86Frame>(source, canonical),
+        Target::RiscV64 => print_ir::<RiscVFrame>(source, canonical),
+        Target::Mips => print_ir::<MipsFrame>(source, canonical),
+        Target::AArch64 => print_ir::<AArch64Frame>(source, canonical),
+        Target::Wasm => print_ir::<WasmFrame>(source, canonical),
+        Target::C => Err(String::from("the C target is compiled from the syntax tree, without IR")),
+    }
+}
+
+// the number of builds so far, naming their directory of temporary files
+static BUILDS: AtomicUsize = AtomicUsize::new(0);
+
+// compiles a program to an executable at output, linked with the runtime
+// system; for SPIM and wasm, whose files carry their own runtime, output
+// is the assembly file or the wasm text module
+pub fn build(source: &str, target: Target, allocator: Allocator, output: &Path) -> Result<(), String> {
+    let code = compile(source, target, allocator)?;
+    let (cc, flags) = match target.c_compiler() {
+        Some(compiler) => compiler,
+        None => return fs::write(output, code).map_err(|e| format!("{}: {}", output.display(), e)),
+    };
+
+    let dir = env::temp_dir().join(format!("tiger-{}-{}", process::id(), BUILDS.fetch_add(1, Ordering::SeqCst)));
+    fs::create_dir_all(&dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let program = dir.join(format!("program.{}", target.extension()));
+    let runtime = dir.join("runtime.c");
+    let result = fs::write(&program, code)
+        .and_then(|_| fs::write(&runtime, NATIVE_RUNTIME))
+        .map_err(|e| e.to_string())
+        .and_then(|_| {
+            let mut command = Command::new(cc);
+            command.args(flags).arg("-o").arg(output).arg(&program);
+            if target != Target::C {
+                command.arg(&runtime);
+            }
+            command.output().map_err(|e| format!("{}: {}", cc, e))
+        })
+        .and_then(|out| if out.status.success() {
+            Ok(())
+        } else {
+            Err(format!("{} failed:\n{}", cc, String::from_utf8_lossy(&out.stderr)))
+        });
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+// runs a program, returning its exit status; runtime errors are described
+// with their position in source
+pub fn run(source: &str, mode: Mode, input: &mut dyn Read, output: &mut dyn Write) -> Result<i32, String> {
+    let (exp, mut symbol_table, types) = front_end(source)?;
+    let result = match mode {
+        Mode::Interpret => Interpreter::new(&mut symbol_table, input, output).run(&exp).map(|_| ()),
+        Mode::Bytecode => {
+            let program = bytecode::compile(&exp, &types, &mut symbol_table);
+            Vm::new(&program, input, output).run()
+        },
+    };
+    match result {
+        Ok(()) => Ok(0),
+        Err(Control::Exit(status)) => Ok(status),
+        Err(Control::Error(e)) => Err(e.describe(source)),
+        Err(Control::Break) => Err(String::from("break outside a loop")),
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn test_build() {
+    let program = "let type a = array of int \
+                       var x := a[3] of 7 \
+                   in print(concat(\"ab\", chr(ord(\"c\")))); printi(x[2]); x[3] := 0 end";
+    let dir = env::temp_dir().join(format!("tiger-test-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    for &target in [Target::X86_64(Syntax::Att), Target::X86_64(Syntax::Intel), Target::C].iter() {
+        let exe = dir.join("program");
+        build(program, target, Allocator::Coloring, &exe).unwrap();
+        let out = Command::new(&exe).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "abc7");
+        assert!(String::from_utf8_lossy(&out.stderr).contains("out of bounds"));
+        assert_eq!(out.status.code(), Some(1));
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// whether a tool is on the path, for the tests needing a cross compiler or
+// an emulator, which are skipped without them
+#[cfg(test)]
+pub fn installed(tool: &str) -> bool {
+    Command::new(tool).arg("--version").output().is_ok()
+}
+
+// builds a program passing arguments on the stack and using constants too
+// big for immediates for target, and runs it under emulator
+#[cfg(test)]
+fn test_cross(target: Target, emulator: &str) {
+    let (cc, _) = target.c_compiler().unwrap();
+    if !installed(cc) || !installed(emulator) {
+        println!("skipped: needs {} and {}", cc, emulator);
+        return;
+    }
+    let program = "let type a = array of int \
+                       function sum(a: int, b: int, c: int, d: int, e: int, \
+                                    f: int, g: int, h: int, i: int, j: int): int = \
+                         a + b + c + d + e + f + g + h + i * 1000 + j * 5000 \
+                       var x := a[3] of 7 \
+                   in print(concat(\"ab\", chr(ord(\"c\")))); printi(x[2]); print(\" \"); \
+                      printi(sum(1, 2, 3, 4, 5, 6, 7, 8, 9, 10)); x[3] := 0 end";
+    let dir = env::temp_dir().join(format!("tiger-{}-test-{}", emulator, process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    for &allocator in [Allocator::Coloring, Allocator::LinearScan].iter() {
+        let exe = dir.join("program");
+        build(program, target, allocator, &exe).unwrap();
+        let out = Command::new(emulator).arg(&exe).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "abc7 59036");
+        assert!(String::from_utf8_lossy(&out.stderr).contains("out of bounds"));
+        assert!(!out.status.success());
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_build_riscv64() {
+    test_cross(Target::RiscV64, "qemu-riscv64");
+}
+
+#[test]
+fn test_build_aarch64() {
+    test_cross(Target::AArch64, "qemu-aarch64");
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn test_garbage_collection() {
+    // much more is allocated than the first heap holds, while a list and a
+    // string stay live across the collections
+    let program = "let type list = {hd: int, tl: list} \
+                       function build(n: int, tail: list): list = \
+                         if n = 0 then tail else list {hd = n, tl = build(n - 1, tail)} \
+                       function sum(l: list): int = if l = nil then 0 else l.hd + sum(l.tl) \
+                       var keep := build(100, nil) \
+                       var s := \"\" \
+                   in for i := 1 to 2000 do (build(100, nil); s := concat(s, chr(ord(\"a\") + i - i / 26 * 26))); \
+                      printi(sum(keep)); print(\" \"); printi(size(s)); print(substring(s, 24, 4)) end";
+    let dir = env::temp_dir().join(format!("tiger-gc-test-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    for &allocator in [Allocator::Coloring, Allocator::LinearScan].iter() {
+        let exe = dir.join("program");
+        build(program, Target::X86_64(Syntax::Att), allocator, &exe).unwrap();
+        let out = Command::new(&exe).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "5050 2000zabc");
+        assert!(out.status.success());
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_lex_errors() {
+    assert!(parse(r#""a\^Gb\065\n""#).is_ok());
+    for &(source, error) in [
+        (r#"print("a\^xb")"#, "invalid control character escape: \\^x at pos 6"),
+        (r#"print("\300")"#, "character code 300 is out of range at pos 6"),
+        (r#"print("\12")"#, "invalid escape: \\ddd needs three digits at pos 6"),
+        (r#"print("\q")"#, "invalid escape: \\q at pos 6"),
+        ("print(\"ab\\\ncd\")", "invalid escape: \\f___f\\ needs a closing \\ at pos 6"),
+        (r#"print("ab"#, "unterminated string at pos 6"),
+        ("1 + #", "unexpected character # at pos 4"),
+        ("printi(99999999999)", "integer 99999999999 is out of range at pos 7"),
+    ].iter() {
+        assert_eq!(parse(source).err(), Some(String::from(error)));
+    }
+}
+
+#[test]
+fn test_run_break() {
+    // a break in a function called from a loop does not leave the loop
+    let source = "while 1 do let function f() = break in f() end";
+    for &mode in [Mode::Interpret, Mode::Bytecode].iter() {
+        let status = run(source, mode, &mut "".as_bytes(), &mut vec![]);
+        assert_eq!(status, Err(String::from("break outside of a loop at pos 30")));
+    }
+}
@@ -0,0 +1,230 @@
+use ast;
+use types::Table;
+
+use std::collections::HashSet;
+use std::rc::Rc;
+
+// Escape analysis (Appel's FindEscape): a variable escapes if it is used
+// from a function nested deeper than the one declaring it, and then has to
+// live in the frame instead of a register.
+//
+// The first pass collects the declarations (identified by their node ids)
+// that escape, the second pass sets their escape flags.
+
+type Depth = u32;
+type DeclId = ast::NodeId;
+type EscapeEnv<'a> = Table<'a, (Depth, DeclId)>;
+
+fn traverse_var(env: &EscapeEnv, depth: Depth, var: &ast::Var, escapes: &mut HashSet<DeclId>) {
+    match var {
+        &ast::Var::SimpleVar(symbol, _, _) => {
+            if let Some(entry) = env.look(symbol) {
+                if depth > entry.0 {
+                    escapes.insert(entry.1);
+                }
+            }
+        },
+        &ast::Var::FieldVar(ref var, _, _, _) => traverse_var(env, depth, var, escapes),
+        &ast::Var::SubscriptVar(ref var, ref exp, _, _) => {
+            traverse_var(env, depth, var, escapes);
+            traverse_exp(env, depth, exp, escapes);
+        },
+    }
+}
+
+fn traverse_decs(env: &EscapeEnv, depth: Depth, decs: &Vec<Box<ast::Dec>>, body: &ast::Exp,
+                 escapes: &mut HashSet<DeclId>) {
+    let mut env = EscapeEnv::new(Some(env));
+    for dec in decs.iter() {
+        match dec.as_ref() {
+            &ast::Dec::VarDec { name, ref init, .. } => {
+                traverse_exp(&env, depth, init, escapes);
+                env.enter(name, Rc::new((depth, dec.id())));
+            },
+            &ast::Dec::FunDec { ref params, ref body, .. } => {
+                let mut fenv = EscapeEnv::new(Some(&env));
+                for param in params.iter() {
+                    fenv.enter(param.name, Rc::new((depth + 1, param.id)));
+                }
+                traverse_exp(&fenv, depth + 1, body, escapes);
+            },
+            &ast::Dec::TypeDec { .. } => (),
+        }
+    }
+    traverse_exp(&env, depth, body, escapes);
+}
+
+fn traverse_exp(env: &EscapeEnv, depth: Depth, exp: &ast::Exp, escapes: &mut HashSet<DeclId>) {
+    match exp {
+        &ast::Exp::VarExp(ref var, _) => traverse_var(env, depth, var, escapes),
+        &ast::Exp::NilExp(_) | &ast::Exp::IntExp(_, _) | &ast::Exp::StringExp(_, _, _) | &ast::Exp::BreakExp(_, _) => (),
+        &ast::Exp::CallExp { ref args, .. } => {
+            for arg in args.iter() {
+                traverse_exp(env, depth, arg, escapes);
+            }
+        },
+        &ast::Exp::OpExp { ref left, ref right, .. } => {
+            traverse_exp(env, depth, left, escapes);
+            traverse_exp(env, depth, right, escapes);
+        },
+        &ast::Exp::RecordExp { ref fields, .. } => {
+            for &(_, ref exp, _) in fields.iter() {
+                traverse_exp(env, depth, exp, escapes);
+            }
+        },
+        &ast::Exp::SeqExp(ref exps, _) => {
+            for exp in exps.iter() {
+                traverse_exp(env, depth, exp, escapes);
+            }
+        },
+        &ast::Exp::AssignExp { ref var, ref exp, .. } => {
+            traverse_var(env, depth, var, escapes);
+            traverse_exp(env, depth, exp, escapes);
+        },
+        &ast::Exp::IfExp { ref test, ref then_, ref else_, .. } => {
+            traverse_exp(env, depth, test, escapes);
+            traverse_exp(env, depth, then_, escapes);
+            if let &Some(ref else_) = else_ {
+                traverse_exp(env, depth, else_, escapes);
+            }
+        },
+        &ast::Exp::WhileExp { ref test, ref body, .. } => {
+            traverse_exp(env, depth, test, escapes);
+            traverse_exp(env, depth, body, escapes);
+        },
+        &ast::Exp::ForExp { var, ref lo, ref hi, ref body, .. } => {
+            traverse_exp(env, depth, lo, escapes);
+            traverse_exp(env, depth, hi, escapes);
+            let mut env = EscapeEnv::new(Some(env));
+            env.enter(var, Rc::new((depth, exp.id())));
+            traverse_exp(&env, depth, body, escapes);
+        },
+        &ast::Exp::LetExp { ref decs, ref body, .. } => traverse_decs(env, depth, decs, body, escapes),
+        &ast::Exp::ArrayExp { ref size, ref init, .. } => {
+            traverse_exp(env, depth, size, escapes);
+            traverse_exp(env, depth, init, escapes);
+        },
+    }
+}
+
+fn mark_var(var: &mut ast::Var, escapes: &HashSet<DeclId>) {
+    match var {
+        &mut ast::Var::SimpleVar(_, _, _) => (),
+        &mut ast::Var::FieldVar(ref mut var, _, _, _) => mark_var(var, escapes),
+        &mut ast::Var::SubscriptVar(ref mut var, ref mut exp, _, _) => {
+            mark_var(var, escapes);
+            mark_exp(exp, escapes);
+        },
+    }
+}
+
+fn mark_exp(exp: &mut ast::Exp, escapes: &HashSet<DeclId>) {
+    let id = exp.id();
+    match exp {
+        &mut ast::Exp::VarExp(ref mut var, _) => mark_var(var, escapes),
+        &mut ast::Exp::NilExp(_) | &mut ast::Exp::IntExp(_, _) |
+        &mut ast::Exp::StringExp(_, _, _) | &mut ast::Exp::BreakExp(_, _) => (),
+        &mut ast::Exp::CallExp { ref mut args, .. } => {
+            for arg in args.iter_mut() {
+                mark_exp(arg, escapes);
+            }
+        },
+        &mut ast::Exp::OpExp { ref mut left, ref mut right, .. } => {
+            mark_exp(left, escapes);
+            mark_exp(right, escapes);
+        },
+        &mut ast::Exp::RecordExp { ref mut fields, .. } => {
+            for &mut (_, ref mut exp, _) in fields.iter_mut() {
+                mark_exp(exp, escapes);
+            }
+        },
+        &mut ast::Exp::SeqExp(ref mut exps, _) => {
+            for exp in exps.iter_mut() {
+                mark_exp(exp, escapes);
+            }
+        },
+        &mut ast::Exp::AssignExp { ref mut var, ref mut exp, .. } => {
+            mark_var(var, escapes);
+            mark_exp(exp, escapes);
+        },
+        &mut ast::Exp::IfExp { ref mut test, ref mut then_, ref mut else_, .. } => {
+            mark_exp(test, escapes);
+            mark_exp(then_, escapes);
+            if let &mut Some(ref mut else_) = else_ {
+                mark_exp(else_, escapes);
+            }
+        },
+        &mut ast::Exp::WhileExp { ref mut test, ref mut body, .. } => {
+            mark_exp(test, escapes);
+            mark_exp(body, escapes);
+        },
+        &mut ast::Exp::ForExp { ref mut escape, ref mut lo, ref mut hi, ref mut body, .. } => {
+            *escape = escapes.contains(&id);
+            mark_exp(lo, escapes);
+            mark_exp(hi, escapes);
+            mark_exp(body, escapes);
+        },
+        &mut ast::Exp::LetExp { ref mut decs, ref mut body, .. } => {
+            for dec in decs.iter_mut() {
+                let dec_id = dec.id();
+                match dec.as_mut() {
+                    &mut ast::Dec::VarDec { ref mut escape, ref mut init, .. } => {
+                        *escape = escapes.contains(&dec_id);
+                        mark_exp(init, escapes);
+                    },
+                    &mut ast::Dec::FunDec { ref mut params, ref mut body, .. } => {
+                        for param in params.iter_mut() {
+                            let param_id = param.id;
+                            param.escape = escapes.contains(&param_id);
+                        }
+                        mark_exp(body, escapes);
+                    },
+                    &mut ast::Dec::TypeDec { .. } => (),
+                }
+            }
+            mark_exp(body, escapes);
+        },
+        &mut ast::Exp::ArrayExp { ref mut size, ref mut init, .. } => {
+            mark_exp(size, escapes);
+            mark_exp(init, escapes);
+        },
+    }
+}
+
+pub fn find_escapes(exp: &mut ast::Exp) {
+    let mut escapes = HashSet::new();
+    traverse_exp(&EscapeEnv::new(None), 0, exp, &mut escapes);
+    mark_exp(exp, &escapes);
+}
+
+#[test]
+fn test_find_escapes() {
+    use parser::parse;
+
+    let (mut p, _) = parse("let var a := 1 var b := 2 \
+                            function f(x: int, y: int): int = a + x + (let function g(): int = y in g() end) \
+                            in for i := 0 to 1 do b := i end").unwrap();
+    find_escapes(&mut p);
+
+    if let ast::Exp::LetExp { ref decs, ref body, .. } = *p {
+        let escapes: Vec<bool> = decs.iter().filter_map(|d| match d.as_ref() {
+            &ast::Dec::VarDec { escape, .. } => Some(escape),
+            _ => None,
+        }).collect();
+        assert_eq!(escapes, vec![true, false]);
+
+        if let ast::Dec::FunDec { ref params, .. } = *decs[2] {
+            assert_eq!(params.iter().map(|p| p.escape).collect::<Vec<bool>>(), vec![false, true]);
+        } else {
+            panic!("expected a function declaration");
+        }
+
+        if let ast::Exp::ForExp { escape, .. } = **body {
+            assert!(!escape);
+        } else {
+            panic!("expected a for loop");
+        }
+    } else {
+        panic!("expected a let expression");
+    }
+}
@@ -0,0 +1,98 @@
+use assem::Instr;
+use temp::{Temp, Label};
+
+use std::collections::HashMap;
+
+// The control-flow graph of a procedure body (Appel chapter 10), with one
+// node per instruction: node i is instrs[i].
+pub struct FlowGraph {
+    pub succ: Vec<Vec<usize>>,
+    pub pred: Vec<Vec<usize>>,
+    pub defs: Vec<Vec<Temp>>,
+    pub uses: Vec<Vec<Temp>>,
+    // a move whose source and destination may share a register
+    pub is_move: Vec<bool>,
+}
+
+impl FlowGraph {
+    pub fn len(&self) -> usize {
+        self.succ.len()
+    }
+}
+
+// Control falls through to the next instruction unless the instruction
+// jumps; a jump to a label outside the body leaves the procedure.
+pub fn flow_graph(instrs: &[Instr]) -> FlowGraph {
+    let mut labels: HashMap<Label, usize> = HashMap::new();
+    for (i, instr) in instrs.iter().enumerate() {
+        if let &Instr::Label { label, .. } = instr {
+            labels.insert(label, i);
+        }
+    }
+
+    let n = instrs.len();
+    let mut graph = FlowGraph {
+        succ: vec![vec![]; n],
+        pred: vec![vec![]; n],
+        defs: Vec::with_capacity(n),
+        uses: Vec::with_capacity(n),
+        is_move: Vec::with_capacity(n),
+    };
+    for (i, instr) in instrs.iter().enumerate() {
+        let mut succ = match instr.jumps() {
+            Some(targets) => targets.iter().filter_map(|l| labels.get(l).cloned()).collect(),
+            None if i + 1 < n => vec![i + 1],
+            None => vec![],
+        };
+        succ.sort();
+        succ.dedup();
+        for &s in succ.iter() {
+            graph.pred[s].push(i);
+        }
+        graph.succ[i] = succ;
+
+        let mut defs = instr.defs();
+        defs.sort();
+        defs.dedup();
+        let mut uses = instr.uses();
+        uses.sort();
+        uses.dedup();
+        graph.defs.push(defs);
+        graph.uses.push(uses);
+        graph.is_move.push(match instr {
+            &Instr::Move { .. } => true,
+            _ => false,
+        });
+    }
+    graph
+}
+
+#[test]
+fn test_flow_graph() {
+    use symbol::SymbolTable;
+
+    let mut table = SymbolTable::new();
+    let (top, exit, done) = (Label(table.symbol("top")), Label(table.symbol("exit")), Label(table.symbol("done")));
+    let (a, b) = (Temp(100), Temp(101));
+    let jump = |assem: &str, src: Vec<Temp>, targets: Vec<Label>| {
+        Instr::Oper { assem: String::from(assem), dst: vec![], src: src, jump: Some(targets) }
+    };
+    let instrs = vec![
+        Instr::Label { assem: String::from("top:"), label: top },
+        Instr::oper(String::from("addi `d0, `s0, 1"), vec![a], vec![a, a]),
+        // a conditional branch lists both targets, the second one the
+        // label right after it
+        jump("beqz `s0, `j0", vec![a], vec![exit, top]),
+        Instr::Label { assem: String::from("exit:"), label: exit },
+        Instr::Move { assem: String::from("mv `d0, `s0"), dst: b, src: a },
+        // done is outside the body
+        jump("j `j0", vec![], vec![done]),
+    ];
+    let graph = flow_graph(&instrs);
+    assert_eq!(graph.len(), 6);
+    assert_eq!(graph.succ, vec![vec![1], vec![2], vec![0, 3], vec![4], vec![5], vec![]]);
+    assert_eq!(graph.pred, vec![vec![2], vec![0], vec![1], vec![2], vec![3], vec![4]]);
+    assert_eq!((&graph.defs[1], &graph.uses[1]), (&vec![a], &vec![a]));
+    assert_eq!((&graph.defs[4], &graph.uses[4]), (&vec![b], &vec![a]));
+    assert_eq!(graph.is_move, vec![false, false, false, false, true, false]);
+}
@@ -0,0 +1,642 @@
+use ast::{self, Dec, Exp, Field, Oper, Position, Ty, Var};
+use lexer::Span;
+use parser;
+use symbol::SymbolTable;
+
+// Formats programs: every let, if, while, for and function breaks over
+// indented lines unless it fits on one line (a let never does), and
+// expressions only get the parentheses that the precedence of the grammar
+// asks for. Comments are kept, each on a line of its own before the
+// declaration or expression that follows them, as are blank lines between
+// declarations and between the expressions of a sequence.
+
+const WIDTH: usize = 80;
+const INDENT: usize = 2;
+
+// A document in the style of Wadler's "prettier printer": a group is laid
+// out on one line if it fits, and otherwise breaks all of its lines.
+enum Doc {
+    Text(String),
+    // a space, or a new line when the group around it breaks
+    Line,
+    // nothing, or a new line when the group around it breaks
+    Break,
+    // a new line that breaks every group around it
+    HardLine,
+    Nest(Box<Doc>),
+    Group(Box<Doc>),
+    Concat(Vec<Doc>),
+}
+
+fn text<S: Into<String>>(s: S) -> Doc {
+    Doc::Text(s.into())
+}
+
+fn nest(docs: Vec<Doc>) -> Doc {
+    Doc::Nest(Box::new(Doc::Concat(docs)))
+}
+
+fn group(docs: Vec<Doc>) -> Doc {
+    Doc::Group(Box::new(Doc::Concat(docs)))
+}
+
+impl Doc {
+    // the width of the document on one line, None if it has to break
+    fn flat_width(&self) -> Option<usize> {
+        match self {
+            &Doc::Text(ref s) => Some(s.chars().count()),
+            &Doc::Line => Some(1),
+            &Doc::Break => Some(0),
+            &Doc::HardLine => None,
+            &Doc::Nest(ref doc) | &Doc::Group(ref doc) => doc.flat_width(),
+            &Doc::Concat(ref docs) => docs.iter().map(|doc| doc.flat_width()).sum(),
+        }
+    }
+}
+
+struct Printer {
+    out: String,
+    column: usize,
+    // the indentation of a new line, written with its first text so that
+    // blank lines stay empty
+    indent: Option<usize>,
+}
+
+impl Printer {
+    fn newline(&mut self, indent: usize) {
+        self.out.push('\n');
+        self.indent = Some(indent);
+        self.column = indent;
+    }
+
+    fn text(&mut self, s: &str) {
+        if let Some(indent) = self.indent.take() {
+            for _ in 0..indent {
+                self.out.push(' ');
+            }
+        }
+        self.out.push_str(s);
+        self.column = match s.rfind('\n') {
+            Some(i) => s[i + 1..].chars().count(),
+            None => self.column + s.chars().count(),
+        };
+    }
+
+    fn print(&mut self, doc: &Doc, indent: usize, flat: bool) {
+        match doc {
+            &Doc::Text(ref s) => self.text(s),
+            &Doc::Line if flat => self.text(" "),
+            &Doc::Break if flat => (),
+            &Doc::Line | &Doc::Break | &Doc::HardLine => self.newline(indent),
+            &Doc::Nest(ref doc) => self.print(doc, indent + INDENT, flat),
+            &Doc::Group(ref doc) => {
+                let fits = doc.flat_width().map_or(false, |width| self.column + width <= WIDTH);
+                self.print(doc, indent, flat || fits)
+            },
+            &Doc::Concat(ref docs) => {
+                for doc in docs.iter() {
+                    self.print(doc, indent, flat);
+                }
+            },
+        }
+    }
+}
+
+// The precedence levels of the grammar, loosest first.
+const EXP: u8 = 0;
+const ARRAY: u8 = 1;
+const OR: u8 = 2;
+const AND: u8 = 3;
+const COMPARE: u8 = 4;
+const ARITH: u8 = 5;
+const FACTOR: u8 = 6;
+const TERM: u8 = 7;
+
+// the expression inside parentheses around a single expression
+fn unparen(exp: &Exp) -> &Exp {
+    match exp {
+        &Exp::SeqExp(ref exps, _) if exps.len() == 1 => unparen(&exps[0]),
+        _ => exp,
+    }
+}
+
+// whether an expression is the integer n, as the sugar for -, & and | puts
+// them in the tree
+fn is_int(exp: &Exp, n: i32) -> bool {
+    match exp {
+        &Exp::IntExp(i, _) => i == n,
+        _ => false,
+    }
+}
+
+// where an expression starts in the source, if it says
+fn start(exp: &Exp) -> Option<Position> {
+    match exp {
+        &Exp::VarExp(ref var, _) => match **var {
+            Var::SimpleVar(_, pos, _) | Var::FieldVar(_, _, pos, _) | Var::SubscriptVar(_, _, pos, _) => Some(pos),
+        },
+        &Exp::SeqExp(ref exps, _) if exps.len() == 1 => start(&exps[0]),
+        &Exp::NilExp(_) | &Exp::IntExp(_, _) | &Exp::SeqExp(_, _) => None,
+        &Exp::StringExp(_, pos, _) | &Exp::BreakExp(pos, _) => Some(pos),
+        &Exp::CallExp { pos, .. } | &Exp::OpExp { pos, .. } | &Exp::RecordExp { pos, .. } |
+        &Exp::AssignExp { pos, .. } | &Exp::IfExp { pos, .. } | &Exp::WhileExp { pos, .. } |
+        &Exp::ForExp { pos, .. } | &Exp::LetExp { pos, .. } | &Exp::ArrayExp { pos, .. } => Some(pos),
+    }
+}
+
+fn operator(op: Oper) -> (&'static str, u8) {
+    match op {
+        Oper::PlusOp => ("+", ARITH),
+        Oper::MinusOp => ("-", ARITH),
+        Oper::TimesOp => ("*", FACTOR),
+        Oper::DivideOp => ("/", FACTOR),
+        Oper::EqOp => ("=", COMPARE),
+        Oper::NeqOp => ("<>", COMPARE),
+        Oper::LtOp => ("<", COMPARE),
+        Oper::LeOp => ("<=", COMPARE),
+        Oper::GtOp => (">", COMPARE),
+        Oper::GeOp => (">=", COMPARE),
+    }
+}
+
+fn quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 || c as u32 == 0x7f => quoted.push_str(&format!("\\{:03}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+struct Formatter<'a> {
+    source: &'a str,
+    comments: &'a [Span],
+    // the first comment not written yet
+    next_comment: usize,
+    symbol_table: &'a SymbolTable,
+}
+
+impl<'a> Formatter<'a> {
+    fn name(&self, symbol: ast::Symbol) -> String {
+        self.symbol_table.name(&symbol).clone()
+    }
+
+    // the operator and operands of the "a | b" or "a & b" that an if
+    // expression was written as, which leaves it at the position of a
+    // rather than at that of an if keyword
+    fn logic_op<'e>(&self, exp: &'e Exp) -> Option<(&'static str, &'e Exp, &'e Exp)> {
+        match exp {
+            &Exp::IfExp{ ref test, ref then_, else_: Some(ref else_), pos, .. } => {
+                let rest = &self.source[pos..];
+                let keyword = rest.starts_with("if") &&
+                    !rest[2..].starts_with(|c: char| c.is_alphanumeric() || c == '_');
+                if keyword {
+                    None
+                } else if is_int(then_, 1) {
+                    Some(("|", test, else_))
+                } else if is_int(else_, 0) {
+                    Some(("&", test, then_))
+                } else {
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+
+    fn precedence(&self, exp: &Exp) -> u8 {
+        let exp = unparen(exp);
+        match self.logic_op(exp) {
+            Some(("|", _, _)) => return OR,
+            Some(_) => return AND,
+            None => (),
+        }
+        match exp {
+            &Exp::IfExp { .. } | &Exp::WhileExp { .. } | &Exp::ForExp { .. } | &Exp::AssignExp { .. } => EXP,
+            &Exp::ArrayExp { .. } => ARRAY,
+            &Exp::OpExp { ref left, op: Oper::MinusOp, .. } if is_int(left, 0) => TERM,
+            &Exp::OpExp { op, .. } => operator(op).1,
+            _ => TERM,
+        }
+    }
+
+    // whether an expression ends with an if without an else, which would
+    // take an else written after it
+    fn is_open(&self, exp: &Exp) -> bool {
+        let exp = unparen(exp);
+        if self.logic_op(exp).is_some() {
+            return false;
+        }
+        match exp {
+            &Exp::IfExp { else_: None, .. } => true,
+            &Exp::IfExp { else_: Some(ref else_), .. } => self.is_open(else_),
+            &Exp::WhileExp { ref body, .. } | &Exp::ForExp { ref body, .. } => self.is_open(body),
+            &Exp::AssignExp { ref exp, .. } => self.is_open(exp),
+            _ => false,
+        }
+    }
+
+    fn blank_line_before(&self, pos: Position) -> bool {
+        let before = &self.source[..pos];
+        let space = &before[before.trim_end().len()..];
+        space.matches('\n').count() > 1
+    }
+
+    // the comments to write before something at pos, each followed by a new
+    // line; a blank line before them, or before the code, stays, except at
+    // the start of a block
+    fn comments_before(&mut self, pos: Option<Position>, first: bool) -> Doc {
+        let pos = match pos {
+            Some(pos) => pos,
+            None => return Doc::Concat(vec![]),
+        };
+        let mut docs = vec![];
+        let mut first = first;
+        while self.next_comment < self.comments.len() && self.comments[self.next_comment].lo < pos {
+            let span = self.comments[self.next_comment];
+            if !first && self.blank_line_before(span.lo) {
+                docs.push(Doc::HardLine);
+            }
+            docs.push(text(&self.source[span.lo..span.hi]));
+            docs.push(Doc::HardLine);
+            self.next_comment += 1;
+            first = false;
+        }
+        if !first && self.blank_line_before(pos) {
+            docs.push(Doc::HardLine);
+        }
+        Doc::Concat(docs)
+    }
+
+    // the comments after the program
+    fn trailing_comments(&mut self) -> Doc {
+        let mut docs = vec![];
+        for span in self.comments[self.next_comment..].iter() {
+            docs.push(Doc::HardLine);
+            if self.blank_line_before(span.lo) {
+                docs.push(Doc::HardLine);
+            }
+            docs.push(text(&self.source[span.lo..span.hi]));
+        }
+        self.next_comment = self.comments.len();
+        Doc::Concat(docs)
+    }
+
+    // expressions separated by sep, each on a line of its own when they break
+    fn list(&mut self, exps: &[&Exp], sep: &str, line: fn() -> Doc) -> Vec<Doc> {
+        let mut docs = vec![];
+        for (i, exp) in exps.iter().enumerate() {
+            if i > 0 {
+                docs.push(text(sep));
+                docs.push(line());
+            }
+            docs.push(self.comments_before(start(exp), i == 0));
+            docs.push(self.exp(exp, EXP));
+        }
+        docs
+    }
+
+    // a body on the lines after what introduces it
+    fn body(&mut self, exp: &Exp) -> Doc {
+        let comments = self.comments_before(start(exp), true);
+        nest(vec![Doc::Line, comments, self.exp(exp, EXP)])
+    }
+
+    fn parens(&mut self, exp: &Exp) -> Doc {
+        Doc::Concat(vec![text("("), self.exp(exp, EXP), text(")")])
+    }
+
+    // an expression where the grammar wants one of precedence level or
+    // tighter
+    fn exp(&mut self, exp: &Exp, level: u8) -> Doc {
+        let exp = unparen(exp);
+        if self.precedence(exp) < level {
+            return self.parens(exp);
+        }
+        if let Some((op, left, right)) = self.logic_op(exp) {
+            let level = if op == "|" { OR } else { AND };
+            return Doc::Concat(vec![self.exp(left, level), text(format!(" {} ", op)), self.exp(right, level + 1)]);
+        }
+        match exp {
+            &Exp::VarExp(ref var, _) => self.var(var),
+            &Exp::NilExp(_) => text("nil"),
+            &Exp::IntExp(i, _) => text(i.to_string()),
+            &Exp::StringExp(ref s, _, _) => text(quote(s)),
+            &Exp::CallExp { func, ref args, .. } => {
+                let args: Vec<&Exp> = args.iter().map(|arg| &**arg).collect();
+                let mut docs = vec![text(format!("{}(", self.name(func)))];
+                if !args.is_empty() {
+                    let mut list = vec![Doc::Break];
+                    list.extend(self.list(&args, ",", || Doc::Line));
+                    docs.push(nest(list));
+                    docs.push(Doc::Break);
+                }
+                docs.push(text(")"));
+                group(docs)
+            },
+            &Exp::OpExp { ref left, op: Oper::MinusOp, ref right, .. } if is_int(left, 0) =>
+                Doc::Concat(vec![text("-"), self.exp(right, TERM)]),
+            &Exp::OpExp { ref left, op, ref right, .. } => {
+                let (op, level) = operator(op);
+                Doc::Concat(vec![self.exp(left, level), text(format!(" {} ", op)), self.exp(right, level + 1)])
+            },
+            &Exp::RecordExp { ref fields, typ, .. } => {
+                let mut docs = vec![text(format!("{} {{", self.name(typ)))];
+                if !fields.is_empty() {
+                    let mut list = vec![Doc::Break];
+                    for (i, &(name, ref exp, pos)) in fields.iter().enumerate() {
+                        if i > 0 {
+                            list.push(text(","));
+                            list.push(Doc::Line);
+                        }
+                        list.push(self.comments_before(Some(pos), i == 0));
+                        list.push(text(format!("{} = ", self.name(name))));
+                        list.push(self.exp(exp, EXP));
+                    }
+                    docs.push(nest(list));
+                    docs.push(Doc::Break);
+                }
+                docs.push(text("}"));
+                group(docs)
+            },
+            &Exp::SeqExp(ref exps, _) => {
+                if exps.is_empty() {
+                    return text("()");
+                }
+                let exps: Vec<&Exp> = exps.iter().map(|exp| &**exp).collect();
+                let mut list = vec![Doc::Break];
+                list.extend(self.list(&exps, ";", || Doc::Line));
+                group(vec![text("("), nest(list), Doc::Break, text(")")])
+            },
+            &Exp::AssignExp { ref var, ref exp, .. } =>
+                Doc::Concat(vec![self.var(var), text(" := "), self.exp(exp, EXP)]),
+            &Exp::IfExp { ref test, ref then_, ref else_, .. } => {
+                let mut docs = vec![text("if "), self.exp(test, EXP), text(" then")];
+                match else_ {
+                    &None => docs.push(self.body(then_)),
+                    &Some(ref else_) => {
+                        // an if without an else would take this one
+                        if self.is_open(then_) {
+                            let comments = self.comments_before(start(then_), true);
+                            docs.push(nest(vec![Doc::Line, comments, self.parens(then_)]));
+                        } else {
+                            docs.push(self.body(then_));
+                        }
+                        docs.push(Doc::Line);
+                        let else_ = unparen(else_);
+                        match else_ {
+                            &Exp::IfExp { .. } if self.logic_op(else_).is_none() => {
+                                docs.push(self.comments_before(start(else_), true));
+                                docs.push(text("else "));
+                                docs.push(self.exp(else_, EXP));
+                            },
+                            _ => {
+                                docs.push(text("else"));
+                                docs.push(self.body(else_));
+                            },
+                        }
+                    },
+                }
+                group(docs)
+            },
+            &Exp::WhileExp { ref test, ref body, .. } => {
+                let test = self.exp(test, EXP);
+                group(vec![text("while "), test, text(" do"), self.body(body)])
+            },
+            &Exp::ForExp { var, ref lo, ref hi, ref body, .. } => {
+                let (lo, hi) = (self.exp(lo, EXP), self.exp(hi, EXP));
+                group(vec![text(format!("for {} := ", self.name(var))), lo, text(" to "), hi, text(" do"),
+                           self.body(body)])
+            },
+            &Exp::BreakExp(_, _) => text("break"),
+            &Exp::LetExp { ref decs, ref body, .. } => {
+                let mut docs = vec![text("let")];
+                let mut list = vec![];
+                for (i, dec) in decs.iter().enumerate() {
+                    list.push(Doc::HardLine);
+                    list.push(self.comments_before(Some(dec_pos(dec)), i == 0));
+                    list.push(self.dec(dec));
+                }
+                docs.push(nest(list));
+                docs.push(Doc::HardLine);
+                docs.push(text("in"));
+                let exps: Vec<&Exp> = match unparen(body) {
+                    &Exp::SeqExp(ref exps, _) => exps.iter().map(|exp| &**exp).collect(),
+                    body => vec![body],
+                };
+                if !exps.is_empty() {
+                    let mut list = vec![Doc::HardLine];
+                    list.extend(self.list(&exps, ";", || Doc::HardLine));
+                    docs.push(nest(list));
+                }
+                docs.push(Doc::HardLine);
+                docs.push(text("end"));
+                Doc::Concat(docs)
+            },
+            &Exp::ArrayExp { typ, ref size, ref init, .. } => {
+                let size = self.exp(size, EXP);
+                Doc::Concat(vec![text(format!("{}[", self.name(typ))), size, text("] of "), self.exp(init, ARRAY)])
+            },
+        }
+    }
+
+    fn var(&mut self, var: &Var) -> Doc {
+        match var {
+            &Var::SimpleVar(name, _, _) => text(self.name(name)),
+            &Var::FieldVar(ref var, name, _, _) => Doc::Concat(vec![self.var(var), text(format!(".{}", self.name(name)))]),
+            &Var::SubscriptVar(ref var, ref exp, _, _) =>
+                Doc::Concat(vec![self.var(var), text("["), self.exp(exp, EXP), text("]")]),
+        }
+    }
+
+    fn fields(&self, fields: &[Box<Field>]) -> String {
+        let fields: Vec<String> = fields.iter()
+            .map(|field| format!("{}: {}", self.name(field.name), self.name(field.typ)))
+            .collect();
+        fields.join(", ")
+    }
+
+    fn dec(&mut self, dec: &Dec) -> Doc {
+        match dec {
+            &Dec::TypeDec { name, ref ty, .. } => {
+                let ty = match **ty {
+                    Ty::NameTy(name, _) => self.name(name),
+                    Ty::RecordTy(ref fields) => format!("{{{}}}", self.fields(fields)),
+                    Ty::ArrayTy(name, _) => format!("array of {}", self.name(name)),
+                };
+                text(format!("type {} = {}", self.name(name), ty))
+            },
+            &Dec::VarDec { name, ref typ, ref init, .. } => {
+                let typ = match typ {
+                    &Some((typ, _)) => format!(": {}", self.name(typ)),
+                    &None => String::new(),
+                };
+                Doc::Concat(vec![text(format!("var {}{} := ", self.name(name), typ)), self.exp(init, EXP)])
+            },
+            &Dec::FunDec { name, ref params, ref result, ref body, .. } => {
+                let result = match result {
+                    &Some((result, _)) => format!(": {}", self.name(result)),
+                    &None => String::new(),
+                };
+                let header = format!("function {}({}){} =", self.name(name), self.fields(params), result);
+                group(vec![text(header), self.body(body)])
+            },
+        }
+    }
+}
+
+fn dec_pos(dec: &Dec) -> Position {
+    match dec {
+        &Dec::FunDec { pos, .. } | &Dec::VarDec { pos, .. } | &Dec::TypeDec { pos, .. } => pos,
+    }
+}
+
+// the program in source, formatted
+pub fn format(source: &str) -> Result<String, String> {
+    let (exp, symbol_table, comments) = parser::parse_with_comments(source).map_err(|e| parser::error_message(&e))?;
+    let mut formatter = Formatter {
+        source: source,
+        comments: &comments,
+        next_comment: 0,
+        symbol_table: &symbol_table,
+    };
+    let comments = formatter.comments_before(start(&exp), true);
+    let exp = formatter.exp(&exp, EXP);
+    let doc = Doc::Concat(vec![comments, exp, formatter.trailing_comments()]);
+    let mut printer = Printer {
+        out: String::new(),
+        column: 0,
+        indent: None,
+    };
+    printer.print(&doc, 0, false);
+    printer.out.push('\n');
+    Ok(printer.out)
+}
+
+#[test]
+fn test_fmt() {
+    // the tree without its positions and ids, nor the parentheses around
+    // single expressions, which the formatter keeps only where they are
+    // needed
+    fn erase(exp: &Exp) -> Exp {
+        let b = |exp: &Exp| Box::new(erase(exp));
+        match *unparen(exp) {
+            Exp::VarExp(ref var, _) => Exp::VarExp(Box::new(erase_var(var)), 0),
+            Exp::StringExp(ref s, _, _) => Exp::StringExp(s.clone(), 0, 0),
+            Exp::CallExp { func, ref args, .. } =>
+                Exp::CallExp { func: func, args: args.iter().map(|a| b(a)).collect(), pos: 0, id: 0 },
+            Exp::OpExp { ref left, op, ref right, .. } => Exp::OpExp { left: b(left), op: op, right: b(right), pos: 0, id: 0 },
+            Exp::RecordExp { ref fields, typ, .. } => Exp::RecordExp {
+                fields: fields.iter().map(|&(name, ref exp, _)| (name, b(exp), 0)).collect(),
+                typ: typ,
+                pos: 0,
+                id: 0,
+            },
+            Exp::SeqExp(ref exps, _) => Exp::SeqExp(exps.iter().map(|e| b(e)).collect(), 0),
+            Exp::AssignExp { ref var, ref exp, .. } =>
+                Exp::AssignExp { var: Box::new(erase_var(var)), exp: b(exp), pos: 0, id: 0 },
+            Exp::IfExp { ref test, ref then_, ref else_, .. } =>
+                Exp::IfExp { test: b(test), then_: b(then_), else_: else_.as_ref().map(|e| b(e)), pos: 0, id: 0 },
+            Exp::WhileExp { ref test, ref body, .. } => Exp::WhileExp { test: b(test), body: b(body), pos: 0, id: 0 },
+            Exp::ForExp { var, escape, ref lo, ref hi, ref body, .. } =>
+                Exp::ForExp { var: var, escape: escape, lo: b(lo), hi: b(hi), body: b(body), pos: 0, id: 0 },
+            Exp::BreakExp(_, _) => Exp::BreakExp(0, 0),
+            Exp::NilExp(_) => Exp::NilExp(0),
+            Exp::IntExp(i, _) => Exp::IntExp(i, 0),
+            Exp::LetExp { ref decs, ref body, .. } =>
+                Exp::LetExp { decs: decs.iter().map(|d| Box::new(erase_dec(d))).collect(), body: b(body), pos: 0, id: 0 },
+            Exp::ArrayExp { typ, ref size, ref init, .. } =>
+                Exp::ArrayExp { typ: typ, size: b(size), init: b(init), pos: 0, id: 0 },
+        }
+    }
+    fn erase_var(var: &Var) -> Var {
+        match *var {
+            Var::SimpleVar(name, _, _) => Var::SimpleVar(name, 0, 0),
+            Var::FieldVar(ref var, name, _, _) => Var::FieldVar(Box::new(erase_var(var)), name, 0, 0),
+            Var::SubscriptVar(ref var, ref exp, _, _) =>
+                Var::SubscriptVar(Box::new(erase_var(var)), Box::new(erase(exp)), 0, 0),
+        }
+    }
+    fn erase_fields(fields: &[Box<Field>]) -> Vec<Box<Field>> {
+        fields.iter().map(|f| Box::new(Field { pos: 0, id: 0, ..(**f).clone() })).collect()
+    }
+    fn erase_dec(dec: &Dec) -> Dec {
+        match *dec {
+            Dec::FunDec { name, ref params, result, ref body, .. } => Dec::FunDec {
+                name: name,
+                params: erase_fields(params),
+                result: result.map(|(r, _)| (r, 0)),
+                body: Box::new(erase(body)),
+                pos: 0,
+                id: 0,
+            },
+            Dec::VarDec { name, escape, typ, ref init, .. } =>
+                Dec::VarDec { name: name, escape: escape, typ: typ.map(|(t, _)| (t, 0)), init: Box::new(erase(init)), pos: 0, id: 0 },
+            Dec::TypeDec { name, ref ty, .. } => Dec::TypeDec {
+                name: name,
+                ty: Box::new(match **ty {
+                    Ty::NameTy(t, _) => Ty::NameTy(t, 0),
+                    Ty::RecordTy(ref fields) => Ty::RecordTy(erase_fields(fields)),
+                    Ty::ArrayTy(t, _) => Ty::ArrayTy(t, 0),
+                }),
+                pos: 0,
+                id: 0,
+            },
+        }
+    }
+
+    let programs = [
+        "/* a comment */ let type point = {x: int, y: int} type points = array of point
+            // the origin
+            var o := point {x = 0, y = 0}  var ps := points [10] of o
+            function norm(p: point): int = (if p.x < 0 then -p.x else p.x) + (if p.y < 0 then -p.y else p.y)
+         in for i := 0 to 9 do (ps[i] := point {x = i, y = -i * 2}; printi(norm(ps[i]))); ps[1].x end
+         // done",
+        "if a then if b then c else d",
+        "if a then (if b then c) else d",
+        "if a then (while b do if c then d) else e",
+        "(a | b & c) & -(d + e) * f - (g - h) / -(-i) = (j = k)",
+        "x := (if a then b else c) + (x := 1; 2)",
+        "(f(if a then b, (c; d), ()); g())",
+        "print(\"a\\\"b\\\\c\\nd\\te\\001\") ",
+        "let var a: int := 1 in end",
+        "let function f() = () function g(x: int, y: string): string = let in y end in f(); g(1, \"\") end",
+        "a [if x then 1 else 2] of b [3] of 4",
+        "if a then b else if c then d else e",
+        "while 1 do (if a then break; a := a - 1)",
+    ];
+    for program in programs.iter() {
+        let formatted = format(program).unwrap();
+        let (exp, _) = parser::parse(program).unwrap();
+        let (again, _) = parser::parse(&formatted).unwrap_or_else(|e| panic!("{:?} in\n{}", e, formatted));
+        assert_eq!(erase(&again), erase(&exp), "\n{}", formatted);
+        assert_eq!(format(&formatted).unwrap(), formatted);
+    }
+
+    assert_eq!(format(programs[0]).unwrap(), "\
+/* a comment */
+let
+  type point = {x: int, y: int}
+  type points = array of point
+  // the origin
+  var o := point {x = 0, y = 0}
+  var ps := points[10] of o
+  function norm(p: point): int =
+    (if p.x < 0 then -p.x else p.x) + (if p.y < 0 then -p.y else p.y)
+in
+  for i := 0 to 9 do (ps[i] := point {x = i, y = -i * 2}; printi(norm(ps[i])));
+  ps[1].x
+end
+// done
+");
+    assert_eq!(format("if a then (if b then c) else d").unwrap(), "if a then (if b then c) else d\n");
+    assert_eq!(format("(a | b & c) & -(d + e)").unwrap(), "(a | b & c) & -(d + e)\n");
+    assert_eq!(format("( (1 + 2) * 3 )").unwrap(), "(1 + 2) * 3\n");
+}
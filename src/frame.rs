@@ -0,0 +1,99 @@
+use ir;
+use ir::{Exp, Stm, seq};
+use temp::{Temp, Label, TempGenerator};
+
+// Where a formal parameter or local variable lives: at an offset from the
+// frame pointer, or in a register (temp) if it does not escape.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    InFrame(i32),
+    InReg(Temp),
+}
+
+// An activation record layout for one target architecture. The translate
+// phase is written against this trait only.
+pub trait Frame: Sized + Clone {
+    // formals_escape[i] is true if the i-th formal escapes (is accessed from
+    // a nested function) and must therefore live in memory
+    fn new_frame(name: Label, formals_escape: Vec<bool>, gen: &mut TempGenerator) -> Self;
+
+    fn name(&self) -> Label;
+
+    // the formals as seen from inside the callee
+    fn formals(&self) -> &[Access];
+
+    fn alloc_local(&mut self, escape: bool, gen: &mut TempGenerator) -> Access;
+
+    // frame pointer register
+    fn fp() -> Temp;
+
+    // return value register
+    fn rv() -> Temp;
+
+    fn word_size() -> i32;
+
+    // the expression that reads or writes the variable at access, given the
+    // address of the frame it lives in
+    fn exp(access: Access, fp: ir::Exp) -> ir::Exp {
+        match access {
+            Access::InFrame(offset) => ir::Exp::Mem(Box::new(
+                ir::Exp::BinOp(ir::BinOp::Plus, Box::new(fp), Box::new(ir::Exp::Const(offset))))),
+            Access::InReg(t) => ir::Exp::Temp(t),
+        }
+    }
+
+    // the assembly-level name of a runtime function, for targets whose C
+    // symbols differ from the names used in Tiger
+    fn external_name(name: &str) -> String {
+        name.to_owned()
+    }
+
+    // a call to a function of the runtime system, which takes no static link
+    fn external_call(name: Label, args: Vec<ir::Exp>) -> ir::Exp {
+        ir::Exp::Call(Box::new(ir::Exp::Name(name)), args)
+    }
+
+    // wraps a function body with the moves of incoming arguments to where
+    // the function sees them, and the saving and restoring of callee-save
+    // registers
+    fn proc_entry_exit1(&self, body: ir::Stm, gen: &mut TempGenerator) -> ir::Stm;
+}
+
+// proc_entry_exit1 for targets that pass the first arguments in registers:
+// moves every incoming argument to where the body expects it (the others
+// are at stack_arg(i) from the frame pointer), and keeps the callee-save
+// registers in temps during the body, which the register allocator can
+// coalesce away.
+pub fn shift_view<F: Frame>(frame: &F, body: Stm, arg_registers: &[Temp], stack_arg: &dyn Fn(usize) -> Access,
+                            callee_saves: &[Temp], gen: &mut TempGenerator) -> Stm {
+    let fp = || Exp::Temp(F::fp());
+    let saved: Vec<Temp> = callee_saves.iter().map(|_| gen.new_temp()).collect();
+    let mut stms: Vec<Stm> = saved.iter().zip(callee_saves.iter())
+        .map(|(&t, &r)| Stm::Move(Box::new(Exp::Temp(t)), Box::new(Exp::Temp(r))))
+        .collect();
+
+    for (i, &access) in frame.formals().iter().enumerate() {
+        let src = if i < arg_registers.len() {
+            Exp::Temp(arg_registers[i])
+        } else if access == stack_arg(i) {
+            continue;
+        } else {
+            F::exp(stack_arg(i), fp())
+        };
+        stms.push(Stm::Move(Box::new(F::exp(access, fp())), Box::new(src)));
+    }
+
+    stms.push(body);
+    for (&t, &r) in saved.iter().zip(callee_saves.iter()) {
+        stms.push(Stm::Move(Box::new(Exp::Temp(r)), Box::new(Exp::Temp(t))));
+    }
+    seq(stms)
+}
+
+pub enum Fragment<F: Frame> {
+    Proc {
+        body: ir::Stm,
+        frame: F,
+    },
+    String(Label, String),
+}
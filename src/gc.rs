@@ -0,0 +1,138 @@
+use assem::Instr;
+use codegen::Codegen;
+use flow::flow_graph;
+use frame::{Access, Frame};
+use ir::{Exp, Stm};
+use liveness::liveness;
+use regalloc::rewrite_program;
+use symbol::SymbolTable;
+use temp::{Label, Temp, TempGenerator};
+
+use std::collections::{HashMap, HashSet};
+
+// Support for the copying garbage collector of the native runtime
+// (runtime.c), which must find and update every pointer to the heap held
+// by the compiled program when it collects, that is during a call to the
+// runtime. Pointers are only kept in frame slots across calls, and each
+// call has a pointer map listing those slots.
+
+// The offsets from the frame pointer of the slots holding pointers while a
+// call is under way, by the label of the address the call returns to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PointerMap {
+    pub return_label: Label,
+    pub offsets: Vec<i32>,
+}
+
+// Labels the return address of every call, and moves the temps holding
+// pointers (as marked in gen) that are live across a call to frame slots
+// for their whole life, loading them before each use and storing them
+// after each definition. Done before register allocation, so that no
+// register holds a pointer during a call. The slots of escaping variables
+// holding pointers are in every map.
+pub fn pointer_maps<C: Codegen>(codegen: &C, frame: &mut C::Frame, instrs: Vec<Instr>, gen: &mut TempGenerator,
+                                symbol_table: &mut SymbolTable) -> (Vec<Instr>, Vec<PointerMap>) {
+    let mut labelled = vec![];
+    let mut calls = vec![];
+    for instr in instrs.into_iter() {
+        let call = codegen.is_call(&instr);
+        labelled.push(instr);
+        if call {
+            let label = gen.new_label(symbol_table);
+            calls.push((labelled.len() - 1, label));
+            labelled.extend(codegen.codegen(frame, Stm::Label(label), gen, symbol_table));
+        }
+    }
+
+    let flow = flow_graph(&labelled);
+    let live = liveness(&flow);
+    let mut slots: HashMap<Temp, i32> = HashMap::new();
+    let mut maps = vec![];
+    for &(node, label) in calls.iter() {
+        let mut offsets = gen.pointer_slots(frame.name()).to_vec();
+        let pointers: Vec<Temp> = live.live_out(node).into_iter().filter(|&t| gen.is_pointer(t)).collect();
+        for t in pointers.into_iter() {
+            if !slots.contains_key(&t) {
+                match frame.alloc_local(true, gen) {
+                    Access::InFrame(offset) => slots.insert(t, offset),
+                    Access::InReg(_) => panic!("escaping local allocated in a register"),
+                };
+            }
+            offsets.push(slots[&t]);
+        }
+        offsets.sort();
+        maps.push(PointerMap { return_label: label, offsets: offsets });
+    }
+    if slots.is_empty() {
+        return (labelled, maps);
+    }
+
+    // a temp live on entry may not be set before some call, where the
+    // collector would read whatever its slot held
+    let mut cleared = vec![];
+    for t in live.live_in(0).into_iter().filter(|t| slots.contains_key(t)) {
+        let slot = C::Frame::exp(Access::InFrame(slots[&t]), Exp::Temp(C::Frame::fp()));
+        let stm = Stm::Move(Box::new(slot), Box::new(Exp::Const(0)));
+        cleared.extend(codegen.codegen(frame, stm, gen, symbol_table));
+    }
+    cleared.extend(labelled.into_iter());
+    (rewrite_program(codegen, cleared, &slots, gen, &mut HashSet::new()), maps)
+}
+
+// The table of pointer maps the runtime looks up return addresses in, at
+// the label tig_pointer_maps: for each call its return address, the number
+// of slots and their offsets, and a zero address at the end. word is the
+// assembler directive for a machine word.
+pub fn pointer_map_table(maps: &[PointerMap], word: &str, symbol_table: &SymbolTable) -> String {
+    let mut res = String::from("\t.globl tig_pointer_maps\ntig_pointer_maps:\n");
+    for map in maps.iter() {
+        res.push_str(&format!("\t{} {}\n\t{} {}\n", word, map.return_label.name(symbol_table), word, map.offsets.len()));
+        for offset in map.offsets.iter() {
+            res.push_str(&format!("\t{} {}\n", word, offset));
+        }
+    }
+    res.push_str(&format!("\t{} 0\n", word));
+    res
+}
+
+#[test]
+fn test_pointer_maps() {
+    use canon::canonicalize;
+    use driver::front_end;
+    use frame::Fragment;
+    use translate::translate;
+    use x86_64::{X86Codegen, Syntax};
+
+    // s and t are live across the call to f, s alone across the one
+    // printing t
+    let (exp, mut symbol_table, types) = front_end("let function f(): int = 1 \
+                                                        function g(s: string, t: string) = \
+                                                          (f(); print(t); print(s)) \
+                                                    in g(\"a\", \"b\") end").unwrap();
+    let mut gen = TempGenerator::new();
+    let fragments = translate(&exp, &types, &mut symbol_table, &mut gen);
+    let codegen = X86Codegen { syntax: Syntax::Att };
+    let (body, mut frame) = match fragments.into_iter().nth(1) {
+        Some(Fragment::Proc { body, frame }) => (body, frame),
+        _ => panic!("g is not the second fragment"),
+    };
+    let mut instrs = vec![];
+    for stm in canonicalize(body, &mut gen, &mut symbol_table).into_iter() {
+        instrs.extend(codegen.codegen(&frame, stm, &mut gen, &symbol_table));
+    }
+    let (instrs, maps) = pointer_maps(&codegen, &mut frame, instrs, &mut gen, &mut symbol_table);
+
+    let counts: Vec<usize> = maps.iter().map(|m| m.offsets.len()).collect();
+    assert_eq!(counts, vec![2, 1, 0]);
+    assert!(maps[0].offsets.contains(&maps[1].offsets[0]));
+    for map in maps.iter() {
+        let i = instrs.iter().position(|instr| match instr {
+            &Instr::Label { label, .. } => label == map.return_label,
+            _ => false,
+        }).unwrap();
+        assert!(codegen.is_call(&instrs[i - 1]));
+    }
+    let table = pointer_map_table(&maps, ".quad", &symbol_table);
+    assert!(table.starts_with("\t.globl tig_pointer_maps\ntig_pointer_maps:\n"));
+    assert!(table.ends_with("\t.quad 0\n"));
+}
@@ -0,0 +1,615 @@
+use ast;
+use symbol::SymbolTable;
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::rc::{Rc, Weak};
+
+// A tree-walking interpreter for type-checked programs. Records and arrays
+// are heap references, compared by identity; strings are compared by value.
+
+#[derive(Clone)]
+pub enum Value {
+    Int(i32),
+    Str(Rc<String>),
+    Record(Rc<RefCell<Vec<(ast::Symbol, Value)>>>),
+    Array(Rc<RefCell<Vec<Value>>>),
+    Nil,
+    Unit,
+}
+
+impl Value {
+    fn int(&self) -> i32 {
+        match self {
+            &Value::Int(i) => i,
+            _ => panic!("expected an integer"),
+        }
+    }
+
+    fn str(&self) -> Rc<String> {
+        match self {
+            &Value::Str(ref s) => s.clone(),
+            _ => panic!("expected a string"),
+        }
+    }
+
+    // a rendering in the syntax of Tiger where there is one, e.g.
+    // {hd = 1, tl = nil}; the records and arrays on a cycle are shown as ...
+    pub fn show(&self, symbol_table: &SymbolTable) -> String {
+        self.show_(symbol_table, &mut vec![])
+    }
+
+    fn show_(&self, symbol_table: &SymbolTable, outer: &mut Vec<usize>) -> String {
+        let address = match self {
+            &Value::Record(ref fields) => fields.as_ptr() as usize,
+            &Value::Array(ref elements) => elements.as_ptr() as usize,
+            _ => 0,
+        };
+        if outer.contains(&address) {
+            return String::from("...");
+        }
+        outer.push(address);
+        let res = match self {
+            &Value::Int(i) => i.to_string(),
+            &Value::Str(ref s) => format!("{:?}", s),
+            &Value::Record(ref fields) => {
+                let fields: Vec<String> = fields.borrow().iter()
+                    .map(|&(name, ref value)| format!("{} = {}", symbol_table.name(&name), value.show_(symbol_table, outer)))
+                    .collect();
+                format!("{{{}}}", fields.join(", "))
+            },
+            &Value::Array(ref elements) => {
+                let elements: Vec<String> = elements.borrow().iter().map(|e| e.show_(symbol_table, outer)).collect();
+                format!("[{}]", elements.join(", "))
+            },
+            &Value::Nil => String::from("nil"),
+            &Value::Unit => String::from("()"),
+        };
+        outer.pop();
+        res
+    }
+}
+
+fn equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (&Value::Int(a), &Value::Int(b)) => a == b,
+        (&Value::Str(ref a), &Value::Str(ref b)) => a == b,
+        (&Value::Record(ref a), &Value::Record(ref b)) => Rc::ptr_eq(a, b),
+        (&Value::Array(ref a), &Value::Array(ref b)) => Rc::ptr_eq(a, b),
+        (&Value::Nil, &Value::Nil) => true,
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    pub pos: ast::Position,
+}
+
+impl RuntimeError {
+    pub fn describe(&self, source: &str) -> String {
+        let (line, col) = ast::line_col(source, self.pos);
+        format!("runtime error at {}:{}: {}", line, col, self.message)
+    }
+}
+
+// Anything that stops the evaluation of an expression early.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Control {
+    Break,
+    Exit(i32),
+    Error(RuntimeError),
+}
+
+fn error<T>(message: String, pos: ast::Position) -> Result<T, Control> {
+    Err(Control::Error(RuntimeError { message: message, pos: pos }))
+}
+
+#[derive(Clone, Copy)]
+enum Builtin {
+    Print,
+    PrintI,
+    Flush,
+    GetChar,
+    Ord,
+    Chr,
+    Size,
+    Substring,
+    Concat,
+    Not,
+    Exit,
+}
+
+struct Function {
+    params: Vec<ast::Symbol>,
+    body: Rc<ast::Exp>,
+    // a function can only be called from inside the scope declaring it,
+    // so a weak reference avoids a cycle between the two
+    scope: Weak<Scope>,
+}
+
+#[derive(Clone)]
+enum Entry {
+    Var(Rc<RefCell<Value>>),
+    Fun(Rc<Function>),
+    Builtin(Builtin),
+}
+
+struct Scope {
+    parent: Option<Rc<Scope>>,
+    entries: RefCell<HashMap<ast::Symbol, Entry>>,
+}
+
+impl Scope {
+    fn new(parent: Option<Rc<Scope>>) -> Rc<Scope> {
+        Rc::new(Scope {
+            parent: parent,
+            entries: RefCell::new(HashMap::new()),
+        })
+    }
+
+    fn enter(&self, symbol: ast::Symbol, entry: Entry) {
+        self.entries.borrow_mut().insert(symbol, entry);
+    }
+
+    fn look(&self, symbol: ast::Symbol) -> Entry {
+        if let Some(entry) = self.entries.borrow().get(&symbol) {
+            return entry.clone();
+        }
+        match self.parent {
+            Some(ref parent) => parent.look(symbol),
+            None => panic!("unbound symbol {} in a type-checked program", symbol),
+        }
+    }
+}
+
+// The top-level scope of an interpreter, holding the standard library and
+// whatever was declared there, which the REPL passes from one interpreter
+// to the next.
+#[derive(Clone)]
+pub struct Globals {
+    scope: Rc<Scope>,
+}
+
+impl Globals {
+    pub fn new(symbol_table: &mut SymbolTable) -> Globals {
+        let scope = Scope::new(None);
+        for &(name, builtin) in [("print", Builtin::Print), ("printi", Builtin::PrintI),
+                                 ("flush", Builtin::Flush), ("getchar", Builtin::GetChar),
+                                 ("ord", Builtin::Ord), ("chr", Builtin::Chr), ("size", Builtin::Size),
+                                 ("substring", Builtin::Substring), ("concat", Builtin::Concat),
+                                 ("not", Builtin::Not), ("exit", Builtin::Exit)].iter() {
+            scope.enter(symbol_table.symbol(name), Entry::Builtin(builtin));
+        }
+        Globals { scope: scope }
+    }
+
+    // the value of a variable declared at the top level
+    pub fn look_var(&self, symbol: ast::Symbol) -> Value {
+        match self.scope.look(symbol) {
+            Entry::Var(value) => value.borrow().clone(),
+            _ => panic!("{} is not a variable", symbol),
+        }
+    }
+}
+
+pub struct Interpreter<'io> {
+    input: &'io mut dyn Read,
+    output: &'io mut dyn Write,
+    global: Rc<Scope>,
+    // the bodies of the functions declared so far, by declaration: a body is
+    // copied out of the tree once, so that the function can outlive the tree
+    // (as in the REPL), and shared by every later evaluation of its let
+    bodies: HashMap<ast::NodeId, Rc<ast::Exp>>,
+}
+
+impl<'io> Interpreter<'io> {
+    pub fn new(symbol_table: &mut SymbolTable, input: &'io mut dyn Read,
+               output: &'io mut dyn Write) -> Interpreter<'io> {
+        Interpreter::with_globals(Globals::new(symbol_table), input, output)
+    }
+
+    pub fn with_globals(globals: Globals, input: &'io mut dyn Read, output: &'io mut dyn Write) -> Interpreter<'io> {
+        Interpreter {
+            input: input,
+            output: output,
+            global: globals.scope,
+            bodies: HashMap::new(),
+        }
+    }
+
+    pub fn globals(&self) -> Globals {
+        Globals { scope: self.global.clone() }
+    }
+
+    // evaluates a program; exit() and runtime errors are returned as Control
+    pub fn run(&mut self, exp: &ast::Exp) -> Result<Value, Control> {
+        let global = self.global.clone();
+        let result = self.eval(&global, exp);
+        let _ = self.output.flush();
+        result
+    }
+
+    // evaluates declarations at the top level, where later runs see them;
+    // on an error, those evaluated so far are dropped
+    pub fn declare(&mut self, decs: &Vec<Box<ast::Dec>>) -> Result<(), Control> {
+        let global = self.global.clone();
+        let result = self.eval_decs(&global, decs);
+        let _ = self.output.flush();
+        self.global = result?;
+        Ok(())
+    }
+
+    fn write(&mut self, s: &str, pos: ast::Position) -> Result<(), Control> {
+        match self.output.write_all(s.as_bytes()) {
+            Ok(()) => Ok(()),
+            Err(e) => error(format!("cannot write output: {}", e), pos),
+        }
+    }
+
+    fn eval_var(&mut self, scope: &Rc<Scope>, var: &ast::Var) -> Result<Value, Control> {
+        match var {
+            &ast::Var::SimpleVar(symbol, _, _) => {
+                match scope.look(symbol) {
+                    Entry::Var(value) => Ok(value.borrow().clone()),
+                    _ => panic!("{} is not a variable", symbol),
+                }
+            },
+            &ast::Var::FieldVar(ref var, field, pos, _) => {
+                match self.eval_var(scope, var)? {
+                    Value::Record(fields) => {
+                        let fields = fields.borrow();
+                        Ok(fields.iter().find(|f| f.0 == field).unwrap().1.clone())
+                    },
+                    _ => error(String::from("nil record dereference"), pos),
+                }
+            },
+            &ast::Var::SubscriptVar(ref var, ref index, pos, _) => {
+                let array = self.eval_var(scope, var)?;
+                let index = self.eval(scope, index)?.int();
+                let elements = match array {
+                    Value::Array(elements) => elements,
+                    _ => panic!("subscript of a non-array"),
+                };
+                let elements = elements.borrow();
+                if index < 0 || index as usize >= elements.len() {
+                    return error(format!("index {} out of bounds for array of size {}", index, elements.len()), pos);
+                }
+                Ok(elements[index as usize].clone())
+            },
+        }
+    }
+
+    fn assign(&mut self, scope: &Rc<Scope>, var: &ast::Var, exp: &ast::Exp) -> Result<(), Control> {
+        match var {
+            &ast::Var::SimpleVar(symbol, _, _) => {
+                let value = self.eval(scope, exp)?;
+                match scope.look(symbol) {
+                    Entry::Var(cell) => *cell.borrow_mut() = value,
+                    _ => panic!("{} is not a variable", symbol),
+                }
+            },
+            &ast::Var::FieldVar(ref var, field, pos, _) => {
+                let fields = match self.eval_var(scope, var)? {
+                    Value::Record(fields) => fields,
+                    _ => return error(String::from("nil record dereference"), pos),
+                };
+                let value = self.eval(scope, exp)?;
+                let mut fields = fields.borrow_mut();
+                fields.iter_mut().find(|f| f.0 == field).unwrap().1 = value;
+            },
+            &ast::Var::SubscriptVar(ref var, ref index, pos, _) => {
+                let elements = match self.eval_var(scope, var)? {
+                    Value::Array(elements) => elements,
+                    _ => panic!("subscript of a non-array"),
+                };
+                let index = self.eval(scope, index)?.int();
+                let size = elements.borrow().len();
+                if index < 0 || index as usize >= size {
+                    return error(format!("index {} out of bounds for array of size {}", index, size), pos);
+                }
+                let value = self.eval(scope, exp)?;
+                elements.borrow_mut()[index as usize] = value;
+            },
+        }
+        Ok(())
+    }
+
+    fn call_builtin(&mut self, builtin: Builtin, args: Vec<Value>, pos: ast::Position) -> Result<Value, Control> {
+        Ok(match builtin {
+            Builtin::Print => {
+                self.write(&args[0].str(), pos)?;
+                Value::Unit
+            },
+            Builtin::PrintI => {
+                self.write(&args[0].int().to_string(), pos)?;
+                Value::Unit
+            },
+            Builtin::Flush => {
+                let _ = self.output.flush();
+                Value::Unit
+            },
+            Builtin::GetChar => {
+                let mut buf = [0u8; 1];
+                let s = match self.input.read(&mut buf) {
+                    Ok(1) => (buf[0] as char).to_string(),
+                    _ => String::new(),
+                };
+                Value::Str(Rc::new(s))
+            },
+            Builtin::Ord => Value::Int(args[0].str().chars().next().map(|c| c as i32).unwrap_or(-1)),
+            Builtin::Chr => {
+                let i = args[0].int();
+                if i < 0 || i > 255 {
+                    return error(format!("chr({}) is out of range", i), pos);
+                }
+                Value::Str(Rc::new((i as u8 as char).to_string()))
+            },
+            Builtin::Size => Value::Int(args[0].str().chars().count() as i32),
+            Builtin::Substring => {
+                let s = args[0].str();
+                let (first, n) = (args[1].int(), args[2].int());
+                let size = s.chars().count() as i32;
+                if first < 0 || n < 0 || first > size - n {
+                    return error(format!("substring({:?}, {}, {}) is out of range", s, first, n), pos);
+                }
+                Value::Str(Rc::new(s.chars().skip(first as usize).take(n as usize).collect()))
+            },
+            Builtin::Concat => Value::Str(Rc::new(format!("{}{}", args[0].str(), args[1].str()))),
+            Builtin::Not => Value::Int(if args[0].int() == 0 { 1 } else { 0 }),
+            Builtin::Exit => return Err(Control::Exit(args[0].int())),
+        })
+    }
+
+    fn eval_op(&mut self, op: ast::Oper, left: Value, right: Value, pos: ast::Position) -> Result<Value, Control> {
+        let bool_value = |b: bool| Value::Int(if b { 1 } else { 0 });
+        Ok(match op {
+            ast::Oper::EqOp => bool_value(equal(&left, &right)),
+            ast::Oper::NeqOp => bool_value(!equal(&left, &right)),
+            ast::Oper::LtOp | ast::Oper::LeOp | ast::Oper::GtOp | ast::Oper::GeOp => {
+                let ordering = match (&left, &right) {
+                    (&Value::Str(ref a), &Value::Str(ref b)) => a.cmp(b),
+                    _ => left.int().cmp(&right.int()),
+                };
+                bool_value(match op {
+                    ast::Oper::LtOp => ordering == Ordering::Less,
+                    ast::Oper::LeOp => ordering != Ordering::Greater,
+                    ast::Oper::GtOp => ordering == Ordering::Greater,
+                    _ => ordering != Ordering::Less,
+                })
+            },
+            ast::Oper::PlusOp => Value::Int(left.int().wrapping_add(right.int())),
+            ast::Oper::MinusOp => Value::Int(left.int().wrapping_sub(right.int())),
+            ast::Oper::TimesOp => Value::Int(left.int().wrapping_mul(right.int())),
+            ast::Oper::DivideOp => {
+                if right.int() == 0 {
+                    return error(String::from("division by zero"), pos);
+                }
+                Value::Int(left.int().wrapping_div(right.int()))
+            },
+        })
+    }
+
+    fn eval_decs(&mut self, scope: &Rc<Scope>, decs: &Vec<Box<ast::Dec>>) -> Result<Rc<Scope>, Control> {
+        let mut scope = scope.clone();
+        // adjacent function declarations share a scope so that they can
+        // call each other; every variable gets a new one
+        let mut in_functions = false;
+        for dec in decs.iter() {
+            match dec.as_ref() {
+                &ast::Dec::VarDec { name, ref init, .. } => {
+                    let value = self.eval(&scope, init)?;
+                    scope = Scope::new(Some(scope));
+                    scope.enter(name, Entry::Var(Rc::new(RefCell::new(value))));
+                    in_functions = false;
+                },
+                &ast::Dec::FunDec { name, ref params, ref body, id, .. } => {
+                    if !in_functions {
+                        scope = Scope::new(Some(scope));
+                        in_functions = true;
+                    }
+                    let body = self.bodies.entry(id).or_insert_with(|| Rc::new((**body).clone())).clone();
+                    scope.enter(name, Entry::Fun(Rc::new(Function {
+                        params: params.iter().map(|p| p.name).collect(),
+                        body: body,
+                        scope: Rc::downgrade(&scope),
+                    })));
+                },
+                &ast::Dec::TypeDec { .. } => in_functions = false,
+            }
+        }
+        Ok(scope)
+    }
+
+    fn eval(&mut self, scope: &Rc<Scope>, exp: &ast::Exp) -> Result<Value, Control> {
+        match exp {
+            &ast::Exp::VarExp(ref var, _) => self.eval_var(scope, var),
+            &ast::Exp::NilExp(_) => Ok(Value::Nil),
+            &ast::Exp::IntExp(i, _) => Ok(Value::Int(i)),
+            &ast::Exp::StringExp(ref s, _, _) => Ok(Value::Str(Rc::new(s.clone()))),
+            &ast::Exp::CallExp{ func, ref args, pos, .. } => {
+                let mut values = vec![];
+                for arg in args.iter() {
+                    values.push(self.eval(scope, arg)?);
+                }
+                match scope.look(func) {
+                    Entry::Fun(f) => {
+                        let fscope = Scope::new(Some(f.scope.upgrade().unwrap()));
+                        for (&param, value) in f.params.iter().zip(values.into_iter()) {
+                            fscope.enter(param, Entry::Var(Rc::new(RefCell::new(value))));
+                        }
+                        self.eval(&fscope, &f.body)
+                    },
+                    Entry::Builtin(builtin) => self.call_builtin(builtin, values, pos),
+                    Entry::Var(_) => panic!("{} is not a function", func),
+                }
+            },
+            &ast::Exp::OpExp{ ref left, op, ref right, pos, .. } => {
+                let left = self.eval(scope, left)?;
+                let right = self.eval(scope, right)?;
+                self.eval_op(op, left, right, pos)
+            },
+            &ast::Exp::RecordExp { ref fields, .. } => {
+                let mut values = vec![];
+                for &(name, ref exp, _) in fields.iter() {
+                    values.push((name, self.eval(scope, exp)?));
+                }
+                Ok(Value::Record(Rc::new(RefCell::new(values))))
+            },
+            &ast::Exp::SeqExp(ref exps, _) => {
+                let mut value = Value::Unit;
+                for exp in exps.iter() {
+                    value = self.eval(scope, exp)?;
+                }
+                Ok(value)
+            },
+            &ast::Exp::AssignExp { ref var, ref exp, .. } => {
+                self.assign(scope, var, exp)?;
+                Ok(Value::Unit)
+            },
+            &ast::Exp::IfExp { ref test, ref then_, ref else_, .. } => {
+                let test = self.eval(scope, test)?.int();
+                match else_ {
+                    &Some(ref else_) => self.eval(scope, if test != 0 { then_ } else { else_ }),
+                    &None => {
+                        if test != 0 {
+                            self.eval(scope, then_)?;
+                        }
+                        Ok(Value::Unit)
+                    },
+                }
+            },
+            &ast::Exp::WhileExp { ref test, ref body, .. } => {
+                while self.eval(scope, test)?.int() != 0 {
+                    match self.eval(scope, body) {
+                        Ok(_) => (),
+                        Err(Control::Break) => break,
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(Value::Unit)
+            },
+            &ast::Exp::ForExp { var, ref lo, ref hi, ref body, .. } => {
+                let lo = self.eval(scope, lo)?.int();
+                let hi = self.eval(scope, hi)?.int();
+                let cell = Rc::new(RefCell::new(Value::Int(lo)));
+                let fscope = Scope::new(Some(scope.clone()));
+                fscope.enter(var, Entry::Var(cell.clone()));
+                let mut i = lo;
+                while i <= hi {
+                    *cell.borrow_mut() = Value::Int(i);
+                    match self.eval(&fscope, body) {
+                        Ok(_) => (),
+                        Err(Control::Break) => break,
+                        Err(e) => return Err(e),
+                    }
+                    // checked before incrementing, so that hi = maxint terminates
+                    if i == hi {
+                        break;
+                    }
+                    i += 1;
+                }
+                Ok(Value::Unit)
+            },
+            &ast::Exp::BreakExp(_, _) => Err(Control::Break),
+            &ast::Exp::LetExp { ref decs, ref body, .. } => {
+                let scope = self.eval_decs(scope, decs)?;
+                self.eval(&scope, body)
+            },
+            &ast::Exp::ArrayExp { ref size, ref init, pos, .. } => {
+                let size = self.eval(scope, size)?.int();
+                let init = self.eval(scope, init)?;
+                if size < 0 {
+                    return error(format!("negative array size {}", size), pos);
+                }
+                Ok(Value::Array(Rc::new(RefCell::new(vec![init; size as usize]))))
+            },
+        }
+    }
+}
+
+#[test]
+fn test_interpret_programs() {
+    use parser::parse;
+    use type_check::type_check;
+
+    let run = |program: &str, input: &str| -> (Result<Value, Control>, String) {
+        let (p, mut table) = parse(program).unwrap();
+        type_check(&p, &mut table).unwrap();
+        let mut input = input.as_bytes();
+        let mut output = vec![];
+        let result = Interpreter::new(&mut table, &mut input, &mut output).run(&p);
+        (result, String::from_utf8(output).unwrap())
+    };
+
+    let (result, output) = run("let function fact(n: int): int = if n = 0 then 1 else n * fact(n - 1) \
+                                in printi(fact(10)); print(\"\\n\") end", "");
+    assert!(result.is_ok());
+    assert_eq!(output, "3628800\n");
+
+    let (_, output) = run("let type list = { hd: int, tl: list } \
+                               type ints = array of int \
+                               var l := list { hd = 1, tl = list { hd = 2, tl = nil } } \
+                               var a := ints[3] of 7 \
+                           in a[1] := 8; \
+                              while l <> nil do (printi(l.hd); l := l.tl); \
+                              for i := 0 to 2 do (if i = 2 then break; printi(a[i])) end", "");
+    assert_eq!(output, "1278");
+
+    let (_, output) = run("let var c := getchar() in \
+                             while c <> \"\" do (print(chr(ord(c) + 1)); c := getchar()) end", "HAL");
+    assert_eq!(output, "IBM");
+
+    let (result, output) = run("(print(\"a\"); exit(3); print(\"b\"))", "");
+    assert_eq!(result.err(), Some(Control::Exit(3)));
+    assert_eq!(output, "a");
+}
+
+#[test]
+fn test_runtime_errors() {
+    use parser::parse;
+    use type_check::type_check;
+
+    let run = |program: &str| -> String {
+        let (p, mut table) = parse(program).unwrap();
+        type_check(&p, &mut table).unwrap();
+        let mut input = "".as_bytes();
+        let mut output = vec![];
+        match Interpreter::new(&mut table, &mut input, &mut output).run(&p) {
+            Err(Control::Error(e)) => e.describe(program),
+            _ => panic!("expected a runtime error"),
+        }
+    };
+
+    assert_eq!(run("let type r = { x: int }\n    var v: r := nil\nin v.x end"),
+               "runtime error at 3:4: nil record dereference");
+    assert_eq!(run("let type a = array of int\nvar v := a[2] of 0 in\n  v[2] := 1 end"),
+               "runtime error at 3:3: index 2 out of bounds for array of size 2");
+    assert_eq!(run("1 / (2 - 2)"), "runtime error at 1:1: division by zero");
+}
+
+#[test]
+fn test_shared_bodies() {
+    use parser::parse;
+    use type_check::type_check;
+
+    // the let runs a hundred times, but its functions are copied out once
+    let program = "let var n := 0 in for i := 1 to 100 do \
+                     let function f(x: int): int = if x = 0 then 0 else g(x - 1) + 1 \
+                         function g(x: int): int = f(x) \
+                     in n := n + f(i) end; printi(n) end";
+    let (p, mut table) = parse(program).unwrap();
+    type_check(&p, &mut table).unwrap();
+    let mut input = "".as_bytes();
+    let mut output = vec![];
+    let bodies = {
+        let mut interpreter = Interpreter::new(&mut table, &mut input, &mut output);
+        assert!(interpreter.run(&p).is_ok());
+        interpreter.bodies.len()
+    };
+    assert_eq!(bodies, 2);
+    assert_eq!(String::from_utf8(output).unwrap(), "5050");
+}
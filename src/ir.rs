@@ -0,0 +1,263 @@
+use std::io::Write;
+
+use symbol::SymbolTable;
+use temp::{Temp, Label};
+
+// Appel's intermediate representation trees (chapter 7)
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Exp {
+    Const(i32),
+    Name(Label),
+    Temp(Temp),
+    BinOp(BinOp, Box<Exp>, Box<Exp>),
+    Mem(Box<Exp>),
+    Call(Box<Exp>, Vec<Exp>),
+    ESeq(Box<Stm>, Box<Exp>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stm {
+    // destination, source
+    Move(Box<Exp>, Box<Exp>),
+    Exp(Box<Exp>),
+    Jump(Box<Exp>, Vec<Label>),
+    CJump(RelOp, Box<Exp>, Box<Exp>, Label, Label),
+    Seq(Box<Stm>, Box<Stm>),
+    Label(Label),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Plus,
+    Minus,
+    Mul,
+    Div,
+    And,
+    Or,
+    LShift,
+    RShift,
+    ARShift,
+    Xor,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelOp {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    ULt,
+    ULe,
+    UGt,
+    UGe,
+}
+
+impl RelOp {
+    // the relation that holds exactly when self does not
+    pub fn not(self) -> RelOp {
+        use self::RelOp::*;
+        match self {
+            Eq => Ne,
+            Ne => Eq,
+            Lt => Ge,
+            Ge => Lt,
+            Gt => Le,
+            Le => Gt,
+            ULt => UGe,
+            UGe => ULt,
+            UGt => ULe,
+            ULe => UGt,
+        }
+    }
+
+    // the relation to use when the operands are swapped
+    pub fn commute(self) -> RelOp {
+        use self::RelOp::*;
+        match self {
+            Eq => Eq,
+            Ne => Ne,
+            Lt => Gt,
+            Gt => Lt,
+            Le => Ge,
+            Ge => Le,
+            ULt => UGt,
+            UGt => ULt,
+            ULe => UGe,
+            UGe => ULe,
+        }
+    }
+}
+
+// Builds a right-nested SEQ out of a list of statements. An empty list
+// becomes a no-op statement.
+pub fn seq(mut stms: Vec<Stm>) -> Stm {
+    match stms.len() {
+        0 => Stm::Exp(Box::new(Exp::Const(0))),
+        1 => stms.pop().unwrap(),
+        _ => {
+            let first = stms.remove(0);
+            Stm::Seq(Box::new(first), Box::new(seq(stms)))
+        }
+    }
+}
+
+fn indent<W: Write>(out: &mut W, depth: usize) {
+    for _ in 0..depth {
+        write!(out, " ").unwrap();
+    }
+}
+
+fn binop_name(op: BinOp) -> &'static str {
+    match op {
+        BinOp::Plus => "PLUS",
+        BinOp::Minus => "MINUS",
+        BinOp::Mul => "MUL",
+        BinOp::Div => "DIV",
+        BinOp::And => "AND",
+        BinOp::Or => "OR",
+        BinOp::LShift => "LSHIFT",
+        BinOp::RShift => "RSHIFT",
+        BinOp::ARShift => "ARSHIFT",
+        BinOp::Xor => "XOR",
+    }
+}
+
+fn relop_name(op: RelOp) -> &'static str {
+    match op {
+        RelOp::Eq => "EQ",
+        RelOp::Ne => "NE",
+        RelOp::Lt => "LT",
+        RelOp::Gt => "GT",
+        RelOp::Le => "LE",
+        RelOp::Ge => "GE",
+        RelOp::ULt => "ULT",
+        RelOp::ULe => "ULE",
+        RelOp::UGt => "UGT",
+        RelOp::UGe => "UGE",
+    }
+}
+
+// Pretty-printer in the format of Appel's printtree.sml
+
+fn print_stm_depth<W: Write>(out: &mut W, stm: &Stm, symbol_table: &SymbolTable, depth: usize) {
+    indent(out, depth);
+    match stm {
+        &Stm::Seq(ref a, ref b) => {
+            writeln!(out, "SEQ(").unwrap();
+            print_stm_depth(out, a, symbol_table, depth + 1);
+            writeln!(out, ",").unwrap();
+            print_stm_depth(out, b, symbol_table, depth + 1);
+            write!(out, ")").unwrap();
+        },
+        &Stm::Label(l) => {
+            write!(out, "LABEL {}", l.name(symbol_table)).unwrap();
+        },
+        &Stm::Jump(ref e, _) => {
+            writeln!(out, "JUMP(").unwrap();
+            print_exp_depth(out, e, symbol_table, depth + 1);
+            write!(out, ")").unwrap();
+        },
+        &Stm::CJump(op, ref a, ref b, t, f) => {
+            writeln!(out, "CJUMP({},", relop_name(op)).unwrap();
+            print_exp_depth(out, a, symbol_table, depth + 1);
+            writeln!(out, ",").unwrap();
+            print_exp_depth(out, b, symbol_table, depth + 1);
+            writeln!(out, ",").unwrap();
+            indent(out, depth + 1);
+            write!(out, "{},{})", t.name(symbol_table), f.name(symbol_table)).unwrap();
+        },
+        &Stm::Move(ref dst, ref src) => {
+            writeln!(out, "MOVE(").unwrap();
+            print_exp_depth(out, dst, symbol_table, depth + 1);
+            writeln!(out, ",").unwrap();
+            print_exp_depth(out, src, symbol_table, depth + 1);
+            write!(out, ")").unwrap();
+        },
+        &Stm::Exp(ref e) => {
+            writeln!(out, "EXP(").unwrap();
+            print_exp_depth(out, e, symbol_table, depth + 1);
+            write!(out, ")").unwrap();
+        },
+    }
+}
+
+fn print_exp_depth<W: Write>(out: &mut W, exp: &Exp, symbol_table: &SymbolTable, depth: usize) {
+    indent(out, depth);
+    match exp {
+        &Exp::BinOp(op, ref a, ref b) => {
+            writeln!(out, "BINOP({},", binop_name(op)).unwrap();
+            print_exp_depth(out, a, symbol_table, depth + 1);
+            writeln!(out, ",").unwrap();
+            print_exp_depth(out, b, symbol_table, depth + 1);
+            write!(out, ")").unwrap();
+        },
+        &Exp::Mem(ref e) => {
+            writeln!(out, "MEM(").unwrap();
+            print_exp_depth(out, e, symbol_table, depth + 1);
+            write!(out, ")").unwrap();
+        },
+        &Exp::Temp(t) => {
+            write!(out, "TEMP t{}", t.0).unwrap();
+        },
+        &Exp::ESeq(ref s, ref e) => {
+            writeln!(out, "ESEQ(").unwrap();
+            print_stm_depth(out, s, symbol_table, depth + 1);
+            writeln!(out, ",").unwrap();
+            print_exp_depth(out, e, symbol_table, depth + 1);
+            write!(out, ")").unwrap();
+        },
+        &Exp::Name(l) => {
+            write!(out, "NAME {}", l.name(symbol_table)).unwrap();
+        },
+        &Exp::Const(i) => {
+            write!(out, "CONST {}", i).unwrap();
+        },
+        &Exp::Call(ref f, ref args) => {
+            writeln!(out, "CALL(").unwrap();
+            print_exp_depth(out, f, symbol_table, depth + 1);
+            for a in args.iter() {
+                writeln!(out, ",").unwrap();
+                print_exp_depth(out, a, symbol_table, depth + 2);
+            }
+            write!(out, ")").unwrap();
+        },
+    }
+}
+
+pub fn print_stm<W: Write>(out: &mut W, stm: &Stm, symbol_table: &SymbolTable) {
+    print_stm_depth(out, stm, symbol_table, 0);
+    writeln!(out).unwrap();
+}
+
+pub fn print_exp<W: Write>(out: &mut W, exp: &Exp, symbol_table: &SymbolTable) {
+    print_exp_depth(out, exp, symbol_table, 0);
+    writeln!(out).unwrap();
+}
+
+#[test]
+fn test_print_stm() {
+    let mut table = SymbolTable::new();
+    let l = Label(table.symbol("done"));
+    let stm = seq(vec![
+        Stm::Move(Box::new(Exp::Temp(Temp(100))),
+                  Box::new(Exp::BinOp(BinOp::Plus,
+                                      Box::new(Exp::Const(1)),
+                                      Box::new(Exp::Mem(Box::new(Exp::Temp(Temp(101)))))))),
+        Stm::Label(l),
+    ]);
+
+    let mut out = Vec::new();
+    print_stm(&mut out, &stm, &table);
+    assert_eq!(String::from_utf8(out).unwrap(),
+               "SEQ(\n MOVE(\n  TEMP t100,\n  BINOP(PLUS,\n   CONST 1,\n   MEM(\n    TEMP t101))),\n LABEL done)\n");
+}
+
+#[test]
+fn test_relop() {
+    assert_eq!(RelOp::Lt.not(), RelOp::Ge);
+    assert_eq!(RelOp::Lt.commute(), RelOp::Gt);
+    assert_eq!(RelOp::ULe.not().not(), RelOp::ULe);
+}
@@ -0,0 +1,625 @@
+use frame::{Access, Frame, Fragment};
+use interp::RuntimeError;
+use ir::{Exp, Stm, BinOp, RelOp};
+use symbol::SymbolTable;
+use temp::{Temp, Label, TempGenerator};
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::mem;
+use std::rc::Rc;
+
+// An interpreter for IR trees, both before canonicalization (with SEQ and
+// ESEQ nodes) and after. Loaded with the fragments of a program, it
+// simulates frames, the heap and the runtime system; calls to labels it
+// does not know are only recorded.
+
+// Non-local control flow while executing: a jump to a label that is not in
+// the statement list being executed, a runtime error, or a call to exit.
+pub enum Control {
+    Jump(Label),
+    Error(String),
+    Runtime(RuntimeError),
+    Exit(i32),
+}
+
+const WORD: i64 = 8;
+const HEAP_START: i64 = 1 << 20;
+const STACK_TOP: i64 = 1 << 40;
+// every activation gets this many bytes of stack, for its locals and the
+// arguments of the calls it makes
+const FRAME_SIZE: i64 = 1 << 16;
+
+// A frame for running translated programs in the interpreter: formals are
+// passed at positive offsets from the frame pointer, or directly in temps
+// if they do not escape.
+#[derive(Clone)]
+pub struct SimFrame {
+    name: Label,
+    formals: Vec<Access>,
+    locals: i32,
+}
+
+impl Frame for SimFrame {
+    fn new_frame(name: Label, formals_escape: Vec<bool>, gen: &mut TempGenerator) -> SimFrame {
+        let formals = formals_escape.iter().enumerate().map(|(i, &escape)| {
+            if escape { Access::InFrame(i as i32 * WORD as i32) } else { Access::InReg(gen.new_temp()) }
+        }).collect();
+        SimFrame { name: name, formals: formals, locals: 0 }
+    }
+
+    fn name(&self) -> Label {
+        self.name
+    }
+
+    fn formals(&self) -> &[Access] {
+        &self.formals
+    }
+
+    fn alloc_local(&mut self, escape: bool, gen: &mut TempGenerator) -> Access {
+        if escape {
+            self.locals += 1;
+            Access::InFrame(-self.locals * WORD as i32)
+        } else {
+            Access::InReg(gen.new_temp())
+        }
+    }
+
+    fn fp() -> Temp {
+        Temp(0)
+    }
+
+    fn rv() -> Temp {
+        Temp(1)
+    }
+
+    fn word_size() -> i32 {
+        WORD as i32
+    }
+
+    fn proc_entry_exit1(&self, body: Stm, _: &mut TempGenerator) -> Stm {
+        body
+    }
+}
+
+struct Proc {
+    formals: Vec<Access>,
+    body: Vec<Stm>,
+}
+
+#[derive(Clone, Copy)]
+enum Runtime {
+    Print,
+    PrintI,
+    Flush,
+    GetChar,
+    Ord,
+    Chr,
+    Size,
+    Substring,
+    Concat,
+    Not,
+    Exit,
+    AllocRecord,
+    InitArray,
+    StringEqual,
+    StringCompare,
+    NilError,
+    BoundsError,
+}
+
+pub struct Interpreter<'io> {
+    temps: HashMap<Temp, i64>,
+    memory: HashMap<i64, i64>,
+    // every call to an unknown label, with its arguments
+    pub calls: Vec<(Label, Vec<i64>)>,
+
+    procs: HashMap<Label, Rc<Proc>>,
+    runtime: HashMap<Label, Runtime>,
+    labels: HashMap<Label, i64>,
+    // strings are kept aside, by address
+    strings: HashMap<i64, Rc<String>>,
+    heap: i64,
+    sp: i64,
+    fp: Temp,
+    rv: Temp,
+    input: Option<&'io mut dyn Read>,
+    output: Option<&'io mut dyn Write>,
+}
+
+fn flatten<'s>(stm: &'s Stm, out: &mut Vec<&'s Stm>) {
+    match stm {
+        &Stm::Seq(ref a, ref b) => {
+            flatten(a, out);
+            flatten(b, out);
+        },
+        s => out.push(s),
+    }
+}
+
+fn compare(op: RelOp, a: i64, b: i64) -> bool {
+    match op {
+        RelOp::Eq => a == b,
+        RelOp::Ne => a != b,
+        RelOp::Lt => a < b,
+        RelOp::Gt => a > b,
+        RelOp::Le => a <= b,
+        RelOp::Ge => a >= b,
+        RelOp::ULt => (a as u64) < (b as u64),
+        RelOp::UGt => (a as u64) > (b as u64),
+        RelOp::ULe => (a as u64) <= (b as u64),
+        RelOp::UGe => (a as u64) >= (b as u64),
+    }
+}
+
+impl<'io> Interpreter<'io> {
+    pub fn new() -> Interpreter<'io> {
+        Interpreter {
+            temps: HashMap::new(),
+            memory: HashMap::new(),
+            calls: vec![],
+            procs: HashMap::new(),
+            runtime: HashMap::new(),
+            labels: HashMap::new(),
+            strings: HashMap::new(),
+            heap: HEAP_START,
+            sp: STACK_TOP,
+            fp: Temp(0),
+            rv: Temp(1),
+            input: None,
+            output: None,
+        }
+    }
+
+    pub fn with_io(input: &'io mut dyn Read, output: &'io mut dyn Write) -> Interpreter<'io> {
+        let mut interp = Interpreter::new();
+        interp.input = Some(input);
+        interp.output = Some(output);
+        interp
+    }
+
+    // loads the fragments of a program, and the runtime system it calls
+    pub fn load_program<F: Frame>(&mut self, fragments: &[Fragment<F>], symbol_table: &mut SymbolTable) {
+        self.fp = F::fp();
+        self.rv = F::rv();
+        for &(name, f) in [("print", Runtime::Print), ("printi", Runtime::PrintI), ("flush", Runtime::Flush),
+                           ("getchar", Runtime::GetChar), ("ord", Runtime::Ord), ("chr", Runtime::Chr),
+                           ("size", Runtime::Size), ("substring", Runtime::Substring),
+                           ("concat", Runtime::Concat), ("not", Runtime::Not), ("exit", Runtime::Exit),
+                           ("allocRecord", Runtime::AllocRecord), ("initArray", Runtime::InitArray),
+                           ("stringEqual", Runtime::StringEqual), ("stringCompare", Runtime::StringCompare),
+                           ("nilError", Runtime::NilError), ("boundsError", Runtime::BoundsError)].iter() {
+            self.runtime.insert(Label(symbol_table.symbol(&F::external_name(name))), f);
+        }
+
+        for fragment in fragments.iter() {
+            match fragment {
+                &Fragment::Proc { ref body, ref frame } => {
+                    self.procs.insert(frame.name(), Rc::new(Proc {
+                        formals: frame.formals().to_vec(),
+                        body: vec![body.clone()],
+                    }));
+                },
+                &Fragment::String(label, ref s) => {
+                    let address = self.new_string(s.clone());
+                    self.labels.insert(label, address);
+                },
+            }
+        }
+    }
+
+    // calls a loaded procedure (without a static link) and returns the
+    // value it leaves in the return value register
+    pub fn run_proc(&mut self, name: Label) -> Result<i64, Control> {
+        let result = self.call(name, vec![]);
+        if let Some(ref mut output) = self.output {
+            let _ = output.flush();
+        }
+        result
+    }
+
+    pub fn temp(&self, t: Temp) -> i64 {
+        *self.temps.get(&t).unwrap_or(&0)
+    }
+
+    pub fn set_temp(&mut self, t: Temp, value: i64) {
+        self.temps.insert(t, value);
+    }
+
+    pub fn load(&self, address: i64) -> i64 {
+        *self.memory.get(&address).unwrap_or(&0)
+    }
+
+    pub fn store(&mut self, address: i64, value: i64) {
+        self.memory.insert(address, value);
+    }
+
+    pub fn run(&mut self, stms: &[Stm]) -> Result<(), String> {
+        match self.exec_list(stms) {
+            Ok(()) => Ok(()),
+            Err(Control::Jump(l)) => Err(format!("jump to unknown label {:?}", l)),
+            Err(Control::Error(e)) => Err(e),
+            Err(Control::Runtime(e)) => Err(e.message),
+            Err(Control::Exit(i)) => Err(format!("exit({})", i)),
+        }
+    }
+
+    // executes a list of statements, resolving jumps to labels in the list
+    fn exec_list(&mut self, stms: &[Stm]) -> Result<(), Control> {
+        let mut flat = vec![];
+        for stm in stms.iter() {
+            flatten(stm, &mut flat);
+        }
+
+        let mut pc = 0;
+        while pc < flat.len() {
+            match self.exec(flat[pc]) {
+                Ok(()) => pc += 1,
+                Err(Control::Jump(l)) => {
+                    match flat.iter().position(|s| **s == Stm::Label(l)) {
+                        Some(i) => pc = i,
+                        None => return Err(Control::Jump(l)),
+                    }
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn exec(&mut self, stm: &Stm) -> Result<(), Control> {
+        match stm {
+            &Stm::Move(ref dst, ref src) => {
+                match dst.as_ref() {
+                    &Exp::Temp(t) => {
+                        let v = self.eval(src)?;
+                        self.set_temp(t, v);
+                    },
+                    &Exp::Mem(ref address) => {
+                        let address = self.eval(address)?;
+                        let v = self.eval(src)?;
+                        self.store(address, v);
+                    },
+                    &Exp::ESeq(ref s, ref e) => {
+                        self.exec_list(&[(**s).clone()])?;
+                        self.exec(&Stm::Move(e.clone(), src.clone()))?;
+                    },
+                    e => return Err(Control::Error(format!("cannot move to {:?}", e))),
+                }
+                Ok(())
+            },
+            &Stm::Exp(ref e) => {
+                self.eval(e)?;
+                Ok(())
+            },
+            &Stm::Jump(ref e, _) => {
+                match e.as_ref() {
+                    &Exp::Name(l) => Err(Control::Jump(l)),
+                    e => Err(Control::Error(format!("cannot jump to {:?}", e))),
+                }
+            },
+            &Stm::CJump(op, ref a, ref b, t, f) => {
+                let a = self.eval(a)?;
+                let b = self.eval(b)?;
+                Err(Control::Jump(if compare(op, a, b) { t } else { f }))
+            },
+            &Stm::Seq(_, _) => self.exec_list(&[stm.clone()]),
+            &Stm::Label(_) => Ok(()),
+        }
+    }
+
+    fn eval(&mut self, exp: &Exp) -> Result<i64, Control> {
+        match exp {
+            &Exp::Const(i) => Ok(i as i64),
+            &Exp::Name(l) => match self.labels.get(&l) {
+                Some(&address) => Ok(address),
+                None => Err(Control::Error(format!("no address for label {:?}", l))),
+            },
+            &Exp::Temp(t) => Ok(self.temp(t)),
+            &Exp::BinOp(op, ref a, ref b) => {
+                let a = self.eval(a)?;
+                let b = self.eval(b)?;
+                Ok(match op {
+                    BinOp::Plus => a.wrapping_add(b),
+                    BinOp::Minus => a.wrapping_sub(b),
+                    BinOp::Mul => a.wrapping_mul(b),
+                    BinOp::Div => {
+                        if b == 0 {
+                            return Err(Control::Error(String::from("division by zero")));
+                        }
+                        a.wrapping_div(b)
+                    },
+                    BinOp::And => a & b,
+                    BinOp::Or => a | b,
+                    BinOp::Xor => a ^ b,
+                    BinOp::LShift => a.wrapping_shl(b as u32),
+                    BinOp::RShift => ((a as u64).wrapping_shr(b as u32)) as i64,
+                    BinOp::ARShift => a.wrapping_shr(b as u32),
+                })
+            },
+            &Exp::Mem(ref address) => {
+                let address = self.eval(address)?;
+                Ok(self.load(address))
+            },
+            &Exp::Call(ref f, ref args) => {
+                let f = match f.as_ref() {
+                    &Exp::Name(l) => l,
+                    e => return Err(Control::Error(format!("cannot call {:?}", e))),
+                };
+                let mut values = vec![];
+                for arg in args.iter() {
+                    values.push(self.eval(arg)?);
+                }
+                self.call(f, values)
+            },
+            &Exp::ESeq(ref s, ref e) => {
+                self.exec_list(&[(**s).clone()])?;
+                self.eval(e)
+            },
+        }
+    }
+
+    fn alloc(&mut self, bytes: i64) -> i64 {
+        let address = self.heap;
+        self.heap += (bytes + WORD - 1) / WORD * WORD;
+        address
+    }
+
+    fn new_string(&mut self, s: String) -> i64 {
+        let address = self.alloc(WORD);
+        self.strings.insert(address, Rc::new(s));
+        address
+    }
+
+    fn string(&self, address: i64) -> Result<Rc<String>, Control> {
+        match self.strings.get(&address) {
+            Some(s) => Ok(s.clone()),
+            None => Err(Control::Error(format!("no string at address {}", address))),
+        }
+    }
+
+    fn write(&mut self, s: &str) -> Result<(), Control> {
+        match self.output {
+            Some(ref mut output) => output.write_all(s.as_bytes())
+                .map_err(|e| Control::Error(format!("cannot write output: {}", e))),
+            None => Err(Control::Error(String::from("no output"))),
+        }
+    }
+
+    fn call(&mut self, f: Label, args: Vec<i64>) -> Result<i64, Control> {
+        if let Some(&runtime) = self.runtime.get(&f) {
+            return self.call_runtime(runtime, args);
+        }
+        let p = match self.procs.get(&f) {
+            Some(p) => p.clone(),
+            None => return Ok(self.call_unknown(f, args)),
+        };
+
+        let saved_temps = mem::replace(&mut self.temps, HashMap::new());
+        let fp = self.sp;
+        self.sp -= FRAME_SIZE;
+        let fp_temp = self.fp;
+        self.set_temp(fp_temp, fp);
+        for (&access, &arg) in p.formals.iter().zip(args.iter()) {
+            match access {
+                Access::InFrame(offset) => self.store(fp + offset as i64, arg),
+                Access::InReg(t) => self.set_temp(t, arg),
+            }
+        }
+
+        let result = self.exec_list(&p.body);
+        let rv_temp = self.rv;
+        let rv = self.temp(rv_temp);
+        self.temps = saved_temps;
+        self.sp += FRAME_SIZE;
+        match result {
+            Ok(()) => Ok(rv),
+            Err(Control::Jump(l)) => Err(Control::Error(format!("jump to unknown label {:?}", l))),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn call_runtime(&mut self, f: Runtime, args: Vec<i64>) -> Result<i64, Control> {
+        let bool_value = |b: bool| if b { 1 } else { 0 };
+        Ok(match f {
+            Runtime::Print => {
+                let s = self.string(args[0])?;
+                self.write(&s)?;
+                0
+            },
+            Runtime::PrintI => {
+                self.write(&args[0].to_string())?;
+                0
+            },
+            Runtime::Flush => {
+                if let Some(ref mut output) = self.output {
+                    let _ = output.flush();
+                }
+                0
+            },
+            Runtime::GetChar => {
+                let mut buf = [0u8; 1];
+                let s = match self.input {
+                    Some(ref mut input) => match input.read(&mut buf) {
+                        Ok(1) => (buf[0] as char).to_string(),
+                        _ => String::new(),
+                    },
+                    None => String::new(),
+                };
+                self.new_string(s)
+            },
+            Runtime::Ord => self.string(args[0])?.chars().next().map(|c| c as i64).unwrap_or(-1),
+            Runtime::Chr => {
+                if args[0] < 0 || args[0] > 255 {
+                    return Err(Control::Error(format!("chr({}) is out of range", args[0])));
+                }
+                self.new_string((args[0] as u8 as char).to_string())
+            },
+            Runtime::Size => self.string(args[0])?.chars().count() as i64,
+            Runtime::Substring => {
+                let s = self.string(args[0])?;
+                let (first, n) = (args[1], args[2]);
+                if first < 0 || n < 0 || first > s.chars().count() as i64 - n {
+                    return Err(Control::Error(format!("substring({:?}, {}, {}) is out of range", s, first, n)));
+                }
+                self.new_string(s.chars().skip(first as usize).take(n as usize).collect())
+            },
+            Runtime::Concat => {
+                let s = format!("{}{}", self.string(args[0])?, self.string(args[1])?);
+                self.new_string(s)
+            },
+            Runtime::Not => bool_value(args[0] == 0),
+            Runtime::Exit => return Err(Control::Exit(args[0] as i32)),
+            Runtime::AllocRecord => self.alloc(args[0]),
+            Runtime::InitArray => {
+                if args[0] < 0 {
+                    return Err(Control::Error(format!("negative array size {}", args[0])));
+                }
+                // the size word, then the elements
+                let p = self.alloc((args[0] + 1) * WORD);
+                self.store(p, args[0]);
+                for i in 0..args[0] {
+                    self.store(p + (i + 1) * WORD, args[1]);
+                }
+                p
+            },
+            Runtime::StringEqual => bool_value(self.string(args[0])? == self.string(args[1])?),
+            Runtime::StringCompare => self.string(args[0])?.cmp(&self.string(args[1])?) as i64,
+            Runtime::NilError => return Err(Control::Runtime(RuntimeError {
+                message: String::from("nil record dereference"),
+                pos: args[0] as usize,
+            })),
+            Runtime::BoundsError => return Err(Control::Runtime(RuntimeError {
+                message: format!("index {} out of bounds", args[1]),
+                pos: args[0] as usize,
+            })),
+        })
+    }
+
+    // Calls to unknown labels are recorded, and return a value computed
+    // from the label and the arguments, so that reordered calls show up in
+    // the results.
+    fn call_unknown(&mut self, f: Label, args: Vec<i64>) -> i64 {
+        let result = args.iter().fold(f.0 as i64 + 1, |acc, &a| acc.wrapping_mul(31).wrapping_add(a));
+        self.calls.push((f, args));
+        result
+    }
+}
+
+#[test]
+fn test_interpret_loop() {
+    use ir::seq;
+    use symbol::SymbolTable;
+
+    // t100 := 0; for t101 := 1 to 10: t100 := t100 + t101
+    let mut table = SymbolTable::new();
+    let test = Label(table.symbol("test"));
+    let body = Label(table.symbol("body"));
+    let done = Label(table.symbol("done"));
+    let sum = || Box::new(Exp::Temp(Temp(100)));
+    let i = || Box::new(Exp::Temp(Temp(101)));
+    let program = seq(vec![
+        Stm::Move(sum(), Box::new(Exp::Const(0))),
+        Stm::Move(i(), Box::new(Exp::Const(1))),
+        Stm::Label(test),
+        Stm::CJump(RelOp::Le, i(), Box::new(Exp::Const(10)), body, done),
+        Stm::Label(body),
+        Stm::Move(sum(), Box::new(Exp::BinOp(BinOp::Plus, sum(), i()))),
+        Stm::Move(i(), Box::new(Exp::BinOp(BinOp::Plus, i(), Box::new(Exp::Const(1))))),
+        Stm::Jump(Box::new(Exp::Name(test)), vec![test]),
+        Stm::Label(done),
+    ]);
+
+    let mut interp = Interpreter::new();
+    interp.run(&[program]).unwrap();
+    assert_eq!(interp.temp(Temp(100)), 55);
+}
+
+#[test]
+fn test_run_translated_programs() {
+    use canon::canonicalize;
+    use escape::find_escapes;
+    use interp;
+    use ir::seq;
+    use parser::parse;
+    use translate::translate;
+    use type_check::type_check;
+
+    // runs a program with the AST interpreter and on its IR, before and after
+    // canonicalization; all three must print the same
+    let check = |program: &str| {
+        let (mut p, mut table) = parse(program).unwrap();
+        find_escapes(&mut p);
+        let types = type_check(&p, &mut table).unwrap();
+
+        let mut expected = vec![];
+        let expected_error = {
+            let mut input = "".as_bytes();
+            match interp::Interpreter::new(&mut table, &mut input, &mut expected).run(&p) {
+                Err(interp::Control::Error(e)) => Some(e.pos),
+                _ => None,
+            }
+        };
+        assert!(!expected.is_empty());
+
+        let mut gen = TempGenerator::new();
+        let fragments: Vec<Fragment<SimFrame>> = translate(&p, &types, &mut table, &mut gen);
+        let canonical: Vec<Fragment<SimFrame>> = fragments.iter().map(|f| match f {
+            &Fragment::Proc { ref body, ref frame } => Fragment::Proc {
+                body: seq(canonicalize(body.clone(), &mut gen, &mut table)),
+                frame: frame.clone(),
+            },
+            &Fragment::String(l, ref s) => Fragment::String(l, s.clone()),
+        }).collect();
+
+        let main = Label(table.symbol("tigermain"));
+        for fragments in [fragments, canonical].iter() {
+            let mut input = "".as_bytes();
+            let mut output = vec![];
+            let error = {
+                let mut interp = Interpreter::with_io(&mut input, &mut output);
+                interp.load_program(fragments, &mut table);
+                match interp.run_proc(main) {
+                    Ok(_) => None,
+                    Err(Control::Runtime(e)) => Some(e.pos),
+                    Err(_) => panic!("unexpected error"),
+                }
+            };
+            assert_eq!(String::from_utf8(output).unwrap(), String::from_utf8(expected.clone()).unwrap());
+            assert_eq!(error, expected_error);
+        }
+    };
+
+    check("let var N := 6 \
+               type intArray = array of int \
+               var row := intArray [N] of 0 \
+               var col := intArray [N] of 0 \
+               var diag1 := intArray [N+N-1] of 0 \
+               var diag2 := intArray [N+N-1] of 0 \
+               function printboard() = \
+                 (for i := 0 to N-1 \
+                    do (for j := 0 to N-1 \
+                          do print(if col[i]=j then \" O\" else \" .\"); \
+                        print(\"\\n\")); \
+                  print(\"\\n\")) \
+               function try(c:int) = \
+                 if c=N then printboard() \
+                 else for r := 0 to N-1 \
+                   do if row[r]=0 & diag1[r+c]=0 & diag2[r+N-1-c]=0 \
+                      then (row[r]:=1; diag1[r+c]:=1; diag2[r+N-1-c]:=1; col[c]:=r; \
+                            try(c+1); \
+                            row[r]:=0; diag1[r+c]:=0; diag2[r+N-1-c]:=0) \
+           in try(0) end");
+    check("let type list = { hd: int, tl: list } \
+               function range(lo: int, hi: int): list = \
+                 if lo > hi then nil else list { hd = lo, tl = range(lo + 1, hi) } \
+               function sum(l: list): int = \
+                 let function go(l: list): int = if l = nil then 0 else l.hd + go(l.tl) in go(l) end \
+               var s := \"\" \
+           in printi(sum(range(1, 10))); \
+              for i := 0 to 9 do (if i = 5 then break; s := concat(s, chr(ord(\"a\") + i))); \
+              print(s); print(substring(s, 1, 3)); \
+              if s > \"abc\" & s <> \"abd\" then print(\"!\") \
+           end");
+    check("let type r = { x: int } var v: r := nil in print(\"a\"); v.x := 1 end");
+}
@@ -0,0 +1,361 @@
+use std::fmt;
+
+// Just enough JSON for the language server and the AST dumps: objects keep
+// the order of their members, and numbers are doubles, printed without a
+// fraction when they have none.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object(members: Vec<(&str, Json)>) -> Json {
+        Json::Object(members.into_iter().map(|(k, v)| (k.to_owned(), v)).collect())
+    }
+
+    pub fn str(s: &str) -> Json {
+        Json::String(s.to_owned())
+    }
+
+    // the member of an object, or Null
+    pub fn get(&self, key: &str) -> &Json {
+        match self {
+            &Json::Object(ref members) => {
+                for &(ref k, ref v) in members.iter() {
+                    if k == key {
+                        return v;
+                    }
+                }
+                &NULL
+            },
+            _ => &NULL,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            &Json::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            &Json::Number(n) if n.fract() == 0.0 => Some(n as i64),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            &Json::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Json>> {
+        match self {
+            &Json::Array(ref elements) => Some(elements),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        *self == Json::Null
+    }
+
+    // whether the value is an array or object with something in it
+    pub fn is_compound(&self) -> bool {
+        match self {
+            &Json::Array(ref elements) => !elements.is_empty(),
+            &Json::Object(ref members) => !members.is_empty(),
+            _ => false,
+        }
+    }
+
+    // the value spread over lines, two spaces to a level of nesting
+    pub fn pretty(&self) -> String {
+        let mut out = String::new();
+        write_pretty(&mut out, self, 0);
+        out
+    }
+
+    pub fn parse(s: &str) -> Result<Json, String> {
+        let mut parser = Parser { chars: s.char_indices().peekable(), source: s };
+        let value = parser.value()?;
+        parser.whitespace();
+        match parser.chars.next() {
+            None => Ok(value),
+            Some((i, _)) => Err(format!("extra input at {}", i)),
+        }
+    }
+}
+
+static NULL: Json = Json::Null;
+
+fn write_pretty(out: &mut String, json: &Json, indent: usize) {
+    let (open, close, items): (char, char, Vec<(Option<&str>, &Json)>) = match json {
+        &Json::Array(ref elements) => ('[', ']', elements.iter().map(|e| (None, e)).collect()),
+        &Json::Object(ref members) => ('{', '}', members.iter().map(|&(ref k, ref v)| (Some(k.as_str()), v)).collect()),
+        json => return out.push_str(&json.to_string()),
+    };
+    // on one line unless something inside is an object or array
+    let flat = !items.iter().any(|&(_, v)| v.is_compound());
+    out.push(open);
+    for (i, &(key, value)) in items.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        if flat {
+            if i > 0 {
+                out.push(' ');
+            }
+        } else {
+            out.push('\n');
+            out.push_str(&" ".repeat(indent + 2));
+        }
+        if let Some(key) = key {
+            out.push_str(&Json::str(key).to_string());
+            out.push_str(": ");
+        }
+        write_pretty(out, value, indent + 2);
+    }
+    if !flat {
+        out.push('\n');
+        out.push_str(&" ".repeat(indent));
+    }
+    out.push(close);
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Json::Null => write!(f, "null"),
+            &Json::Bool(b) => write!(f, "{}", b),
+            &Json::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", n as i64),
+            &Json::Number(n) => write!(f, "{}", n),
+            &Json::String(ref s) => write_string(f, s),
+            &Json::Array(ref elements) => {
+                write!(f, "[")?;
+                for (i, e) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", e)?;
+                }
+                write!(f, "]")
+            },
+            &Json::Object(ref members) => {
+                write!(f, "{{")?;
+                for (i, &(ref k, ref v)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, k)?;
+                    write!(f, ":{}", v)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: ::std::iter::Peekable<::std::str::CharIndices<'a>>,
+    source: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn whitespace(&mut self) {
+        while let Some(&(_, c)) = self.chars.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.chars.next();
+        }
+    }
+
+    fn error<T>(&mut self, what: &str) -> Result<T, String> {
+        match self.chars.peek() {
+            Some(&(i, c)) => Err(format!("expected {} at {}, found {:?}", what, i, c)),
+            None => Err(format!("expected {} at the end of the input", what)),
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        self.whitespace();
+        match self.chars.peek() {
+            Some(&(_, d)) if d == c => {
+                self.chars.next();
+                Ok(())
+            },
+            _ => self.error(&format!("{:?}", c)),
+        }
+    }
+
+    // the rest of a literal like true, whose first character was matched
+    fn keyword(&mut self, rest: &str, value: Json) -> Result<Json, String> {
+        for c in rest.chars() {
+            match self.chars.next() {
+                Some((_, d)) if d == c => (),
+                _ => return Err(format!("invalid literal, expected {:?}", c)),
+            }
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.whitespace();
+        let c = match self.chars.peek() {
+            Some(&(_, c)) => c,
+            None => return self.error("a value"),
+        };
+        match c {
+            'n' => { self.chars.next(); self.keyword("ull", Json::Null) },
+            't' => { self.chars.next(); self.keyword("rue", Json::Bool(true)) },
+            'f' => { self.chars.next(); self.keyword("alse", Json::Bool(false)) },
+            '"' => self.string().map(Json::String),
+            '[' => {
+                self.chars.next();
+                let mut elements = vec![];
+                self.whitespace();
+                if let Some(&(_, ']')) = self.chars.peek() {
+                    self.chars.next();
+                    return Ok(Json::Array(elements));
+                }
+                loop {
+                    elements.push(self.value()?);
+                    self.whitespace();
+                    match self.chars.next() {
+                        Some((_, ',')) => (),
+                        Some((_, ']')) => return Ok(Json::Array(elements)),
+                        _ => return Err(String::from("expected ',' or ']' in an array")),
+                    }
+                }
+            },
+            '{' => {
+                self.chars.next();
+                let mut members = vec![];
+                self.whitespace();
+                if let Some(&(_, '}')) = self.chars.peek() {
+                    self.chars.next();
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    self.whitespace();
+                    let key = self.string()?;
+                    self.expect(':')?;
+                    members.push((key, self.value()?));
+                    self.whitespace();
+                    match self.chars.next() {
+                        Some((_, ',')) => (),
+                        Some((_, '}')) => return Ok(Json::Object(members)),
+                        _ => return Err(String::from("expected ',' or '}' in an object")),
+                    }
+                }
+            },
+            '-' | '0'..='9' => self.number(),
+            _ => self.error("a value"),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.chars.peek().unwrap().0;
+        let mut end = start;
+        while let Some(&(i, c)) = self.chars.peek() {
+            match c {
+                '-' | '+' | '.' | 'e' | 'E' | '0'..='9' => {
+                    self.chars.next();
+                    end = i + 1;
+                },
+                _ => break,
+            }
+        }
+        let text = &self.source[start..end];
+        text.parse().map(Json::Number).map_err(|_| format!("invalid number {}", text))
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let mut n = 0;
+        for _ in 0..4 {
+            match self.chars.next().and_then(|(_, c)| c.to_digit(16)) {
+                Some(d) => n = n * 16 + d,
+                None => return Err(String::from("invalid \\u escape")),
+            }
+        }
+        Ok(n)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        match self.chars.next() {
+            Some((_, '"')) => (),
+            _ => return Err(String::from("expected a string")),
+        }
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                None => return Err(String::from("unterminated string")),
+                Some((_, '"')) => return Ok(s),
+                Some((_, '\\')) => {
+                    let c = match self.chars.next() {
+                        Some((_, 'n')) => '\n',
+                        Some((_, 't')) => '\t',
+                        Some((_, 'r')) => '\r',
+                        Some((_, 'b')) => '\u{8}',
+                        Some((_, 'f')) => '\u{c}',
+                        Some((_, 'u')) => {
+                            let mut code = self.hex4()?;
+                            // a surrogate pair
+                            if code >= 0xd800 && code < 0xdc00 {
+                                self.keyword("\\u", Json::Null)?;
+                                code = 0x10000 + ((code - 0xd800) << 10) + (self.hex4()? - 0xdc00);
+                            }
+                            ::std::char::from_u32(code).unwrap_or('\u{fffd}')
+                        },
+                        Some((_, c)) => c,
+                        None => return Err(String::from("unterminated string")),
+                    };
+                    s.push(c);
+                },
+                Some((_, c)) => s.push(c),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_json() {
+    let text = r#" {"a": [1, -2.5, true, null], "b\n": "x\"é😀", "c": {}} "#;
+    let json = Json::parse(text).unwrap();
+    assert_eq!(json.get("a").as_array().unwrap()[1], Json::Number(-2.5));
+    assert_eq!(json.get("b\n").as_str(), Some("x\"é😀"));
+    assert!(json.get("d").is_null());
+    assert_eq!(json.to_string(), "{\"a\":[1,-2.5,true,null],\"b\\n\":\"x\\\"é😀\",\"c\":{}}");
+    assert_eq!(json.pretty(), "{\n  \"a\": [1, -2.5, true, null],\n  \"b\\n\": \"x\\\"é😀\",\n  \"c\": {}\n}");
+    assert_eq!(Json::parse(&json.pretty()), Ok(json.clone()));
+    assert_eq!(Json::parse(&json.to_string()), Ok(json));
+    assert!(Json::parse("[1,]").is_err());
+    assert!(Json::parse("{\"a\" 1}").is_err());
+}
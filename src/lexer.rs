@@ -5,6 +5,12 @@ pub enum Token {
     IdentString(String),
     Ident(SymbolId),
     String(String),
+    // what is wrong with text that is not a token, like a string with an
+    // invalid escape or an unexpected character: no rule of the grammar
+    // takes it, so it ends up as a parse error
+    Error(String),
+    // a string the input ends in before its closing quote
+    UnterminatedString,
 
     While,
     For,
@@ -82,18 +88,27 @@ lexer! {
     r#"nil"# => (Token::Nil, text),
     r#"new"# => (Token::New, text),
 
-        r#"[0-9]+"# => {
+    r#"[0-9]+"# => {
         (if let Ok(i) = text.parse() {
             Token::Integer(i)
         } else {
-            panic!("integer {} is out of range", text)
+            Token::Error(format!("integer {} is out of range", text))
         }, text)
     },
 
-    r#""[a-zA-Z_][a-zA-Z0-9_]*""# => {
-         let len = text.len();
-               (Token::String(text[1..len-1].to_owned()), text)
+    // escapes are \n, \t, \", \\, \^c, \ddd and \f___f\ (whitespace between
+    // backslashes is ignored, so strings can span several lines)
+    r#""([^"\\]|\\(.|[ \t\r\n]+\\))*""# => {
+        let len = text.len();
+        (match unescape(&text[1..len-1]) {
+            Ok(s) => Token::String(s),
+            Err(e) => Token::Error(e),
+        }, text)
     },
+    // the start of a string up to the end of the input, or up to a \f___f\
+    // missing its closing backslash; a closing quote would make the rule
+    // above match more
+    r#""([^"\\]|\\(.|[ \t\r\n]+\\))*(\\[ \t\r\n]*)?"# => (Token::UnterminatedString, text),
 
     r#"[a-zA-Z_][a-zA-Z0-9_]*"# => (Token::IdentString(text.to_owned()), text),
 
@@ -123,13 +138,57 @@ lexer! {
     r#">="# => (Token::GreaterEqual, text),
     r#":="# => (Token::Assign, text),
 
-    r#"."# => panic!("unexpected character: {}", text),
+    r#"."# => (Token::Error(format!("unexpected character {}", text)), text),
+}
+
+fn unescape(s: &str) -> Result<String, String> {
+    let mut res = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => res.push('\n'),
+            Some('t') => res.push('\t'),
+            Some('"') => res.push('"'),
+            Some('\\') => res.push('\\'),
+            Some('^') => match chars.next() {
+                Some(c) if c >= '@' && c <= '_' => res.push(((c as u8) - b'@') as char),
+                c => return Err(format!("invalid control character escape: \\^{}", c.map_or(String::new(), |c| c.to_string()))),
+            },
+            Some(c) if c.is_digit(10) => {
+                let mut code = c.to_digit(10).unwrap();
+                for _ in 0..2 {
+                    match chars.next().and_then(|c| c.to_digit(10)) {
+                        Some(d) => code = code * 10 + d,
+                        None => return Err(String::from("invalid escape: \\ddd needs three digits")),
+                    }
+                }
+                if code > 255 {
+                    return Err(format!("character code {} is out of range", code));
+                }
+                res.push(code as u8 as char);
+            },
+            Some(c) if c.is_whitespace() => {
+                while let Some(c) = chars.next() {
+                    if c == '\\' {
+                        break;
+                    }
+                }
+            },
+            c => return Err(format!("invalid escape: \\{}", c.map_or(String::new(), |c| c.to_string()))),
+        }
+    }
+    Ok(res)
 }
 
 pub struct Lexer<'a> {
     original: &'a str,
     remaining: &'a str,
     symbol_table: &'a mut SymbolTable,
+    comments: Option<&'a mut Vec<Span>>,
 }
 
 impl<'a> Lexer<'a> {
@@ -138,6 +197,18 @@ impl<'a> Lexer<'a> {
             original: s,
             remaining: s,
             symbol_table: symbol_table,
+            comments: None,
+        }
+    }
+
+    // a lexer that also keeps the spans of the comments it skips, for the
+    // formatter
+    pub fn with_comments(s: &'a str, symbol_table: &'a mut SymbolTable, comments: &'a mut Vec<Span>) -> Lexer<'a> {
+        Lexer {
+            original: s,
+            remaining: s,
+            symbol_table: symbol_table,
+            comments: Some(comments),
         }
     }
 }
@@ -166,13 +237,23 @@ impl<'a> Iterator for Lexer<'a> {
                 return None
             };
             match tok {
-                (Token::Whitespace, _) | (Token::Comment, _) => {
+                (Token::Whitespace, _) => {
+                    continue;
+                }
+                (Token::Comment, span) => {
+                    if let Some(ref mut comments) = self.comments {
+                        comments.push(span_in(span, self.original));
+                    }
                     continue;
                 }
                 (Token::IdentString(ref str), span) => {
                     let s = span_in(span, self.original);
                     return Some((s.lo, Token::Ident(self.symbol_table.symbol(str)), s.hi));
                 }
+                (Token::UnterminatedString, span) if !self.remaining.is_empty() => {
+                    let s = span_in(span, self.original);
+                    return Some((s.lo, Token::Error(String::from("invalid escape: \\f___f\\ needs a closing \\")), s.hi));
+                }
                 (tok, span) => {
                     let s = span_in(span, self.original);
                     return Some((s.lo, tok, s.hi));
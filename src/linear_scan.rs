@@ -0,0 +1,361 @@
+use assem::Instr;
+use codegen::Codegen;
+use flow::{FlowGraph, flow_graph};
+use frame::{Access, Frame};
+use liveness::{Liveness, liveness};
+use regalloc::{Allocation, rewrite_program};
+use temp::{Temp, TempGenerator, FIRST_FREE_TEMP};
+
+use std::collections::{HashMap, HashSet};
+
+// Linear-scan register allocation (Poletto and Sarkar): every temp gets a
+// live interval over the instructions in order, and the intervals are
+// given registers in one pass by increasing start, spilling the interval
+// that ends last when registers run out. Intervals are not split: a
+// spilled temp lives in a frame slot for the whole procedure, and
+// rewrite_program replaces it at each instruction using or defining it by
+// a new temp, loaded before the instruction and stored after it. The scan
+// is then run again over the rewritten program, where the new temps have
+// short intervals of their own.
+pub fn allocate<C: Codegen>(codegen: &C, frame: &mut C::Frame, mut instrs: Vec<Instr>,
+                            gen: &mut TempGenerator) -> (Vec<Instr>, Allocation) {
+    let registers = codegen.registers();
+    let mut spill_temps = HashSet::new();
+    loop {
+        let flow = flow_graph(&instrs);
+        let live = liveness(&flow);
+        let mut scan = Scan::new(&instrs, &flow, &live, &registers, &spill_temps);
+        scan.run();
+        if scan.spilled.is_empty() {
+            return (instrs, scan.allocation());
+        }
+        let slots = scan.spill_slots(frame, gen);
+        instrs = rewrite_program(codegen, instrs, &slots, gen, &mut spill_temps);
+    }
+}
+
+// Positions: instruction i is at 2i, and the point right after it at
+// 2i + 1. A temp occupies the positions of the instructions it is live
+// into, used or defined at, and the points after those it is live out of.
+// The destination of a move is only written after the move, so that it
+// can share the register of a source that dies there.
+struct Interval {
+    temp: Temp,
+    start: usize,
+    end: usize,
+}
+
+struct Scan<'a> {
+    registers: &'a [Temp],
+    intervals: Vec<Interval>,
+    // for each register, the sorted positions where it holds a value of
+    // the machine's own, like an argument or the result of a call
+    fixed: Vec<Vec<usize>>,
+    // the temp a temp is moved from or to, whose register it would rather
+    // share
+    hints: HashMap<Temp, Temp>,
+    spill_temps: &'a HashSet<Temp>,
+    precolored: Vec<Temp>,
+    register: Vec<Option<usize>>,
+    spilled: Vec<usize>,
+}
+
+impl<'a> Scan<'a> {
+    fn new(instrs: &[Instr], flow: &FlowGraph, live: &Liveness, registers: &'a [Temp],
+           spill_temps: &'a HashSet<Temp>) -> Scan<'a> {
+        let mut ranges: Vec<Option<(usize, usize)>> = vec![None; live.temps.len()];
+        let mut fixed = vec![vec![]; registers.len()];
+        let register_index: HashMap<Temp, usize> = registers.iter().enumerate().map(|(i, &r)| (r, i)).collect();
+        {
+            let mut touch = |t: usize, position: usize| {
+                let temp = live.temps[t];
+                if temp.0 >= FIRST_FREE_TEMP {
+                    ranges[t] = Some(match ranges[t] {
+                        Some((start, end)) => (start.min(position), end.max(position)),
+                        None => (position, position),
+                    });
+                } else if let Some(&r) = register_index.get(&temp) {
+                    if fixed[r].last() != Some(&position) {
+                        fixed[r].push(position);
+                    }
+                }
+            };
+            for node in 0..flow.len() {
+                let def_position = if flow.is_move[node] { 2 * node + 1 } else { 2 * node };
+                for t in live.live_in[node].iter() {
+                    touch(t, 2 * node);
+                }
+                for t in flow.uses[node].iter() {
+                    touch(live.index[t], 2 * node);
+                }
+                for t in flow.defs[node].iter() {
+                    touch(live.index[t], def_position);
+                }
+                for t in live.live_out[node].iter() {
+                    touch(t, 2 * node + 1);
+                }
+            }
+        }
+
+        let mut hints = HashMap::new();
+        for instr in instrs.iter() {
+            if let &Instr::Move { dst, src, .. } = instr {
+                hints.entry(dst).or_insert(src);
+                hints.entry(src).or_insert(dst);
+            }
+        }
+
+        let mut intervals: Vec<Interval> = ranges.iter().enumerate()
+            .filter_map(|(t, range)| range.map(|(start, end)| Interval { temp: live.temps[t], start: start, end: end }))
+            .collect();
+        intervals.sort_by_key(|i| (i.start, i.end));
+        let n = intervals.len();
+        Scan {
+            registers: registers,
+            intervals: intervals,
+            fixed: fixed,
+            hints: hints,
+            spill_temps: spill_temps,
+            precolored: live.temps.iter().cloned().filter(|t| t.0 < FIRST_FREE_TEMP).collect(),
+            register: vec![None; n],
+            spilled: vec![],
+        }
+    }
+
+    // whether register r holds no value of the machine in the interval
+    fn available(&self, r: usize, interval: &Interval) -> bool {
+        let positions = &self.fixed[r];
+        match positions.binary_search(&interval.start) {
+            Ok(_) => false,
+            Err(i) => i == positions.len() || positions[i] > interval.end,
+        }
+    }
+
+    fn can_spill(&self, i: usize) -> bool {
+        !self.spill_temps.contains(&self.intervals[i].temp)
+    }
+
+    fn run(&mut self) {
+        let temp_index: HashMap<Temp, usize> = self.intervals.iter().enumerate().map(|(i, v)| (v.temp, i)).collect();
+        let mut active: Vec<usize> = vec![];
+        let mut free = vec![true; self.registers.len()];
+        for current in 0..self.intervals.len() {
+            let start = self.intervals[current].start;
+            {
+                let intervals = &self.intervals;
+                let register = &self.register;
+                active.retain(|&i| {
+                    if intervals[i].end < start {
+                        free[register[i].unwrap()] = true;
+                        false
+                    } else {
+                        true
+                    }
+                });
+            }
+
+            // the register of the temp this one is moved from or to, if
+            // it is free, then the first free register
+            let hint = self.hints.get(&self.intervals[current].temp).and_then(|h| {
+                if h.0 < FIRST_FREE_TEMP {
+                    self.registers.iter().position(|r| r == h)
+                } else {
+                    temp_index.get(h).and_then(|&i| self.register[i])
+                }
+            });
+            let choice = {
+                let interval = &self.intervals[current];
+                hint.into_iter().chain(0..self.registers.len()).find(|&r| free[r] && self.available(r, interval))
+            };
+            if let Some(r) = choice {
+                free[r] = false;
+                self.register[current] = Some(r);
+                active.push(current);
+                continue;
+            }
+
+            // the active interval ending last among those in a register
+            // the current one could have, one created by spilling only if
+            // nothing else is left, as in select_spill of the coloring
+            // allocator
+            let evict = {
+                let interval = &self.intervals[current];
+                let fits = |i: usize| self.available(self.register[i].unwrap(), interval);
+                let victim = active.iter().cloned()
+                    .filter(|&i| self.can_spill(i) && fits(i))
+                    .max_by_key(|&i| self.intervals[i].end);
+                let last_resort = active.iter().cloned()
+                    .filter(|&i| fits(i))
+                    .max_by_key(|&i| self.intervals[i].end);
+                match victim {
+                    Some(v) if !self.can_spill(current) || self.intervals[v].end > interval.end => Some(v),
+                    _ if self.can_spill(current) => None,
+                    _ => last_resort,
+                }
+            };
+            match evict {
+                Some(v) => {
+                    self.register[current] = self.register[v].take();
+                    active.retain(|&i| i != v);
+                    active.push(current);
+                    self.spilled.push(v);
+                },
+                None => self.spilled.push(current),
+            }
+        }
+    }
+
+    fn allocation(&self) -> Allocation {
+        let mut allocation: Allocation = self.intervals.iter().zip(self.register.iter())
+            .map(|(interval, r)| (interval.temp, self.registers[r.unwrap()]))
+            .collect();
+        for &t in self.precolored.iter() {
+            allocation.insert(t, t);
+        }
+        allocation
+    }
+
+    // Frame slots for the spilled temps, shared by temps whose intervals
+    // don't overlap.
+    fn spill_slots<F: Frame>(&self, frame: &mut F, gen: &mut TempGenerator) -> HashMap<Temp, i32> {
+        let mut spilled = self.spilled.clone();
+        spilled.sort_by_key(|&i| self.intervals[i].start);
+        // the end of the last interval in each slot
+        let mut slots: Vec<(usize, i32)> = vec![];
+        let mut res = HashMap::new();
+        for &i in spilled.iter() {
+            let interval = &self.intervals[i];
+            let offset = match slots.iter_mut().find(|&&mut (end, _)| end < interval.start) {
+                Some(slot) => {
+                    slot.0 = interval.end;
+                    slot.1
+                },
+                None => match frame.alloc_local(true, gen) {
+                    Access::InFrame(offset) => {
+                        slots.push((interval.end, offset));
+                        offset
+                    },
+                    Access::InReg(_) => panic!("escaping local allocated in a register"),
+                },
+            };
+            res.insert(interval.temp, offset);
+        }
+        res
+    }
+}
+
+#[test]
+fn test_intervals() {
+    let (a, b) = (Temp(100), Temp(101));
+    let instrs = vec![
+        Instr::oper(String::from("def `d0"), vec![a], vec![]),
+        Instr::Move { assem: String::from("move `d0, `s0"), dst: b, src: a },
+        Instr::oper(String::from("call"), vec![Temp(1)], vec![]),
+        Instr::oper(String::from("use `s0"), vec![], vec![b]),
+    ];
+    let flow = flow_graph(&instrs);
+    let live = liveness(&flow);
+    let registers = [Temp(1), Temp(2)];
+    let spill_temps = HashSet::new();
+    let mut scan = Scan::new(&instrs, &flow, &live, &registers, &spill_temps);
+    let intervals: Vec<(Temp, usize, usize)> = scan.intervals.iter().map(|i| (i.temp, i.start, i.end)).collect();
+    // b is written after the move, where a dies, and lives across the call
+    assert_eq!(intervals, vec![(a, 0, 2), (b, 3, 6)]);
+    assert_eq!(scan.fixed, vec![vec![4], vec![]]);
+    scan.run();
+    assert!(scan.spilled.is_empty());
+    // a takes the first register, and b cannot share it for the call
+    let allocation = scan.allocation();
+    assert_eq!(allocation[&a], Temp(1));
+    assert_eq!(allocation[&b], Temp(2));
+    assert_eq!(allocation[&Temp(1)], Temp(1));
+}
+
+#[test]
+fn test_spill_slots() {
+    use symbol::SymbolTable;
+    use temp::Label;
+    use x86_64::X86Frame;
+
+    // with one register, the long intervals of a and c are spilled for
+    // the short ones of b and d, and share a slot as they don't overlap
+    let (a, b, c, d) = (Temp(100), Temp(101), Temp(102), Temp(103));
+    let def = |t| Instr::oper(String::from("def `d0"), vec![t], vec![]);
+    let use_ = |t| Instr::oper(String::from("use `s0"), vec![], vec![t]);
+    let instrs = vec![def(a), def(b), use_(b), use_(a), def(c), def(d), use_(d), use_(c)];
+    let flow = flow_graph(&instrs);
+    let live = liveness(&flow);
+    let registers = [Temp(1)];
+    let spill_temps = HashSet::new();
+    let mut scan = Scan::new(&instrs, &flow, &live, &registers, &spill_temps);
+    scan.run();
+    let spilled: Vec<Temp> = scan.spilled.iter().map(|&i| scan.intervals[i].temp).collect();
+    assert_eq!(spilled, vec![a, c]);
+
+    let mut table = SymbolTable::new();
+    let mut gen = TempGenerator::new();
+    let mut frame = X86Frame::new_frame(Label(table.symbol("f")), vec![], &mut gen);
+    let slots = scan.spill_slots(&mut frame, &mut gen);
+    assert_eq!(slots.len(), 2);
+    assert_eq!(slots[&a], slots[&c]);
+    // and only one slot was taken from the frame
+    assert_eq!(frame.alloc_local(true, &mut gen), Access::InFrame(slots[&a] - 8));
+}
+
+#[test]
+fn test_spill_temps_last() {
+    // two temps created by spilling, both used by the same instruction,
+    // and one register: one of them goes again rather than the scan
+    // giving up
+    let (a, b) = (Temp(100), Temp(101));
+    let instrs = vec![
+        Instr::oper(String::from("def `d0"), vec![a], vec![]),
+        Instr::oper(String::from("def `d0"), vec![b], vec![]),
+        Instr::oper(String::from("use `s0, `s1"), vec![], vec![a, b]),
+    ];
+    let flow = flow_graph(&instrs);
+    let live = liveness(&flow);
+    let registers = [Temp(1)];
+    let spill_temps = [a, b].iter().cloned().collect();
+    let mut scan = Scan::new(&instrs, &flow, &live, &registers, &spill_temps);
+    scan.run();
+    let spilled: Vec<Temp> = scan.spilled.iter().map(|&i| scan.intervals[i].temp).collect();
+    assert_eq!(spilled, vec![a]);
+    assert_eq!(scan.register[1], Some(0));
+}
+
+#[test]
+fn test_allocate_under_pressure() {
+    use codegen::Codegen;
+    use liveness::interference_graph;
+    use symbol::SymbolTable;
+    use temp::Label;
+    use x86_64::{X86Codegen, X86Frame, Syntax};
+
+    let mut table = SymbolTable::new();
+    let codegen = X86Codegen { syntax: Syntax::Att };
+    let registers = codegen.registers();
+    let mut gen = TempGenerator::new();
+    let mut frame = X86Frame::new_frame(Label(table.symbol("f")), vec![], &mut gen);
+
+    // three times as many values live as there are registers, read many
+    // at once
+    let temps: Vec<Temp> = (0..3 * registers.len()).map(|_| gen.new_temp()).collect();
+    let mut instrs: Vec<Instr> = temps.iter().map(|&t| Instr::oper(String::from("def `d0"), vec![t], vec![])).collect();
+    for chunk in temps.chunks(registers.len() / 2) {
+        instrs.push(Instr::oper(String::from("use"), vec![], chunk.to_vec()));
+    }
+    instrs.extend(temps.iter().map(|&t| Instr::oper(String::from("use `s0"), vec![], vec![t])));
+
+    let (instrs, allocation) = allocate(&codegen, &mut frame, instrs, &mut gen);
+    let flow = flow_graph(&instrs);
+    let igraph = interference_graph(&flow, &liveness(&flow));
+    for (i, &t) in igraph.temps.iter().enumerate() {
+        assert!(t.0 < FIRST_FREE_TEMP || registers.contains(&allocation[&t]));
+        for &j in igraph.adj_list[i].iter() {
+            assert!(allocation[&t] != allocation[&igraph.temps[j]]);
+        }
+    }
+    // the spilled temps are all live at once, so each needs its own slot
+    assert!(frame.frame_size() <= 8 * temps.len() as i32 + 8);
+}
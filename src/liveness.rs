@@ -0,0 +1,258 @@
+use flow::FlowGraph;
+use temp::Temp;
+
+use std::collections::{HashMap, VecDeque};
+
+// A fixed-size set of small integers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BitSet {
+    words: Vec<u64>,
+}
+
+impl BitSet {
+    pub fn new(size: usize) -> BitSet {
+        BitSet { words: vec![0; (size + 63) / 64] }
+    }
+
+    pub fn contains(&self, i: usize) -> bool {
+        self.words[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    pub fn insert(&mut self, i: usize) {
+        self.words[i / 64] |= 1 << (i % 64);
+    }
+
+    pub fn remove(&mut self, i: usize) {
+        self.words[i / 64] &= !(1 << (i % 64));
+    }
+
+    // adds the elements of other, and tells whether any was new
+    pub fn union_with(&mut self, other: &BitSet) -> bool {
+        let mut changed = false;
+        for (w, &o) in self.words.iter_mut().zip(other.words.iter()) {
+            let new = *w | o;
+            changed |= new != *w;
+            *w = new;
+        }
+        changed
+    }
+
+    pub fn iter<'a>(&'a self) -> BitSetIter<'a> {
+        BitSetIter { words: &self.words, index: 0, word: self.words.first().cloned().unwrap_or(0) }
+    }
+}
+
+pub struct BitSetIter<'a> {
+    words: &'a [u64],
+    index: usize,
+    // the bits of words[index] not yet returned
+    word: u64,
+}
+
+impl<'a> Iterator for BitSetIter<'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        while self.word == 0 {
+            self.index += 1;
+            if self.index >= self.words.len() {
+                return None;
+            }
+            self.word = self.words[self.index];
+        }
+        let bit = self.word.trailing_zeros() as usize;
+        self.word &= self.word - 1;
+        Some(self.index * 64 + bit)
+    }
+}
+
+// The temps live on entry to and on exit from each node of a flow graph.
+// Temps are numbered densely in order of appearance, so that the sets can
+// be bitsets.
+pub struct Liveness {
+    pub temps: Vec<Temp>,
+    pub index: HashMap<Temp, usize>,
+    pub live_in: Vec<BitSet>,
+    pub live_out: Vec<BitSet>,
+}
+
+impl Liveness {
+    pub fn live_out(&self, node: usize) -> Vec<Temp> {
+        self.live_out[node].iter().map(|i| self.temps[i]).collect()
+    }
+
+    pub fn live_in(&self, node: usize) -> Vec<Temp> {
+        self.live_in[node].iter().map(|i| self.temps[i]).collect()
+    }
+}
+
+// Solves in[n] = use[n] + (out[n] - def[n]) and out[n] = the union of in[s]
+// over the successors s of n. Nodes are visited backwards, and a node goes
+// back on the worklist only when the live-in set of a successor grew.
+pub fn liveness(graph: &FlowGraph) -> Liveness {
+    let mut temps = vec![];
+    let mut index = HashMap::new();
+    for node in 0..graph.len() {
+        for &t in graph.defs[node].iter().chain(graph.uses[node].iter()) {
+            if !index.contains_key(&t) {
+                index.insert(t, temps.len());
+                temps.push(t);
+            }
+        }
+    }
+
+    let n = graph.len();
+    let mut uses = vec![BitSet::new(temps.len()); n];
+    let mut defs = vec![BitSet::new(temps.len()); n];
+    for node in 0..n {
+        for t in graph.uses[node].iter() {
+            uses[node].insert(index[t]);
+        }
+        for t in graph.defs[node].iter() {
+            defs[node].insert(index[t]);
+        }
+    }
+
+    let mut live_in = uses.clone();
+    let mut live_out = vec![BitSet::new(temps.len()); n];
+    let mut worklist: VecDeque<usize> = (0..n).rev().collect();
+    let mut on_worklist = vec![true; n];
+    while let Some(node) = worklist.pop_front() {
+        on_worklist[node] = false;
+        let mut out = BitSet::new(temps.len());
+        for &s in graph.succ[node].iter() {
+            out.union_with(&live_in[s]);
+        }
+        let mut in_ = out.clone();
+        for (w, &d) in in_.words.iter_mut().zip(defs[node].words.iter()) {
+            *w &= !d;
+        }
+        in_.union_with(&uses[node]);
+        live_out[node] = out;
+        if live_in[node].union_with(&in_) {
+            for &p in graph.pred[node].iter() {
+                if !on_worklist[p] {
+                    on_worklist[p] = true;
+                    worklist.push_back(p);
+                }
+            }
+        }
+    }
+
+    Liveness { temps: temps, index: index, live_in: live_in, live_out: live_out }
+}
+
+// Temps that can't share a register (Appel 10.6). Nodes are the temps of
+// the liveness analysis; the source of a move doesn't interfere with its
+// destination because of the move, and the move is recorded instead as a
+// candidate for coalescing.
+pub struct InterferenceGraph {
+    pub temps: Vec<Temp>,
+    pub index: HashMap<Temp, usize>,
+    pub adj_set: Vec<BitSet>,
+    pub adj_list: Vec<Vec<usize>>,
+    // (dst, src) of every move between distinct temps
+    pub moves: Vec<(Temp, Temp)>,
+}
+
+impl InterferenceGraph {
+    pub fn interferes(&self, a: Temp, b: Temp) -> bool {
+        match (self.index.get(&a), self.index.get(&b)) {
+            (Some(&a), Some(&b)) => self.adj_set[a].contains(b),
+            _ => false,
+        }
+    }
+
+    pub fn neighbors(&self, t: Temp) -> Vec<Temp> {
+        match self.index.get(&t) {
+            Some(&i) => self.adj_list[i].iter().map(|&j| self.temps[j]).collect(),
+            None => vec![],
+        }
+    }
+
+    fn add_edge(&mut self, a: usize, b: usize) {
+        if a != b && !self.adj_set[a].contains(b) {
+            self.adj_set[a].insert(b);
+            self.adj_set[b].insert(a);
+            self.adj_list[a].push(b);
+            self.adj_list[b].push(a);
+        }
+    }
+}
+
+pub fn interference_graph(graph: &FlowGraph, live: &Liveness) -> InterferenceGraph {
+    let n = live.temps.len();
+    let mut igraph = InterferenceGraph {
+        temps: live.temps.clone(),
+        index: live.index.clone(),
+        adj_set: vec![BitSet::new(n); n],
+        adj_list: vec![vec![]; n],
+        moves: vec![],
+    };
+    for node in 0..graph.len() {
+        let mut out = live.live_out[node].clone();
+        if graph.is_move[node] {
+            let (dst, src) = (graph.defs[node][0], graph.uses[node][0]);
+            if dst != src {
+                igraph.moves.push((dst, src));
+            }
+            out.remove(live.index[&src]);
+        }
+        // the destinations of one instruction interfere with each other
+        // even if some are dead, as they are all written at once
+        for &d in graph.defs[node].iter() {
+            out.insert(live.index[&d]);
+        }
+        for &d in graph.defs[node].iter() {
+            let d = live.index[&d];
+            for t in out.iter() {
+                igraph.add_edge(d, t);
+            }
+        }
+    }
+    igraph
+}
+
+#[test]
+fn test_liveness() {
+    use assem::Instr;
+    use flow::flow_graph;
+    use symbol::SymbolTable;
+    use temp::Label;
+
+    // the loop of Appel's figure 10.1, returning c through a move
+    let mut table = SymbolTable::new();
+    let (l1, l2) = (Label(table.symbol("L1")), Label(table.symbol("L2")));
+    let (a, b, c, d) = (Temp(100), Temp(101), Temp(102), Temp(103));
+    let oper = |dst, src, jump| Instr::Oper { assem: String::new(), dst: dst, src: src, jump: jump };
+    let instrs = vec![
+        oper(vec![a], vec![], None),
+        Instr::Label { assem: String::new(), label: l1 },
+        oper(vec![b], vec![a], None),
+        oper(vec![c], vec![c, b], None),
+        oper(vec![a], vec![b], None),
+        oper(vec![], vec![a], Some(vec![l1, l2])),
+        Instr::Label { assem: String::new(), label: l2 },
+        Instr::Move { assem: String::new(), dst: d, src: c },
+        oper(vec![], vec![d], Some(vec![])),
+    ];
+
+    let graph = flow_graph(&instrs);
+    assert_eq!(graph.succ[5], vec![1, 6]);
+    assert_eq!(graph.pred[1], vec![0, 5]);
+    assert!(graph.succ[8].is_empty());
+
+    let live = liveness(&graph);
+    let sorted = |mut v: Vec<Temp>| { v.sort(); v };
+    assert_eq!(sorted(live.live_in(0)), vec![c]);
+    assert_eq!(sorted(live.live_out(0)), vec![a, c]);
+    assert_eq!(sorted(live.live_out(2)), vec![b, c]);
+    assert_eq!(sorted(live.live_out(5)), vec![a, c]);
+    assert_eq!(sorted(live.live_out(7)), vec![d]);
+
+    let igraph = interference_graph(&graph, &live);
+    assert!(igraph.interferes(a, c) && igraph.interferes(c, b));
+    assert!(!igraph.interferes(a, b));
+    assert!(!igraph.interferes(c, d));
+    assert_eq!(igraph.moves, vec![(d, c)]);
+}
@@ -0,0 +1,815 @@
+use ast;
+use json::Json;
+use lexer::{Lexer, Token};
+use parser;
+use symbol::{SymbolId, SymbolTable};
+use type_check::{TypeMap, type_check_partial};
+use types::{EnvEntry, base_tenv, base_venv};
+
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+// A language server speaking LSP over a pair of streams. Documents are
+// synchronized in full; every change is parsed and type checked, which
+// publishes the first error as a diagnostic. Hover, definitions,
+// references, document symbols and completion use the last version of the
+// document that parsed, whose names are resolved to their declarations
+// here, with the scoping rules of the type checker.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Variable,
+    LoopVariable,
+    Parameter,
+    Function,
+    Type,
+}
+
+#[derive(Debug)]
+struct Definition {
+    name: String,
+    kind: Kind,
+    // the span of the name in the declaration; None for the standard library
+    span: Option<(usize, usize)>,
+    // the declaration as shown on hover, e.g. "var x: int"
+    detail: String,
+    // the function the declaration is in
+    container: Option<String>,
+}
+
+// A mention of a declared name, the declaration itself included.
+#[derive(Debug)]
+struct Occurrence {
+    start: usize,
+    end: usize,
+    definition: usize,
+}
+
+// A part of the source in which declarations are in scope.
+#[derive(Debug)]
+struct Scope {
+    start: usize,
+    end: usize,
+    definitions: Vec<usize>,
+}
+
+struct Analysis {
+    definitions: Vec<Definition>,
+    occurrences: Vec<Occurrence>,
+    scopes: Vec<Scope>,
+}
+
+type Env = HashMap<SymbolId, usize>;
+
+fn dec_pos(dec: &ast::Dec) -> usize {
+    match dec {
+        &ast::Dec::FunDec { pos, .. } | &ast::Dec::VarDec { pos, .. } | &ast::Dec::TypeDec { pos, .. } => pos,
+    }
+}
+
+struct Resolver<'a> {
+    symbol_table: &'a SymbolTable,
+    types: &'a TypeMap,
+    // the identifiers of the source in order
+    idents: Vec<(usize, SymbolId, usize)>,
+    // the positions of the in and the end of each let, by that of the let
+    lets: HashMap<usize, (usize, usize)>,
+    analysis: Analysis,
+}
+
+impl<'a> Resolver<'a> {
+    fn name(&self, symbol: SymbolId) -> String {
+        self.symbol_table.name(&symbol).clone()
+    }
+
+    // the span of the first mention of symbol at or after pos
+    fn ident(&self, symbol: SymbolId, pos: usize) -> Option<(usize, usize)> {
+        let i = match self.idents.binary_search_by_key(&pos, |t| t.0) {
+            Ok(i) | Err(i) => i,
+        };
+        self.idents[i..].iter().find(|t| t.1 == symbol).map(|t| (t.0, t.2))
+    }
+
+    fn define(&mut self, symbol: SymbolId, kind: Kind, span: Option<(usize, usize)>, detail: String,
+              container: &Option<String>) -> usize {
+        let id = self.analysis.definitions.len();
+        self.analysis.definitions.push(Definition {
+            name: self.name(symbol),
+            kind: kind,
+            span: span,
+            detail: detail,
+            container: container.clone(),
+        });
+        if let Some((start, end)) = span {
+            self.analysis.occurrences.push(Occurrence { start: start, end: end, definition: id });
+        }
+        id
+    }
+
+    fn refer(&mut self, env: &Env, symbol: SymbolId, pos: usize) {
+        if let (Some(&id), Some((start, end))) = (env.get(&symbol), self.ident(symbol, pos)) {
+            self.analysis.occurrences.push(Occurrence { start: start, end: end, definition: id });
+        }
+    }
+
+    // the type named after a field, whose own name comes first
+    fn field_type(&mut self, field: &ast::Field, tenv: &Env) {
+        let after = self.ident(field.name, field.pos).map(|span| span.1).unwrap_or(field.pos);
+        self.refer(tenv, field.typ, after);
+    }
+
+    fn ty(&mut self, ty: &ast::Ty, tenv: &Env) {
+        match ty {
+            &ast::Ty::NameTy(symbol, pos) | &ast::Ty::ArrayTy(symbol, pos) => self.refer(tenv, symbol, pos),
+            &ast::Ty::RecordTy(ref fields) => {
+                for field in fields.iter() {
+                    self.field_type(field, tenv);
+                }
+            },
+        }
+    }
+
+    fn var(&mut self, var: &ast::Var, venv: &Env, tenv: &Env, end: usize, container: &Option<String>) {
+        match var {
+            &ast::Var::SimpleVar(symbol, pos, _) => self.refer(venv, symbol, pos),
+            &ast::Var::FieldVar(ref var, _, _, _) => self.var(var, venv, tenv, end, container),
+            &ast::Var::SubscriptVar(ref var, ref index, _, _) => {
+                self.var(var, venv, tenv, end, container);
+                self.exp(index, venv, tenv, end, container);
+            },
+        }
+    }
+
+    // end is where the innermost let around exp ends
+    fn exp(&mut self, exp: &ast::Exp, venv: &Env, tenv: &Env, end: usize, container: &Option<String>) {
+        match exp {
+            &ast::Exp::VarExp(ref var, _) => self.var(var, venv, tenv, end, container),
+            &ast::Exp::NilExp(_) | &ast::Exp::IntExp(_, _) | &ast::Exp::StringExp(_, _, _) | &ast::Exp::BreakExp(_, _) => (),
+            &ast::Exp::CallExp{ func, ref args, pos, .. } => {
+                self.refer(venv, func, pos);
+                for arg in args.iter() {
+                    self.exp(arg, venv, tenv, end, container);
+                }
+            },
+            &ast::Exp::OpExp { ref left, ref right, .. } => {
+                self.exp(left, venv, tenv, end, container);
+                self.exp(right, venv, tenv, end, container);
+            },
+            &ast::Exp::RecordExp{ typ, ref fields, pos, .. } => {
+                self.refer(tenv, typ, pos);
+                for &(_, ref exp, _) in fields.iter() {
+                    self.exp(exp, venv, tenv, end, container);
+                }
+            },
+            &ast::Exp::SeqExp(ref exps, _) => {
+                for exp in exps.iter() {
+                    self.exp(exp, venv, tenv, end, container);
+                }
+            },
+            &ast::Exp::AssignExp { ref var, ref exp, .. } => {
+                self.var(var, venv, tenv, end, container);
+                self.exp(exp, venv, tenv, end, container);
+            },
+            &ast::Exp::IfExp { ref test, ref then_, ref else_, .. } => {
+                self.exp(test, venv, tenv, end, container);
+                self.exp(then_, venv, tenv, end, container);
+                if let &Some(ref else_) = else_ {
+                    self.exp(else_, venv, tenv, end, container);
+                }
+            },
+            &ast::Exp::WhileExp { ref test, ref body, .. } => {
+                self.exp(test, venv, tenv, end, container);
+                self.exp(body, venv, tenv, end, container);
+            },
+            &ast::Exp::ForExp { var, ref lo, ref hi, ref body, pos, .. } => {
+                self.exp(lo, venv, tenv, end, container);
+                self.exp(hi, venv, tenv, end, container);
+                let span = self.ident(var, pos);
+                let detail = format!("var {}: int", self.name(var));
+                let id = self.define(var, Kind::LoopVariable, span, detail, container);
+                let mut venv = venv.clone();
+                venv.insert(var, id);
+                self.analysis.scopes.push(Scope { start: pos, end: end, definitions: vec![id] });
+                self.exp(body, &venv, tenv, end, container);
+            },
+            &ast::Exp::LetExp{ ref decs, ref body, pos, .. } => {
+                let (in_pos, let_end) = self.lets.get(&pos).cloned().unwrap_or((end, end));
+                let (venv, tenv) = self.decs(decs, venv, tenv, in_pos, let_end, container);
+                self.exp(body, &venv, &tenv, let_end, container);
+            },
+            &ast::Exp::ArrayExp{ typ, ref size, ref init, pos, .. } => {
+                self.refer(tenv, typ, pos);
+                self.exp(size, venv, tenv, end, container);
+                self.exp(init, venv, tenv, end, container);
+            },
+        }
+    }
+
+    fn fun_detail(&self, name: ast::Symbol, params: &[Box<ast::Field>], result: &Option<(ast::Symbol, ast::Position)>) -> String {
+        let params: Vec<String> = params.iter()
+            .map(|p| format!("{}: {}", self.name(p.name), self.name(p.typ)))
+            .collect();
+        let result = match result {
+            &Some((typ, _)) => format!(": {}", self.name(typ)),
+            &None => String::new(),
+        };
+        format!("function {}({}){}", self.name(name), params.join(", "), result)
+    }
+
+    // the declarations of a let, whose in is at in_pos and whose end is at
+    // end, grouped as the type checker does; returns the environments of
+    // its body
+    fn decs(&mut self, decs: &[Box<ast::Dec>], venv: &Env, tenv: &Env, in_pos: usize, end: usize,
+            container: &Option<String>) -> (Env, Env) {
+        let mut venv = venv.clone();
+        let mut tenv = tenv.clone();
+        let mut i = 0;
+        while i < decs.len() {
+            let mut j = i + 1;
+            let mut ids = vec![];
+            let mut start = dec_pos(&decs[i]);
+            match decs[i].as_ref() {
+                &ast::Dec::TypeDec { .. } => {
+                    while j < decs.len() {
+                        if let &ast::Dec::TypeDec { .. } = decs[j].as_ref() { j += 1 } else { break }
+                    }
+                    for dec in decs[i..j].iter() {
+                        if let &ast::Dec::TypeDec { name, pos, .. } = dec.as_ref() {
+                            let detail = match self.types.dec_ty(dec) {
+                                Some(ty) => format!("type {} = {}", self.name(name), ty.name(self.symbol_table)),
+                                None => format!("type {}", self.name(name)),
+                            };
+                            let span = self.ident(name, pos);
+                            let id = self.define(name, Kind::Type, span, detail, container);
+                            tenv.insert(name, id);
+                            ids.push(id);
+                        }
+                    }
+                    for dec in decs[i..j].iter() {
+                        if let &ast::Dec::TypeDec { ref ty, .. } = dec.as_ref() {
+                            self.ty(ty, &tenv);
+                        }
+                    }
+                },
+                &ast::Dec::FunDec { .. } => {
+                    while j < decs.len() {
+                        if let &ast::Dec::FunDec { .. } = decs[j].as_ref() { j += 1 } else { break }
+                    }
+                    for dec in decs[i..j].iter() {
+                        if let &ast::Dec::FunDec { name, ref params, ref result, pos, .. } = dec.as_ref() {
+                            let detail = self.fun_detail(name, params, result);
+                            let span = self.ident(name, pos);
+                            let id = self.define(name, Kind::Function, span, detail, container);
+                            venv.insert(name, id);
+                            ids.push(id);
+                        }
+                    }
+                    for k in i..j {
+                        if let &ast::Dec::FunDec{ name, ref params, ref result, ref body, pos, .. } = decs[k].as_ref() {
+                            // the body goes on up to the next declaration
+                            let body_end = decs.get(k + 1).map(|dec| dec_pos(dec)).unwrap_or(in_pos);
+                            let inner = Some(self.name(name));
+                            let mut fvenv = venv.clone();
+                            let mut params_ids = vec![];
+                            for param in params.iter() {
+                                self.field_type(param, &tenv);
+                                let detail = format!("{}: {}", self.name(param.name), self.name(param.typ));
+                                let span = self.ident(param.name, param.pos);
+                                let id = self.define(param.name, Kind::Parameter, span, detail, &inner);
+                                fvenv.insert(param.name, id);
+                                params_ids.push(id);
+                            }
+                            if let &Some((typ, typ_pos)) = result {
+                                self.refer(&tenv, typ, typ_pos);
+                            }
+                            self.analysis.scopes.push(Scope { start: pos, end: body_end, definitions: params_ids });
+                            self.exp(body, &fvenv, &tenv, body_end, &inner);
+                        }
+                    }
+                },
+                &ast::Dec::VarDec { name, ref typ, ref init, pos, .. } => {
+                    self.exp(init, &venv, &tenv, end, container);
+                    if let &Some((typ, typ_pos)) = typ {
+                        self.refer(&tenv, typ, typ_pos);
+                    }
+                    let detail = match self.types.dec_ty(&decs[i]) {
+                        Some(ty) => format!("var {}: {}", self.name(name), ty.name(self.symbol_table)),
+                        None => format!("var {}", self.name(name)),
+                    };
+                    let span = self.ident(name, pos);
+                    let id = self.define(name, Kind::Variable, span, detail, container);
+                    venv.insert(name, id);
+                    ids.push(id);
+                    // not in scope in its own initializer
+                    start = decs.get(j).map(|dec| dec_pos(dec)).unwrap_or(in_pos);
+                },
+            }
+            self.analysis.scopes.push(Scope { start: start, end: end, definitions: ids });
+            i = j;
+        }
+        (venv, tenv)
+    }
+}
+
+impl Analysis {
+    fn new(text: &str, exp: &ast::Exp, types: &TypeMap, mut symbol_table: Box<SymbolTable>) -> Analysis {
+        let mut idents = vec![];
+        let mut lets = HashMap::new();
+        let mut open = vec![];
+        for (lo, token, hi) in Lexer::new(text, &mut symbol_table) {
+            match token {
+                Token::Ident(symbol) => idents.push((lo, symbol, hi)),
+                Token::Let => {
+                    open.push(lo);
+                    lets.insert(lo, (text.len(), text.len()));
+                },
+                Token::In => {
+                    if let Some(l) = open.last() {
+                        let bounds = lets.get_mut(l).unwrap();
+                        if bounds.0 == text.len() {
+                            bounds.0 = lo;
+                        }
+                    }
+                },
+                Token::End => {
+                    if let Some(l) = open.pop() {
+                        lets.get_mut(&l).unwrap().1 = hi;
+                    }
+                },
+                _ => (),
+            }
+        }
+        let builtins = base_venv(&mut symbol_table).into_entries();
+        let base_types = base_tenv(&mut symbol_table).into_entries();
+
+        let mut resolver = Resolver {
+            symbol_table: &symbol_table,
+            types: types,
+            idents: idents,
+            lets: lets,
+            analysis: Analysis { definitions: vec![], occurrences: vec![], scopes: vec![] },
+        };
+        let (mut venv, mut tenv) = (HashMap::new(), HashMap::new());
+        let mut ids = vec![];
+        for (symbol, entry) in builtins.into_iter() {
+            if let &EnvEntry::FunEntry { ref formals, ref result } = entry.as_ref() {
+                let formals: Vec<String> = formals.iter().map(|ty| ty.name(&symbol_table)).collect();
+                let detail = format!("function {}({}): {}", symbol_table.name(&symbol), formals.join(", "),
+                                     result.name(&symbol_table));
+                let id = resolver.define(symbol, Kind::Function, None, detail, &None);
+                venv.insert(symbol, id);
+                ids.push(id);
+            }
+        }
+        for (symbol, _) in base_types.into_iter() {
+            let id = resolver.define(symbol, Kind::Type, None, format!("type {}", symbol_table.name(&symbol)), &None);
+            tenv.insert(symbol, id);
+            ids.push(id);
+        }
+        resolver.analysis.scopes.push(Scope { start: 0, end: text.len(), definitions: ids });
+        resolver.exp(exp, &venv, &tenv, text.len(), &None);
+        resolver.analysis
+    }
+
+    fn occurrence_at(&self, offset: usize) -> Option<&Occurrence> {
+        self.occurrences.iter().find(|o| o.start <= offset && offset <= o.end)
+    }
+
+    // the declarations in scope at offset, the innermost of each name
+    fn visible(&self, offset: usize) -> Vec<&Definition> {
+        let mut scopes: Vec<&Scope> = self.scopes.iter().filter(|s| s.start <= offset && offset <= s.end).collect();
+        scopes.sort_by_key(|s| s.end - s.start);
+        let mut res: Vec<&Definition> = vec![];
+        for scope in scopes.into_iter() {
+            for &id in scope.definitions.iter() {
+                let definition = &self.definitions[id];
+                if !res.iter().any(|d| d.name == definition.name) {
+                    res.push(definition);
+                }
+            }
+        }
+        res
+    }
+}
+
+// a parsed and type checked document, and its first error with its offset
+fn analyze(text: &str) -> (Option<Analysis>, Option<(usize, String)>) {
+    let (exp, mut symbol_table) = match parser::parse(text) {
+        Ok(res) => res,
+        Err(e) => {
+            let message = parser::error_message(&e);
+            return (None, Some((error_offset(&message, text.len()), message)));
+        },
+    };
+    let (types, result) = type_check_partial(&exp, &mut symbol_table);
+    let error = result.err().map(|message| (error_offset(&message, text.len()), message));
+    (Some(Analysis::new(text, &exp, &types, symbol_table)), error)
+}
+
+// the offset that ends an error message as "at pos N"; the end otherwise
+fn error_offset(message: &str, len: usize) -> usize {
+    match message.rfind(" at pos ") {
+        Some(i) => message[i + 8..].trim().parse().unwrap_or(len).min(len),
+        None => len,
+    }
+}
+
+// the position of an offset, with columns in UTF-16 code units
+fn position(text: &str, offset: usize) -> Json {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line = before.matches('\n').count();
+    let character: usize = before[line_start..].chars().map(|c| c.len_utf16()).sum();
+    Json::object(vec![("line", Json::Number(line as f64)), ("character", Json::Number(character as f64))])
+}
+
+fn range(text: &str, start: usize, end: usize) -> Json {
+    Json::object(vec![("start", position(text, start)), ("end", position(text, end))])
+}
+
+// the offset of a position, which is kept within its line
+fn offset(text: &str, position: &Json) -> usize {
+    let line = position.get("line").as_i64().unwrap_or(0);
+    let character = position.get("character").as_i64().unwrap_or(0) as usize;
+    let mut start = 0;
+    for _ in 0..line {
+        match text[start..].find('\n') {
+            Some(i) => start += i + 1,
+            None => return text.len(),
+        }
+    }
+    let mut units = 0;
+    for (i, c) in text[start..].char_indices() {
+        if units >= character || c == '\n' {
+            return start + i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+struct Document {
+    text: String,
+    // of the last version that parsed
+    analysis: Option<Analysis>,
+}
+
+pub struct Server {
+    documents: HashMap<String, Document>,
+    shut_down: bool,
+}
+
+fn notification(method: &str, params: Json) -> Json {
+    Json::object(vec![("jsonrpc", Json::str("2.0")), ("method", Json::str(method)), ("params", params)])
+}
+
+fn location(uri: &str, text: &str, start: usize, end: usize) -> Json {
+    Json::object(vec![("uri", Json::str(uri)), ("range", range(text, start, end))])
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server {
+            documents: HashMap::new(),
+            shut_down: false,
+        }
+    }
+
+    // takes in a new version of a document, returning its diagnostics
+    fn update(&mut self, uri: &str, text: String) -> Json {
+        let (analysis, error) = analyze(&text);
+        let mut diagnostics = vec![];
+        if let Some((start, message)) = error {
+            let word = text[start..].find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(text.len() - start);
+            let end = (start + word.max(1)).min(text.len());
+            diagnostics.push(Json::object(vec![
+                ("range", range(&text, start, end)),
+                ("severity", Json::Number(1.0)),
+                ("source", Json::str("tiger")),
+                ("message", Json::String(message)),
+            ]));
+        }
+        let document = self.documents.entry(uri.to_owned()).or_insert(Document { text: String::new(), analysis: None });
+        document.text = text;
+        if analysis.is_some() {
+            document.analysis = analysis;
+        }
+        notification("textDocument/publishDiagnostics",
+                     Json::object(vec![("uri", Json::str(uri)), ("diagnostics", Json::Array(diagnostics))]))
+    }
+
+    // the document, its analysis and the offset a request is about
+    fn at<'a>(&'a self, params: &'a Json) -> Option<(&'a str, &'a Document, &'a Analysis, usize)> {
+        let uri = params.get("textDocument").get("uri").as_str()?;
+        let document = self.documents.get(uri)?;
+        let analysis = document.analysis.as_ref()?;
+        Some((uri, document, analysis, offset(&document.text, params.get("position"))))
+    }
+
+    fn hover(&self, params: &Json) -> Json {
+        let (_, document, analysis, offset) = match self.at(params) {
+            Some(at) => at,
+            None => return Json::Null,
+        };
+        match analysis.occurrence_at(offset) {
+            Some(occurrence) => Json::object(vec![
+                ("contents", Json::object(vec![
+                    ("kind", Json::str("markdown")),
+                    ("value", Json::String(format!("```tiger\n{}\n```", analysis.definitions[occurrence.definition].detail))),
+                ])),
+                ("range", range(&document.text, occurrence.start, occurrence.end)),
+            ]),
+            None => Json::Null,
+        }
+    }
+
+    fn definition(&self, params: &Json) -> Json {
+        let (uri, document, analysis, offset) = match self.at(params) {
+            Some(at) => at,
+            None => return Json::Null,
+        };
+        match analysis.occurrence_at(offset).and_then(|o| analysis.definitions[o.definition].span) {
+            Some((start, end)) => location(uri, &document.text, start, end),
+            None => Json::Null,
+        }
+    }
+
+    fn references(&self, params: &Json) -> Json {
+        let (uri, document, analysis, offset) = match self.at(params) {
+            Some(at) => at,
+            None => return Json::Null,
+        };
+        let definition = match analysis.occurrence_at(offset) {
+            Some(occurrence) => occurrence.definition,
+            None => return Json::Array(vec![]),
+        };
+        let declaration = analysis.definitions[definition].span;
+        let include_declaration = params.get("context").get("includeDeclaration").as_bool().unwrap_or(true);
+        let mut occurrences: Vec<&Occurrence> = analysis.occurrences.iter()
+            .filter(|o| o.definition == definition)
+            .filter(|o| include_declaration || Some((o.start, o.end)) != declaration)
+            .collect();
+        occurrences.sort_by_key(|o| o.start);
+        Json::Array(occurrences.into_iter().map(|o| location(uri, &document.text, o.start, o.end)).collect())
+    }
+
+    fn document_symbols(&self, params: &Json) -> Json {
+        let uri = params.get("textDocument").get("uri").as_str().unwrap_or("");
+        let (document, analysis) = match self.documents.get(uri) {
+            Some(&Document { ref text, analysis: Some(ref analysis) }) => (text, analysis),
+            _ => return Json::Null,
+        };
+        let mut symbols = vec![];
+        for definition in analysis.definitions.iter() {
+            let kind = match definition.kind {
+                Kind::Variable => 13,
+                Kind::Function => 12,
+                Kind::Type => 23,
+                Kind::LoopVariable | Kind::Parameter => continue,
+            };
+            if let Some((start, end)) = definition.span {
+                let mut members = vec![
+                    ("name", Json::str(&definition.name)),
+                    ("kind", Json::Number(kind as f64)),
+                    ("location", location(uri, document, start, end)),
+                ];
+                if let Some(ref container) = definition.container {
+                    members.push(("containerName", Json::str(container)));
+                }
+                symbols.push(Json::object(members));
+            }
+        }
+        Json::Array(symbols)
+    }
+
+    fn completion(&self, params: &Json) -> Json {
+        let (_, _, analysis, offset) = match self.at(params) {
+            Some(at) => at,
+            None => return Json::Array(vec![]),
+        };
+        let items = analysis.visible(offset).into_iter().map(|definition| {
+            let kind = match definition.kind {
+                Kind::Function => 3,
+                Kind::Type => 22,
+                _ => 6,
+            };
+            Json::object(vec![
+                ("label", Json::str(&definition.name)),
+                ("kind", Json::Number(kind as f64)),
+                ("detail", Json::str(&definition.detail)),
+            ])
+        }).collect();
+        Json::Array(items)
+    }
+
+    // handles a message from the client, returning the messages to send
+    // back, and the exit status once the client asks the server to exit
+    pub fn handle(&mut self, message: &Json) -> (Vec<Json>, Option<i32>) {
+        let method = message.get("method").as_str().unwrap_or("");
+        let params = message.get("params");
+        let id = message.get("id");
+        if id.is_null() {
+            let mut replies = vec![];
+            match method {
+                "textDocument/didOpen" => {
+                    let document = params.get("textDocument");
+                    let text = document.get("text").as_str().unwrap_or("").to_owned();
+                    replies.push(self.update(document.get("uri").as_str().unwrap_or(""), text));
+                },
+                "textDocument/didChange" => {
+                    let changes = params.get("contentChanges").as_array().cloned().unwrap_or(vec![]);
+                    if let Some(text) = changes.last().and_then(|change| change.get("text").as_str()) {
+                        replies.push(self.update(params.get("textDocument").get("uri").as_str().unwrap_or(""),
+                                                 text.to_owned()));
+                    }
+                },
+                "textDocument/didClose" => {
+                    let uri = params.get("textDocument").get("uri").as_str().unwrap_or("");
+                    self.documents.remove(uri);
+                    replies.push(notification("textDocument/publishDiagnostics",
+                                              Json::object(vec![("uri", Json::str(uri)),
+                                                                ("diagnostics", Json::Array(vec![]))])));
+                },
+                "exit" => return (replies, Some(if self.shut_down { 0 } else { 1 })),
+                _ => (),
+            }
+            return (replies, None);
+        }
+        if method.is_empty() {
+            // a response, to a request the server never makes
+            return (vec![], None);
+        }
+
+        let result = match method {
+            "initialize" => Ok(Json::object(vec![
+                ("capabilities", Json::object(vec![
+                    ("textDocumentSync", Json::Number(1.0)),
+                    ("hoverProvider", Json::Bool(true)),
+                    ("definitionProvider", Json::Bool(true)),
+                    ("referencesProvider", Json::Bool(true)),
+                    ("documentSymbolProvider", Json::Bool(true)),
+                    ("completionProvider", Json::object(vec![])),
+                ])),
+                ("serverInfo", Json::object(vec![("name", Json::str("tiger"))])),
+            ])),
+            "shutdown" => {
+                self.shut_down = true;
+                Ok(Json::Null)
+            },
+            "textDocument/hover" => Ok(self.hover(params)),
+            "textDocument/definition" => Ok(self.definition(params)),
+            "textDocument/references" => Ok(self.references(params)),
+            "textDocument/documentSymbol" => Ok(self.document_symbols(params)),
+            "textDocument/completion" => Ok(self.completion(params)),
+            _ => Err((-32601, format!("unknown method {}", method))),
+        };
+        let reply = match result {
+            Ok(result) => Json::object(vec![("jsonrpc", Json::str("2.0")), ("id", id.clone()), ("result", result)]),
+            Err((code, message)) => Json::object(vec![
+                ("jsonrpc", Json::str("2.0")),
+                ("id", id.clone()),
+                ("error", Json::object(vec![("code", Json::Number(code as f64)), ("message", Json::String(message))])),
+            ]),
+        };
+        (vec![reply], None)
+    }
+}
+
+// a message with its header, or None at the end of the input
+fn read_message<R: BufRead>(input: &mut R) -> Result<Option<Json>, String> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line).map_err(|e| e.to_string())? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let lower = line.to_lowercase();
+        if lower.starts_with("content-length:") {
+            length = line["content-length:".len()..].trim().parse::<usize>().ok();
+        }
+    }
+    let length = length.ok_or_else(|| String::from("no Content-Length header"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body).map_err(|e| e.to_string())?;
+    let body = String::from_utf8(body).map_err(|e| e.to_string())?;
+    Json::parse(&body).map(Some)
+}
+
+fn write_message(output: &mut dyn Write, message: &Json) {
+    let body = message.to_string();
+    let _ = write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+    let _ = output.flush();
+}
+
+// Serves a client until it asks to exit, returning the exit status: 0 if
+// it asked to shut down first, 1 otherwise or if the input ends.
+pub fn serve<R: BufRead>(input: &mut R, output: &mut dyn Write) -> i32 {
+    let mut server = Server::new();
+    loop {
+        let message = match read_message(input) {
+            Ok(Some(message)) => message,
+            Ok(None) => return 1,
+            Err(e) => {
+                write_message(output, &Json::object(vec![
+                    ("jsonrpc", Json::str("2.0")),
+                    ("id", Json::Null),
+                    ("error", Json::object(vec![("code", Json::Number(-32700.0)), ("message", Json::String(e))])),
+                ]));
+                continue;
+            },
+        };
+        let (replies, status) = server.handle(&message);
+        for reply in replies.iter() {
+            write_message(output, reply);
+        }
+        if let Some(status) = status {
+            return status;
+        }
+    }
+}
+
+#[test]
+fn test_lsp() {
+    let uri = "file:///t.tig";
+    let source = "let type point = {x: int, y: int}\n\
+                  \x20   var origin := point {x = 0, y = 0}\n\
+                  \x20   function norm(p: point): int = p.x + p.y\n\
+                  in norm(origin) + norm(point {x = 1, y = 2}) end";
+    let requests = vec![
+        Json::object(vec![("id", Json::Number(1.0)), ("method", Json::str("initialize")), ("params", Json::object(vec![]))]),
+        Json::object(vec![("method", Json::str("initialized")), ("params", Json::object(vec![]))]),
+        Json::object(vec![("method", Json::str("textDocument/didOpen")), ("params", Json::object(vec![
+            ("textDocument", Json::object(vec![("uri", Json::str(uri)), ("text", Json::str(source))])),
+        ]))]),
+    ];
+    let at = |id: f64, method: &str, line: f64, character: f64| Json::object(vec![
+        ("id", Json::Number(id)),
+        ("method", Json::str(method)),
+        ("params", Json::object(vec![
+            ("textDocument", Json::object(vec![("uri", Json::str(uri))])),
+            ("position", Json::object(vec![("line", Json::Number(line)), ("character", Json::Number(character))])),
+        ])),
+    ]);
+    let mut requests = requests;
+    // origin in the body, norm in its declaration, p in the body of norm
+    requests.push(at(2.0, "textDocument/hover", 3.0, 9.0));
+    requests.push(at(3.0, "textDocument/definition", 3.0, 10.0));
+    requests.push(at(4.0, "textDocument/references", 2.0, 14.0));
+    requests.push(at(5.0, "textDocument/documentSymbol", 0.0, 0.0));
+    requests.push(at(6.0, "textDocument/completion", 2.0, 36.0));
+    requests.push(Json::object(vec![("method", Json::str("textDocument/didChange")), ("params", Json::object(vec![
+        ("textDocument", Json::object(vec![("uri", Json::str(uri))])),
+        ("contentChanges", Json::Array(vec![Json::object(vec![("text", Json::str("let var a := 1 in a + \"b\" end"))])])),
+    ]))]));
+    // a string being typed, which does not stop the server
+    requests.push(Json::object(vec![("method", Json::str("textDocument/didChange")), ("params", Json::object(vec![
+        ("textDocument", Json::object(vec![("uri", Json::str(uri))])),
+        ("contentChanges", Json::Array(vec![Json::object(vec![("text", Json::str("print(\"ab"))])])),
+    ]))]));
+    requests.push(Json::object(vec![("id", Json::Number(7.0)), ("method", Json::str("shutdown"))]));
+    requests.push(Json::object(vec![("method", Json::str("exit"))]));
+
+    let mut input = vec![];
+    for request in requests.iter() {
+        write_message(&mut input, request);
+    }
+    let mut output = vec![];
+    assert_eq!(serve(&mut &input[..], &mut output), 0);
+    let mut output = &output[..];
+    let mut replies = vec![];
+    while let Some(reply) = read_message(&mut output).unwrap() {
+        replies.push(reply);
+    }
+    assert_eq!(replies.len(), 10);
+
+    assert_eq!(replies[0].get("result").get("capabilities").get("hoverProvider"), &Json::Bool(true));
+    assert_eq!(replies[1].get("params").get("diagnostics"), &Json::Array(vec![]));
+    assert_eq!(replies[2].get("result").get("contents").get("value").as_str(),
+               Some("```tiger\nvar origin: {x: int, y: int}\n```"));
+    assert_eq!(replies[3].get("result").get("range").to_string(),
+               "{\"start\":{\"line\":1,\"character\":8},\"end\":{\"line\":1,\"character\":14}}");
+    let references = replies[4].get("result").as_array().unwrap();
+    let lines: Vec<i64> = references.iter().map(|r| r.get("range").get("start").get("line").as_i64().unwrap()).collect();
+    assert_eq!(lines, vec![2, 3, 3]);
+    let symbols: Vec<&str> = replies[5].get("result").as_array().unwrap().iter()
+        .map(|s| s.get("name").as_str().unwrap()).collect();
+    assert_eq!(symbols, vec!["point", "origin", "norm"]);
+    let completions: Vec<&str> = replies[6].get("result").as_array().unwrap().iter()
+        .map(|c| c.get("label").as_str().unwrap()).collect();
+    assert_eq!(&completions[..4], &["p", "origin", "norm", "point"]);
+    assert_eq!(replies[6].get("result").as_array().unwrap()[2].get("detail").as_str(),
+               Some("function norm(p: point): int"));
+    assert!(completions.contains(&"print") && completions.contains(&"int"));
+    let diagnostics = replies[7].get("params").get("diagnostics").as_array().unwrap();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].get("range").get("start").get("character").as_i64(), Some(18));
+    let diagnostics = replies[8].get("params").get("diagnostics").as_array().unwrap();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].get("message").as_str(), Some("unterminated string at pos 6"));
+    assert_eq!(diagnostics[0].get("range").get("start").get("character").as_i64(), Some(6));
+    assert_eq!(replies[9].get("id").as_i64(), Some(7));
+}
@@ -9,43 +9,53 @@ pub mod types;
 pub mod type_check;
 pub mod parser;
 pub mod dot;
+pub mod temp;
+pub mod frame;
+pub mod ir;
+pub mod escape;
+pub mod translate;
+pub mod canon;
+pub mod ir_interp;
+pub mod interp;
+pub mod bytecode;
+pub mod vm;
+pub mod assem;
+pub mod flow;
+pub mod liveness;
+pub mod regalloc;
+pub mod linear_scan;
+pub mod gc;
+pub mod codegen;
+pub mod x86_64;
+pub mod riscv64;
+pub mod mips;
+pub mod aarch64;
+pub mod c_backend;
+pub mod wasm;
+pub mod driver;
+pub mod repl;
+pub mod json;
+pub mod lsp;
+pub mod fmt;
+pub mod serialize;
+pub mod cli;
+#[cfg(test)]
+mod appel;
 
 extern crate lalrpop_util;
+#[cfg(test)]
+extern crate wasmi;
+#[cfg(test)]
+extern crate wat;
 
-use parser::parse;
-
-use std::io::Write;
+use std::env;
+use std::io;
+use std::process;
 
 fn main() {
-    let _ = parse("nil");
-    let _ = parse("123123");;
-    let _ = parse(r#""foobar""#);
-    let _ = parse(r#"int[5] of 2"#);
-    let _ = parse(r#"foobar { i = 4, j = 2 }"#);
-    let _ = parse(r#"foobar {  }"#);
-    let _ = parse(r#"foobar"#);
-    let _ = parse(r#"foobar[2]"#);
-    let _ = parse(r#"foobar[2].foobar"#);
-    let _ = parse(r#"foobar[2][3]"#);
-    let _ = parse(r#"foobar.blabla"#);
-    let _ = parse(r#"foobar.blabla[2]"#);
-    let _ = parse(r#"foobar.blabla[2] of 4"#);
-    let _ = parse(r#"foobar.blabla.blip.blip"#);
-    let _ = parse(r#"foobar.blabla.blip.blip[foobar]"#);
-    let _ = parse(r#"foobar("bla", angry.foobar, foo[0])"#);
-    let _ = parse(r#"foo[34] of 34 + 36 + 89 * 89"#);
-    let _ = parse(r#"if foobar.symbol then 23"#);
-
-    let (ast, table) = parse(r#"if foobar.symbol then 23"#).unwrap();
-    let mut stdout = std::io::stdout();
-
-    writeln!(stdout, "{}", "digraph G {");
-    dot::render_ast(&mut stdout, &ast, &table);
-    writeln!(stdout, "}}");
-
-
-//    let lex = lexer::Lexer::new("function foobar() { 123; }");
-//    for l in lex {
-//        println!("{:?}", l);
-//    }
+    let args: Vec<String> = env::args().skip(1).collect();
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let status = cli::main(&args, &mut stdin.lock(), &mut stdout.lock(), &mut io::stderr());
+    process::exit(status);
 }
@@ -0,0 +1,856 @@
+use assem::Instr;
+use codegen::Codegen;
+use frame::{Access, Frame, shift_view};
+use gc::{PointerMap, pointer_map_table};
+use ir::{Exp, Stm, BinOp, RelOp};
+use symbol::SymbolTable;
+use temp::{Temp, Label, TempGenerator};
+
+// The MIPS back end of Appel's book, emitting assembly for the SPIM
+// simulator: $a0-$a3 for the first arguments, the rest on the stack, $fp as
+// the frame pointer. Temp(i) is register $i. Tiger integers are 32 bits, as
+// the machine words.
+//
+// SPIM has no linker, so every file carries its own runtime system, written
+// against the SPIM syscalls under the names the book's runtime uses.
+
+pub const ZERO: Temp = Temp(0);
+pub const V0: Temp = Temp(2);
+pub const V1: Temp = Temp(3);
+pub const T7: Temp = Temp(15);
+pub const T8: Temp = Temp(24);
+pub const T9: Temp = Temp(25);
+pub const SP: Temp = Temp(29);
+pub const FP: Temp = Temp(30);
+pub const RA: Temp = Temp(31);
+
+const REGISTER_NAMES: [&'static str; 32] = [
+    "$zero", "$at", "$v0", "$v1", "$a0", "$a1", "$a2", "$a3", "$t0", "$t1", "$t2", "$t3", "$t4", "$t5", "$t6",
+    "$t7", "$s0", "$s1", "$s2", "$s3", "$s4", "$s5", "$s6", "$s7", "$t8", "$t9", "$k0", "$k1", "$gp", "$sp",
+    "$fp", "$ra"];
+
+pub const ARG_REGISTERS: [Temp; 4] = [Temp(4), Temp(5), Temp(6), Temp(7)];
+pub const CALLEE_SAVES: [Temp; 8] = [Temp(16), Temp(17), Temp(18), Temp(19), Temp(20), Temp(21), Temp(22),
+                                     Temp(23)];
+pub const CALLER_SAVES: [Temp; 17] = [RA, V0, V1, Temp(4), Temp(5), Temp(6), Temp(7), Temp(8), Temp(9),
+                                      Temp(10), Temp(11), Temp(12), Temp(13), Temp(14), T7, T8, T9];
+
+const WORD: i32 = 4;
+
+// the return address and the caller's frame pointer are saved just below
+// the frame pointer
+const SAVED_REGISTERS_SIZE: i32 = 2 * WORD;
+
+fn fits_imm16(i: i32) -> bool {
+    i >= -32768 && i <= 32767
+}
+
+// the immediate of andi, ori and xori is zero-extended
+fn fits_uimm16(i: i32) -> bool {
+    i >= 0 && i <= 65535
+}
+
+#[derive(Clone)]
+pub struct MipsFrame {
+    name: Label,
+    formals: Vec<Access>,
+    locals: i32,
+}
+
+impl MipsFrame {
+    // the whole frame, keeping $sp 8-byte aligned
+    pub fn frame_size(&self) -> i32 {
+        (SAVED_REGISTERS_SIZE + self.locals * WORD + 7) / 8 * 8
+    }
+}
+
+// arguments after the fourth are where the caller's stack pointer was
+fn stack_arg_offset(i: usize) -> i32 {
+    (i - ARG_REGISTERS.len()) as i32 * WORD
+}
+
+impl Frame for MipsFrame {
+    fn new_frame(name: Label, formals_escape: Vec<bool>, gen: &mut TempGenerator) -> MipsFrame {
+        let mut frame = MipsFrame { name: name, formals: vec![], locals: 0 };
+        for (i, &escape) in formals_escape.iter().enumerate() {
+            let access = if escape && i >= ARG_REGISTERS.len() {
+                Access::InFrame(stack_arg_offset(i))
+            } else {
+                frame.alloc_local(escape, gen)
+            };
+            frame.formals.push(access);
+        }
+        frame
+    }
+
+    fn name(&self) -> Label {
+        self.name
+    }
+
+    fn formals(&self) -> &[Access] {
+        &self.formals
+    }
+
+    fn alloc_local(&mut self, escape: bool, gen: &mut TempGenerator) -> Access {
+        if escape {
+            self.locals += 1;
+            Access::InFrame(-SAVED_REGISTERS_SIZE - self.locals * WORD)
+        } else {
+            Access::InReg(gen.new_temp())
+        }
+    }
+
+    fn fp() -> Temp {
+        FP
+    }
+
+    fn rv() -> Temp {
+        V0
+    }
+
+    fn word_size() -> i32 {
+        WORD
+    }
+
+    fn proc_entry_exit1(&self, body: Stm, gen: &mut TempGenerator) -> Stm {
+        shift_view(self, body, &ARG_REGISTERS, &|i| Access::InFrame(stack_arg_offset(i)), &CALLEE_SAVES, gen)
+    }
+}
+
+pub struct MipsCodegen;
+
+// every jump and branch is followed by a nop in its delay slot, so that the
+// code runs the same whether SPIM simulates delay slots (-delayed_branches)
+// or not
+const DELAY_SLOT: &'static str = "\n\tnop";
+
+// branches other than beq and bne are SPIM pseudo-instructions
+fn branch(op: RelOp) -> &'static str {
+    match op {
+        RelOp::Eq => "beq",
+        RelOp::Ne => "bne",
+        RelOp::Lt => "blt",
+        RelOp::Gt => "bgt",
+        RelOp::Le => "ble",
+        RelOp::Ge => "bge",
+        RelOp::ULt => "bltu",
+        RelOp::UGt => "bgtu",
+        RelOp::ULe => "bleu",
+        RelOp::UGe => "bgeu",
+    }
+}
+
+struct Muncher<'a> {
+    gen: &'a mut TempGenerator,
+    symbol_table: &'a SymbolTable,
+    instrs: Vec<Instr>,
+}
+
+impl<'a> Muncher<'a> {
+    fn emit(&mut self, assem: String, dst: Vec<Temp>, src: Vec<Temp>) {
+        self.instrs.push(Instr::oper(assem, dst, src));
+    }
+
+    fn emit_move(&mut self, dst: Temp, src: Temp) {
+        self.instrs.push(Instr::Move { assem: String::from("move `d0, `s0"), dst: dst, src: src });
+    }
+
+    fn label_name(&self, l: Label) -> String {
+        l.name(self.symbol_table).clone()
+    }
+
+    // the base temp and offset of a memory operand at address e
+    fn munch_address(&mut self, e: &Exp) -> (Temp, i32) {
+        match e {
+            &Exp::BinOp(BinOp::Plus, ref a, ref b) => match (a.as_ref(), b.as_ref()) {
+                (a, &Exp::Const(i)) | (&Exp::Const(i), a) if fits_imm16(i) => (self.munch_exp(a), i),
+                _ => (self.munch_exp(e), 0),
+            },
+            &Exp::BinOp(BinOp::Minus, ref a, ref b) => match b.as_ref() {
+                &Exp::Const(i) if i != i32::min_value() && fits_imm16(-i) => (self.munch_exp(a), -i),
+                _ => (self.munch_exp(e), 0),
+            },
+            e => (self.munch_exp(e), 0),
+        }
+    }
+
+    // a register holding the value of e, using $zero for 0
+    fn munch_operand(&mut self, e: &Exp) -> Temp {
+        match e {
+            &Exp::Const(0) => ZERO,
+            e => self.munch_exp(e),
+        }
+    }
+
+    fn munch_stm(&mut self, stm: &Stm) {
+        match stm {
+            &Stm::Move(ref dst, ref src) => match (dst.as_ref(), src.as_ref()) {
+                (&Exp::Mem(ref address), src) => {
+                    let (base, offset) = self.munch_address(address);
+                    let src = self.munch_operand(src);
+                    self.emit(format!("sw `s0, {}(`s1)", offset), vec![], vec![src, base]);
+                },
+                (&Exp::Temp(t), &Exp::Const(i)) => self.emit(format!("li `d0, {}", i), vec![t], vec![]),
+                (&Exp::Temp(t), &Exp::Mem(ref address)) => {
+                    let (base, offset) = self.munch_address(address);
+                    self.emit(format!("lw `d0, {}(`s0)", offset), vec![t], vec![base]);
+                },
+                (&Exp::Temp(t), src) => {
+                    let src = self.munch_exp(src);
+                    self.emit_move(t, src);
+                },
+                _ => panic!("cannot move to {:?}", dst),
+            },
+            &Stm::Exp(ref e) => match e.as_ref() {
+                &Exp::Call(ref f, ref args) => self.munch_call(f, args),
+                e => {
+                    self.munch_exp(e);
+                },
+            },
+            &Stm::Jump(ref e, ref labels) => match e.as_ref() {
+                &Exp::Name(l) => {
+                    self.instrs.push(Instr::Oper {
+                        assem: format!("j `j0{}", DELAY_SLOT),
+                        dst: vec![],
+                        src: vec![],
+                        jump: Some(vec![l]),
+                    });
+                },
+                e => {
+                    let t = self.munch_exp(e);
+                    self.instrs.push(Instr::Oper {
+                        assem: format!("jr `s0{}", DELAY_SLOT),
+                        dst: vec![],
+                        src: vec![t],
+                        jump: Some(labels.clone()),
+                    });
+                },
+            },
+            &Stm::CJump(op, ref a, ref b, t, f) => {
+                let a = self.munch_operand(a);
+                let b = self.munch_operand(b);
+                self.instrs.push(Instr::Oper {
+                    assem: format!("{} `s0, `s1, `j0{}", branch(op), DELAY_SLOT),
+                    dst: vec![],
+                    src: vec![a, b],
+                    jump: Some(vec![t, f]),
+                });
+            },
+            &Stm::Label(l) => {
+                let assem = format!("{}:", self.label_name(l));
+                self.instrs.push(Instr::Label { assem: assem, label: l });
+            },
+            &Stm::Seq(_, _) => panic!("SEQ in canonical IR"),
+        }
+    }
+
+    fn munch_call(&mut self, f: &Exp, args: &[Exp]) {
+        let name = match f {
+            &Exp::Name(l) => self.label_name(l),
+            f => panic!("cannot call {:?}", f),
+        };
+
+        let temps: Vec<Temp> = args.iter().map(|a| self.munch_exp(a)).collect();
+        let in_registers = temps.len().min(ARG_REGISTERS.len());
+        // arguments after the fourth go to the bottom of the stack, which
+        // stays 8-byte aligned
+        let stack_size = ((temps.len() - in_registers) as i32 * WORD + 7) / 8 * 8;
+        if stack_size > 0 {
+            self.emit(format!("addiu `d0, `s0, {}", -stack_size), vec![SP], vec![SP]);
+        }
+        for (i, &t) in temps[in_registers..].iter().enumerate() {
+            self.emit(format!("sw `s0, {}(`s1)", i as i32 * WORD), vec![], vec![t, SP]);
+        }
+        for (&t, &r) in temps.iter().zip(ARG_REGISTERS.iter()) {
+            self.emit_move(r, t);
+        }
+
+        self.emit(format!("jal {}{}", name, DELAY_SLOT), CALLER_SAVES.to_vec(), ARG_REGISTERS[..in_registers].to_vec());
+        if stack_size > 0 {
+            self.emit(format!("addiu `d0, `s0, {}", stack_size), vec![SP], vec![SP]);
+        }
+    }
+
+    fn munch_exp(&mut self, exp: &Exp) -> Temp {
+        match exp {
+            &Exp::Const(i) => {
+                let r = self.gen.new_temp();
+                self.emit(format!("li `d0, {}", i), vec![r], vec![]);
+                r
+            },
+            &Exp::Temp(t) => t,
+            &Exp::Name(l) => {
+                let r = self.gen.new_temp();
+                let name = self.label_name(l);
+                self.emit(format!("la `d0, {}", name), vec![r], vec![]);
+                r
+            },
+            &Exp::Mem(ref address) => {
+                let r = self.gen.new_temp();
+                let (base, offset) = self.munch_address(address);
+                self.emit(format!("lw `d0, {}(`s0)", offset), vec![r], vec![base]);
+                r
+            },
+            &Exp::BinOp(BinOp::Div, ref a, ref b) => {
+                // the quotient goes to the LO register
+                let a = self.munch_operand(a);
+                let b = self.munch_operand(b);
+                let r = self.gen.new_temp();
+                self.emit(String::from("div `s0, `s1"), vec![], vec![a, b]);
+                self.emit(String::from("mflo `d0"), vec![r], vec![]);
+                r
+            },
+            &Exp::BinOp(op, ref a, ref b) => {
+                let mnemonic = match op {
+                    BinOp::Plus => "addu",
+                    BinOp::Minus => "subu",
+                    BinOp::Mul => "mul",
+                    BinOp::And => "and",
+                    BinOp::Or => "or",
+                    BinOp::Xor => "xor",
+                    BinOp::LShift => "sllv",
+                    BinOp::RShift => "srlv",
+                    BinOp::ARShift => "srav",
+                    BinOp::Div => unreachable!(),
+                };
+                // the instruction with an immediate operand, and the
+                // immediate it needs for b
+                let immediate = |i: i32| match op {
+                    BinOp::Plus if fits_imm16(i) => Some(("addiu", i)),
+                    BinOp::Minus if i != i32::min_value() && fits_imm16(-i) => Some(("addiu", -i)),
+                    BinOp::And if fits_uimm16(i) => Some(("andi", i)),
+                    BinOp::Or if fits_uimm16(i) => Some(("ori", i)),
+                    BinOp::Xor if fits_uimm16(i) => Some(("xori", i)),
+                    BinOp::LShift if i >= 0 && i < 32 => Some(("sll", i)),
+                    BinOp::RShift if i >= 0 && i < 32 => Some(("srl", i)),
+                    BinOp::ARShift if i >= 0 && i < 32 => Some(("sra", i)),
+                    _ => None,
+                };
+                let commutes = match op {
+                    BinOp::Plus | BinOp::And | BinOp::Or | BinOp::Xor => true,
+                    _ => false,
+                };
+
+                let with_immediate = match (a.as_ref(), b.as_ref()) {
+                    (a, &Exp::Const(i)) => immediate(i).map(|imm| (a, imm)),
+                    (&Exp::Const(i), b) if commutes => immediate(i).map(|imm| (b, imm)),
+                    _ => None,
+                };
+                let r = self.gen.new_temp();
+                match with_immediate {
+                    Some((a, (mnemonic, i))) => {
+                        let a = self.munch_operand(a);
+                        self.emit(format!("{} `d0, `s0, {}", mnemonic, i), vec![r], vec![a]);
+                    },
+                    None => {
+                        let a = self.munch_operand(a);
+                        let b = self.munch_operand(b);
+                        self.emit(format!("{} `d0, `s0, `s1", mnemonic), vec![r], vec![a, b]);
+                    },
+                }
+                r
+            },
+            &Exp::Call(ref f, ref args) => {
+                self.munch_call(f, args);
+                let r = self.gen.new_temp();
+                self.emit_move(r, V0);
+                r
+            },
+            &Exp::ESeq(_, _) => panic!("ESEQ in canonical IR"),
+        }
+    }
+}
+
+// The runtime system. Strings are a length word followed by the
+// characters, arrays a size word followed by the elements; the heap grows
+// with sbrk and is never freed, so the layouts of records and arrays and
+// the pointer maps meant for a garbage collector go unused. Labels
+// starting with an underscore cannot clash with those of Tiger programs.
+const RUNTIME_TEXT: &'static str = "\
+main:
+\taddiu $sp, $sp, -8
+\tsw $ra, 4($sp)
+\tjal tigermain
+\tnop
+\tli $v0, 10
+\tsyscall
+
+_alloc:
+\taddiu $a0, $a0, 3
+\tli $t9, -4
+\tand $a0, $a0, $t9
+\tli $v0, 9
+\tsyscall
+\tjr $ra
+\tnop
+
+_fail:
+\tli $v0, 4
+\tsyscall
+\tli $a0, 1
+\tli $v0, 17
+\tsyscall
+
+allocRecord:
+\tj _alloc
+\tnop
+
+initArray:
+\tbltz $a0, _initArray_error
+\tnop
+\tmove $t0, $a0
+\tmove $t1, $ra
+\taddiu $a0, $a0, 1
+\tsll $a0, $a0, 2
+\tjal _alloc
+\tnop
+\tmove $ra, $t1
+\tsw $t0, 0($v0)
+\taddiu $t1, $v0, 4
+_initArray_loop:
+\tbeqz $t0, _initArray_done
+\tnop
+\tsw $a1, 0($t1)
+\taddiu $t1, $t1, 4
+\taddiu $t0, $t0, -1
+\tj _initArray_loop
+\tnop
+_initArray_done:
+\tjr $ra
+\tnop
+_initArray_error:
+\tla $a0, _initArray_message
+\tj _fail
+\tnop
+
+stringEqual:
+\tlw $t0, 0($a0)
+\tlw $t1, 0($a1)
+\tli $v0, 0
+\tbne $t0, $t1, _stringEqual_done
+\tnop
+_stringEqual_loop:
+\tbeqz $t0, _stringEqual_true
+\tnop
+\tlbu $t2, 4($a0)
+\tlbu $t3, 4($a1)
+\tbne $t2, $t3, _stringEqual_done
+\tnop
+\taddiu $a0, $a0, 1
+\taddiu $a1, $a1, 1
+\taddiu $t0, $t0, -1
+\tj _stringEqual_loop
+\tnop
+_stringEqual_true:
+\tli $v0, 1
+_stringEqual_done:
+\tjr $ra
+\tnop
+
+stringCompare:
+\tlw $t0, 0($a0)
+\tlw $t1, 0($a1)
+_stringCompare_loop:
+\tbeqz $t0, _stringCompare_end
+\tnop
+\tbeqz $t1, _stringCompare_greater
+\tnop
+\tlbu $t2, 4($a0)
+\tlbu $t3, 4($a1)
+\tbltu $t2, $t3, _stringCompare_less
+\tnop
+\tbgtu $t2, $t3, _stringCompare_greater
+\tnop
+\taddiu $a0, $a0, 1
+\taddiu $a1, $a1, 1
+\taddiu $t0, $t0, -1
+\taddiu $t1, $t1, -1
+\tj _stringCompare_loop
+\tnop
+_stringCompare_end:
+\tli $v0, 0
+\tbeqz $t1, _stringCompare_done
+\tnop
+_stringCompare_less:
+\tli $v0, -1
+\tjr $ra
+\tnop
+_stringCompare_greater:
+\tli $v0, 1
+_stringCompare_done:
+\tjr $ra
+\tnop
+
+print:
+\tlw $t0, 0($a0)
+\taddiu $t1, $a0, 4
+_print_loop:
+\tbeqz $t0, _print_done
+\tnop
+\tlbu $a0, 0($t1)
+\tli $v0, 11
+\tsyscall
+\taddiu $t1, $t1, 1
+\taddiu $t0, $t0, -1
+\tj _print_loop
+\tnop
+_print_done:
+\tjr $ra
+\tnop
+
+printi:
+\tli $v0, 1
+\tsyscall
+\tjr $ra
+\tnop
+
+flush:
+\tjr $ra
+\tnop
+
+getchar:
+\tli $v0, 12
+\tsyscall
+\tmove $a0, $v0
+\tbgez $a0, chr
+\tnop
+\tla $v0, _empty
+\tjr $ra
+\tnop
+
+ord:
+\tlw $t0, 0($a0)
+\tli $v0, -1
+\tbeqz $t0, _ord_done
+\tnop
+\tlbu $v0, 4($a0)
+_ord_done:
+\tjr $ra
+\tnop
+
+chr:
+\tbltz $a0, _chr_error
+\tnop
+\tbgt $a0, 255, _chr_error
+\tnop
+\tmove $t0, $a0
+\tmove $t1, $ra
+\tli $a0, 5
+\tjal _alloc
+\tnop
+\tmove $ra, $t1
+\tli $t1, 1
+\tsw $t1, 0($v0)
+\tsb $t0, 4($v0)
+\tjr $ra
+\tnop
+_chr_error:
+\tla $a0, _chr_message
+\tj _fail
+\tnop
+
+size:
+\tlw $v0, 0($a0)
+\tjr $ra
+\tnop
+
+substring:
+\tlw $t0, 0($a0)
+\tbltz $a1, _substring_error
+\tnop
+\tbltz $a2, _substring_error
+\tnop
+\taddu $t1, $a1, $a2
+\tbgt $t1, $t0, _substring_error
+\tnop
+\taddu $t2, $a0, $a1
+\tmove $t1, $ra
+\taddiu $a0, $a2, 4
+\tjal _alloc
+\tnop
+\tmove $ra, $t1
+\tsw $a2, 0($v0)
+\tmove $t3, $v0
+_substring_loop:
+\tbeqz $a2, _substring_done
+\tnop
+\tlbu $t4, 4($t2)
+\tsb $t4, 4($t3)
+\taddiu $t2, $t2, 1
+\taddiu $t3, $t3, 1
+\taddiu $a2, $a2, -1
+\tj _substring_loop
+\tnop
+_substring_done:
+\tjr $ra
+\tnop
+_substring_error:
+\tla $a0, _substring_message
+\tj _fail
+\tnop
+
+concat:
+\tlw $t0, 0($a0)
+\tlw $t1, 0($a1)
+\tmove $t2, $a0
+\tmove $t4, $ra
+\taddu $a0, $t0, $t1
+\taddiu $a0, $a0, 4
+\tjal _alloc
+\tnop
+\tmove $ra, $t4
+\taddu $t3, $t0, $t1
+\tsw $t3, 0($v0)
+\tmove $t3, $v0
+_concat_first:
+\tbeqz $t0, _concat_second
+\tnop
+\tlbu $t4, 4($t2)
+\tsb $t4, 4($t3)
+\taddiu $t2, $t2, 1
+\taddiu $t3, $t3, 1
+\taddiu $t0, $t0, -1
+\tj _concat_first
+\tnop
+_concat_second:
+\tbeqz $t1, _concat_done
+\tnop
+\tlbu $t4, 4($a1)
+\tsb $t4, 4($t3)
+\taddiu $a1, $a1, 1
+\taddiu $t3, $t3, 1
+\taddiu $t1, $t1, -1
+\tj _concat_second
+\tnop
+_concat_done:
+\tjr $ra
+\tnop
+
+not:
+\tsltiu $v0, $a0, 1
+\tjr $ra
+\tnop
+
+exit:
+\tli $v0, 17
+\tsyscall
+
+nilError:
+\tmove $t0, $a0
+\tla $a0, _nilError_message
+\tli $v0, 4
+\tsyscall
+\tj _position
+\tnop
+
+boundsError:
+\tmove $t0, $a0
+\tla $a0, _boundsError_message
+\tli $v0, 4
+\tsyscall
+\tmove $a0, $a1
+\tli $v0, 1
+\tsyscall
+\tla $a0, _outOfBounds_message
+\tli $v0, 4
+\tsyscall
+_position:
+\tmove $a0, $t0
+\tli $v0, 1
+\tsyscall
+\tla $a0, _newline
+\tj _fail
+\tnop
+
+";
+
+const RUNTIME_DATA: &'static str = "\
+_empty:
+\t.word 0
+_newline:
+\t.asciiz \"\\n\"
+_initArray_message:
+\t.asciiz \"negative array size\\n\"
+_chr_message:
+\t.asciiz \"chr out of range\\n\"
+_substring_message:
+\t.asciiz \"substring out of range\\n\"
+_nilError_message:
+\t.asciiz \"nil record dereference at position \"
+_boundsError_message:
+\t.asciiz \"index \"
+_outOfBounds_message:
+\t.asciiz \" out of bounds at position \"
+";
+
+impl Codegen for MipsCodegen {
+    type Frame = MipsFrame;
+
+    fn codegen(&self, _: &MipsFrame, stm: Stm, gen: &mut TempGenerator, symbol_table: &SymbolTable) -> Vec<Instr> {
+        let mut muncher = Muncher {
+            gen: gen,
+            symbol_table: symbol_table,
+            instrs: vec![],
+        };
+        muncher.munch_stm(&stm);
+        muncher.instrs
+    }
+
+    fn proc_entry_exit2(&self, _: &MipsFrame, mut body: Vec<Instr>) -> Vec<Instr> {
+        let mut live = vec![ZERO, V0, SP, FP];
+        live.extend(CALLEE_SAVES.iter());
+        body.push(Instr::oper(String::new(), vec![], live));
+        body
+    }
+
+    fn proc_entry_exit3(&self, frame: &MipsFrame, symbol_table: &SymbolTable) -> (String, String) {
+        let name = frame.name.name(symbol_table);
+        let mut prologue = format!("{}:\n", name);
+        prologue.push_str(&format!("\taddiu $sp, $sp, -{}\n\tsw $ra, {}($sp)\n\tsw $fp, 0($sp)\n\taddiu $fp, $sp, {}\n",
+                                   SAVED_REGISTERS_SIZE, WORD, SAVED_REGISTERS_SIZE));
+        let locals_size = frame.frame_size() - SAVED_REGISTERS_SIZE;
+        if fits_imm16(-locals_size) {
+            prologue.push_str(&format!("\taddiu $sp, $sp, -{}\n", locals_size));
+        } else {
+            prologue.push_str(&format!("\tli $t0, {}\n\tsubu $sp, $sp, $t0\n", locals_size));
+        }
+        let epilogue = format!("\tlw $ra, -{}($fp)\n\tmove $t0, $fp\n\tlw $fp, -{}($fp)\n\tmove $sp, $t0\n\tjr $ra{}\n\n",
+                               WORD, SAVED_REGISTERS_SIZE, DELAY_SLOT);
+        (prologue, epilogue)
+    }
+
+    fn file_header(&self) -> String {
+        format!("\t.text\n{}", RUNTIME_TEXT)
+    }
+
+    fn data_section(&self) -> String {
+        format!("\t.data\n{}", RUNTIME_DATA)
+    }
+
+    // SPIM's .ascii knows only a few escapes, so other characters are
+    // given as bytes
+    fn string(&self, label: Label, s: &str, symbol_table: &SymbolTable) -> String {
+        let mut res = format!("\t.align 2\n{}:\n\t.word {}\n", label.name(symbol_table), s.chars().count());
+        let mut run = String::new();
+        for c in s.chars() {
+            match c {
+                '"' | '\\' => {
+                    run.push('\\');
+                    run.push(c);
+                },
+                c if c >= ' ' && c <= '~' => run.push(c),
+                c => {
+                    if !run.is_empty() {
+                        res.push_str(&format!("\t.ascii \"{}\"\n", run));
+                        run.clear();
+                    }
+                    res.push_str(&format!("\t.byte {}\n", c as u32 & 0xff));
+                },
+            }
+        }
+        if !run.is_empty() {
+            res.push_str(&format!("\t.ascii \"{}\"\n", run));
+        }
+        res
+    }
+
+    fn register_name(&self, t: Temp) -> String {
+        match REGISTER_NAMES.get(t.0 as usize) {
+            Some(name) => name.to_string(),
+            None => format!("t{}", t.0),
+        }
+    }
+
+    // frame offsets beyond an immediate go through $v1, which instruction
+    // selection never uses
+    fn load(&self, dst: Temp, offset: i32) -> Vec<Instr> {
+        if fits_imm16(offset) {
+            vec![Instr::oper(format!("lw `d0, {}(`s0)", offset), vec![dst], vec![FP])]
+        } else {
+            vec![Instr::oper(format!("li `d0, {}", offset), vec![V1], vec![]),
+                 Instr::oper(String::from("addu `d0, `s0, `s1"), vec![V1], vec![V1, FP]),
+                 Instr::oper(String::from("lw `d0, 0(`s0)"), vec![dst], vec![V1])]
+        }
+    }
+
+    fn store(&self, src: Temp, offset: i32) -> Vec<Instr> {
+        if fits_imm16(offset) {
+            vec![Instr::oper(format!("sw `s0, {}(`s1)", offset), vec![], vec![src, FP])]
+        } else {
+            vec![Instr::oper(format!("li `d0, {}", offset), vec![V1], vec![]),
+                 Instr::oper(String::from("addu `d0, `s0, `s1"), vec![V1], vec![V1, FP]),
+                 Instr::oper(String::from("sw `s0, 0(`s1)"), vec![], vec![src, V1])]
+        }
+    }
+
+    // $v1 is left for the addressing of distant frame slots
+    fn registers(&self) -> Vec<Temp> {
+        CALLER_SAVES.iter().chain(CALLEE_SAVES.iter()).cloned().filter(|&r| r != V1).collect()
+    }
+
+    fn is_call(&self, instr: &Instr) -> bool {
+        match instr {
+            &Instr::Oper { ref assem, .. } => assem.starts_with("jal "),
+            _ => false,
+        }
+    }
+
+    fn pointer_map_table(&self, maps: &[PointerMap], symbol_table: &SymbolTable) -> String {
+        format!("\t.align 2\n{}", pointer_map_table(maps, ".word", symbol_table))
+    }
+}
+
+#[test]
+fn test_munch() {
+    let mut table = SymbolTable::new();
+    let frame = MipsFrame::new_frame(Label(table.symbol("f")), vec![], &mut TempGenerator::new());
+    let (g, yes, no) = (Label(table.symbol("g")), Label(table.symbol("yes")), Label(table.symbol("no")));
+    let codegen = MipsCodegen;
+    let name = |t: Temp| codegen.register_name(t);
+    let label = |l: Label| l.name(&table).clone();
+    let munch = |stm: Stm| -> Vec<String> {
+        let instrs = codegen.codegen(&frame, stm, &mut TempGenerator::new(), &table);
+        instrs.iter().map(|i| i.format(&name, &label)).collect()
+    };
+    let temp = |t: Temp| Box::new(Exp::Temp(t));
+
+    // the delay slot of a branch or a call holds a nop, and the fifth
+    // argument goes on the stack
+    assert_eq!(munch(Stm::CJump(RelOp::Lt, temp(Temp(200)), temp(Temp(201)), yes, no)),
+               vec!["blt t200, t201, yes\n\tnop"]);
+    assert_eq!(munch(Stm::Jump(Box::new(Exp::Name(yes)), vec![yes])), vec!["j yes\n\tnop"]);
+    let args = (0..5).map(|i| Exp::Temp(Temp(210 + i))).collect();
+    assert_eq!(munch(Stm::Exp(Box::new(Exp::Call(Box::new(Exp::Name(g)), args)))),
+               vec!["addiu $sp, $sp, -8", "sw t214, 0($sp)", "move $a0, t210", "move $a1, t211",
+                    "move $a2, t212", "move $a3, t213", "jal g\n\tnop", "addiu $sp, $sp, 8"]);
+    // MOVE(TEMP t, BINOP(DIV, TEMP t, CONST 3)) reads the quotient from LO
+    assert_eq!(munch(Stm::Move(temp(Temp(200)), Box::new(Exp::BinOp(BinOp::Div, temp(Temp(200)),
+                                                                    Box::new(Exp::Const(3)))))),
+               vec!["li t100, 3", "div t200, t100", "mflo t101", "move t200, t101"]);
+}
+
+#[test]
+fn test_delay_slots_and_syscalls() {
+    use driver::{Target, compile};
+    use regalloc::Allocator;
+
+    let program = "let function f(n: int): int = if n < 2 then n else f(n - 1) + f(n - 2) \
+                   in printi(f(10)); print(chr(ord(getchar()) + 1)); exit(f(3)) end";
+    let asm = compile(program, Target::Mips, Allocator::Coloring).unwrap();
+    let lines: Vec<&str> = asm.lines().map(|l| l.trim()).collect();
+    let mut jumps = 0;
+    for (i, &line) in lines.iter().enumerate() {
+        let mnemonic = line.split_whitespace().next().unwrap_or("");
+        if (mnemonic.starts_with('j') || mnemonic.starts_with('b')) && !line.ends_with(':') {
+            assert_eq!(lines[i + 1], "nop", "no nop after {}", line);
+            jumps += 1;
+        }
+        // the service is chosen in $v0, with the numbers of SPIM's
+        if line == "syscall" {
+            let service = lines[i - 1].trim_start_matches("li $v0, ").parse::<i32>().unwrap();
+            assert!([1, 4, 9, 10, 11, 12, 17].contains(&service), "unknown syscall {}", service);
+        }
+    }
+    assert!(jumps > 50);
+    assert!(asm.contains("exit:\n\tli $v0, 17\n\tsyscall"));
+}
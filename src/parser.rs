@@ -7,11 +7,55 @@ use tiger;
 pub fn parse(s: &str) -> Result<(Box<ast::Exp>, Box<symbol::SymbolTable>),
     lalrpop_util::ParseError<usize, lexer::Token, ()>> {
     let mut st = Box::new(symbol::SymbolTable::new());
+    let p = parse_exp(s, &mut st)?;
+    Ok((p, st))
+}
+
+// parses a program with the symbols of an existing table, such as the one
+// of the REPL
+pub fn parse_exp(s: &str, symbol_table: &mut symbol::SymbolTable) -> Result<Box<ast::Exp>,
+    lalrpop_util::ParseError<usize, lexer::Token, ()>> {
+    tiger::parse_Program(lexer::Lexer::new(s, symbol_table))
+}
+
+// parses a program, also returning the spans of its comments in order
+pub fn parse_with_comments(s: &str) -> Result<(Box<ast::Exp>, Box<symbol::SymbolTable>, Vec<lexer::Span>),
+    lalrpop_util::ParseError<usize, lexer::Token, ()>> {
+    let mut st = Box::new(symbol::SymbolTable::new());
+    let mut comments = vec![];
+    let p = tiger::parse_Program(lexer::Lexer::with_comments(s, &mut st, &mut comments))?;
+    Ok((p, st, comments))
+}
+
+// parses the declarations of a let, without the let
+pub fn parse_decs(s: &str, symbol_table: &mut symbol::SymbolTable) -> Result<Vec<Box<ast::Dec>>,
+    lalrpop_util::ParseError<usize, lexer::Token, ()>> {
+    tiger::parse_Decs(lexer::Lexer::new(s, symbol_table))
+}
 
-    let p;
-    {
-        let l = lexer::Lexer::new(s, &mut st);
-        p = tiger::parse_Program(l)?;
+// whether the input ended before what it started, so that more of it may
+// make it parse; this includes a string the input ends in
+pub fn is_incomplete(e: &lalrpop_util::ParseError<usize, lexer::Token, ()>) -> bool {
+    match e {
+        &lalrpop_util::ParseError::UnrecognizedToken { token: None, .. } => true,
+        &lalrpop_util::ParseError::UnrecognizedToken { token: Some((_, lexer::Token::UnterminatedString, _)), .. } => true,
+        _ => false,
+    }
+}
+
+pub fn error_message(e: &lalrpop_util::ParseError<usize, lexer::Token, ()>) -> String {
+    match e {
+        &lalrpop_util::ParseError::InvalidToken { location } => format!("invalid token at pos {}", location),
+        &lalrpop_util::ParseError::UnrecognizedToken { token: Some((lo, lexer::Token::Error(ref e), _)), .. } =>
+            format!("{} at pos {}", e, lo),
+        &lalrpop_util::ParseError::UnrecognizedToken { token: Some((lo, lexer::Token::UnterminatedString, _)), .. } =>
+            format!("unterminated string at pos {}", lo),
+        &lalrpop_util::ParseError::UnrecognizedToken { token: Some((lo, ref token, _)), .. } =>
+            format!("unexpected token {:?} at pos {}", token, lo),
+        &lalrpop_util::ParseError::UnrecognizedToken { token: None, .. } =>
+            String::from("unexpected end of input"),
+        &lalrpop_util::ParseError::ExtraToken { token: (lo, ref token, _) } =>
+            format!("extra token {:?} at pos {}", token, lo),
+        &lalrpop_util::ParseError::User { .. } => String::from("parse error"),
     }
-    Ok((p, st))
 }
@@ -0,0 +1,607 @@
+use assem::Instr;
+use codegen::Codegen;
+use flow::{FlowGraph, flow_graph};
+use frame::{Access, Frame};
+use linear_scan;
+use liveness::{BitSet, InterferenceGraph, liveness, interference_graph};
+use temp::{Temp, TempGenerator, FIRST_FREE_TEMP};
+
+use std::collections::{HashMap, HashSet};
+
+// The register assigned to every temp of a procedure; machine registers
+// are assigned to themselves.
+pub type Allocation = HashMap<Temp, Temp>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Allocator {
+    // iterated register coalescing, for the best code
+    Coloring,
+    // linear scan, for fast compiles
+    LinearScan,
+}
+
+impl Allocator {
+    pub fn from_name(name: &str) -> Option<Allocator> {
+        match name {
+            "coloring" | "irc" => Some(Allocator::Coloring),
+            "linear-scan" | "linear" => Some(Allocator::LinearScan),
+            _ => None,
+        }
+    }
+}
+
+// Assigns a register of the target to every temp of a procedure body.
+// Returns the body rewritten with the code for spilled temps, whose slots
+// are allocated in frame.
+pub fn allocate<C: Codegen>(allocator: Allocator, codegen: &C, frame: &mut C::Frame, instrs: Vec<Instr>,
+                            gen: &mut TempGenerator) -> (Vec<Instr>, Allocation) {
+    match allocator {
+        Allocator::Coloring => iterated_coalescing(codegen, frame, instrs, gen),
+        Allocator::LinearScan => linear_scan::allocate(codegen, frame, instrs, gen),
+    }
+}
+
+// Register allocation by iterated register coalescing (Appel chapter 11):
+// colors the interference graph with the registers of the target, and
+// rewrites the program with spill code until every temp gets a register.
+fn iterated_coalescing<C: Codegen>(codegen: &C, frame: &mut C::Frame, mut instrs: Vec<Instr>,
+                                   gen: &mut TempGenerator) -> (Vec<Instr>, Allocation) {
+    let registers = codegen.registers();
+    // temps created by spilling, which have tiny live ranges and spilling
+    // them again would not help
+    let mut spill_temps = HashSet::new();
+    loop {
+        let flow = flow_graph(&instrs);
+        let live = liveness(&flow);
+        let igraph = interference_graph(&flow, &live);
+        let mut coloring = Coloring::new(&igraph, &flow, &registers, &spill_temps);
+        coloring.run();
+        if coloring.spilled.is_empty() {
+            let allocation = coloring.allocation();
+            return (instrs, allocation);
+        }
+        let mut slots = HashMap::new();
+        for &n in coloring.spilled.iter() {
+            match frame.alloc_local(true, gen) {
+                Access::InFrame(offset) => slots.insert(igraph.temps[n], offset),
+                Access::InReg(_) => panic!("escaping local allocated in a register"),
+            };
+        }
+        instrs = rewrite_program(codegen, instrs, &slots, gen, &mut spill_temps);
+    }
+}
+
+// Replaces each occurrence of a temp spilled to the frame slot at
+// slots[temp] by a new temp, loaded from the slot before the instruction
+// and stored back after it. The new temps are added to spill_temps.
+pub fn rewrite_program<C: Codegen>(codegen: &C, instrs: Vec<Instr>, slots: &HashMap<Temp, i32>,
+                                   gen: &mut TempGenerator, spill_temps: &mut HashSet<Temp>) -> Vec<Instr> {
+    let mut res = vec![];
+    for instr in instrs.into_iter() {
+        let mut temps: Vec<Temp> = instr.temps().into_iter().filter(|t| slots.contains_key(t)).collect();
+        if temps.is_empty() {
+            res.push(instr);
+            continue;
+        }
+        temps.sort();
+        let new_temps: HashMap<Temp, Temp> = temps.iter().map(|&t| (t, gen.new_temp())).collect();
+        spill_temps.extend(new_temps.values().cloned());
+
+        let uses = instr.uses();
+        let defs = instr.defs();
+        for t in temps.iter().filter(|t| uses.contains(t)) {
+            res.extend(codegen.load(new_temps[t], slots[t]));
+        }
+        res.push(instr.map_temps(|t| *new_temps.get(&t).unwrap_or(&t)));
+        for t in temps.iter().filter(|t| defs.contains(t)) {
+            res.extend(codegen.store(new_temps[t], slots[t]));
+        }
+    }
+    res
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NodeState {
+    Precolored,
+    Initial,
+    Simplify,
+    Freeze,
+    Spill,
+    Spilled,
+    Coalesced,
+    Colored,
+    Selected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MoveState {
+    Worklist,
+    Active,
+    Coalesced,
+    Constrained,
+    Frozen,
+}
+
+// The state of the algorithm of George and Appel, following the
+// pseudo-code of the book. Nodes are the indices of the interference
+// graph; the worklists hold nodes and moves whose state may have changed
+// since, which are skipped when taken out.
+struct Coloring<'a> {
+    temps: &'a [Temp],
+    registers: &'a [Temp],
+    k: usize,
+
+    state: Vec<NodeState>,
+    adj_set: Vec<BitSet>,
+    adj_list: Vec<Vec<usize>>,
+    degree: Vec<usize>,
+    alias: Vec<usize>,
+    color: Vec<Option<Temp>>,
+    move_list: Vec<Vec<usize>>,
+    spill_cost: Vec<f64>,
+
+    simplify_worklist: Vec<usize>,
+    freeze_worklist: Vec<usize>,
+    spill_worklist: Vec<usize>,
+    select_stack: Vec<usize>,
+    spilled: Vec<usize>,
+
+    moves: Vec<(usize, usize)>,
+    move_state: Vec<MoveState>,
+    worklist_moves: Vec<usize>,
+}
+
+// the degree of machine registers, which can't be simplified
+const INFINITE_DEGREE: usize = ::std::usize::MAX / 2;
+
+impl<'a> Coloring<'a> {
+    fn new(igraph: &'a InterferenceGraph, flow: &FlowGraph, registers: &'a [Temp],
+           spill_temps: &HashSet<Temp>) -> Coloring<'a> {
+        let n = igraph.temps.len();
+        let precolored = |i: usize| igraph.temps[i].0 < FIRST_FREE_TEMP;
+
+        let mut occurrences = vec![0; n];
+        for node in 0..flow.len() {
+            for t in flow.defs[node].iter().chain(flow.uses[node].iter()) {
+                occurrences[igraph.index[t]] += 1;
+            }
+        }
+
+        let mut coloring = Coloring {
+            temps: &igraph.temps,
+            registers: registers,
+            k: registers.len(),
+            state: (0..n).map(|i| if precolored(i) { NodeState::Precolored } else { NodeState::Initial }).collect(),
+            adj_set: igraph.adj_set.clone(),
+            adj_list: igraph.adj_list.clone(),
+            degree: (0..n).map(|i| if precolored(i) { INFINITE_DEGREE } else { igraph.adj_list[i].len() }).collect(),
+            alias: (0..n).collect(),
+            color: (0..n).map(|i| if precolored(i) { Some(igraph.temps[i]) } else { None }).collect(),
+            move_list: vec![vec![]; n],
+            spill_cost: (0..n).map(|i| {
+                if spill_temps.contains(&igraph.temps[i]) {
+                    ::std::f64::INFINITY
+                } else {
+                    occurrences[i] as f64
+                }
+            }).collect(),
+            simplify_worklist: vec![],
+            freeze_worklist: vec![],
+            spill_worklist: vec![],
+            select_stack: vec![],
+            spilled: vec![],
+            moves: vec![],
+            move_state: vec![],
+            worklist_moves: vec![],
+        };
+
+        for &(dst, src) in igraph.moves.iter() {
+            let m = coloring.moves.len();
+            let (dst, src) = (igraph.index[&dst], igraph.index[&src]);
+            coloring.moves.push((dst, src));
+            coloring.move_state.push(MoveState::Worklist);
+            coloring.worklist_moves.push(m);
+            coloring.move_list[dst].push(m);
+            coloring.move_list[src].push(m);
+        }
+        coloring
+    }
+
+    fn run(&mut self) {
+        self.make_worklist();
+        loop {
+            if let Some(n) = self.take(NodeState::Simplify) {
+                self.simplify(n);
+            } else if let Some(m) = self.take_move() {
+                self.coalesce(m);
+            } else if let Some(n) = self.take(NodeState::Freeze) {
+                self.freeze(n);
+            } else if let Some(n) = self.select_spill() {
+                self.spill(n);
+            } else {
+                break;
+            }
+        }
+        self.assign_colors();
+    }
+
+    fn allocation(&self) -> Allocation {
+        (0..self.temps.len()).map(|n| (self.temps[n], self.color[n].unwrap())).collect()
+    }
+
+    fn is_precolored(&self, n: usize) -> bool {
+        self.state[n] == NodeState::Precolored
+    }
+
+    // a machine register the allocator must leave alone, like the stack
+    // pointer
+    fn is_reserved(&self, n: usize) -> bool {
+        self.is_precolored(n) && !self.registers.contains(&self.temps[n])
+    }
+
+    fn worklist(&mut self, state: NodeState) -> &mut Vec<usize> {
+        match state {
+            NodeState::Simplify => &mut self.simplify_worklist,
+            NodeState::Freeze => &mut self.freeze_worklist,
+            NodeState::Spill => &mut self.spill_worklist,
+            _ => panic!("no worklist for {:?}", state),
+        }
+    }
+
+    fn set_state(&mut self, n: usize, state: NodeState) {
+        self.state[n] = state;
+        match state {
+            NodeState::Simplify | NodeState::Freeze | NodeState::Spill => self.worklist(state).push(n),
+            _ => (),
+        }
+    }
+
+    fn take(&mut self, state: NodeState) -> Option<usize> {
+        while let Some(n) = self.worklist(state).pop() {
+            if self.state[n] == state {
+                return Some(n);
+            }
+        }
+        None
+    }
+
+    fn take_move(&mut self) -> Option<usize> {
+        while let Some(m) = self.worklist_moves.pop() {
+            if self.move_state[m] == MoveState::Worklist {
+                return Some(m);
+            }
+        }
+        None
+    }
+
+    fn make_worklist(&mut self) {
+        for n in 0..self.temps.len() {
+            if self.state[n] != NodeState::Initial {
+                continue;
+            }
+            if self.degree[n] >= self.k {
+                self.set_state(n, NodeState::Spill);
+            } else if self.move_related(n) {
+                self.set_state(n, NodeState::Freeze);
+            } else {
+                self.set_state(n, NodeState::Simplify);
+            }
+        }
+    }
+
+    fn adjacent(&self, n: usize) -> Vec<usize> {
+        self.adj_list[n].iter().cloned()
+            .filter(|&m| self.state[m] != NodeState::Selected && self.state[m] != NodeState::Coalesced)
+            .collect()
+    }
+
+    fn node_moves(&self, n: usize) -> Vec<usize> {
+        self.move_list[n].iter().cloned()
+            .filter(|&m| self.move_state[m] == MoveState::Active || self.move_state[m] == MoveState::Worklist)
+            .collect()
+    }
+
+    fn move_related(&self, n: usize) -> bool {
+        !self.node_moves(n).is_empty()
+    }
+
+    fn simplify(&mut self, n: usize) {
+        self.state[n] = NodeState::Selected;
+        self.select_stack.push(n);
+        for m in self.adjacent(n) {
+            self.decrement_degree(m);
+        }
+    }
+
+    fn decrement_degree(&mut self, m: usize) {
+        if self.is_precolored(m) {
+            return;
+        }
+        let d = self.degree[m];
+        self.degree[m] -= 1;
+        if d == self.k {
+            let mut nodes = self.adjacent(m);
+            nodes.push(m);
+            self.enable_moves(&nodes);
+            if self.state[m] == NodeState::Spill {
+                if self.move_related(m) {
+                    self.set_state(m, NodeState::Freeze);
+                } else {
+                    self.set_state(m, NodeState::Simplify);
+                }
+            }
+        }
+    }
+
+    fn enable_moves(&mut self, nodes: &[usize]) {
+        for &n in nodes.iter() {
+            for m in self.node_moves(n) {
+                if self.move_state[m] == MoveState::Active {
+                    self.move_state[m] = MoveState::Worklist;
+                    self.worklist_moves.push(m);
+                }
+            }
+        }
+    }
+
+    fn add_work_list(&mut self, u: usize) {
+        if self.state[u] == NodeState::Freeze && !self.move_related(u) && self.degree[u] < self.k {
+            self.set_state(u, NodeState::Simplify);
+        }
+    }
+
+    // George's test, for coalescing with a machine register r
+    fn ok(&self, t: usize, r: usize) -> bool {
+        self.degree[t] < self.k || self.is_precolored(t) || self.adj_set[t].contains(r)
+    }
+
+    // Briggs's test
+    fn conservative(&self, nodes: &[usize]) -> bool {
+        nodes.iter().filter(|&&n| self.degree[n] >= self.k).count() < self.k
+    }
+
+    fn get_alias(&self, n: usize) -> usize {
+        let mut n = n;
+        while self.state[n] == NodeState::Coalesced {
+            n = self.alias[n];
+        }
+        n
+    }
+
+    fn coalesce(&mut self, m: usize) {
+        let (x, y) = self.moves[m];
+        let (x, y) = (self.get_alias(x), self.get_alias(y));
+        let (u, v) = if self.is_precolored(y) { (y, x) } else { (x, y) };
+        if u == v {
+            self.move_state[m] = MoveState::Coalesced;
+            self.add_work_list(u);
+        } else if self.is_precolored(v) || self.is_reserved(u) || self.adj_set[u].contains(v) {
+            self.move_state[m] = MoveState::Constrained;
+            self.add_work_list(u);
+            self.add_work_list(v);
+        } else if self.can_combine(u, v) {
+            self.move_state[m] = MoveState::Coalesced;
+            self.combine(u, v);
+            self.add_work_list(u);
+        } else {
+            self.move_state[m] = MoveState::Active;
+        }
+    }
+
+    fn can_combine(&self, u: usize, v: usize) -> bool {
+        if self.is_precolored(u) {
+            self.adjacent(v).into_iter().all(|t| self.ok(t, u))
+        } else {
+            let mut nodes = self.adjacent(u);
+            nodes.extend(self.adjacent(v).into_iter().filter(|&t| !self.adj_set[u].contains(t)));
+            self.conservative(&nodes)
+        }
+    }
+
+    fn combine(&mut self, u: usize, v: usize) {
+        self.state[v] = NodeState::Coalesced;
+        self.alias[v] = u;
+        let moves = self.move_list[v].clone();
+        self.move_list[u].extend(moves);
+        self.enable_moves(&[v]);
+        for t in self.adjacent(v) {
+            self.add_edge(t, u);
+            self.decrement_degree(t);
+        }
+        if self.degree[u] >= self.k && self.state[u] == NodeState::Freeze {
+            self.set_state(u, NodeState::Spill);
+        }
+    }
+
+    fn add_edge(&mut self, u: usize, v: usize) {
+        if u == v || self.adj_set[u].contains(v) {
+            return;
+        }
+        self.adj_set[u].insert(v);
+        self.adj_set[v].insert(u);
+        for &(a, b) in [(u, v), (v, u)].iter() {
+            if !self.is_precolored(a) {
+                self.adj_list[a].push(b);
+                self.degree[a] += 1;
+            }
+        }
+    }
+
+    fn freeze(&mut self, u: usize) {
+        self.set_state(u, NodeState::Simplify);
+        self.freeze_moves(u);
+    }
+
+    fn freeze_moves(&mut self, u: usize) {
+        for m in self.node_moves(u) {
+            let (x, y) = self.moves[m];
+            let v = if self.get_alias(y) == self.get_alias(u) { self.get_alias(x) } else { self.get_alias(y) };
+            self.move_state[m] = MoveState::Frozen;
+            if self.state[v] == NodeState::Freeze && !self.move_related(v) && self.degree[v] < self.k {
+                self.set_state(v, NodeState::Simplify);
+            }
+        }
+    }
+
+    // the node of the spill worklist that is cheapest to spill: used
+    // rarely, and in conflict with many others. Temps created by spilling
+    // are left out unless nothing else is, since spilling them again
+    // shortens no live range; then the one with the most conflicts goes.
+    fn select_spill(&mut self) -> Option<usize> {
+        let mut best: Option<usize> = None;
+        let mut last_resort: Option<usize> = None;
+        for &n in self.spill_worklist.iter() {
+            if self.state[n] != NodeState::Spill {
+                continue;
+            }
+            if self.spill_cost[n].is_infinite() {
+                match last_resort {
+                    Some(r) if self.degree[r] >= self.degree[n] => (),
+                    _ => last_resort = Some(n),
+                }
+                continue;
+            }
+            let cost = self.spill_cost[n] / self.degree[n] as f64;
+            match best {
+                Some(b) if self.spill_cost[b] / self.degree[b] as f64 <= cost => (),
+                _ => best = Some(n),
+            }
+        }
+        best.or(last_resort)
+    }
+
+    fn spill(&mut self, n: usize) {
+        self.set_state(n, NodeState::Simplify);
+        self.freeze_moves(n);
+    }
+
+    fn assign_colors(&mut self) {
+        while let Some(n) = self.select_stack.pop() {
+            let mut ok_colors: Vec<Temp> = self.registers.to_vec();
+            for &w in self.adj_list[n].iter() {
+                let w = self.get_alias(w);
+                match self.state[w] {
+                    NodeState::Colored | NodeState::Precolored => {
+                        let c = self.color[w];
+                        ok_colors.retain(|&r| Some(r) != c);
+                    },
+                    _ => (),
+                }
+            }
+            if ok_colors.is_empty() {
+                self.state[n] = NodeState::Spilled;
+                self.spilled.push(n);
+            } else {
+                self.state[n] = NodeState::Colored;
+                self.color[n] = Some(ok_colors[0]);
+            }
+        }
+        for n in 0..self.temps.len() {
+            if self.state[n] == NodeState::Coalesced {
+                let a = self.get_alias(n);
+                self.color[n] = self.color[a];
+            }
+        }
+    }
+}
+
+#[test]
+fn test_allocate() {
+    use symbol::SymbolTable;
+    use temp::Label;
+    use x86_64::{X86Codegen, X86Frame, Syntax, RDI};
+
+    let mut table = SymbolTable::new();
+    let codegen = X86Codegen { syntax: Syntax::Att };
+    let registers = codegen.registers();
+    for &allocator in [Allocator::Coloring, Allocator::LinearScan].iter() {
+        let mut gen = TempGenerator::new();
+        let mut frame = X86Frame::new_frame(Label(table.symbol("f")), vec![], &mut gen);
+
+        // more values live at once than there are registers, and a chain
+        // of moves that should be coalesced
+        let temps: Vec<Temp> = (0..20).map(|_| gen.new_temp()).collect();
+        let mut instrs: Vec<Instr> = temps.iter().map(|&t| Instr::oper(String::from("def `d0"), vec![t], vec![])).collect();
+        let (a, b) = (gen.new_temp(), gen.new_temp());
+        instrs.push(Instr::Move { assem: String::from("mov `s0, `d0"), dst: a, src: RDI });
+        instrs.push(Instr::Move { assem: String::from("mov `s0, `d0"), dst: b, src: a });
+        instrs.extend(temps.iter().map(|&t| Instr::oper(String::from("use `s0"), vec![], vec![t])));
+        instrs.push(Instr::oper(String::from("use `s0"), vec![], vec![b]));
+
+        let (instrs, allocation) = allocate(allocator, &codegen, &mut frame, instrs, &mut gen);
+        let flow = flow_graph(&instrs);
+        let igraph = interference_graph(&flow, &liveness(&flow));
+        for (i, &t) in igraph.temps.iter().enumerate() {
+            assert!(t.0 < FIRST_FREE_TEMP || registers.contains(&allocation[&t]));
+            for &j in igraph.adj_list[i].iter() {
+                assert!(allocation[&t] != allocation[&igraph.temps[j]], "{:?}", allocator);
+            }
+        }
+        assert!(frame.frame_size() > 0);
+        assert_eq!(allocation[&a], RDI);
+        if allocator == Allocator::Coloring {
+            assert_eq!(allocation[&b], RDI);
+        }
+    }
+}
+
+#[test]
+fn test_spill_under_pressure() {
+    use symbol::SymbolTable;
+    use temp::Label;
+    use x86_64::{X86Codegen, X86Frame, Syntax};
+
+    let mut table = SymbolTable::new();
+    let codegen = X86Codegen { syntax: Syntax::Att };
+    let registers = codegen.registers();
+    let mut gen = TempGenerator::new();
+    let mut frame = X86Frame::new_frame(Label(table.symbol("f")), vec![], &mut gen);
+
+    // three times as many values live as there are registers, and
+    // instructions reading many of them at once, so that the temps loading
+    // them back interfere with many others
+    let temps: Vec<Temp> = (0..3 * registers.len()).map(|_| gen.new_temp()).collect();
+    let mut instrs: Vec<Instr> = temps.iter().map(|&t| Instr::oper(String::from("def `d0"), vec![t], vec![])).collect();
+    for chunk in temps.chunks(registers.len() / 2) {
+        instrs.push(Instr::oper(String::from("use"), vec![], chunk.to_vec()));
+    }
+    instrs.extend(temps.iter().map(|&t| Instr::oper(String::from("use `s0"), vec![], vec![t])));
+    let size = instrs.len();
+
+    let (instrs, allocation) = allocate(Allocator::Coloring, &codegen, &mut frame, instrs, &mut gen);
+    let flow = flow_graph(&instrs);
+    let igraph = interference_graph(&flow, &liveness(&flow));
+    for (i, &t) in igraph.temps.iter().enumerate() {
+        assert!(t.0 < FIRST_FREE_TEMP || registers.contains(&allocation[&t]));
+        for &j in igraph.adj_list[i].iter() {
+            assert!(allocation[&t] != allocation[&igraph.temps[j]]);
+        }
+    }
+    // only the original temps went to the frame, each at most once
+    assert!(instrs.len() > size);
+    assert!(frame.frame_size() <= 8 * temps.len() as i32 + 8);
+}
+
+#[test]
+fn test_select_spill() {
+    // three temps in conflict with each other and two registers: one of
+    // them goes, and not one created by spilling while another is left
+    let (a, b, c) = (Temp(100), Temp(101), Temp(102));
+    let mut instrs: Vec<Instr> = [a, b, c].iter().map(|&t| Instr::oper(String::from("def `d0"), vec![t], vec![])).collect();
+    instrs.push(Instr::oper(String::from("use"), vec![], vec![a, b, c]));
+    instrs.push(Instr::oper(String::from("use `s0"), vec![], vec![c]));
+    let flow = flow_graph(&instrs);
+    let igraph = interference_graph(&flow, &liveness(&flow));
+    let registers = [Temp(1), Temp(2)];
+    let select = |spill_temps: &[Temp]| {
+        let spill_temps = spill_temps.iter().cloned().collect();
+        let mut coloring = Coloring::new(&igraph, &flow, &registers, &spill_temps);
+        coloring.make_worklist();
+        coloring.select_spill().map(|n| igraph.temps[n])
+    };
+    // a and b are used the least
+    assert!(select(&[]) != Some(c));
+    // c is used the most, but the only one left to spill
+    assert_eq!(select(&[a, b]), Some(c));
+    assert!(select(&[a, b, c]).is_some());
+}
@@ -0,0 +1,346 @@
+use ast;
+use dot;
+use interp::{Control, Globals, Interpreter};
+use lalrpop_util::ParseError;
+use lexer::Token;
+use parser::{self, parse_decs, parse_exp};
+use symbol::SymbolTable;
+use type_check::Environment;
+use types::{EnvEntry, Ty};
+
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+
+// An interactive session with the interpreter. Each input is either
+// declarations, which stay in scope for the inputs after it, or an
+// expression, whose value and type are printed. An input goes on over the
+// next lines for as long as it only fails to parse by ending too soon.
+
+pub const HELP: &'static str = "\
+<exp>          evaluate an expression, printing its value and type
+<decs>         declare types, variables and functions for the rest of the session
+:type <exp>    print the type of an expression without evaluating it
+:ast <input>   print the syntax tree of an expression or of declarations
+:dot <exp>     print the syntax tree of an expression as a Graphviz graph
+:load <file>   evaluate the expression or the declarations in a file
+:help          print this message
+:quit          end the session
+";
+
+enum Input {
+    Decs(Vec<Box<ast::Dec>>),
+    Exp(Box<ast::Exp>),
+}
+
+// What ends an input early.
+enum Stop {
+    Exit(i32),
+    Error(String),
+}
+
+fn stop(control: Control, source: &str) -> Stop {
+    match control {
+        Control::Exit(status) => Stop::Exit(status),
+        Control::Error(e) => Stop::Error(e.describe(source)),
+        Control::Break => Stop::Error(String::from("break outside a loop")),
+    }
+}
+
+// The output of the session, which knows whether the program left a line
+// unfinished, so that results start on a line of their own.
+struct Output<'a> {
+    out: &'a mut dyn Write,
+    at_line_start: bool,
+}
+
+impl<'a> Write for Output<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.out.write(buf)?;
+        if n > 0 {
+            self.at_line_start = buf[n - 1] == b'\n';
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// the name of a command and its argument
+fn split_command(line: &str) -> (&str, &str) {
+    let end = line.find(char::is_whitespace).unwrap_or(line.len());
+    (&line[..end], line[end..].trim())
+}
+
+pub struct Repl {
+    symbol_table: SymbolTable,
+    env: Environment,
+    globals: Globals,
+}
+
+impl Repl {
+    pub fn new() -> Repl {
+        let mut symbol_table = SymbolTable::new();
+        let env = Environment::new(&mut symbol_table);
+        let globals = Globals::new(&mut symbol_table);
+        Repl {
+            symbol_table: symbol_table,
+            env: env,
+            globals: globals,
+        }
+    }
+
+    fn parse(&mut self, source: &str) -> Result<Input, ParseError<usize, Token, ()>> {
+        match source.split_whitespace().next() {
+            Some("type") | Some("var") | Some("function") =>
+                parse_decs(source, &mut self.symbol_table).map(Input::Decs),
+            _ => parse_exp(source, &mut self.symbol_table).map(Input::Exp),
+        }
+    }
+
+    // whether source is the start of an input that goes on over the next lines
+    pub fn is_incomplete(&mut self, source: &str) -> bool {
+        let mut source = source.trim();
+        if source.starts_with(':') {
+            match split_command(&source[1..]) {
+                ("type", arg) | ("ast", arg) | ("dot", arg) => source = arg,
+                _ => return false,
+            }
+        }
+        match self.parse(source) {
+            Err(ref e) => !source.is_empty() && parser::is_incomplete(e),
+            Ok(_) => false,
+        }
+    }
+
+    // evaluates an input or runs a command, writing the output of the
+    // program and then the result or error to output; returns the exit
+    // status when the session ends
+    pub fn eval(&mut self, source: &str, input: &mut dyn Read, output: &mut dyn Write) -> Option<i32> {
+        let mut out = Output { out: output, at_line_start: true };
+        let source = source.trim();
+        let result = if source.starts_with(':') {
+            self.command(&source[1..], input, &mut out)
+        } else {
+            self.eval_input(source, input, &mut out)
+        };
+        let text = match result {
+            Ok(ref text) if text.is_empty() => return None,
+            Ok(text) => text,
+            Err(Stop::Exit(status)) => return Some(status),
+            Err(Stop::Error(e)) => format!("error: {}", e),
+        };
+        if !out.at_line_start {
+            let _ = writeln!(out);
+        }
+        let _ = writeln!(out, "{}", text.trim_end());
+        None
+    }
+
+    fn eval_input(&mut self, source: &str, input: &mut dyn Read, out: &mut Output) -> Result<String, Stop> {
+        if source.is_empty() {
+            return Ok(String::new());
+        }
+        match self.parse(source).map_err(|e| Stop::Error(parser::error_message(&e)))? {
+            Input::Exp(exp) => {
+                let types = self.env.check(&exp, &self.symbol_table).map_err(Stop::Error)?;
+                let value = Interpreter::with_globals(self.globals.clone(), input, out).run(&exp)
+                    .map_err(|c| stop(c, source))?;
+                let ty = types.exp_ty(&exp).unwrap().name(&self.symbol_table);
+                Ok(format!("{} : {}", value.show(&self.symbol_table), ty))
+            },
+            Input::Decs(decs) => {
+                // the declarations are forgotten if evaluating them fails
+                let env = self.env.clone();
+                self.env.declare(&decs, &self.symbol_table).map_err(Stop::Error)?;
+                let globals = {
+                    let mut interp = Interpreter::with_globals(self.globals.clone(), input, out);
+                    interp.declare(&decs).map(|_| interp.globals())
+                };
+                match globals {
+                    Ok(globals) => self.globals = globals,
+                    Err(c) => {
+                        self.env = env;
+                        return Err(stop(c, source));
+                    },
+                }
+                let lines: Vec<String> = decs.iter().map(|dec| self.describe(dec)).collect();
+                Ok(lines.join("\n"))
+            },
+        }
+    }
+
+    // a declaration as it stands after evaluation, e.g. "var x = 1 : int",
+    // or that a later one in the same input hides it
+    fn describe(&self, dec: &ast::Dec) -> String {
+        let st = &self.symbol_table;
+        match dec {
+            &ast::Dec::VarDec { name, .. } => {
+                let ty = match self.env.look_value(name).map(|e| e.as_ref()) {
+                    Some(&EnvEntry::VarEntry { ref ty, .. }) => ty.name(st),
+                    _ => return format!("var {} is hidden by a later declaration", st.name(&name)),
+                };
+                format!("var {} = {} : {}", st.name(&name), self.globals.look_var(name).show(st), ty)
+            },
+            &ast::Dec::FunDec { name, .. } => {
+                match self.env.look_value(name).map(|e| e.as_ref()) {
+                    Some(&EnvEntry::FunEntry { ref formals, ref result }) => {
+                        let formals: Vec<String> = formals.iter().map(|ty| ty.name(st)).collect();
+                        format!("function {} : ({}) -> {}", st.name(&name), formals.join(", "), result.name(st))
+                    },
+                    _ => format!("function {} is hidden by a later declaration", st.name(&name)),
+                }
+            },
+            &ast::Dec::TypeDec { name, .. } => {
+                let ty = self.env.look_ty(name).unwrap();
+                let actual = match ty.as_ref() {
+                    &Ty::Name(_, ref r) => r.borrow().clone().unwrap(),
+                    _ => ty.clone(),
+                };
+                format!("type {} = {}", st.name(&name), actual.name(st))
+            },
+        }
+    }
+
+    fn parse_exp(&mut self, source: &str) -> Result<Box<ast::Exp>, Stop> {
+        parse_exp(source, &mut self.symbol_table).map_err(|e| Stop::Error(parser::error_message(&e)))
+    }
+
+    fn command(&mut self, line: &str, input: &mut dyn Read, out: &mut Output) -> Result<String, Stop> {
+        let (command, arg) = split_command(line);
+        match command {
+            "type" | "t" => {
+                let exp = self.parse_exp(arg)?;
+                let types = self.env.check(&exp, &self.symbol_table).map_err(Stop::Error)?;
+                Ok(types.exp_ty(&exp).unwrap().name(&self.symbol_table))
+            },
+            "ast" => match self.parse(arg).map_err(|e| Stop::Error(parser::error_message(&e)))? {
+                Input::Exp(exp) => Ok(format!("{:#?}", exp)),
+                Input::Decs(decs) => Ok(format!("{:#?}", decs)),
+            },
+            "dot" => {
+                let exp = self.parse_exp(arg)?;
+                // labelled with types if it type checks
+                let types = self.env.check(&exp, &self.symbol_table).ok();
+                let mut graph = vec![];
+                let _ = writeln!(graph, "digraph G {{");
+                dot::render_ast(&mut graph, &exp, &self.symbol_table, types.as_ref());
+                let _ = writeln!(graph, "}}");
+                Ok(String::from_utf8(graph).unwrap())
+            },
+            "load" | "l" => {
+                let source = fs::read_to_string(arg).map_err(|e| Stop::Error(format!("{}: {}", arg, e)))?;
+                self.eval_input(source.trim(), input, out)
+            },
+            "help" | "h" | "?" => Ok(String::from(HELP)),
+            "quit" | "q" => Err(Stop::Exit(0)),
+            _ => Err(Stop::Error(format!("unknown command :{}, :help lists them", command))),
+        }
+    }
+}
+
+// Runs a session on the lines of input until it ends or the program exits,
+// returning the exit status. The program reads what follows its input
+// from input too.
+pub fn run<R: BufRead>(repl: &mut Repl, input: &mut R, output: &mut dyn Write) -> i32 {
+    let mut source = String::new();
+    loop {
+        let _ = write!(output, "{}", if source.is_empty() { "tiger> " } else { "   ... " });
+        let _ = output.flush();
+        let mut line = String::new();
+        match input.read_line(&mut line) {
+            Ok(0) | Err(_) => {
+                if !source.trim().is_empty() {
+                    let _ = write!(output, "\nerror: unexpected end of input");
+                }
+                let _ = writeln!(output);
+                return 0;
+            },
+            Ok(_) => source.push_str(&line),
+        }
+        if repl.is_incomplete(&source) {
+            continue;
+        }
+        if let Some(status) = repl.eval(&source, input, output) {
+            return status;
+        }
+        source.clear();
+    }
+}
+
+#[test]
+fn test_repl() {
+    let session = |lines: &str| -> (i32, String) {
+        let mut input = lines.as_bytes();
+        let mut output = vec![];
+        let status = run(&mut Repl::new(), &mut input, &mut output);
+        (status, String::from_utf8(output).unwrap().replace("tiger> ", "").replace("   ... ", ""))
+    };
+
+    let (status, output) = session("type point = { x: int, y: int }\n\
+                                    var p := point { x = 1, y = 2 }\n\
+                                    function sum(p: point): int =\n  \
+                                      p.x + p.y\n\
+                                    sum(p) * 2\n\
+                                    (p.x := 5; p)\n\
+                                    (print(\"hi\"); \"there\")\n\
+                                    :type sum\n\
+                                    :type sum(nil)\n\
+                                    var q := p.z\n\
+                                    q\n\
+                                    1 / 0\n");
+    assert_eq!(status, 0);
+    assert_eq!(output, "type point = {x: int, y: int}\n\
+                        var p = {x = 1, y = 2} : {x: int, y: int}\n\
+                        function sum : (point) -> int\n\
+                        6 : int\n\
+                        {x = 5, y = 2} : {x: int, y: int}\n\
+                        hi\n\"there\" : string\n\
+                        error: Unknown variable sum at pos 0\n\
+                        int\n\
+                        error: Record of type {x: int, y: int} has no field named z at pos 9\n\
+                        error: Unknown variable q at pos 0\n\
+                        error: runtime error at 1:1: division by zero\n\
+                        \n");
+
+    // declarations failing at run time are not kept
+    let (_, output) = session("var a := 1\nvar b := 1 / (a - 1)\nb\na");
+    assert!(output.contains("error: Unknown variable b"));
+    assert!(output.ends_with("1 : int\n\n"));
+
+    // a variable and a function of the same name in one input
+    let (status, output) = session("var f := 1 function f(): int = 2\n\
+                                    function g(): int = 3 var g := 4\n\
+                                    f() + g\n");
+    assert_eq!(status, 0);
+    assert_eq!(output, "var f is hidden by a later declaration\n\
+                        function f : () -> int\n\
+                        function g is hidden by a later declaration\n\
+                        var g = 4 : int\n\
+                        6 : int\n\
+                        \n");
+
+    // a string over two lines, and errors in lexing, which keep the session
+    let (status, output) = session("var s := \"ab\n\
+                                    cd\"\n\
+                                    1 # 2\n\
+                                    printi(99999999999)\n\
+                                    size(s)\n");
+    assert_eq!(status, 0);
+    assert_eq!(output, "var s = \"ab\\ncd\" : string\n\
+                        error: unexpected character # at pos 2\n\
+                        error: integer 99999999999 is out of range at pos 7\n\
+                        5 : int\n\
+                        \n");
+
+    let (status, output) = session("let type l = {hd: int, tl: l} var x := l {hd = 1, tl = nil} in x.tl := x; x end\n\
+                                    :ast 1 +\n2\n\
+                                    :frob\n\
+                                    (printi(getchar() = \"z\"); exit(4))\n\
+                                    z");
+    assert_eq!(status, 4);
+    assert!(output.starts_with("{hd = 1, tl = ...} : {hd: int, tl: l}\nOpExp {"));
+    assert!(output.contains("error: unknown command :frob"));
+    assert!(output.ends_with("1"));
+}
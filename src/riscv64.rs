@@ -0,0 +1,479 @@
+use assem::Instr;
+use codegen::{Codegen, ascii_literal};
+use frame::{Access, Frame, shift_view};
+use gc::{PointerMap, pointer_map_table};
+use ir::{Exp, Stm, BinOp, RelOp};
+use symbol::SymbolTable;
+use temp::{Temp, Label, TempGenerator};
+
+// The RV64 (RV64IM) back end, with the standard calling convention: a0-a7
+// for the first arguments, the rest on the stack, s0 as the frame pointer.
+// Temp(i) is register xi.
+
+pub const ZERO: Temp = Temp(0);
+pub const RA: Temp = Temp(1);
+pub const SP: Temp = Temp(2);
+pub const S0: Temp = Temp(8);
+pub const A0: Temp = Temp(10);
+pub const T3: Temp = Temp(28);
+pub const T4: Temp = Temp(29);
+pub const T5: Temp = Temp(30);
+pub const T6: Temp = Temp(31);
+
+const REGISTER_NAMES: [&'static str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6"];
+
+pub const ARG_REGISTERS: [Temp; 8] = [Temp(10), Temp(11), Temp(12), Temp(13), Temp(14), Temp(15), Temp(16), Temp(17)];
+// s1-s11; s0 is the frame pointer
+pub const CALLEE_SAVES: [Temp; 11] = [Temp(9), Temp(18), Temp(19), Temp(20), Temp(21), Temp(22), Temp(23),
+                                      Temp(24), Temp(25), Temp(26), Temp(27)];
+pub const CALLER_SAVES: [Temp; 16] = [RA, Temp(5), Temp(6), Temp(7), Temp(10), Temp(11), Temp(12), Temp(13),
+                                      Temp(14), Temp(15), Temp(16), Temp(17), T3, T4, T5, T6];
+
+const WORD: i32 = 8;
+
+// the return address and the caller's frame pointer are saved just below
+// the frame pointer
+const SAVED_REGISTERS_SIZE: i32 = 2 * WORD;
+
+fn fits_imm12(i: i32) -> bool {
+    i >= -2048 && i <= 2047
+}
+
+#[derive(Clone)]
+pub struct RiscVFrame {
+    name: Label,
+    formals: Vec<Access>,
+    locals: i32,
+}
+
+impl RiscVFrame {
+    pub fn frame_size(&self) -> i32 {
+        (SAVED_REGISTERS_SIZE + self.locals * WORD + 15) / 16 * 16
+    }
+}
+
+// arguments after the eighth are where the caller's stack pointer was
+fn stack_arg_offset(i: usize) -> i32 {
+    (i - ARG_REGISTERS.len()) as i32 * WORD
+}
+
+impl Frame for RiscVFrame {
+    fn new_frame(name: Label, formals_escape: Vec<bool>, gen: &mut TempGenerator) -> RiscVFrame {
+        let mut frame = RiscVFrame { name: name, formals: vec![], locals: 0 };
+        for (i, &escape) in formals_escape.iter().enumerate() {
+            let access = if escape && i >= ARG_REGISTERS.len() {
+                Access::InFrame(stack_arg_offset(i))
+            } else {
+                frame.alloc_local(escape, gen)
+            };
+            frame.formals.push(access);
+        }
+        frame
+    }
+
+    fn name(&self) -> Label {
+        self.name
+    }
+
+    fn formals(&self) -> &[Access] {
+        &self.formals
+    }
+
+    fn alloc_local(&mut self, escape: bool, gen: &mut TempGenerator) -> Access {
+        if escape {
+            self.locals += 1;
+            Access::InFrame(-SAVED_REGISTERS_SIZE - self.locals * WORD)
+        } else {
+            Access::InReg(gen.new_temp())
+        }
+    }
+
+    fn fp() -> Temp {
+        S0
+    }
+
+    fn rv() -> Temp {
+        A0
+    }
+
+    fn word_size() -> i32 {
+        WORD
+    }
+
+    fn external_name(name: &str) -> String {
+        format!("tig_{}", name)
+    }
+
+    fn proc_entry_exit1(&self, body: Stm, gen: &mut TempGenerator) -> Stm {
+        shift_view(self, body, &ARG_REGISTERS, &|i| Access::InFrame(stack_arg_offset(i)), &CALLEE_SAVES, gen)
+    }
+}
+
+pub struct RiscVCodegen;
+
+fn branch(op: RelOp) -> &'static str {
+    match op {
+        RelOp::Eq => "beq",
+        RelOp::Ne => "bne",
+        RelOp::Lt => "blt",
+        RelOp::Gt => "bgt",
+        RelOp::Le => "ble",
+        RelOp::Ge => "bge",
+        RelOp::ULt => "bltu",
+        RelOp::UGt => "bgtu",
+        RelOp::ULe => "bleu",
+        RelOp::UGe => "bgeu",
+    }
+}
+
+struct Muncher<'a> {
+    gen: &'a mut TempGenerator,
+    symbol_table: &'a SymbolTable,
+    instrs: Vec<Instr>,
+}
+
+impl<'a> Muncher<'a> {
+    fn emit(&mut self, assem: String, dst: Vec<Temp>, src: Vec<Temp>) {
+        self.instrs.push(Instr::oper(assem, dst, src));
+    }
+
+    fn emit_move(&mut self, dst: Temp, src: Temp) {
+        self.instrs.push(Instr::Move { assem: String::from("mv `d0, `s0"), dst: dst, src: src });
+    }
+
+    fn label_name(&self, l: Label) -> String {
+        l.name(self.symbol_table).clone()
+    }
+
+    // the base temp and offset of a memory operand at address e
+    fn munch_address(&mut self, e: &Exp) -> (Temp, i32) {
+        match e {
+            &Exp::BinOp(BinOp::Plus, ref a, ref b) => match (a.as_ref(), b.as_ref()) {
+                (a, &Exp::Const(i)) | (&Exp::Const(i), a) if fits_imm12(i) => (self.munch_exp(a), i),
+                _ => (self.munch_exp(e), 0),
+            },
+            &Exp::BinOp(BinOp::Minus, ref a, ref b) => match b.as_ref() {
+                &Exp::Const(i) if i != i32::min_value() && fits_imm12(-i) => (self.munch_exp(a), -i),
+                _ => (self.munch_exp(e), 0),
+            },
+            e => (self.munch_exp(e), 0),
+        }
+    }
+
+    // a register holding the value of e, using the zero register for 0
+    fn munch_operand(&mut self, e: &Exp) -> Temp {
+        match e {
+            &Exp::Const(0) => ZERO,
+            e => self.munch_exp(e),
+        }
+    }
+
+    fn munch_stm(&mut self, stm: &Stm) {
+        match stm {
+            &Stm::Move(ref dst, ref src) => match (dst.as_ref(), src.as_ref()) {
+                (&Exp::Mem(ref address), src) => {
+                    let (base, offset) = self.munch_address(address);
+                    let src = self.munch_operand(src);
+                    self.emit(format!("sd `s0, {}(`s1)", offset), vec![], vec![src, base]);
+                },
+                (&Exp::Temp(t), &Exp::Const(i)) => self.emit(format!("li `d0, {}", i), vec![t], vec![]),
+                (&Exp::Temp(t), &Exp::Mem(ref address)) => {
+                    let (base, offset) = self.munch_address(address);
+                    self.emit(format!("ld `d0, {}(`s0)", offset), vec![t], vec![base]);
+                },
+                (&Exp::Temp(t), src) => {
+                    let src = self.munch_exp(src);
+                    self.emit_move(t, src);
+                },
+                _ => panic!("cannot move to {:?}", dst),
+            },
+            &Stm::Exp(ref e) => match e.as_ref() {
+                &Exp::Call(ref f, ref args) => self.munch_call(f, args),
+                e => {
+                    self.munch_exp(e);
+                },
+            },
+            &Stm::Jump(ref e, ref labels) => match e.as_ref() {
+                &Exp::Name(l) => {
+                    self.instrs.push(Instr::Oper {
+                        assem: String::from("j `j0"),
+                        dst: vec![],
+                        src: vec![],
+                        jump: Some(vec![l]),
+                    });
+                },
+                e => {
+                    let t = self.munch_exp(e);
+                    self.instrs.push(Instr::Oper {
+                        assem: String::from("jr `s0"),
+                        dst: vec![],
+                        src: vec![t],
+                        jump: Some(labels.clone()),
+                    });
+                },
+            },
+            &Stm::CJump(op, ref a, ref b, t, f) => {
+                let a = self.munch_operand(a);
+                let b = self.munch_operand(b);
+                self.instrs.push(Instr::Oper {
+                    assem: format!("{} `s0, `s1, `j0", branch(op)),
+                    dst: vec![],
+                    src: vec![a, b],
+                    jump: Some(vec![t, f]),
+                });
+            },
+            &Stm::Label(l) => {
+                let assem = format!("{}:", self.label_name(l));
+                self.instrs.push(Instr::Label { assem: assem, label: l });
+            },
+            &Stm::Seq(_, _) => panic!("SEQ in canonical IR"),
+        }
+    }
+
+    fn munch_call(&mut self, f: &Exp, args: &[Exp]) {
+        let name = match f {
+            &Exp::Name(l) => self.label_name(l),
+            f => panic!("cannot call {:?}", f),
+        };
+
+        let temps: Vec<Temp> = args.iter().map(|a| self.munch_exp(a)).collect();
+        let in_registers = temps.len().min(ARG_REGISTERS.len());
+        // arguments after the eighth go to the bottom of the stack, which
+        // stays 16-byte aligned
+        let stack_size = ((temps.len() - in_registers) as i32 * WORD + 15) / 16 * 16;
+        if stack_size > 0 {
+            self.emit(format!("addi `d0, `s0, {}", -stack_size), vec![SP], vec![SP]);
+        }
+        for (i, &t) in temps[in_registers..].iter().enumerate() {
+            self.emit(format!("sd `s0, {}(`s1)", i as i32 * WORD), vec![], vec![t, SP]);
+        }
+        for (&t, &r) in temps.iter().zip(ARG_REGISTERS.iter()) {
+            self.emit_move(r, t);
+        }
+
+        self.emit(format!("call {}", name), CALLER_SAVES.to_vec(), ARG_REGISTERS[..in_registers].to_vec());
+        if stack_size > 0 {
+            self.emit(format!("addi `d0, `s0, {}", stack_size), vec![SP], vec![SP]);
+        }
+    }
+
+    fn munch_exp(&mut self, exp: &Exp) -> Temp {
+        match exp {
+            &Exp::Const(i) => {
+                let r = self.gen.new_temp();
+                self.emit(format!("li `d0, {}", i), vec![r], vec![]);
+                r
+            },
+            &Exp::Temp(t) => t,
+            &Exp::Name(l) => {
+                let r = self.gen.new_temp();
+                let name = self.label_name(l);
+                self.emit(format!("la `d0, {}", name), vec![r], vec![]);
+                r
+            },
+            &Exp::Mem(ref address) => {
+                let r = self.gen.new_temp();
+                let (base, offset) = self.munch_address(address);
+                self.emit(format!("ld `d0, {}(`s0)", offset), vec![r], vec![base]);
+                r
+            },
+            &Exp::BinOp(op, ref a, ref b) => {
+                let mnemonic = match op {
+                    BinOp::Plus => "add",
+                    BinOp::Minus => "sub",
+                    BinOp::Mul => "mul",
+                    BinOp::Div => "div",
+                    BinOp::And => "and",
+                    BinOp::Or => "or",
+                    BinOp::Xor => "xor",
+                    BinOp::LShift => "sll",
+                    BinOp::RShift => "srl",
+                    BinOp::ARShift => "sra",
+                };
+                // the instruction with an immediate operand, and the
+                // immediate it needs for b
+                let immediate = |i: i32| match op {
+                    BinOp::Plus if fits_imm12(i) => Some(("addi", i)),
+                    BinOp::Minus if i != i32::min_value() && fits_imm12(-i) => Some(("addi", -i)),
+                    BinOp::And if fits_imm12(i) => Some(("andi", i)),
+                    BinOp::Or if fits_imm12(i) => Some(("ori", i)),
+                    BinOp::Xor if fits_imm12(i) => Some(("xori", i)),
+                    BinOp::LShift if i >= 0 && i < 64 => Some(("slli", i)),
+                    BinOp::RShift if i >= 0 && i < 64 => Some(("srli", i)),
+                    BinOp::ARShift if i >= 0 && i < 64 => Some(("srai", i)),
+                    _ => None,
+                };
+                let commutes = match op {
+                    BinOp::Plus | BinOp::And | BinOp::Or | BinOp::Xor => true,
+                    _ => false,
+                };
+
+                let with_immediate = match (a.as_ref(), b.as_ref()) {
+                    (a, &Exp::Const(i)) => immediate(i).map(|imm| (a, imm)),
+                    (&Exp::Const(i), b) if commutes => immediate(i).map(|imm| (b, imm)),
+                    _ => None,
+                };
+                let r = self.gen.new_temp();
+                match with_immediate {
+                    Some((a, (mnemonic, i))) => {
+                        let a = self.munch_operand(a);
+                        self.emit(format!("{} `d0, `s0, {}", mnemonic, i), vec![r], vec![a]);
+                    },
+                    None => {
+                        let a = self.munch_operand(a);
+                        let b = self.munch_operand(b);
+                        self.emit(format!("{} `d0, `s0, `s1", mnemonic), vec![r], vec![a, b]);
+                    },
+                }
+                r
+            },
+            &Exp::Call(ref f, ref args) => {
+                self.munch_call(f, args);
+                let r = self.gen.new_temp();
+                self.emit_move(r, A0);
+                r
+            },
+            &Exp::ESeq(_, _) => panic!("ESEQ in canonical IR"),
+        }
+    }
+}
+
+impl Codegen for RiscVCodegen {
+    type Frame = RiscVFrame;
+
+    fn codegen(&self, _: &RiscVFrame, stm: Stm, gen: &mut TempGenerator, symbol_table: &SymbolTable) -> Vec<Instr> {
+        let mut muncher = Muncher {
+            gen: gen,
+            symbol_table: symbol_table,
+            instrs: vec![],
+        };
+        muncher.munch_stm(&stm);
+        muncher.instrs
+    }
+
+    fn proc_entry_exit2(&self, _: &RiscVFrame, mut body: Vec<Instr>) -> Vec<Instr> {
+        let mut live = vec![ZERO, A0, SP, S0];
+        live.extend(CALLEE_SAVES.iter());
+        body.push(Instr::oper(String::new(), vec![], live));
+        body
+    }
+
+    fn proc_entry_exit3(&self, frame: &RiscVFrame, symbol_table: &SymbolTable) -> (String, String) {
+        let name = frame.name.name(symbol_table);
+        let mut prologue = format!("\t.globl {}\n{}:\n", name, name);
+        prologue.push_str(&format!("\taddi sp, sp, -{}\n\tsd ra, {}(sp)\n\tsd s0, 0(sp)\n\taddi s0, sp, {}\n",
+                                   SAVED_REGISTERS_SIZE, WORD, SAVED_REGISTERS_SIZE));
+        let locals_size = frame.frame_size() - SAVED_REGISTERS_SIZE;
+        if fits_imm12(-locals_size) {
+            prologue.push_str(&format!("\taddi sp, sp, -{}\n", locals_size));
+        } else {
+            prologue.push_str(&format!("\tli t0, {}\n\tsub sp, sp, t0\n", locals_size));
+        }
+        let epilogue = format!("\tld ra, -{}(s0)\n\tmv t0, s0\n\tld s0, -{}(s0)\n\tmv sp, t0\n\tret\n",
+                               WORD, SAVED_REGISTERS_SIZE);
+        (prologue, epilogue)
+    }
+
+    fn file_header(&self) -> String {
+        String::from("\t.text\n")
+    }
+
+    fn data_section(&self) -> String {
+        String::from("\t.data\n")
+    }
+
+    fn string(&self, label: Label, s: &str, symbol_table: &SymbolTable) -> String {
+        format!("\t.balign 8\n{}:\n\t.dword {}\n\t.ascii {}\n",
+                label.name(symbol_table), s.chars().count(), ascii_literal(s))
+    }
+
+    fn register_name(&self, t: Temp) -> String {
+        match REGISTER_NAMES.get(t.0 as usize) {
+            Some(name) => name.to_string(),
+            None => format!("t{}", t.0),
+        }
+    }
+
+    // frame offsets beyond an immediate go through t3, which instruction
+    // selection never uses
+    fn load(&self, dst: Temp, offset: i32) -> Vec<Instr> {
+        if fits_imm12(offset) {
+            vec![Instr::oper(format!("ld `d0, {}(`s0)", offset), vec![dst], vec![S0])]
+        } else {
+            vec![Instr::oper(format!("li `d0, {}", offset), vec![T3], vec![]),
+                 Instr::oper(String::from("add `d0, `s0, `s1"), vec![T3], vec![T3, S0]),
+                 Instr::oper(String::from("ld `d0, 0(`s0)"), vec![dst], vec![T3])]
+        }
+    }
+
+    fn store(&self, src: Temp, offset: i32) -> Vec<Instr> {
+        if fits_imm12(offset) {
+            vec![Instr::oper(format!("sd `s0, {}(`s1)", offset), vec![], vec![src, S0])]
+        } else {
+            vec![Instr::oper(format!("li `d0, {}", offset), vec![T3], vec![]),
+                 Instr::oper(String::from("add `d0, `s0, `s1"), vec![T3], vec![T3, S0]),
+                 Instr::oper(String::from("sd `s0, 0(`s1)"), vec![], vec![src, T3])]
+        }
+    }
+
+    // t3 is left for the addressing of distant frame slots
+    fn registers(&self) -> Vec<Temp> {
+        CALLER_SAVES.iter().chain(CALLEE_SAVES.iter()).cloned().filter(|&r| r != T3).collect()
+    }
+
+    fn is_call(&self, instr: &Instr) -> bool {
+        match instr {
+            &Instr::Oper { ref assem, .. } => assem.starts_with("call "),
+            _ => false,
+        }
+    }
+
+    fn pointer_map_table(&self, maps: &[PointerMap], symbol_table: &SymbolTable) -> String {
+        format!("\t.balign 8\n{}", pointer_map_table(maps, ".dword", symbol_table))
+    }
+}
+
+#[test]
+fn test_munch() {
+    let mut table = SymbolTable::new();
+    let frame = RiscVFrame::new_frame(Label(table.symbol("f")), vec![], &mut TempGenerator::new());
+    let f = Label(table.symbol("g"));
+    let (t, a) = (Temp(200), Temp(201));
+    let codegen = RiscVCodegen;
+    let name = |t: Temp| codegen.register_name(t);
+    let label = |l: Label| l.name(&table).clone();
+    let munch = |stm: Stm| -> Vec<String> {
+        let instrs = codegen.codegen(&frame, stm, &mut TempGenerator::new(), &table);
+        instrs.iter().map(|i| i.format(&name, &label)).collect()
+    };
+    let temp = |t: Temp| Box::new(Exp::Temp(t));
+    let plus = |a: Box<Exp>, i: i32| Box::new(Exp::BinOp(BinOp::Plus, a, Box::new(Exp::Const(i))));
+
+    // immediates take 12 bits: 2047 fits an offset and an addi, 2048 is
+    // loaded into a register first, and so is subtracting -2048
+    assert_eq!(munch(Stm::Move(Box::new(Exp::Mem(plus(temp(t), 2047))), temp(a))), vec!["sd t201, 2047(t200)"]);
+    assert_eq!(munch(Stm::Move(Box::new(Exp::Mem(plus(temp(t), 2048))), temp(a))),
+               vec!["li t101, 2048", "add t100, t200, t101", "sd t201, 0(t100)"]);
+    assert_eq!(munch(Stm::Move(temp(t), Box::new(Exp::BinOp(BinOp::Minus, temp(t), Box::new(Exp::Const(2048)))))),
+               vec!["addi t100, t200, -2048", "mv t200, t100"]);
+    assert_eq!(munch(Stm::Move(temp(t), Box::new(Exp::BinOp(BinOp::Minus, temp(t), Box::new(Exp::Const(-2048)))))),
+               vec!["li t101, -2048", "sub t100, t200, t101", "mv t200, t100"]);
+    let instrs = codegen.load(a, -4096);
+    assert_eq!(instrs.iter().map(|i| i.format(&name, &label)).collect::<Vec<String>>(),
+               vec!["li t3, -4096", "add t3, t3, s0", "ld t201, 0(t3)"]);
+
+    // the ninth and tenth arguments go below a 16-byte aligned stack pointer,
+    // where the callee finds its formals
+    let args = (0..10).map(|i| Exp::Temp(Temp(210 + i))).collect();
+    let call = munch(Stm::Exp(Box::new(Exp::Call(Box::new(Exp::Name(f)), args))));
+    assert_eq!(&call[..3], &["addi sp, sp, -16", "sd t218, 0(sp)", "sd t219, 8(sp)"]);
+    assert_eq!(&call[3..], &["mv a0, t210", "mv a1, t211", "mv a2, t212", "mv a3, t213", "mv a4, t214",
+                             "mv a5, t215", "mv a6, t216", "mv a7, t217", "call g", "addi sp, sp, 16"]);
+    let callee = RiscVFrame::new_frame(f, vec![true; 10], &mut TempGenerator::new());
+    match (&callee.formals()[8], &callee.formals()[9]) {
+        (&Access::InFrame(0), &Access::InFrame(8)) => (),
+        _ => panic!("stack formals should be at 0(s0) and 8(s0)"),
+    }
+}
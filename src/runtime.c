@@ -0,0 +1,234 @@
+/* The rest of the runtime system of Tiger programs compiled to assembly,
+   which follows c_runtime.h: the garbage collected heap, the allocation of
+   records and arrays, which compiled C does inline, and the entry point.
+
+   The collector copies the live objects from one semispace to the other
+   (Cheney's algorithm). Every object is preceded by a header word: the
+   descriptor of a record, a Tiger string with a 'p' for each field that
+   holds a pointer and an 'i' for the others, or one of the tags below. An
+   array is a pointer to its size, with the elements after it.
+
+   The roots are the variables registered by the runtime, and the frame
+   slots the compiler lists in the pointer maps of tig_pointer_maps for
+   the calls under way; compiled code keeps no pointer in a register
+   across a call. The stack is walked along the chain of frame pointers,
+   which the compiled code maintains and the runtime must be built with. */
+
+#define TIG_FORWARDED 1
+#define TIG_INT_ARRAY 5
+#define TIG_POINTER_ARRAY 7
+
+#if defined(__riscv)
+/* the frame pointer points above the return address and the saved one */
+#define TIG_CALLER_FRAME(fp) ((long *)(fp)[-2])
+#define TIG_RETURN_ADDRESS(fp) ((fp)[-1])
+#else
+#define TIG_CALLER_FRAME(fp) ((long *)(fp)[0])
+#define TIG_RETURN_ADDRESS(fp) ((fp)[1])
+#endif
+
+/* for each call: the return address, the number of slots holding
+   pointers and their offsets from the frame pointer; 0 at the end */
+extern long tig_pointer_maps[];
+
+static long **tig_maps;
+static long tig_map_count = -1;
+
+static long *tig_space;
+static long *tig_next;
+static long *tig_limit;
+static long tig_space_words = 1 << 16;
+
+static void **tig_roots[16];
+static long tig_root_count;
+
+void tig_gcRoot(void *root)
+{
+    if (tig_root_count == sizeof(tig_roots) / sizeof(tig_roots[0]))
+        tig_fail("too many garbage collection roots");
+    tig_roots[tig_root_count++] = root;
+}
+
+void tig_gcUnroot(long n)
+{
+    tig_root_count -= n;
+}
+
+static int tig_compare_maps(const void *a, const void *b)
+{
+    long x = (*(long **)a)[0], y = (*(long **)b)[0];
+    return x < y ? -1 : x > y;
+}
+
+/* the pointer map of the call returning to address, if it is one */
+static long *tig_find_map(long address)
+{
+    long lo = 0, hi;
+    if (tig_map_count < 0) {
+        long *m;
+        tig_map_count = 0;
+        for (m = tig_pointer_maps; m[0]; m += 2 + m[1])
+            tig_map_count++;
+        tig_maps = malloc((tig_map_count + 1) * sizeof(long *));
+        if (!tig_maps)
+            tig_fail("out of memory");
+        tig_map_count = 0;
+        for (m = tig_pointer_maps; m[0]; m += 2 + m[1])
+            tig_maps[tig_map_count++] = m;
+        qsort(tig_maps, tig_map_count, sizeof(long *), tig_compare_maps);
+    }
+    hi = tig_map_count;
+    while (lo < hi) {
+        long mid = (lo + hi) / 2;
+        if (tig_maps[mid][0] < address)
+            lo = mid + 1;
+        else
+            hi = mid;
+    }
+    return lo < tig_map_count && tig_maps[lo][0] == address ? tig_maps[lo] : NULL;
+}
+
+/* the words of the object at p, besides its header; at least one, for
+   the forwarding address */
+static long tig_object_words(long *p)
+{
+    long header = p[-1], words;
+    if (header == TIG_STRING)
+        words = 1 + (p[0] + 7) / 8;
+    else if (header == TIG_INT_ARRAY || header == TIG_POINTER_ARRAY)
+        words = 1 + p[0];
+    else
+        words = ((struct tig_string *)header)->length;
+    return words ? words : 1;
+}
+
+static long *tig_old_space;
+static long *tig_old_limit;
+
+/* the new address of the object at p, copying it on the first visit;
+   nil and strings in the data section stay where they are */
+static long tig_forward(long p)
+{
+    long *object = (long *)p, *copy;
+    long words;
+    if (object <= tig_old_space || object >= tig_old_limit)
+        return p;
+    if (object[-1] == TIG_FORWARDED)
+        return object[0];
+    words = tig_object_words(object);
+    memcpy(tig_next, object - 1, (words + 1) * sizeof(long));
+    copy = tig_next + 1;
+    tig_next += words + 1;
+    object[-1] = TIG_FORWARDED;
+    object[0] = (long)copy;
+    return (long)copy;
+}
+
+/* copies the live objects into a new space of tig_space_words words */
+static void tig_collect(void)
+{
+    long *fp, *scan, *to;
+    long i;
+    int in_tiger = 0;
+
+    to = malloc(tig_space_words * sizeof(long));
+    if (!to)
+        tig_fail("out of memory");
+    tig_old_space = tig_space;
+    tig_old_limit = tig_limit;
+    tig_space = tig_next = to;
+    tig_limit = to + tig_space_words;
+
+    for (i = 0; i < tig_root_count; i++)
+        *(long *)tig_roots[i] = tig_forward(*(long *)tig_roots[i]);
+
+    /* the frames of the runtime come first, then those of the program up
+       to the call of tigermain */
+    for (fp = __builtin_frame_address(0); fp; fp = TIG_CALLER_FRAME(fp)) {
+        long *map = tig_find_map(TIG_RETURN_ADDRESS(fp));
+        long *caller = TIG_CALLER_FRAME(fp);
+        if (!map) {
+            if (in_tiger)
+                break;
+            continue;
+        }
+        in_tiger = 1;
+        for (i = 0; i < map[1]; i++) {
+            long *slot = (long *)((char *)caller + map[2 + i]);
+            *slot = tig_forward(*slot);
+        }
+    }
+
+    for (scan = tig_space; scan < tig_next; scan += 1 + tig_object_words(scan + 1)) {
+        long header = scan[0], *object = scan + 1;
+        if (header == TIG_POINTER_ARRAY) {
+            for (i = 1; i <= object[0]; i++)
+                object[i] = tig_forward(object[i]);
+        } else if (!(header & 1)) {
+            struct tig_string *descriptor = (struct tig_string *)header;
+            for (i = 0; i < descriptor->length; i++)
+                if (descriptor->chars[i] == 'p')
+                    object[i] = tig_forward(object[i]);
+        }
+    }
+    free(tig_old_space);
+}
+
+/* a zeroed object of words words after its header */
+void *tig_gcAlloc(long words, long header)
+{
+    long *p;
+    if (words < 1)
+        words = 1;
+    if (!tig_space) {
+        tig_space = tig_next = malloc(tig_space_words * sizeof(long));
+        if (!tig_space)
+            tig_fail("out of memory");
+        tig_limit = tig_space + tig_space_words;
+    }
+    if (words + 1 > tig_limit - tig_next) {
+        tig_collect();
+        /* the heap grows when it stays more than half full */
+        if (2 * (tig_next - tig_space + words + 1) > tig_space_words) {
+            while (2 * (tig_next - tig_space + words + 1) > tig_space_words)
+                tig_space_words *= 2;
+            tig_collect();
+        }
+    }
+    p = tig_next;
+    tig_next += words + 1;
+    p[0] = header;
+    memset(p + 1, 0, words * sizeof(long));
+    return p + 1;
+}
+
+long *tig_initArray(long size, long init, long pointers)
+{
+    long *a;
+    long i;
+    tig_checkArraySize(size);
+    if (pointers)
+        tig_gcRoot(&init);
+    a = tig_gcAlloc(size + 1, pointers ? TIG_POINTER_ARRAY : TIG_INT_ARRAY);
+    if (pointers)
+        tig_gcUnroot(1);
+    a[0] = size;
+    for (i = 1; i <= size; i++)
+        a[i] = init;
+    return a;
+}
+
+void *tig_allocRecord(long size, struct tig_string *descriptor)
+{
+    return tig_gcAlloc(size / (long)sizeof(long), (long)descriptor);
+}
+
+/* the main program, whose static link is never followed */
+extern void tigermain(long static_link);
+
+int main(void)
+{
+    tigermain(0);
+    fflush(stdout);
+    return 0;
+}
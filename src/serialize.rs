@@ -0,0 +1,536 @@
+use ast::{Dec, Exp, Field, Oper, Position, Symbol, Ty, Var, node_id};
+use json::Json;
+use symbol::SymbolTable;
+use type_check::TypeMap;
+use types;
+
+use std::rc::Rc;
+
+// Syntax trees as JSON, with names rather than symbol numbers: every node
+// is an object whose "kind" is the name of its variant, followed by a
+// member for each of its fields and a "pos" where it has a position. Given
+// the types from the checker, expressions, variables, declarations and
+// parameters also get a "type", which reading a tree back ignores.
+// S-expressions write the same objects as (Kind :member value ...).
+
+fn node(kind: &str, members: Vec<(&str, Json)>) -> Json {
+    let mut all = vec![("kind", Json::str(kind))];
+    all.extend(members);
+    Json::object(all)
+}
+
+fn number(n: usize) -> Json {
+    Json::Number(n as f64)
+}
+
+const OPERS: [(Oper, &'static str); 10] = [
+    (Oper::PlusOp, "PlusOp"),
+    (Oper::MinusOp, "MinusOp"),
+    (Oper::TimesOp, "TimesOp"),
+    (Oper::DivideOp, "DivideOp"),
+    (Oper::EqOp, "EqOp"),
+    (Oper::NeqOp, "NeqOp"),
+    (Oper::LtOp, "LtOp"),
+    (Oper::LeOp, "LeOp"),
+    (Oper::GtOp, "GtOp"),
+    (Oper::GeOp, "GeOp"),
+];
+
+struct Writer<'a> {
+    symbol_table: &'a SymbolTable,
+    types: Option<&'a TypeMap>,
+}
+
+impl<'a> Writer<'a> {
+    fn name(&self, symbol: Symbol) -> Json {
+        Json::str(self.symbol_table.name(&symbol))
+    }
+
+    // a reference to a type by name, or null
+    fn type_id(&self, typ: &Option<(Symbol, Position)>) -> Json {
+        match typ {
+            &Some((name, pos)) => Json::object(vec![("name", self.name(name)), ("pos", number(pos))]),
+            &None => Json::Null,
+        }
+    }
+
+    fn typed(&self, json: Json, ty: Option<&Rc<types::Ty>>) -> Json {
+        match (json, ty) {
+            (Json::Object(mut members), Some(ty)) => {
+                members.push((String::from("type"), Json::str(&ty.name(self.symbol_table))));
+                Json::Object(members)
+            },
+            (json, _) => json,
+        }
+    }
+
+    fn exps(&self, exps: &[Box<Exp>]) -> Json {
+        Json::Array(exps.iter().map(|exp| self.exp(exp)).collect())
+    }
+
+    fn exp(&self, exp: &Exp) -> Json {
+        let json = match exp {
+            &Exp::VarExp(ref var, _) => node("VarExp", vec![("var", self.var(var))]),
+            &Exp::NilExp(_) => node("NilExp", vec![]),
+            &Exp::IntExp(i, _) => node("IntExp", vec![("value", Json::Number(i as f64))]),
+            &Exp::StringExp(ref s, pos, _) => node("StringExp", vec![("value", Json::str(s)), ("pos", number(pos))]),
+            &Exp::CallExp{ func, ref args, pos, .. } =>
+                node("CallExp", vec![("func", self.name(func)), ("args", self.exps(args)), ("pos", number(pos))]),
+            &Exp::OpExp{ ref left, op, ref right, pos, .. } => {
+                let op = OPERS.iter().find(|&&(o, _)| o == op).unwrap().1;
+                node("OpExp", vec![
+                    ("left", self.exp(left)),
+                    ("op", Json::str(op)),
+                    ("right", self.exp(right)),
+                    ("pos", number(pos)),
+                ])
+            },
+            &Exp::RecordExp{ ref fields, typ, pos, .. } => {
+                let fields = fields.iter()
+                    .map(|&(name, ref exp, pos)| {
+                        Json::object(vec![("name", self.name(name)), ("exp", self.exp(exp)), ("pos", number(pos))])
+                    })
+                    .collect();
+                node("RecordExp", vec![("fields", Json::Array(fields)), ("typ", self.name(typ)), ("pos", number(pos))])
+            },
+            &Exp::SeqExp(ref exps, _) => node("SeqExp", vec![("exps", self.exps(exps))]),
+            &Exp::AssignExp{ ref var, ref exp, pos, .. } =>
+                node("AssignExp", vec![("var", self.var(var)), ("exp", self.exp(exp)), ("pos", number(pos))]),
+            &Exp::IfExp{ ref test, ref then_, ref else_, pos, .. } => node("IfExp", vec![
+                ("test", self.exp(test)),
+                ("then", self.exp(then_)),
+                ("else", else_.as_ref().map_or(Json::Null, |e| self.exp(e))),
+                ("pos", number(pos)),
+            ]),
+            &Exp::WhileExp{ ref test, ref body, pos, .. } =>
+                node("WhileExp", vec![("test", self.exp(test)), ("body", self.exp(body)), ("pos", number(pos))]),
+            &Exp::ForExp{ var, escape, ref lo, ref hi, ref body, pos, .. } => node("ForExp", vec![
+                ("var", self.name(var)),
+                ("escape", Json::Bool(escape)),
+                ("lo", self.exp(lo)),
+                ("hi", self.exp(hi)),
+                ("body", self.exp(body)),
+                ("pos", number(pos)),
+            ]),
+            &Exp::BreakExp(pos, _) => node("BreakExp", vec![("pos", number(pos))]),
+            &Exp::LetExp{ ref decs, ref body, pos, .. } => node("LetExp", vec![
+                ("decs", Json::Array(decs.iter().map(|dec| self.dec(dec)).collect())),
+                ("body", self.exp(body)),
+                ("pos", number(pos)),
+            ]),
+            &Exp::ArrayExp{ typ, ref size, ref init, pos, .. } => node("ArrayExp", vec![
+                ("typ", self.name(typ)),
+                ("size", self.exp(size)),
+                ("init", self.exp(init)),
+                ("pos", number(pos)),
+            ]),
+        };
+        self.typed(json, self.types.and_then(|types| types.exp_ty(exp)))
+    }
+
+    fn var(&self, var: &Var) -> Json {
+        let json = match var {
+            &Var::SimpleVar(name, pos, _) => node("SimpleVar", vec![("name", self.name(name)), ("pos", number(pos))]),
+            &Var::FieldVar(ref v, name, pos, _) =>
+                node("FieldVar", vec![("var", self.var(v)), ("name", self.name(name)), ("pos", number(pos))]),
+            &Var::SubscriptVar(ref v, ref exp, pos, _) =>
+                node("SubscriptVar", vec![("var", self.var(v)), ("exp", self.exp(exp)), ("pos", number(pos))]),
+        };
+        self.typed(json, self.types.and_then(|types| types.var_ty(var)))
+    }
+
+    fn fields(&self, fields: &[Box<Field>]) -> Json {
+        let fields = fields.iter()
+            .map(|field| {
+                let json = Json::object(vec![
+                    ("name", self.name(field.name)),
+                    ("escape", Json::Bool(field.escape)),
+                    ("typ", self.name(field.typ)),
+                    ("pos", number(field.pos)),
+                ]);
+                self.typed(json, self.types.and_then(|types| types.param_ty(field)))
+            })
+            .collect();
+        Json::Array(fields)
+    }
+
+    fn dec(&self, dec: &Dec) -> Json {
+        let json = match dec {
+            &Dec::FunDec{ name, ref params, ref result, ref body, pos, .. } => node("FunDec", vec![
+                ("name", self.name(name)),
+                ("params", self.fields(params)),
+                ("result", self.type_id(result)),
+                ("body", self.exp(body)),
+                ("pos", number(pos)),
+            ]),
+            &Dec::VarDec{ name, escape, ref typ, ref init, pos, .. } => node("VarDec", vec![
+                ("name", self.name(name)),
+                ("escape", Json::Bool(escape)),
+                ("typ", self.type_id(typ)),
+                ("init", self.exp(init)),
+                ("pos", number(pos)),
+            ]),
+            &Dec::TypeDec{ name, ref ty, pos, .. } =>
+                node("TypeDec", vec![("name", self.name(name)), ("ty", self.ty(ty)), ("pos", number(pos))]),
+        };
+        self.typed(json, self.types.and_then(|types| types.dec_ty(dec)))
+    }
+
+    fn ty(&self, ty: &Ty) -> Json {
+        match ty {
+            &Ty::NameTy(name, pos) => node("NameTy", vec![("name", self.name(name)), ("pos", number(pos))]),
+            &Ty::RecordTy(ref fields) => node("RecordTy", vec![("fields", self.fields(fields))]),
+            &Ty::ArrayTy(name, pos) => node("ArrayTy", vec![("name", self.name(name)), ("pos", number(pos))]),
+        }
+    }
+}
+
+// the tree of an expression as JSON, with the types of its parts if there
+// are any
+pub fn to_json(exp: &Exp, symbol_table: &SymbolTable, types: Option<&TypeMap>) -> Json {
+    Writer { symbol_table: symbol_table, types: types }.exp(exp)
+}
+
+fn kind(json: &Json) -> &str {
+    json.get("kind").as_str().unwrap_or("node")
+}
+
+fn member<'j>(json: &'j Json, key: &str) -> Result<&'j Json, String> {
+    match json.get(key) {
+        &Json::Null => Err(format!("{} has no {}", kind(json), key)),
+        value => Ok(value),
+    }
+}
+
+fn string<'j>(json: &'j Json, key: &str) -> Result<&'j str, String> {
+    member(json, key)?.as_str().ok_or_else(|| format!("the {} of {} is not a string", key, kind(json)))
+}
+
+fn integer(json: &Json, key: &str) -> Result<i64, String> {
+    member(json, key)?.as_i64().ok_or_else(|| format!("the {} of {} is not an integer", key, kind(json)))
+}
+
+fn array<'j>(json: &'j Json, key: &str) -> Result<&'j Vec<Json>, String> {
+    member(json, key)?.as_array().ok_or_else(|| format!("the {} of {} is not an array", key, kind(json)))
+}
+
+fn pos(json: &Json) -> Result<Position, String> {
+    let pos = integer(json, "pos")?;
+    if pos < 0 {
+        return Err(format!("{} has a negative pos", kind(json)));
+    }
+    Ok(pos as Position)
+}
+
+// whether a variable escapes, false if the tree does not say
+fn escape(json: &Json) -> bool {
+    json.get("escape").as_bool().unwrap_or(false)
+}
+
+struct Reader<'a> {
+    symbol_table: &'a mut SymbolTable,
+}
+
+impl<'a> Reader<'a> {
+    fn symbol(&mut self, json: &Json, key: &str) -> Result<Symbol, String> {
+        let name = string(json, key)?;
+        Ok(self.symbol_table.symbol(name))
+    }
+
+    fn type_id(&mut self, json: &Json, key: &str) -> Result<Option<(Symbol, Position)>, String> {
+        match json.get(key) {
+            &Json::Null => Ok(None),
+            typ => Ok(Some((self.symbol(typ, "name")?, pos(typ)?))),
+        }
+    }
+
+    fn exps(&mut self, json: &Json, key: &str) -> Result<Vec<Box<Exp>>, String> {
+        array(json, key)?.iter().map(|exp| self.exp(exp)).collect()
+    }
+
+    fn member_exp(&mut self, json: &Json, key: &str) -> Result<Box<Exp>, String> {
+        self.exp(member(json, key)?)
+    }
+
+    fn exp(&mut self, json: &Json) -> Result<Box<Exp>, String> {
+        let exp = match string(json, "kind")? {
+            "VarExp" => Exp::VarExp(self.var(member(json, "var")?)?, node_id()),
+            "NilExp" => Exp::NilExp(node_id()),
+            "IntExp" => {
+                let value = integer(json, "value")?;
+                if value < i32::min_value() as i64 || value > i32::max_value() as i64 {
+                    return Err(format!("integer {} is out of range", value));
+                }
+                Exp::IntExp(value as i32, node_id())
+            },
+            "StringExp" => Exp::StringExp(string(json, "value")?.to_owned(), pos(json)?, node_id()),
+            "CallExp" => Exp::CallExp {
+                func: self.symbol(json, "func")?,
+                args: self.exps(json, "args")?,
+                pos: pos(json)?,
+                id: node_id(),
+            },
+            "OpExp" => {
+                let op = string(json, "op")?;
+                let op = match OPERS.iter().find(|&&(_, name)| name == op) {
+                    Some(&(op, _)) => op,
+                    None => return Err(format!("unknown operator {}", op)),
+                };
+                Exp::OpExp {
+                    left: self.member_exp(json, "left")?,
+                    op: op,
+                    right: self.member_exp(json, "right")?,
+                    pos: pos(json)?,
+                id: node_id(),
+                }
+            },
+            "RecordExp" => {
+                let mut fields = vec![];
+                for field in array(json, "fields")?.iter() {
+                    fields.push((self.symbol(field, "name")?, self.member_exp(field, "exp")?, pos(field)?));
+                }
+                Exp::RecordExp {
+                    fields: fields,
+                    typ: self.symbol(json, "typ")?,
+                    pos: pos(json)?,
+                id: node_id(),
+                }
+            },
+            "SeqExp" => Exp::SeqExp(self.exps(json, "exps")?, node_id()),
+            "AssignExp" => Exp::AssignExp {
+                var: self.var(member(json, "var")?)?,
+                exp: self.member_exp(json, "exp")?,
+                pos: pos(json)?,
+                id: node_id(),
+            },
+            "IfExp" => Exp::IfExp {
+                test: self.member_exp(json, "test")?,
+                then_: self.member_exp(json, "then")?,
+                else_: match json.get("else") {
+                    &Json::Null => None,
+                    else_ => Some(self.exp(else_)?),
+                },
+                pos: pos(json)?,
+                id: node_id(),
+            },
+            "WhileExp" => Exp::WhileExp {
+                test: self.member_exp(json, "test")?,
+                body: self.member_exp(json, "body")?,
+                pos: pos(json)?,
+                id: node_id(),
+            },
+            "ForExp" => Exp::ForExp {
+                var: self.symbol(json, "var")?,
+                escape: escape(json),
+                lo: self.member_exp(json, "lo")?,
+                hi: self.member_exp(json, "hi")?,
+                body: self.member_exp(json, "body")?,
+                pos: pos(json)?,
+                id: node_id(),
+            },
+            "BreakExp" => Exp::BreakExp(pos(json)?, node_id()),
+            "LetExp" => {
+                let mut decs = vec![];
+                for dec in array(json, "decs")?.iter() {
+                    decs.push(self.dec(dec)?);
+                }
+                Exp::LetExp {
+                    decs: decs,
+                    body: self.member_exp(json, "body")?,
+                    pos: pos(json)?,
+                id: node_id(),
+                }
+            },
+            "ArrayExp" => Exp::ArrayExp {
+                typ: self.symbol(json, "typ")?,
+                size: self.member_exp(json, "size")?,
+                init: self.member_exp(json, "init")?,
+                pos: pos(json)?,
+                id: node_id(),
+            },
+            kind => return Err(format!("unknown expression {}", kind)),
+        };
+        Ok(Box::new(exp))
+    }
+
+    fn var(&mut self, json: &Json) -> Result<Box<Var>, String> {
+        let var = match string(json, "kind")? {
+            "SimpleVar" => Var::SimpleVar(self.symbol(json, "name")?, pos(json)?, node_id()),
+            "FieldVar" => Var::FieldVar(self.var(member(json, "var")?)?, self.symbol(json, "name")?, pos(json)?, node_id()),
+            "SubscriptVar" =>
+                Var::SubscriptVar(self.var(member(json, "var")?)?, self.member_exp(json, "exp")?, pos(json)?, node_id()),
+            kind => return Err(format!("unknown variable {}", kind)),
+        };
+        Ok(Box::new(var))
+    }
+
+    fn fields(&mut self, json: &Json, key: &str) -> Result<Vec<Box<Field>>, String> {
+        let mut fields = vec![];
+        for field in array(json, key)?.iter() {
+            fields.push(Box::new(Field {
+                name: self.symbol(field, "name")?,
+                escape: escape(field),
+                typ: self.symbol(field, "typ")?,
+                pos: pos(field)?,
+                id: node_id(),
+            }));
+        }
+        Ok(fields)
+    }
+
+    fn dec(&mut self, json: &Json) -> Result<Box<Dec>, String> {
+        let dec = match string(json, "kind")? {
+            "FunDec" => Dec::FunDec {
+                name: self.symbol(json, "name")?,
+                params: self.fields(json, "params")?,
+                result: self.type_id(json, "result")?,
+                body: self.member_exp(json, "body")?,
+                pos: pos(json)?,
+                id: node_id(),
+            },
+            "VarDec" => Dec::VarDec {
+                name: self.symbol(json, "name")?,
+                escape: escape(json),
+                typ: self.type_id(json, "typ")?,
+                init: self.member_exp(json, "init")?,
+                pos: pos(json)?,
+                id: node_id(),
+            },
+            "TypeDec" => Dec::TypeDec {
+                name: self.symbol(json, "name")?,
+                ty: self.ty(member(json, "ty")?)?,
+                pos: pos(json)?,
+                id: node_id(),
+            },
+            kind => return Err(format!("unknown declaration {}", kind)),
+        };
+        Ok(Box::new(dec))
+    }
+
+    fn ty(&mut self, json: &Json) -> Result<Box<Ty>, String> {
+        let ty = match string(json, "kind")? {
+            "NameTy" => Ty::NameTy(self.symbol(json, "name")?, pos(json)?),
+            "RecordTy" => Ty::RecordTy(self.fields(json, "fields")?),
+            "ArrayTy" => Ty::ArrayTy(self.symbol(json, "name")?, pos(json)?),
+            kind => return Err(format!("unknown type {}", kind)),
+        };
+        Ok(Box::new(ty))
+    }
+}
+
+// the expression of a tree written by to_json, with its names entered in
+// symbol_table
+pub fn from_json(json: &Json, symbol_table: &mut SymbolTable) -> Result<Box<Exp>, String> {
+    Reader { symbol_table: symbol_table }.exp(json)
+}
+
+fn write_sexp(out: &mut String, json: &Json, indent: usize) {
+    let (open, items): (String, Vec<(Option<&str>, &Json)>) = match json {
+        &Json::Null => return out.push_str("nil"),
+        &Json::Object(ref members) => {
+            let mut members = &members[..];
+            let mut open = String::from("(");
+            if let Some(&(ref key, Json::String(ref kind))) = members.first() {
+                if key == "kind" {
+                    open.push_str(kind);
+                    members = &members[1..];
+                }
+            }
+            (open, members.iter().map(|&(ref key, ref value)| (Some(key.as_str()), value)).collect())
+        },
+        &Json::Array(ref elements) => (String::from("("), elements.iter().map(|e| (None, e)).collect()),
+        json => return out.push_str(&json.to_string()),
+    };
+    // on one line unless there is more than one level of nesting
+    let flat = !items.iter().any(|&(_, value)| value.is_compound());
+    out.push_str(&open);
+    for (i, &(key, value)) in items.iter().enumerate() {
+        if flat || (i == 0 && open.len() == 1) {
+            if i > 0 || open.len() > 1 {
+                out.push(' ');
+            }
+        } else {
+            out.push('\n');
+            for _ in 0..indent + 2 {
+                out.push(' ');
+            }
+        }
+        if let Some(key) = key {
+            out.push(':');
+            out.push_str(key);
+            out.push(' ');
+        }
+        write_sexp(out, value, indent + 2);
+    }
+    out.push(')');
+}
+
+// a tree written by to_json as an S-expression
+pub fn to_sexp(json: &Json) -> String {
+    let mut out = String::new();
+    write_sexp(&mut out, json, 0);
+    out
+}
+
+#[test]
+fn test_serialize() {
+    use parser::parse;
+    use type_check::type_check;
+
+    let source = "let type list = {hd: int, tl: list} \
+                  var l: list := list {hd = 1, tl = nil} \
+                  function f(x: int): string = if x > 0 & x <> 3 then \"p\\n\" else (x := -x; \"n\") \
+                  in while l <> nil do (print(f(l.hd)); l := l.tl); \
+                     for i := 0 to 2 do if i = 1 then break; \
+                     let type a = array of int in a [2] of 0 end end";
+    let (exp, mut table) = parse(source).unwrap();
+    let types = type_check(&exp, &mut table).unwrap();
+
+    let json = to_json(&exp, &table, None);
+    let text = json.pretty();
+    assert_eq!(Json::parse(&text), Ok(json.clone()));
+    let mut other = SymbolTable::new();
+    let loaded = from_json(&json, &mut other).unwrap();
+    assert_eq!(to_json(&loaded, &other, None), json);
+
+    // the types are there to read, and ignored when loading
+    let typed = to_json(&exp, &table, Some(&types));
+    assert_eq!(typed.get("type").as_str(), Some("array of int"));
+    let dec = &typed.get("decs").as_array().unwrap()[1];
+    assert_eq!(dec.get("typ").get("name").as_str(), Some("list"));
+    assert_eq!(dec.get("type").as_str(), Some("list"));
+    assert_eq!(to_json(&from_json(&typed, &mut other).unwrap(), &other, None), json);
+
+    let (exp, table) = parse("f(a.b[2], -1)").unwrap();
+    assert_eq!(to_json(&exp, &table, None).to_string(),
+               "{\"kind\":\"CallExp\",\"func\":\"f\",\"args\":[\
+                {\"kind\":\"VarExp\",\"var\":{\"kind\":\"SubscriptVar\",\
+                \"var\":{\"kind\":\"FieldVar\",\"var\":{\"kind\":\"SimpleVar\",\"name\":\"a\",\"pos\":2},\"name\":\"b\",\"pos\":2},\
+                \"exp\":{\"kind\":\"IntExp\",\"value\":2},\"pos\":2}},\
+                {\"kind\":\"OpExp\",\"left\":{\"kind\":\"IntExp\",\"value\":0},\"op\":\"MinusOp\",\
+                \"right\":{\"kind\":\"IntExp\",\"value\":1},\"pos\":10}],\"pos\":0}");
+    assert_eq!(to_sexp(&to_json(&exp, &table, None)), "\
+(CallExp
+  :func \"f\"
+  :args ((VarExp
+      :var (SubscriptVar
+        :var (FieldVar
+          :var (SimpleVar :name \"a\" :pos 2)
+          :name \"b\"
+          :pos 2)
+        :exp (IntExp :value 2)
+        :pos 2))
+    (OpExp
+      :left (IntExp :value 0)
+      :op \"MinusOp\"
+      :right (IntExp :value 1)
+      :pos 10))
+  :pos 0)");
+
+    let load = |text: &str| from_json(&Json::parse(text).unwrap(), &mut SymbolTable::new()).map(|_| ());
+    assert_eq!(load("{\"kind\": \"IntExp\"}"), Err(String::from("IntExp has no value")));
+    assert_eq!(load("{\"kind\": \"FooExp\"}"), Err(String::from("unknown expression FooExp")));
+    assert_eq!(load("{\"kind\": \"BreakExp\", \"pos\": -1}"), Err(String::from("BreakExp has a negative pos")));
+    assert_eq!(load("{\"kind\": \"VarExp\", \"var\": {\"kind\": \"SimpleVar\", \"name\": 3, \"pos\": 0}}"),
+               Err(String::from("the name of SimpleVar is not a string")));
+}
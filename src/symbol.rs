@@ -39,6 +39,6 @@ fn test_symbol_table() {
     let mut s = SymbolTable::new();
 
     assert_eq!(s.symbol("foobar"), 0);
-    assert_eq!(s.name(&0).unwrap(), "foobar");
+    assert_eq!(s.name(&0), "foobar");
     assert_eq!(s.symbol("one"), 1);
 }
\ No newline at end of file
@@ -0,0 +1,106 @@
+use symbol::{SymbolTable, SymbolId};
+
+use std::collections::{HashMap, HashSet};
+
+// Temps below this number are reserved for machine registers, so that every
+// Frame implementation can refer to its registers with fixed constants.
+pub const FIRST_FREE_TEMP: u32 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Temp(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Label(pub SymbolId);
+
+impl Label {
+    pub fn name<'a>(&self, symbol_table: &'a SymbolTable) -> &'a String {
+        symbol_table.name(&self.0)
+    }
+}
+
+pub struct TempGenerator {
+    next_temp: u32,
+    next_label: u32,
+    // the temps holding pointers to the heap, and for each frame the
+    // offsets of its slots that do, which the garbage collector must see
+    pointers: HashSet<Temp>,
+    pointer_slots: HashMap<Label, Vec<i32>>,
+}
+
+impl TempGenerator {
+    pub fn new() -> TempGenerator {
+        TempGenerator {
+            next_temp: FIRST_FREE_TEMP,
+            next_label: 0,
+            pointers: HashSet::new(),
+            pointer_slots: HashMap::new(),
+        }
+    }
+
+    pub fn mark_pointer(&mut self, t: Temp) {
+        self.pointers.insert(t);
+    }
+
+    pub fn is_pointer(&self, t: Temp) -> bool {
+        self.pointers.contains(&t)
+    }
+
+    pub fn mark_pointer_slot(&mut self, frame: Label, offset: i32) {
+        self.pointer_slots.entry(frame).or_insert(vec![]).push(offset);
+    }
+
+    pub fn pointer_slots(&self, frame: Label) -> &[i32] {
+        self.pointer_slots.get(&frame).map(|v| v.as_slice()).unwrap_or(&[])
+    }
+
+    pub fn new_temp(&mut self) -> Temp {
+        let t = Temp(self.next_temp);
+        self.next_temp += 1;
+        t
+    }
+
+    // fresh labels are interned as "L<n>" in the same table as the program's
+    // identifiers, so they can be printed like any other symbol
+    pub fn new_label(&mut self, symbol_table: &mut SymbolTable) -> Label {
+        let name = format!("L{}", self.next_label);
+        self.next_label += 1;
+        Label(symbol_table.symbol(&name))
+    }
+
+    // a fresh label that still shows where it came from, e.g. "fact_3" for
+    // a function named fact
+    pub fn new_named_label(&mut self, symbol_table: &mut SymbolTable, prefix: &str) -> Label {
+        let name = format!("{}_{}", prefix, self.next_label);
+        self.next_label += 1;
+        Label(symbol_table.symbol(&name))
+    }
+
+    pub fn named_label(&self, symbol_table: &mut SymbolTable, name: &str) -> Label {
+        Label(symbol_table.symbol(name))
+    }
+}
+
+#[test]
+fn test_temp_generator() {
+    let mut table = SymbolTable::new();
+    let mut gen = TempGenerator::new();
+
+    let t1 = gen.new_temp();
+    let t2 = gen.new_temp();
+    assert_eq!(t1, Temp(FIRST_FREE_TEMP));
+    assert!(t1 != t2);
+    gen.mark_pointer(t2);
+    assert!(!gen.is_pointer(t1) && gen.is_pointer(t2));
+
+    let l1 = gen.new_label(&mut table);
+    let l2 = gen.new_label(&mut table);
+    assert!(l1 != l2);
+    assert_eq!(l1.name(&table), "L0");
+
+    let f = gen.new_named_label(&mut table, "f");
+    assert_eq!(f.name(&table), "f_2");
+
+    let print = gen.named_label(&mut table, "print");
+    assert_eq!(print, gen.named_label(&mut table, "print"));
+    assert_eq!(print.name(&table), "print");
+}
@@ -0,0 +1,768 @@
+use ast;
+use frame::{Access, Frame, Fragment};
+use ir;
+use ir::{BinOp, RelOp, Stm, seq};
+use symbol::{SymbolId, SymbolTable};
+use temp::{Label, TempGenerator};
+use type_check::TypeMap;
+use types::{Ty, Table, builtin_functions};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// Lowering of the type checked AST to IR trees (Appel chapter 7).
+
+// A nesting level of function definitions, each with its own frame.
+pub struct Level<F: Frame> {
+    parent: Option<Rc<Level<F>>>,
+    frame: RefCell<F>,
+}
+
+// An expression in one of three shapes: a value, a statement without a
+// value, or a conditional jump to one of two labels.
+pub enum Exp {
+    Ex(ir::Exp),
+    Nx(Stm),
+    Cx(Box<dyn Fn(Label, Label) -> Stm>),
+}
+
+enum Entry<F: Frame> {
+    Var(Rc<Level<F>>, Access),
+    // the level is the function's own level
+    Fun(Rc<Level<F>>, Label),
+    External(Label),
+}
+
+type Env<'a, F> = Table<'a, Entry<F>>;
+
+struct Translator<'a, F: Frame> {
+    symbol_table: &'a mut SymbolTable,
+    types: &'a TypeMap,
+    gen: &'a mut TempGenerator,
+    fragments: Vec<Fragment<F>>,
+    // the string fragment describing the fields of each record layout
+    descriptors: HashMap<String, Label>,
+}
+
+fn mem_plus(base: ir::Exp, offset: ir::Exp) -> ir::Exp {
+    ir::Exp::Mem(Box::new(ir::Exp::BinOp(BinOp::Plus, Box::new(base), Box::new(offset))))
+}
+
+impl<'a, F: Frame> Translator<'a, F> {
+    fn runtime_label(&mut self, name: &str) -> Label {
+        self.gen.named_label(self.symbol_table, &F::external_name(name))
+    }
+
+    fn runtime_call(&mut self, name: &str, args: Vec<ir::Exp>) -> ir::Exp {
+        let label = self.runtime_label(name);
+        F::external_call(label, args)
+    }
+
+    // tells the garbage collector where a variable holding pointers lives
+    fn mark_pointer_variable(&mut self, level: &Rc<Level<F>>, access: Access) {
+        match access {
+            Access::InReg(t) => self.gen.mark_pointer(t),
+            Access::InFrame(offset) => {
+                let name = level.frame.borrow().name();
+                self.gen.mark_pointer_slot(name, offset);
+            },
+        }
+    }
+
+    // a pointer computed by a load or a call, kept in a temp known to hold
+    // one, so that it is in a pointer map if it is live across a call
+    fn pointer_value(&mut self, e: ir::Exp) -> ir::Exp {
+        match e {
+            ir::Exp::Temp(_) | ir::Exp::Const(_) | ir::Exp::Name(_) => e,
+            e => {
+                let p = self.gen.new_temp();
+                self.gen.mark_pointer(p);
+                ir::Exp::ESeq(Box::new(Stm::Move(Box::new(ir::Exp::Temp(p)), Box::new(e))), Box::new(ir::Exp::Temp(p)))
+            },
+        }
+    }
+
+    // the layout of a record for the garbage collector: a string with a
+    // 'p' for each field holding a pointer and an 'i' for the others
+    fn record_descriptor(&mut self, fields: &[(SymbolId, Rc<Ty>)]) -> Label {
+        let layout: String = fields.iter().map(|&(_, ref ty)| if Ty::is_pointer(ty) { 'p' } else { 'i' }).collect();
+        if let Some(&label) = self.descriptors.get(&layout) {
+            return label;
+        }
+        let label = self.gen.new_label(self.symbol_table);
+        self.fragments.push(Fragment::String(label, layout.clone()));
+        self.descriptors.insert(layout, label);
+        label
+    }
+
+    fn un_ex(&mut self, exp: Exp) -> ir::Exp {
+        match exp {
+            Exp::Ex(e) => e,
+            Exp::Nx(s) => ir::Exp::ESeq(Box::new(s), Box::new(ir::Exp::Const(0))),
+            Exp::Cx(gen_stm) => {
+                let r = self.gen.new_temp();
+                let t = self.gen.new_label(self.symbol_table);
+                let f = self.gen.new_label(self.symbol_table);
+                ir::Exp::ESeq(Box::new(seq(vec![
+                    Stm::Move(Box::new(ir::Exp::Temp(r)), Box::new(ir::Exp::Const(1))),
+                    gen_stm(t, f),
+                    Stm::Label(f),
+                    Stm::Move(Box::new(ir::Exp::Temp(r)), Box::new(ir::Exp::Const(0))),
+                    Stm::Label(t),
+                ])), Box::new(ir::Exp::Temp(r)))
+            },
+        }
+    }
+
+    fn un_nx(&mut self, exp: Exp) -> Stm {
+        match exp {
+            Exp::Ex(e) => Stm::Exp(Box::new(e)),
+            Exp::Nx(s) => s,
+            Exp::Cx(gen_stm) => {
+                let l = self.gen.new_label(self.symbol_table);
+                seq(vec![gen_stm(l, l), Stm::Label(l)])
+            },
+        }
+    }
+
+    fn un_cx(&mut self, exp: Exp) -> Box<dyn Fn(Label, Label) -> Stm> {
+        match exp {
+            Exp::Ex(ir::Exp::Const(0)) => Box::new(|_, f| Stm::Jump(Box::new(ir::Exp::Name(f)), vec![f])),
+            Exp::Ex(ir::Exp::Const(_)) => Box::new(|t, _| Stm::Jump(Box::new(ir::Exp::Name(t)), vec![t])),
+            Exp::Ex(e) => Box::new(move |t, f| {
+                Stm::CJump(RelOp::Ne, Box::new(e.clone()), Box::new(ir::Exp::Const(0)), t, f)
+            }),
+            Exp::Nx(_) => panic!("a statement without value cannot be used as a condition"),
+            Exp::Cx(gen_stm) => gen_stm,
+        }
+    }
+
+    // follows static links from the frame of level `from` up to the frame
+    // of level `to`, returning the address of the latter
+    fn frame_address(&self, from: &Rc<Level<F>>, to: &Rc<Level<F>>) -> ir::Exp {
+        let mut e = ir::Exp::Temp(F::fp());
+        let mut level = from.clone();
+        while !Rc::ptr_eq(&level, to) {
+            let static_link = level.frame.borrow().formals()[0];
+            e = F::exp(static_link, e);
+            level = level.parent.clone().expect("variable is not visible from this level");
+        }
+        e
+    }
+
+    fn trans_var(&mut self, var: &ast::Var, env: &Env<F>, level: &Rc<Level<F>>, break_label: Option<Label>) -> ir::Exp {
+        match var {
+            &ast::Var::SimpleVar(symbol, _, _) => {
+                match env.look(symbol).map(|e| e.as_ref()) {
+                    Some(&Entry::Var(ref var_level, access)) => {
+                        let fp = self.frame_address(level, var_level);
+                        F::exp(access, fp)
+                    },
+                    _ => panic!("unknown variable {} in translation", self.symbol_table.name(&symbol)),
+                }
+            },
+            &ast::Var::FieldVar(ref record, field, pos, _) => {
+                let index = match self.types.var_ty(record).map(|t| t.as_ref()) {
+                    Some(&Ty::Record { ref fields, .. }) =>
+                        fields.iter().position(|&(s, _)| s == field).expect("unknown field in translation"),
+                    _ => panic!("field access on a non-record in translation"),
+                };
+                let record = self.trans_var(record, env, level, break_label);
+
+                // records may be nil
+                let r = self.gen.new_temp();
+                self.gen.mark_pointer(r);
+                let nil = self.gen.new_label(self.symbol_table);
+                let ok = self.gen.new_label(self.symbol_table);
+                let error = self.runtime_call("nilError", vec![ir::Exp::Const(pos as i32)]);
+                ir::Exp::ESeq(Box::new(seq(vec![
+                    Stm::Move(Box::new(ir::Exp::Temp(r)), Box::new(record)),
+                    Stm::CJump(RelOp::Eq, Box::new(ir::Exp::Temp(r)), Box::new(ir::Exp::Const(0)), nil, ok),
+                    Stm::Label(nil),
+                    Stm::Exp(Box::new(error)),
+                    Stm::Label(ok),
+                ])), Box::new(mem_plus(ir::Exp::Temp(r), ir::Exp::Const(index as i32 * F::word_size()))))
+            },
+            &ast::Var::SubscriptVar(ref array, ref index, pos, _) => {
+                let array = self.trans_var(array, env, level, break_label);
+                let index = self.trans_exp(index, env, level, break_label);
+                let index = self.un_ex(index);
+
+                // arrays store their size in the word before the first element;
+                // a negative index compares as a large unsigned number
+                let a = self.gen.new_temp();
+                self.gen.mark_pointer(a);
+                let i = self.gen.new_temp();
+                let bad = self.gen.new_label(self.symbol_table);
+                let ok = self.gen.new_label(self.symbol_table);
+                let error = self.runtime_call("boundsError", vec![ir::Exp::Const(pos as i32), ir::Exp::Temp(i)]);
+                let offset = ir::Exp::BinOp(BinOp::Mul,
+                    Box::new(ir::Exp::BinOp(BinOp::Plus, Box::new(ir::Exp::Temp(i)), Box::new(ir::Exp::Const(1)))),
+                    Box::new(ir::Exp::Const(F::word_size())));
+                ir::Exp::ESeq(Box::new(seq(vec![
+                    Stm::Move(Box::new(ir::Exp::Temp(a)), Box::new(array)),
+                    Stm::Move(Box::new(ir::Exp::Temp(i)), Box::new(index)),
+                    Stm::CJump(RelOp::UGe, Box::new(ir::Exp::Temp(i)),
+                               Box::new(ir::Exp::Mem(Box::new(ir::Exp::Temp(a)))), bad, ok),
+                    Stm::Label(bad),
+                    Stm::Exp(Box::new(error)),
+                    Stm::Label(ok),
+                ])), Box::new(mem_plus(ir::Exp::Temp(a), offset)))
+            },
+        }
+    }
+
+    fn is_string(&self, exp: &ast::Exp) -> bool {
+        match self.types.exp_ty(exp).map(|t| t.as_ref()) {
+            Some(&Ty::String) => true,
+            _ => false,
+        }
+    }
+
+    fn trans_exp(&mut self, exp: &ast::Exp, env: &Env<F>, level: &Rc<Level<F>>, break_label: Option<Label>) -> Exp {
+        use ast::Oper::*;
+
+        match exp {
+            &ast::Exp::VarExp(ref var, _) => {
+                let value = self.trans_var(var, env, level, break_label);
+                if self.types.var_ty(var).map_or(false, Ty::is_pointer) {
+                    Exp::Ex(self.pointer_value(value))
+                } else {
+                    Exp::Ex(value)
+                }
+            },
+            &ast::Exp::NilExp(_) => Exp::Ex(ir::Exp::Const(0)),
+            &ast::Exp::IntExp(i, _) => Exp::Ex(ir::Exp::Const(i)),
+            &ast::Exp::StringExp(ref s, _, _) => {
+                let label = self.gen.new_label(self.symbol_table);
+                self.fragments.push(Fragment::String(label, s.clone()));
+                Exp::Ex(ir::Exp::Name(label))
+            },
+
+            &ast::Exp::CallExp { func, ref args, .. } => {
+                let mut arg_exps = vec![];
+                for arg in args.iter() {
+                    let e = self.trans_exp(arg, env, level, break_label);
+                    arg_exps.push(self.un_ex(e));
+                }
+                let call = match env.look(func).map(|e| e.as_ref()) {
+                    Some(&Entry::Fun(ref fun_level, label)) => {
+                        let parent = fun_level.parent.as_ref().expect("function without a parent level");
+                        let static_link = self.frame_address(level, parent);
+                        arg_exps.insert(0, static_link);
+                        ir::Exp::Call(Box::new(ir::Exp::Name(label)), arg_exps)
+                    },
+                    Some(&Entry::External(label)) => F::external_call(label, arg_exps),
+                    _ => panic!("unknown function {} in translation", self.symbol_table.name(&func)),
+                };
+                if self.types.exp_ty(exp).map_or(false, Ty::is_pointer) {
+                    Exp::Ex(self.pointer_value(call))
+                } else {
+                    Exp::Ex(call)
+                }
+            },
+
+            &ast::Exp::OpExp { ref left, op, ref right, .. } => {
+                let strings = self.is_string(left) || self.is_string(right);
+                let l = self.trans_exp(left, env, level, break_label);
+                let l = self.un_ex(l);
+                let r = self.trans_exp(right, env, level, break_label);
+                let r = self.un_ex(r);
+
+                let binop = match op {
+                    PlusOp => Some(BinOp::Plus),
+                    MinusOp => Some(BinOp::Minus),
+                    TimesOp => Some(BinOp::Mul),
+                    DivideOp => Some(BinOp::Div),
+                    _ => None,
+                };
+                if let Some(binop) = binop {
+                    return Exp::Ex(ir::Exp::BinOp(binop, Box::new(l), Box::new(r)));
+                }
+
+                let relop = match op {
+                    EqOp => RelOp::Eq,
+                    NeqOp => RelOp::Ne,
+                    LtOp => RelOp::Lt,
+                    LeOp => RelOp::Le,
+                    GtOp => RelOp::Gt,
+                    _ => RelOp::Ge,
+                };
+                // strings are compared by the runtime: stringEqual returns 0 or 1,
+                // stringCompare a negative, zero or positive number
+                let (l, r) = if !strings {
+                    (l, r)
+                } else if relop == RelOp::Eq || relop == RelOp::Ne {
+                    let equal = self.runtime_call("stringEqual", vec![l, r]);
+                    let relop = if relop == RelOp::Eq { RelOp::Ne } else { RelOp::Eq };
+                    return Exp::Cx(Box::new(move |t, f| {
+                        Stm::CJump(relop, Box::new(equal.clone()), Box::new(ir::Exp::Const(0)), t, f)
+                    }));
+                } else {
+                    (self.runtime_call("stringCompare", vec![l, r]), ir::Exp::Const(0))
+                };
+                Exp::Cx(Box::new(move |t, f| Stm::CJump(relop, Box::new(l.clone()), Box::new(r.clone()), t, f)))
+            },
+
+            &ast::Exp::RecordExp { ref fields, .. } => {
+                let descriptor = match self.types.exp_ty(exp).map(Ty::actual) {
+                    Some(ty) => match ty.as_ref() {
+                        &Ty::Record { fields: ref types, .. } => self.record_descriptor(types),
+                        _ => panic!("record expression of a non-record type in translation"),
+                    },
+                    None => panic!("record expression without a type in translation"),
+                };
+                let r = self.gen.new_temp();
+                self.gen.mark_pointer(r);
+                let size = ir::Exp::Const(fields.len() as i32 * F::word_size());
+                let alloc = self.runtime_call("allocRecord", vec![size, ir::Exp::Name(descriptor)]);
+                let mut stms = vec![Stm::Move(Box::new(ir::Exp::Temp(r)), Box::new(alloc))];
+                for (i, &(_, ref field, _)) in fields.iter().enumerate() {
+                    let e = self.trans_exp(field, env, level, break_label);
+                    let e = self.un_ex(e);
+                    let dst = mem_plus(ir::Exp::Temp(r), ir::Exp::Const(i as i32 * F::word_size()));
+                    stms.push(Stm::Move(Box::new(dst), Box::new(e)));
+                }
+                Exp::Ex(ir::Exp::ESeq(Box::new(seq(stms)), Box::new(ir::Exp::Temp(r))))
+            },
+
+            &ast::Exp::SeqExp(ref exps, _) => {
+                let mut stms = vec![];
+                let mut last = None;
+                for (i, e) in exps.iter().enumerate() {
+                    let e = self.trans_exp(e, env, level, break_label);
+                    if i + 1 == exps.len() {
+                        last = Some(e);
+                    } else {
+                        stms.push(self.un_nx(e));
+                    }
+                }
+                match last {
+                    None => Exp::Nx(seq(vec![])),
+                    Some(Exp::Nx(s)) => {
+                        stms.push(s);
+                        Exp::Nx(seq(stms))
+                    },
+                    Some(e) => {
+                        if stms.is_empty() {
+                            e
+                        } else {
+                            let e = self.un_ex(e);
+                            Exp::Ex(ir::Exp::ESeq(Box::new(seq(stms)), Box::new(e)))
+                        }
+                    },
+                }
+            },
+
+            &ast::Exp::AssignExp { ref var, ref exp, .. } => {
+                let dst = self.trans_var(var, env, level, break_label);
+                let src = self.trans_exp(exp, env, level, break_label);
+                let src = self.un_ex(src);
+                Exp::Nx(Stm::Move(Box::new(dst), Box::new(src)))
+            },
+
+            &ast::Exp::IfExp { ref test, ref then_, ref else_, .. } => {
+                let test = self.trans_exp(test, env, level, break_label);
+                let test = self.un_cx(test);
+                let t = self.gen.new_label(self.symbol_table);
+                let f = self.gen.new_label(self.symbol_table);
+                let then_exp = self.trans_exp(then_, env, level, break_label);
+
+                match else_ {
+                    &None => {
+                        let then_stm = self.un_nx(then_exp);
+                        Exp::Nx(seq(vec![test(t, f), Stm::Label(t), then_stm, Stm::Label(f)]))
+                    },
+                    &Some(ref else_) => {
+                        let else_exp = self.trans_exp(else_, env, level, break_label);
+                        let join = self.gen.new_label(self.symbol_table);
+                        let jump_join = Stm::Jump(Box::new(ir::Exp::Name(join)), vec![join]);
+                        let is_unit = match self.types.exp_ty(exp).map(|t| t.as_ref()) {
+                            Some(&Ty::Unit) => true,
+                            _ => false,
+                        };
+
+                        if is_unit {
+                            let then_stm = self.un_nx(then_exp);
+                            let else_stm = self.un_nx(else_exp);
+                            Exp::Nx(seq(vec![test(t, f),
+                                             Stm::Label(t), then_stm, jump_join,
+                                             Stm::Label(f), else_stm,
+                                             Stm::Label(join)]))
+                        } else {
+                            let r = self.gen.new_temp();
+                            if self.types.exp_ty(exp).map_or(false, Ty::is_pointer) {
+                                self.gen.mark_pointer(r);
+                            }
+                            let then_val = self.un_ex(then_exp);
+                            let else_val = self.un_ex(else_exp);
+                            Exp::Ex(ir::Exp::ESeq(Box::new(seq(vec![
+                                test(t, f),
+                                Stm::Label(t),
+                                Stm::Move(Box::new(ir::Exp::Temp(r)), Box::new(then_val)),
+                                jump_join,
+                                Stm::Label(f),
+                                Stm::Move(Box::new(ir::Exp::Temp(r)), Box::new(else_val)),
+                                Stm::Label(join),
+                            ])), Box::new(ir::Exp::Temp(r))))
+                        }
+                    },
+                }
+            },
+
+            &ast::Exp::WhileExp { ref test, ref body, .. } => {
+                let test_label = self.gen.new_label(self.symbol_table);
+                let body_label = self.gen.new_label(self.symbol_table);
+                let done = self.gen.new_label(self.symbol_table);
+
+                let test = self.trans_exp(test, env, level, break_label);
+                let test = self.un_cx(test);
+                let body = self.trans_exp(body, env, level, Some(done));
+                let body = self.un_nx(body);
+                Exp::Nx(seq(vec![
+                    Stm::Label(test_label),
+                    test(body_label, done),
+                    Stm::Label(body_label),
+                    body,
+                    Stm::Jump(Box::new(ir::Exp::Name(test_label)), vec![test_label]),
+                    Stm::Label(done),
+                ]))
+            },
+
+            &ast::Exp::ForExp { var, escape, ref lo, ref hi, ref body, .. } => {
+                let lo = self.trans_exp(lo, env, level, break_label);
+                let lo = self.un_ex(lo);
+                let hi = self.trans_exp(hi, env, level, break_label);
+                let hi = self.un_ex(hi);
+
+                let access = level.frame.borrow_mut().alloc_local(escape, self.gen);
+                let mut body_env = Env::new(Some(env));
+                body_env.enter(var, Rc::new(Entry::Var(level.clone(), access)));
+                let i = F::exp(access, ir::Exp::Temp(F::fp()));
+                let limit = ir::Exp::Temp(self.gen.new_temp());
+
+                let body_label = self.gen.new_label(self.symbol_table);
+                let inc = self.gen.new_label(self.symbol_table);
+                let done = self.gen.new_label(self.symbol_table);
+                let body = self.trans_exp(body, &body_env, level, Some(done));
+                let body = self.un_nx(body);
+
+                // the limit is tested before incrementing, so that a loop up to
+                // the largest integer does not overflow
+                Exp::Nx(seq(vec![
+                    Stm::Move(Box::new(i.clone()), Box::new(lo)),
+                    Stm::Move(Box::new(limit.clone()), Box::new(hi)),
+                    Stm::CJump(RelOp::Le, Box::new(i.clone()), Box::new(limit.clone()), body_label, done),
+                    Stm::Label(body_label),
+                    body,
+                    Stm::CJump(RelOp::Lt, Box::new(i.clone()), Box::new(limit), inc, done),
+                    Stm::Label(inc),
+                    Stm::Move(Box::new(i.clone()),
+                              Box::new(ir::Exp::BinOp(BinOp::Plus, Box::new(i), Box::new(ir::Exp::Const(1))))),
+                    Stm::Jump(Box::new(ir::Exp::Name(body_label)), vec![body_label]),
+                    Stm::Label(done),
+                ]))
+            },
+
+            &ast::Exp::BreakExp(_, _) => {
+                let done = break_label.expect("break outside of a loop in translation");
+                Exp::Nx(Stm::Jump(Box::new(ir::Exp::Name(done)), vec![done]))
+            },
+
+            &ast::Exp::LetExp { ref decs, ref body, .. } => {
+                let mut let_env = Env::new(Some(env));
+                let mut stms = vec![];
+
+                let mut i = 0;
+                while i < decs.len() {
+                    let mut j = i + 1;
+                    match decs[i].as_ref() {
+                        &ast::Dec::FunDec { .. } => {
+                            while j < decs.len() {
+                                if let &ast::Dec::FunDec { .. } = decs[j].as_ref() { j += 1 } else { break }
+                            }
+                            self.trans_fun_decs(&decs[i..j], &mut let_env, level);
+                        },
+                        &ast::Dec::VarDec { name, escape, ref init, .. } => {
+                            let init = {
+                                let init = self.trans_exp(init, &let_env, level, break_label);
+                                self.un_ex(init)
+                            };
+                            let access = level.frame.borrow_mut().alloc_local(escape, self.gen);
+                            if self.types.dec_ty(decs[i].as_ref()).map_or(false, Ty::is_pointer) {
+                                self.mark_pointer_variable(level, access);
+                            }
+                            let dst = F::exp(access, ir::Exp::Temp(F::fp()));
+                            stms.push(Stm::Move(Box::new(dst), Box::new(init)));
+                            let_env.enter(name, Rc::new(Entry::Var(level.clone(), access)));
+                        },
+                        &ast::Dec::TypeDec { .. } => (),
+                    }
+                    i = j;
+                }
+
+                let body = self.trans_exp(body, &let_env, level, break_label);
+                match body {
+                    Exp::Nx(s) => {
+                        stms.push(s);
+                        Exp::Nx(seq(stms))
+                    },
+                    body => {
+                        let body = self.un_ex(body);
+                        if stms.is_empty() {
+                            Exp::Ex(body)
+                        } else {
+                            Exp::Ex(ir::Exp::ESeq(Box::new(seq(stms)), Box::new(body)))
+                        }
+                    },
+                }
+            },
+
+            &ast::Exp::ArrayExp { ref size, ref init, .. } => {
+                let size = self.trans_exp(size, env, level, break_label);
+                let size = self.un_ex(size);
+                let init = self.trans_exp(init, env, level, break_label);
+                let init = self.un_ex(init);
+                let pointers = match self.types.exp_ty(exp).map(Ty::actual) {
+                    Some(ty) => match ty.as_ref() {
+                        &Ty::Array { ref typ, .. } => Ty::is_pointer(typ),
+                        _ => panic!("array expression of a non-array type in translation"),
+                    },
+                    None => panic!("array expression without a type in translation"),
+                };
+                let array = self.runtime_call("initArray", vec![size, init, ir::Exp::Const(pointers as i32)]);
+                Exp::Ex(self.pointer_value(array))
+            },
+        }
+    }
+
+    fn trans_fun_decs(&mut self, decs: &[Box<ast::Dec>], env: &mut Env<F>, level: &Rc<Level<F>>) {
+        let mut levels = vec![];
+        for dec in decs.iter() {
+            if let &ast::Dec::FunDec { name, ref params, .. } = dec.as_ref() {
+                let label = {
+                    let name = self.symbol_table.name(&name).clone();
+                    self.gen.new_named_label(self.symbol_table, &name)
+                };
+                // the static link is passed as an extra, escaping first argument
+                let mut escapes = vec![true];
+                escapes.extend(params.iter().map(|p| p.escape));
+                let fun_level = Rc::new(Level {
+                    parent: Some(level.clone()),
+                    frame: RefCell::new(F::new_frame(label, escapes, self.gen)),
+                });
+                env.enter(name, Rc::new(Entry::Fun(fun_level.clone(), label)));
+                levels.push(fun_level);
+            }
+        }
+
+        for (dec, fun_level) in decs.iter().zip(levels.into_iter()) {
+            if let &ast::Dec::FunDec { ref params, ref result, ref body, .. } = dec.as_ref() {
+                let mut fun_env = Env::new(Some(env));
+                let formals = fun_level.frame.borrow().formals().to_vec();
+                for (param, &access) in params.iter().zip(formals[1..].iter()) {
+                    if self.types.param_ty(param).map_or(false, Ty::is_pointer) {
+                        self.mark_pointer_variable(&fun_level, access);
+                    }
+                    fun_env.enter(param.name, Rc::new(Entry::Var(fun_level.clone(), access)));
+                }
+
+                let body = self.trans_exp(body, &fun_env, &fun_level, None);
+                let body = if result.is_some() {
+                    let value = self.un_ex(body);
+                    Stm::Move(Box::new(ir::Exp::Temp(F::rv())), Box::new(value))
+                } else {
+                    self.un_nx(body)
+                };
+                self.proc_entry_exit(&fun_level, body);
+            }
+        }
+    }
+
+    fn proc_entry_exit(&mut self, level: &Rc<Level<F>>, body: Stm) {
+        let frame = level.frame.borrow().clone();
+        // the slots of escaping local variables holding pointers are
+        // cleared first, as there may be a collection before they are set
+        let mut stms: Vec<Stm> = self.gen.pointer_slots(frame.name()).iter()
+            .filter(|&&offset| !frame.formals().contains(&Access::InFrame(offset)))
+            .map(|&offset| Stm::Move(Box::new(F::exp(Access::InFrame(offset), ir::Exp::Temp(F::fp()))),
+                                     Box::new(ir::Exp::Const(0))))
+            .collect();
+        stms.push(body);
+        let body = frame.proc_entry_exit1(seq(stms), self.gen);
+        self.fragments.push(Fragment::Proc { body: body, frame: frame });
+    }
+}
+
+// Translates a type checked program (with escapes computed) into fragments.
+// The main program becomes the procedure "tigermain", which leaves the
+// program's value (if any) in the return value register.
+pub fn translate<F: Frame>(exp: &ast::Exp, types: &TypeMap, symbol_table: &mut SymbolTable,
+                           gen: &mut TempGenerator) -> Vec<Fragment<F>> {
+    let mut env = Env::new(None);
+    for (name, _, _) in builtin_functions().into_iter() {
+        let label = gen.named_label(symbol_table, &F::external_name(name));
+        env.enter(symbol_table.symbol(name), Rc::new(Entry::External(label)));
+    }
+
+    let main_label = gen.named_label(symbol_table, "tigermain");
+    let main_level = Rc::new(Level {
+        parent: None,
+        frame: RefCell::new(F::new_frame(main_label, vec![], gen)),
+    });
+
+    let mut translator = Translator {
+        symbol_table: symbol_table,
+        types: types,
+        gen: gen,
+        fragments: vec![],
+        descriptors: HashMap::new(),
+    };
+    let body = translator.trans_exp(exp, &env, &main_level, None);
+    let body = match body {
+        Exp::Nx(s) => s,
+        body => Stm::Move(Box::new(ir::Exp::Temp(F::rv())), Box::new(translator.un_ex(body))),
+    };
+    translator.proc_entry_exit(&main_level, body);
+    translator.fragments
+}
+
+#[test]
+fn test_translate() {
+    use escape::find_escapes;
+    use frame;
+    use parser::parse;
+    use temp::Temp;
+    use type_check::type_check;
+
+    // a minimal frame: every formal and local escapes into the frame
+    #[derive(Clone)]
+    struct TestFrame {
+        name: Label,
+        formals: Vec<Access>,
+        locals: i32,
+    }
+
+    impl frame::Frame for TestFrame {
+        fn new_frame(name: Label, formals_escape: Vec<bool>, _: &mut TempGenerator) -> TestFrame {
+            let formals = (0..formals_escape.len()).map(|i| Access::InFrame(8 * i as i32)).collect();
+            TestFrame { name: name, formals: formals, locals: 0 }
+        }
+        fn name(&self) -> Label { self.name }
+        fn formals(&self) -> &[Access] { &self.formals }
+        fn alloc_local(&mut self, _: bool, _: &mut TempGenerator) -> Access {
+            self.locals += 1;
+            Access::InFrame(-8 * self.locals)
+        }
+        fn fp() -> Temp { Temp(0) }
+        fn rv() -> Temp { Temp(1) }
+        fn word_size() -> i32 { 8 }
+        fn proc_entry_exit1(&self, body: Stm, _: &mut TempGenerator) -> Stm { body }
+    }
+
+    let (mut p, mut symbol_table) = parse("let type list = {hd: int, tl: list} \
+                                           var l := list {hd = 1, tl = nil} \
+                                           var s := \"foo\" \
+                                           function count(): int = \
+                                             let function inner(l: list): int = if l = nil then 0 else 1 + inner(l.tl) \
+                                             in inner(l) end \
+                                           in if s = \"bar\" then count() else 2 end").unwrap();
+    find_escapes(&mut p);
+    let types = type_check(&p, &mut symbol_table).unwrap();
+    let mut gen = TempGenerator::new();
+    let fragments: Vec<Fragment<TestFrame>> = translate(&p, &types, &mut symbol_table, &mut gen);
+
+    // the calls made in a tree, with their arguments
+    fn calls<'t>(stm: &'t Stm, out: &mut Vec<(Label, &'t [ir::Exp])>) {
+        fn exp<'t>(e: &'t ir::Exp, out: &mut Vec<(Label, &'t [ir::Exp])>) {
+            match e {
+                &ir::Exp::Call(ref f, ref args) => {
+                    if let ir::Exp::Name(l) = **f {
+                        out.push((l, args));
+                    }
+                    exp(f, out);
+                    for a in args.iter() {
+                        exp(a, out);
+                    }
+                },
+                &ir::Exp::BinOp(_, ref a, ref b) => {
+                    exp(a, out);
+                    exp(b, out);
+                },
+                &ir::Exp::Mem(ref a) => exp(a, out),
+                &ir::Exp::ESeq(ref s, ref e) => {
+                    calls(s, out);
+                    exp(e, out);
+                },
+                &ir::Exp::Const(_) | &ir::Exp::Name(_) | &ir::Exp::Temp(_) => (),
+            }
+        }
+        match stm {
+            &Stm::Move(ref a, ref b) | &Stm::CJump(_, ref a, ref b, _, _) => {
+                exp(a, out);
+                exp(b, out);
+            },
+            &Stm::Exp(ref e) | &Stm::Jump(ref e, _) => exp(e, out),
+            &Stm::Seq(ref a, ref b) => {
+                calls(a, out);
+                calls(b, out);
+            },
+            &Stm::Label(_) => (),
+        }
+    }
+
+    let mut procs = vec![];
+    let mut strings = vec![];
+    for fragment in fragments.iter() {
+        match fragment {
+            &Fragment::Proc { ref body, ref frame } => procs.push((frame.name.name(&symbol_table).clone(), body)),
+            &Fragment::String(_, ref s) => strings.push(s.clone()),
+        }
+    }
+
+    // the layout of list records comes first
+    assert_eq!(strings, vec!["ip", "foo", "bar"]);
+    let names: Vec<&str> = procs.iter().map(|p| p.0.as_str()).collect();
+    assert_eq!(names, vec!["inner_3", "count_2", "tigermain"]);
+    let called = |body: &Stm| -> Vec<String> {
+        let mut out = vec![];
+        calls(body, &mut out);
+        out.iter().map(|&(l, _)| l.name(&symbol_table).clone()).collect()
+    };
+    assert!(called(procs[0].1).contains(&String::from("nilError")));
+    assert!(called(procs[2].1).contains(&String::from("allocRecord")));
+    assert!(called(procs[2].1).contains(&String::from("stringEqual")));
+
+    // count passes its own frame as inner's static link, and reaches l in
+    // tigermain's frame through its own static link, reading the pointer
+    // into a temp
+    let mut count_calls = vec![];
+    calls(procs[1].1, &mut count_calls);
+    let args = match count_calls.iter().find(|&&(l, _)| l.name(&symbol_table) == "inner_3") {
+        Some(&(_, args)) => args,
+        None => panic!("count does not call inner"),
+    };
+    assert_eq!(args.len(), 2);
+    assert_eq!(args[0], ir::Exp::Temp(Temp(0)));
+    let l = ir::Exp::Mem(Box::new(ir::Exp::BinOp(
+        BinOp::Plus,
+        Box::new(ir::Exp::Mem(Box::new(ir::Exp::BinOp(BinOp::Plus, Box::new(ir::Exp::Temp(Temp(0))), Box::new(ir::Exp::Const(0)))))),
+        Box::new(ir::Exp::Const(-8)),
+    )));
+    match args[1] {
+        ir::Exp::ESeq(ref stm, ref result) => match (&**stm, &**result) {
+            (&Stm::Move(ref dst, ref src), &ir::Exp::Temp(t)) => {
+                assert_eq!(**dst, ir::Exp::Temp(t));
+                assert_eq!(**src, l);
+                assert!(gen.is_pointer(t));
+            },
+            _ => panic!("l is not read into a temp: {:?}", args[1]),
+        },
+        _ => panic!("l is not read into a temp: {:?}", args[1]),
+    }
+
+    // l and s hold pointers in tigermain's frame
+    let main = gen.named_label(&mut symbol_table, "tigermain");
+    assert_eq!(gen.pointer_slots(main), &[-8, -16]);
+}
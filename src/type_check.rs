@@ -1,23 +1,22 @@
 #![allow(dead_code, unused_variables)]
 
 use ast;
-use types::{Ty, ValueEnv, TypeEnv, EnvEntry};
-use symbol::SymbolTable;
+use types::{Ty, ValueEnv, TypeEnv, EnvEntry, base_venv, base_tenv};
+use symbol::{SymbolTable, SymbolId};
 
 use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
 
 type AstTy = ast::Ty;
 type AstEx = ast::Exp;
 
-type Exp = ();
-
 #[derive(Debug)]
 pub struct ExpTy {
-    exp: Exp,
-    // this should be Rc<Ty>
-    ty: Ty,
+    ty: Rc<Ty>,
 }
 
+#[derive(Clone)]
 struct UniqueGenerator {
     unique: u32,
 }
@@ -34,23 +33,67 @@ impl UniqueGenerator {
     }
 }
 
+// The resolved type of every expression and variable of a checked program,
+// keyed by the id of the AST node.
+// Declarations get the type of the declared variable, the result type of
+// the declared function or the declared type, and function parameters their
+// declared type.
+pub struct TypeMap {
+    exps: HashMap<ast::NodeId, Rc<Ty>>,
+    vars: HashMap<ast::NodeId, Rc<Ty>>,
+    decs: HashMap<ast::NodeId, Rc<Ty>>,
+    params: HashMap<ast::NodeId, Rc<Ty>>,
+}
+
+impl TypeMap {
+    pub fn new() -> TypeMap {
+        TypeMap {
+            exps: HashMap::new(),
+            vars: HashMap::new(),
+            decs: HashMap::new(),
+            params: HashMap::new(),
+        }
+    }
+
+    pub fn exp_ty(&self, exp: &ast::Exp) -> Option<&Rc<Ty>> {
+        self.exps.get(&exp.id())
+    }
+
+    pub fn var_ty(&self, var: &ast::Var) -> Option<&Rc<Ty>> {
+        self.vars.get(&var.id())
+    }
+
+    pub fn dec_ty(&self, dec: &ast::Dec) -> Option<&Rc<Ty>> {
+        self.decs.get(&dec.id())
+    }
+
+    pub fn param_ty(&self, param: &ast::Field) -> Option<&Rc<Ty>> {
+        self.params.get(&param.id)
+    }
+}
+
 pub struct TypeChecker<'a> {
     symbol_table: &'a SymbolTable,
     venv: &'a ValueEnv<'a>,
     tenv: &'a TypeEnv<'a>,
     unique_gen: &'a RefCell<UniqueGenerator>,
+    types: &'a RefCell<TypeMap>,
+    in_loop: bool,
 }
 
 impl<'a> TypeChecker<'a> {
     fn new(symbol_table: &'a SymbolTable,
            venv: &'a ValueEnv<'a>,
            tenv: &'a TypeEnv<'a>,
-           unique_gen: &'a RefCell<UniqueGenerator>) -> TypeChecker<'a> {
+           unique_gen: &'a RefCell<UniqueGenerator>,
+           types: &'a RefCell<TypeMap>) -> TypeChecker<'a> {
         TypeChecker {
             symbol_table: symbol_table,
             venv: venv,
             tenv: tenv,
-            unique_gen: unique_gen
+            unique_gen: unique_gen,
+            types: types,
+            in_loop: false,
         }
     }
 
@@ -60,6 +103,8 @@ impl<'a> TypeChecker<'a> {
             venv: venv,
             tenv: self.tenv,
             unique_gen: self.unique_gen,
+            types: self.types,
+            in_loop: self.in_loop,
         }
     }
 
@@ -69,35 +114,266 @@ impl<'a> TypeChecker<'a> {
             venv: self.venv,
             tenv: tenv,
             unique_gen: self.unique_gen,
+            types: self.types,
+            in_loop: self.in_loop,
         }
     }
 
-    fn trans_dec(&self, decs: &Vec<Box<ast::Dec>>, body: &Box<ast::Exp>) -> Result<ExpTy, String> {
-        let mut venv = ValueEnv::new(Some(self.venv));
-        let mut tenv = TypeEnv::new(Some(self.tenv));
+    // a checker for a nested scope, which usually lives shorter than self
+    fn scoped<'b>(&'b self, venv: &'b ValueEnv<'b>, tenv: &'b TypeEnv<'b>, in_loop: bool) -> TypeChecker<'b> {
+        TypeChecker {
+            symbol_table: self.symbol_table,
+            venv: venv,
+            tenv: tenv,
+            unique_gen: self.unique_gen,
+            types: self.types,
+            in_loop: in_loop,
+        }
+    }
+
+    fn name(&self, symbol: SymbolId) -> &String {
+        self.symbol_table.name(&symbol)
+    }
+
+    fn ty_name(&self, ty: &Rc<Ty>) -> String {
+        Ty::actual(ty).name(self.symbol_table)
+    }
+
+    fn expect(&self, expected: &Rc<Ty>, actual: &Rc<Ty>, what: &str, pos: ast::Position) -> Result<(), String> {
+        if Ty::is_compatible(expected, actual) {
+            Ok(())
+        } else {
+            Err(format!("{} must be of type {}, found {} at pos {}",
+                        what, self.ty_name(expected), self.ty_name(actual), pos))
+        }
+    }
+
+    fn expect_int(&self, actual: &Rc<Ty>, what: &str, pos: ast::Position) -> Result<(), String> {
+        self.expect(&Rc::new(Ty::Int), actual, what, pos)
+    }
+
+    fn look_ty(&self, symbol: SymbolId, pos: ast::Position) -> Result<Rc<Ty>, String> {
+        match self.tenv.look(symbol) {
+            Some(ty) => Ok(ty.clone()),
+            None => Err(format!("Unknown type {} at pos {}", self.name(symbol), pos)),
+        }
+    }
+
+    fn trans_ty(&self, ty: &AstTy) -> Result<Rc<Ty>, String> {
+        match ty {
+            &ast::Ty::NameTy(symbol, pos) => self.look_ty(symbol, pos),
+            &ast::Ty::RecordTy(ref fields) => {
+                let mut tys = vec![];
+                for field in fields.iter() {
+                    if tys.iter().any(|&(s, _)| s == field.name) {
+                        return Err(format!("Duplicate field {} at pos {}", self.name(field.name), field.pos));
+                    }
+                    tys.push((field.name, self.look_ty(field.typ, field.pos)?));
+                }
+                Ok(Rc::new(Ty::Record {
+                    unique: self.unique_gen.borrow_mut().next(),
+                    fields: tys,
+                }))
+            },
+            &ast::Ty::ArrayTy(symbol, pos) => {
+                Ok(Rc::new(Ty::Array {
+                    typ: self.look_ty(symbol, pos)?,
+                    unique: self.unique_gen.borrow_mut().next(),
+                }))
+            },
+        }
+    }
 
+    // A group of adjacent type declarations may be mutually recursive:
+    // enter a Name header for each, then resolve the bodies.
+    fn trans_type_decs(&self, decs: &[Box<ast::Dec>], tenv: &mut TypeEnv) -> Result<(), String> {
+        let mut headers = vec![];
         for dec in decs.iter() {
-            // dec: &Box<ast::Dec>
+            if let &ast::Dec::TypeDec { name, pos, .. } = dec.as_ref() {
+                if headers.iter().any(|&(s, _)| s == name) {
+                    return Err(format!("Type {} declared twice in the same group at pos {}", self.name(name), pos));
+                }
+                let header = Rc::new(Ty::Name(name, RefCell::new(None)));
+                tenv.enter(name, header.clone());
+                headers.push((name, header));
+            }
         }
 
-        let tcheck = TypeChecker {
-            symbol_table: self.symbol_table,
-            venv: &venv,
-            tenv: &tenv,
-            unique_gen: self.unique_gen,
-        };
+        for (dec, &(_, ref header)) in decs.iter().zip(headers.iter()) {
+            if let &ast::Dec::TypeDec { ref ty, .. } = dec.as_ref() {
+                let resolved = {
+                    let checker = self.new_with_tenv(tenv);
+                    checker.trans_ty(ty)?
+                };
+                self.types.borrow_mut().decs.insert(dec.id(), resolved.clone());
+                if let &Ty::Name(_, ref r) = header.as_ref() {
+                    *r.borrow_mut() = Some(resolved);
+                }
+            }
+        }
+
+        // every cycle must pass through a record or array type
+        for (dec, &(name, ref header)) in decs.iter().zip(headers.iter()) {
+            let mut ty = header.clone();
+            let mut steps = 0;
+            loop {
+                let next = match ty.as_ref() {
+                    &Ty::Name(_, ref r) => r.borrow().clone(),
+                    _ => None,
+                };
+                match next {
+                    Some(next) => ty = next,
+                    None => break,
+                }
+                steps += 1;
+                if steps > headers.len() {
+                    if let &ast::Dec::TypeDec { pos, .. } = dec.as_ref() {
+                        return Err(format!("Illegal cycle in declaration of type {} at pos {}",
+                                           self.name(name), pos));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
 
+    // A group of adjacent function declarations may be mutually recursive:
+    // enter all headers, then check the bodies.
+    fn trans_fun_decs(&self, decs: &[Box<ast::Dec>], venv: &mut ValueEnv, tenv: &TypeEnv) -> Result<(), String> {
+        let mut names = vec![];
+        for dec in decs.iter() {
+            if let &ast::Dec::FunDec { name, ref params, ref result, pos, .. } = dec.as_ref() {
+                if names.contains(&name) {
+                    return Err(format!("Function {} declared twice in the same group at pos {}", self.name(name), pos));
+                }
+                names.push(name);
+
+                let entry = {
+                    let checker = self.new_with_tenv(tenv);
+                    let mut formals = vec![];
+                    for param in params.iter() {
+                        let ty = checker.look_ty(param.typ, param.pos)?;
+                        self.types.borrow_mut().params.insert(param.id, ty.clone());
+                        formals.push(ty);
+                    }
+                    let result = match result {
+                        &Some((typ, pos)) => checker.look_ty(typ, pos)?,
+                        &None => Rc::new(Ty::Unit),
+                    };
+                    self.types.borrow_mut().decs.insert(dec.id(), result.clone());
+                    EnvEntry::FunEntry { formals: formals, result: result }
+                };
+                venv.enter(name, Rc::new(entry));
+            }
+        }
+
+        for dec in decs.iter() {
+            if let &ast::Dec::FunDec{ name, ref params, ref result, ref body, pos, .. } = dec.as_ref() {
+                let (formals, result_ty) = match venv.look(name).map(|e| e.as_ref()) {
+                    Some(&EnvEntry::FunEntry { ref formals, ref result }) => (formals.clone(), result.clone()),
+                    _ => unreachable!(),
+                };
+
+                let mut fvenv = ValueEnv::new(Some(venv));
+                let mut seen = vec![];
+                for (param, ty) in params.iter().zip(formals.into_iter()) {
+                    if seen.contains(&param.name) {
+                        return Err(format!("Parameter {} declared twice at pos {}", self.name(param.name), param.pos));
+                    }
+                    seen.push(param.name);
+                    fvenv.enter(param.name, Rc::new(EnvEntry::VarEntry { ty: ty, read_only: false }));
+                }
+
+                let body_ty = {
+                    let checker = self.scoped(&fvenv, tenv, false);
+                    checker.trans_exp(body)?.ty
+                };
+                if result.is_some() {
+                    self.expect(&result_ty, &body_ty, "Function body", pos)?;
+                } else if *Ty::actual(&body_ty) != Ty::Unit {
+                    return Err(format!("Procedure {} returns a value of type {} at pos {}",
+                                       self.name(name), self.ty_name(&body_ty), pos));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn trans_var_dec(&self, dec: &ast::Dec, venv: &mut ValueEnv, tenv: &TypeEnv) -> Result<(), String> {
+        if let &ast::Dec::VarDec { name, ref typ, ref init, pos, .. } = dec {
+            let ty = {
+                let checker = self.scoped(venv, tenv, self.in_loop);
+                let init_ty = checker.trans_exp(init)?.ty;
+                match typ {
+                    &Some((typ, typ_pos)) => {
+                        let declared = checker.look_ty(typ, typ_pos)?;
+                        checker.expect(&declared, &init_ty, "Initializer", pos)?;
+                        declared
+                    },
+                    &None => {
+                        if *Ty::actual(&init_ty) == Ty::Nil {
+                            return Err(format!("Variable {} initialized with nil needs a record type at pos {}",
+                                               self.name(name), pos));
+                        }
+                        init_ty
+                    }
+                }
+            };
+            self.types.borrow_mut().decs.insert(dec.id(), ty.clone());
+            venv.enter(name, Rc::new(EnvEntry::VarEntry { ty: ty, read_only: false }));
+        }
+        Ok(())
+    }
+
+    fn trans_dec(&self, decs: &Vec<Box<ast::Dec>>, body: &Box<ast::Exp>) -> Result<ExpTy, String> {
+        let mut venv = ValueEnv::new(Some(self.venv));
+        let mut tenv = TypeEnv::new(Some(self.tenv));
+        self.trans_decs(decs, &mut venv, &mut tenv)?;
+
+        let tcheck = self.scoped(&venv, &tenv, self.in_loop);
         tcheck.trans_exp(body.as_ref())
     }
 
+    // enters the declarations of a let in venv and tenv
+    fn trans_decs(&self, decs: &[Box<ast::Dec>], venv: &mut ValueEnv, tenv: &mut TypeEnv) -> Result<(), String> {
+        let mut i = 0;
+        while i < decs.len() {
+            // find the group of adjacent declarations of the same kind
+            let mut j = i + 1;
+            match decs[i].as_ref() {
+                &ast::Dec::TypeDec { .. } => {
+                    while j < decs.len() {
+                        if let &ast::Dec::TypeDec { .. } = decs[j].as_ref() { j += 1 } else { break }
+                    }
+                    self.trans_type_decs(&decs[i..j], tenv)?;
+                },
+                &ast::Dec::FunDec { .. } => {
+                    while j < decs.len() {
+                        if let &ast::Dec::FunDec { .. } = decs[j].as_ref() { j += 1 } else { break }
+                    }
+                    self.trans_fun_decs(&decs[i..j], venv, tenv)?;
+                },
+                &ast::Dec::VarDec { .. } => {
+                    self.trans_var_dec(&decs[i], venv, tenv)?;
+                },
+            }
+            i = j;
+        }
+        Ok(())
+    }
+
     fn trans_var(&self, var: &ast::Var) -> Result<ExpTy, String> {
+        let res = self.trans_var_(var)?;
+        self.types.borrow_mut().vars.insert(var.id(), res.ty.clone());
+        Ok(res)
+    }
+
+    fn trans_var_(&self, var: &ast::Var) -> Result<ExpTy, String> {
         match var {
-            &ast::Var::SimpleVar(symbol, pos) => {
+            &ast::Var::SimpleVar(symbol, pos, _) => {
                 match self.venv.look(symbol) {
                     Some(rc_ty) => match rc_ty.as_ref() {
-                        // ty could be a Name type, which we should catch (return actual types,
-                        // not type aliases)
-                        &EnvEntry::VarEntry(ref ty) => Ok(ExpTy { exp: (), ty: ty.as_ref().clone() }),
+                        &EnvEntry::VarEntry { ref ty, .. } => Ok(ExpTy { ty: Ty::actual(ty) }),
                         _ => {
                             let name = self.symbol_table.name(&symbol);
                             Err(format!("Unknown variable {} at pos {}", name, pos))
@@ -109,131 +385,315 @@ impl<'a> TypeChecker<'a> {
                     }
                 }
             },
-            &ast::Var::FieldVar(ref var, symbol, pos) => {
+            &ast::Var::FieldVar(ref var, symbol, pos, _) => {
                 // var must be of type RecordTy, and have a field matching symbol
-                let ExpTy { ty: var_ty, .. } = self.trans_var(var)?;
-                if let Ty::Record { ref fields, .. } = var_ty {
+                let ExpTy { ty: var_ty } = self.trans_var(var)?;
+                if let &Ty::Record { ref fields, .. } = var_ty.as_ref() {
                     if let Some(field) = fields.iter().find(|&x| x.0 == symbol) {
-                        Ok(ExpTy { exp: (), ty: field.1.as_ref().clone() })
+                        Ok(ExpTy { ty: Ty::actual(&field.1) })
                     } else {
-                        Err(format!("Var {:?} of type {:?} has no field named {}",
-                                    var, var_ty, symbol))
+                        Err(format!("Record of type {} has no field named {} at pos {}",
+                                    self.ty_name(&var_ty), self.name(symbol), pos))
                     }
                 } else {
-                    Err(format!("Var {:?} is not a record", var))
+                    Err(format!("Variable of type {} is not a record at pos {}", self.ty_name(&var_ty), pos))
                 }
             },
-            &ast::Var::SubscriptVar(ref var, ref exp, pos) => {
-                Err("unimplemented".to_string())
+            &ast::Var::SubscriptVar(ref var, ref exp, pos, _) => {
+                let ExpTy { ty: var_ty } = self.trans_var(var)?;
+                let ExpTy { ty: index_ty } = self.trans_exp(exp)?;
+                self.expect_int(&index_ty, "Array index", pos)?;
+                if let &Ty::Array { ref typ, .. } = var_ty.as_ref() {
+                    Ok(ExpTy { ty: Ty::actual(typ) })
+                } else {
+                    Err(format!("Variable of type {} is not an array at pos {}", self.ty_name(&var_ty), pos))
+                }
             }
         }
     }
 
     pub fn trans_exp(&self, exp: &ast::Exp) -> Result<ExpTy, String> {
+        let res = self.trans_exp_(exp)?;
+        self.types.borrow_mut().exps.insert(exp.id(), res.ty.clone());
+        Ok(res)
+    }
+
+    fn trans_exp_(&self, exp: &ast::Exp) -> Result<ExpTy, String> {
         use ast::Oper::*;
 
         match exp {
-            &ast::Exp::VarExp(ref var) => self.trans_var(var),
-
-            &ast::Exp::IntExp(_) => Ok(ExpTy { exp: (), ty: Ty::Int }),
-            &ast::Exp::StringExp(_, _) => Ok(ExpTy { exp: (), ty: Ty::String }),
-            &ast::Exp::NilExp => Ok(ExpTy { exp: (), ty: Ty::Nil }),
-
-            &ast::Exp::CallExp { func, ref args, pos } => {
-                Err("unimplemented".to_string())
+            &ast::Exp::VarExp(ref var, _) => self.trans_var(var),
+
+            &ast::Exp::IntExp(_, _) => Ok(ExpTy { ty: Rc::new(Ty::Int) }),
+            &ast::Exp::StringExp(_, _, _) => Ok(ExpTy { ty: Rc::new(Ty::String) }),
+            &ast::Exp::NilExp(_) => Ok(ExpTy { ty: Rc::new(Ty::Nil) }),
+
+            &ast::Exp::CallExp{ func, ref args, pos, .. } => {
+                match self.venv.look(func).map(|e| e.as_ref()) {
+                    Some(&EnvEntry::FunEntry { ref formals, ref result }) => {
+                        if formals.len() != args.len() {
+                            return Err(format!("Function {} expects {} arguments, got {} at pos {}",
+                                               self.name(func), formals.len(), args.len(), pos));
+                        }
+                        for (formal, arg) in formals.iter().zip(args.iter()) {
+                            let ExpTy { ty: arg_ty } = self.trans_exp(arg)?;
+                            self.expect(formal, &arg_ty, "Argument", pos)?;
+                        }
+                        Ok(ExpTy { ty: Ty::actual(result) })
+                    },
+                    _ => Err(format!("Unknown function {} at pos {}", self.name(func), pos)),
+                }
             },
 
-            &ast::Exp::OpExp { ref left, op, ref right, pos } => {
-                let ExpTy { ty: left_ty, .. } = self.trans_exp(left)?;
-                let ExpTy { ty: right_ty, .. } = self.trans_exp(right)?;
+            &ast::Exp::OpExp{ ref left, op, ref right, pos, .. } => {
+                let ExpTy { ty: left_ty } = self.trans_exp(left)?;
+                let ExpTy { ty: right_ty } = self.trans_exp(right)?;
 
                 match op {
                     PlusOp | MinusOp |
-                    TimesOp | DivideOp |
+                    TimesOp | DivideOp => {
+                        match (left_ty.as_ref(), right_ty.as_ref()) {
+                            (&Ty::Int, &Ty::Int) => Ok(ExpTy { ty: Rc::new(Ty::Int) }),
+                            _ => Err(format!("Integer required at pos {}", pos)),
+                        }
+                    },
+
                     LtOp | LeOp |
                     GtOp | GeOp => {
-                        match (left_ty, right_ty) {
-                            (Ty::Int, Ty::Int) => Ok(ExpTy { exp: (), ty: Ty::Int }),
-                            _ => Err(format!("Integer required at {}", pos)),
+                        match (left_ty.as_ref(), right_ty.as_ref()) {
+                            (&Ty::Int, &Ty::Int) |
+                            (&Ty::String, &Ty::String) => Ok(ExpTy { ty: Rc::new(Ty::Int) }),
+                            _ => Err(format!("Integer or string operands required at pos {}", pos)),
                         }
                     },
 
                     EqOp | NeqOp => {
-                        match (left_ty, right_ty) {
-                            (Ty::Int, Ty::Int) => Ok(ExpTy { exp: (), ty: Ty::Int }),
-                            _ => Err(format!("Integer required at {}", pos)),
+                        match (left_ty.as_ref(), right_ty.as_ref()) {
+                            (&Ty::Nil, &Ty::Nil) => Err(format!("Cannot compare nil with nil at pos {}", pos)),
+                            (&Ty::Unit, _) | (_, &Ty::Unit) =>
+                                Err(format!("Cannot compare valueless expressions at pos {}", pos)),
+                            _ if Ty::is_compatible(&left_ty, &right_ty) => Ok(ExpTy { ty: Rc::new(Ty::Int) }),
+                            _ => Err(format!("Cannot compare {} with {} at pos {}",
+                                             self.ty_name(&left_ty), self.ty_name(&right_ty), pos)),
                         }
                     }
                 }
             },
 
-            &ast::Exp::RecordExp { ref fields, typ, pos } => {
-                Err("unimplemented".to_string())
-            },
-
-
-            &ast::Exp::SeqExp(ref v) => {
-                if v.len() == 0 {
-                    Ok(ExpTy { exp: (), ty: Ty::Nil })
+            &ast::Exp::RecordExp{ ref fields, typ, pos, .. } => {
+                let ty = Ty::actual(&self.look_ty(typ, pos)?);
+                if let &Ty::Record { fields: ref field_tys, .. } = ty.as_ref() {
+                    if field_tys.len() != fields.len() {
+                        return Err(format!("Record {} has {} fields, got {} at pos {}",
+                                           self.name(typ), field_tys.len(), fields.len(), pos));
+                    }
+                    for (&(name, ref field_ty), &(fname, ref exp, fpos)) in field_tys.iter().zip(fields.iter()) {
+                        if name != fname {
+                            return Err(format!("Expected field {}, found {} at pos {}",
+                                               self.name(name), self.name(fname), fpos));
+                        }
+                        let ExpTy { ty: exp_ty } = self.trans_exp(exp)?;
+                        self.expect(field_ty, &exp_ty, "Field", fpos)?;
+                    }
+                    Ok(ExpTy { ty: ty.clone() })
                 } else {
-                    self.trans_exp(&v[v.len() - 1])
+                    Err(format!("Type {} is not a record at pos {}", self.name(typ), pos))
                 }
             },
 
-            &ast::Exp::AssignExp { .. } => Ok(ExpTy { exp: (), ty: Ty::Unit }),
+            &ast::Exp::SeqExp(ref v, _) => {
+                let mut ty = Rc::new(Ty::Unit);
+                for e in v.iter() {
+                    ty = self.trans_exp(e)?.ty;
+                }
+                Ok(ExpTy { ty: ty })
+            },
 
-            &ast::Exp::IfExp { ref test, ref then_, ref else_, pos } => {
-                let ExpTy { ty: test_ty, .. } = self.trans_exp(test)?;
-                let then_ty = self.trans_exp(then_)?;
+            &ast::Exp::AssignExp{ ref var, ref exp, pos, .. } => {
+                if let &ast::Var::SimpleVar(symbol, _, _) = var.as_ref() {
+                    if let Some(&EnvEntry::VarEntry { read_only: true, .. }) = self.venv.look(symbol).map(|e| e.as_ref()) {
+                        return Err(format!("Cannot assign to loop variable {} at pos {}", self.name(symbol), pos));
+                    }
+                }
+                let ExpTy { ty: var_ty } = self.trans_var(var)?;
+                let ExpTy { ty: exp_ty } = self.trans_exp(exp)?;
+                self.expect(&var_ty, &exp_ty, "Assigned value", pos)?;
+                Ok(ExpTy { ty: Rc::new(Ty::Unit) })
+            },
 
-                if test_ty == Ty::Int {
-                    if else_.is_some() {
-                        let else_ty = self.trans_exp(else_.as_ref().unwrap())?;
-                        if else_ty.ty == then_ty.ty {
-                            Ok(ExpTy { exp: (), ty: else_ty.ty })
+            &ast::Exp::IfExp{ ref test, ref then_, ref else_, pos, .. } => {
+                let ExpTy { ty: test_ty } = self.trans_exp(test)?;
+                self.expect_int(&test_ty, "Test", pos)?;
+                let ExpTy { ty: then_ty } = self.trans_exp(then_)?;
+
+                match else_ {
+                    &Some(ref else_) => {
+                        let ExpTy { ty: else_ty } = self.trans_exp(else_)?;
+                        if !Ty::is_compatible(&then_ty, &else_ty) {
+                            return Err(format!("then ({}) and else ({}) branch are not of the same type at pos {}",
+                                               self.ty_name(&then_ty), self.ty_name(&else_ty), pos));
+                        }
+                        // "if c then nil else r" has the record's type
+                        if *then_ty == Ty::Nil {
+                            Ok(ExpTy { ty: else_ty })
                         } else {
-                            Err(format!("then ({:?} and else {:?} branch are not of the same type",
-                                        then_ty.ty, else_ty.ty))
+                            Ok(ExpTy { ty: then_ty })
                         }
-                    } else {
-                        Ok(then_ty)
+                    },
+                    &None => {
+                        if *then_ty != Ty::Unit {
+                            return Err(format!("if-then without else must not return a value at pos {}", pos));
+                        }
+                        Ok(ExpTy { ty: then_ty })
                     }
-                } else {
-                    Err(format!("integer required for test at {}", pos))
                 }
             },
 
-            &ast::Exp::WhileExp { ref test, ref body, pos } => {
-                let ExpTy { ty: test_ty, .. } = self.trans_exp(test)?;
-                let _ = self.trans_exp(body)?;
-                if test_ty == Ty::Int {
-                    Ok(ExpTy { ty: Ty::Unit, exp: () })
-                } else {
-                    Err(format!("integer required for test at {}", pos))
+            &ast::Exp::WhileExp{ ref test, ref body, pos, .. } => {
+                let ExpTy { ty: test_ty } = self.trans_exp(test)?;
+                self.expect_int(&test_ty, "Test", pos)?;
+                let body_ty = {
+                    let checker = self.scoped(self.venv, self.tenv, true);
+                    checker.trans_exp(body)?.ty
+                };
+                if *body_ty != Ty::Unit {
+                    return Err(format!("Body of while loop must not return a value at pos {}", pos));
                 }
+                Ok(ExpTy { ty: Rc::new(Ty::Unit) })
             },
 
             &ast::Exp::ForExp { var, ref lo, ref hi, ref body, pos, .. } => {
-                let ExpTy { ty: lo_ty, .. } = self.trans_exp(lo)?;
-                let ExpTy { ty: hi_ty, .. } = self.trans_exp(hi)?;
-                // add var to environment
-                let _ = self.trans_exp(body)?;
-                Err("unimplemented".to_string())
+                let ExpTy { ty: lo_ty } = self.trans_exp(lo)?;
+                self.expect_int(&lo_ty, "Lower bound", pos)?;
+                let ExpTy { ty: hi_ty } = self.trans_exp(hi)?;
+                self.expect_int(&hi_ty, "Upper bound", pos)?;
+
+                let mut venv = ValueEnv::new(Some(self.venv));
+                venv.enter(var, Rc::new(EnvEntry::VarEntry { ty: Rc::new(Ty::Int), read_only: true }));
+                let body_ty = {
+                    let checker = self.scoped(&venv, self.tenv, true);
+                    checker.trans_exp(body)?.ty
+                };
+                if *body_ty != Ty::Unit {
+                    return Err(format!("Body of for loop must not return a value at pos {}", pos));
+                }
+                Ok(ExpTy { ty: Rc::new(Ty::Unit) })
             }
 
-            &ast::Exp::BreakExp(pos) => Ok(ExpTy { ty: Ty::Unit, exp: () }),
+            &ast::Exp::BreakExp(pos, _) => {
+                if self.in_loop {
+                    Ok(ExpTy { ty: Rc::new(Ty::Unit) })
+                } else {
+                    Err(format!("break outside of a loop at pos {}", pos))
+                }
+            },
 
-            &ast::Exp::LetExp { ref decs, ref body, pos } => {
+            &ast::Exp::LetExp{ ref decs, ref body, pos, .. } => {
                 self.trans_dec(decs, body)
             },
 
-            &ast::Exp::ArrayExp { typ, ref size, ref init, pos } => {
-                Err("unimplemented".to_string())
+            &ast::Exp::ArrayExp{ typ, ref size, ref init, pos, .. } => {
+                let ty = Ty::actual(&self.look_ty(typ, pos)?);
+                if let &Ty::Array { typ: ref elem_ty, .. } = ty.as_ref() {
+                    let ExpTy { ty: size_ty } = self.trans_exp(size)?;
+                    self.expect_int(&size_ty, "Array size", pos)?;
+                    let ExpTy { ty: init_ty } = self.trans_exp(init)?;
+                    self.expect(elem_ty, &init_ty, "Array initializer", pos)?;
+                    Ok(ExpTy { ty: ty.clone() })
+                } else {
+                    Err(format!("Type {} is not an array at pos {}", self.name(typ), pos))
+                }
             },
         }
     }
 }
 
+// Type checks a whole program in the environment of the standard library,
+// returning the types of all its expressions.
+pub fn type_check(exp: &ast::Exp, symbol_table: &mut SymbolTable) -> Result<TypeMap, String> {
+    let venv = base_venv(symbol_table);
+    let tenv = base_tenv(symbol_table);
+    let unique_gen = RefCell::new(UniqueGenerator::new());
+    let types = RefCell::new(TypeMap::new());
+    {
+        let checker = TypeChecker::new(symbol_table, &venv, &tenv, &unique_gen, &types);
+        checker.trans_exp(exp)?;
+    }
+    Ok(types.into_inner())
+}
+
+// Type checks a program up to its first error, returning the types found
+// so far along with the error, for the language server.
+pub fn type_check_partial(exp: &ast::Exp, symbol_table: &mut SymbolTable) -> (TypeMap, Result<(), String>) {
+    let venv = base_venv(symbol_table);
+    let tenv = base_tenv(symbol_table);
+    let unique_gen = RefCell::new(UniqueGenerator::new());
+    let types = RefCell::new(TypeMap::new());
+    let result = {
+        let checker = TypeChecker::new(symbol_table, &venv, &tenv, &unique_gen, &types);
+        checker.trans_exp(exp).map(|_| ())
+    };
+    (types.into_inner(), result)
+}
+
+// The environments of the top level, which the REPL keeps between its
+// inputs: expressions are checked in them, and declarations added to them.
+#[derive(Clone)]
+pub struct Environment {
+    venv: ValueEnv<'static>,
+    tenv: TypeEnv<'static>,
+    unique_gen: RefCell<UniqueGenerator>,
+}
+
+impl Environment {
+    pub fn new(symbol_table: &mut SymbolTable) -> Environment {
+        Environment {
+            venv: base_venv(symbol_table),
+            tenv: base_tenv(symbol_table),
+            unique_gen: RefCell::new(UniqueGenerator::new()),
+        }
+    }
+
+    pub fn check(&self, exp: &ast::Exp, symbol_table: &SymbolTable) -> Result<TypeMap, String> {
+        let types = RefCell::new(TypeMap::new());
+        {
+            let checker = TypeChecker::new(symbol_table, &self.venv, &self.tenv, &self.unique_gen, &types);
+            checker.trans_exp(exp)?;
+        }
+        Ok(types.into_inner())
+    }
+
+    // checks declarations as those of a let, keeping them only if they all
+    // check; they hide earlier ones of the same names
+    pub fn declare(&mut self, decs: &[Box<ast::Dec>], symbol_table: &SymbolTable) -> Result<TypeMap, String> {
+        let types = RefCell::new(TypeMap::new());
+        let (values, tys) = {
+            let mut venv = ValueEnv::new(Some(&self.venv));
+            let mut tenv = TypeEnv::new(Some(&self.tenv));
+            {
+                let checker = TypeChecker::new(symbol_table, &self.venv, &self.tenv, &self.unique_gen, &types);
+                checker.trans_decs(decs, &mut venv, &mut tenv)?;
+            }
+            (venv.into_entries(), tenv.into_entries())
+        };
+        for (symbol, entry) in values.into_iter() {
+            self.venv.enter(symbol, entry);
+        }
+        for (symbol, ty) in tys.into_iter() {
+            self.tenv.enter(symbol, ty);
+        }
+        Ok(types.into_inner())
+    }
+
+    pub fn look_value(&self, symbol: SymbolId) -> Option<&Rc<EnvEntry>> {
+        self.venv.look(symbol)
+    }
+
+    pub fn look_ty(&self, symbol: SymbolId) -> Option<&Rc<Ty>> {
+        self.tenv.look(symbol)
+    }
+}
+
 #[test]
 fn test_trans_exp() {
     use parser::parse;
@@ -242,10 +702,75 @@ fn test_trans_exp() {
     let venv = ValueEnv::new(None);
     let tenv = TypeEnv::new(None);
     let unique_gen = RefCell::new(UniqueGenerator::new());
-    let mut type_checker = TypeChecker::new(&symbol_table, &venv, &tenv, &unique_gen);
+    let types = RefCell::new(TypeMap::new());
+    let mut type_checker = TypeChecker::new(&symbol_table, &venv, &tenv, &unique_gen, &types);
 
     let venv2 = ValueEnv::new(Some(&venv));
     let mut tcheck2 = TypeChecker::new_with_venv(&mut type_checker, &venv2);
     let ExpTy { ty, .. } = tcheck2.trans_exp(&*p).unwrap();
     println!("{:?}", ty);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_type_check_programs() {
+    use parser::parse;
+
+    let ok = [
+        "let type list = {hd: int, tl: list} var l := list {hd = 1, tl = nil} in l.tl.hd end",
+        "let type a = array of int var x := a[10] of 0 in x[2] := 3; x[1] end",
+        "let function f(n: int): int = if n = 0 then 1 else n * f(n - 1) in printi(f(5)) end",
+        "let function even(n: int): int = if n = 0 then 1 else odd(n - 1) \
+                function odd(n: int): int = if n = 0 then 0 else even(n - 1) in even(4) end",
+        "for i := 0 to 10 do (if i = 5 then break; print(\"x\"))",
+        "let var s := \"a\" in s < \"b\" end",
+        "-1 & 2 | 3",
+    ];
+    for program in ok.iter() {
+        let (p, mut symbol_table) = parse(program).unwrap();
+        if let Err(e) = type_check(&p, &mut symbol_table) {
+            panic!("{}: {}", program, e);
+        }
+    }
+
+    let bad = [
+        ("let type a = b type b = a in 0 end", "Illegal cycle"),
+        ("let var x := nil in x end", "needs a record type"),
+        ("for i := 0 to 10 do i := 3", "Cannot assign to loop variable"),
+        ("break", "break outside of a loop"),
+        ("let function f(a: int) = a in f(1) end", "returns a value"),
+        ("1 + \"x\"", "Integer required"),
+        ("nil = nil", "Cannot compare nil"),
+        ("if 1 then 2", "must not return a value"),
+        ("undefined(1)", "Unknown function"),
+        ("let type r = {a: int} in r {b = 1} end", "Expected field a"),
+    ];
+    for &(program, message) in bad.iter() {
+        let (p, mut symbol_table) = parse(program).unwrap();
+        match type_check(&p, &mut symbol_table) {
+            Ok(_) => panic!("{} should not type check", program),
+            Err(e) => assert!(e.contains(message), "{}: {}", program, e),
+        }
+    }
+}
+
+#[test]
+fn test_type_map() {
+    use parser::parse;
+
+    let (p, mut symbol_table) = parse("let type r = {a: string} var x := r {a = \"s\"} in x.a end").unwrap();
+    let types = type_check(&p, &mut symbol_table).unwrap();
+    let string = |ty: Option<&Rc<Ty>>| ty.map(|t| t.name(&symbol_table));
+    // the types stay with a tree when it is cloned and the original dropped
+    let copy = p.clone();
+    drop(p);
+    assert_eq!(string(types.exp_ty(&copy)), Some(String::from("string")));
+    if let ast::Exp::LetExp { ref decs, ref body, .. } = *copy {
+        assert_eq!(string(types.dec_ty(&decs[1])), Some(String::from("{a: string}")));
+        if let ast::Exp::VarExp(ref var, _) = **body {
+            assert_eq!(string(types.var_ty(var)), Some(String::from("string")));
+        }
+    }
+    // nodes of another tree are not mistaken for those of this one
+    let (other, _) = parse("let type r = {a: string} var x := r {a = \"s\"} in x.a end").unwrap();
+    assert!(types.exp_ty(&other).is_none());
+}
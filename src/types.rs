@@ -1,10 +1,12 @@
-use symbol::SymbolId;
+use symbol::{SymbolId, SymbolTable};
 
+use std::cell::RefCell;
+use std::fmt;
 use std::rc::Rc;
 
 pub type Unique = u32;
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Clone)]
 pub enum Ty {
     Int,
     String,
@@ -19,12 +21,101 @@ pub enum Ty {
         unique: Unique,
     },
     Unit,
-    Name(SymbolId, Option<Rc<Ty>>),
+    // the reference is filled in once the declaration group of a (possibly
+    // recursive) type has been processed
+    Name(SymbolId, RefCell<Option<Rc<Ty>>>),
+}
+
+impl Ty {
+    // follows Name types to the underlying type
+    pub fn actual(ty: &Rc<Ty>) -> Rc<Ty> {
+        match ty.as_ref() {
+            &Ty::Name(_, ref r) => match r.borrow().as_ref() {
+                Some(t) => Ty::actual(t),
+                None => ty.clone(),
+            },
+            _ => ty.clone(),
+        }
+    }
+
+    // whether values of the type are pointers to the heap (or nil)
+    pub fn is_pointer(ty: &Rc<Ty>) -> bool {
+        match Ty::actual(ty).as_ref() {
+            &Ty::String | &Ty::Record { .. } | &Ty::Array { .. } => true,
+            _ => false,
+        }
+    }
+
+    // nil can be used wherever a record is expected
+    pub fn is_compatible(expected: &Rc<Ty>, actual: &Rc<Ty>) -> bool {
+        let expected = Ty::actual(expected);
+        let actual = Ty::actual(actual);
+        match (expected.as_ref(), actual.as_ref()) {
+            (&Ty::Record { .. }, &Ty::Nil) | (&Ty::Nil, &Ty::Record { .. }) => true,
+            (e, a) => e == a,
+        }
+    }
+
+    // a readable rendering for error messages, e.g. "{hd: int, tl: list}"
+    pub fn name(&self, symbol_table: &SymbolTable) -> String {
+        match self {
+            &Ty::Int => String::from("int"),
+            &Ty::String => String::from("string"),
+            &Ty::Nil => String::from("nil"),
+            &Ty::Bool => String::from("bool"),
+            &Ty::Unit => String::from("unit"),
+            &Ty::Name(s, _) => symbol_table.name(&s).clone(),
+            &Ty::Array { ref typ, .. } => format!("array of {}", typ.name(symbol_table)),
+            &Ty::Record { ref fields, .. } => {
+                let fields: Vec<String> = fields.iter()
+                    .map(|&(s, ref ty)| format!("{}: {}", symbol_table.name(&s), ty.name(symbol_table)))
+                    .collect();
+                format!("{{{}}}", fields.join(", "))
+            },
+        }
+    }
+}
+
+// Records and arrays are compared by their unique tag, which also keeps
+// equality (and Debug output) finite for recursive types.
+impl PartialEq for Ty {
+    fn eq(&self, other: &Ty) -> bool {
+        match (self, other) {
+            (&Ty::Int, &Ty::Int) |
+            (&Ty::String, &Ty::String) |
+            (&Ty::Nil, &Ty::Nil) |
+            (&Ty::Bool, &Ty::Bool) |
+            (&Ty::Unit, &Ty::Unit) => true,
+            (&Ty::Record { unique: u1, .. }, &Ty::Record { unique: u2, .. }) => u1 == u2,
+            (&Ty::Array { unique: u1, .. }, &Ty::Array { unique: u2, .. }) => u1 == u2,
+            (&Ty::Name(s1, _), &Ty::Name(s2, _)) => s1 == s2,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Debug for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Ty::Int => write!(f, "Int"),
+            &Ty::String => write!(f, "String"),
+            &Ty::Nil => write!(f, "Nil"),
+            &Ty::Bool => write!(f, "Bool"),
+            &Ty::Unit => write!(f, "Unit"),
+            &Ty::Record { unique, ref fields } => write!(f, "Record {{ unique: {}, fields: {:?} }}", unique, fields),
+            &Ty::Array { unique, ref typ } => write!(f, "Array {{ typ: {:?}, unique: {} }}", typ, unique),
+            &Ty::Name(s, _) => write!(f, "Name({})", s),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum EnvEntry {
-    VarEntry(Rc<Ty>),
+    VarEntry {
+        ty: Rc<Ty>,
+        // for loop variables may not be assigned to
+        read_only: bool,
+    },
     FunEntry {
         formals: Vec<Rc<Ty>>,
         result: Rc<Ty>,
@@ -33,6 +124,7 @@ pub enum EnvEntry {
 
 use std::collections::BTreeMap;
 
+#[derive(Clone)]
 pub struct Table<'a, T: 'a> {
     parent: Option<&'a Table<'a, T>>,
     map: BTreeMap<SymbolId, Rc<T>>,
@@ -59,6 +151,11 @@ impl<'a, T: 'a> Table<'a, T> {
         }
     }
 
+    // the entries of this table alone, without those of its parents
+    pub fn into_entries(self) -> Vec<(SymbolId, Rc<T>)> {
+        self.map.into_iter().collect()
+    }
+
     pub fn contains(&self, s: SymbolId) -> bool {
         match (self.map.contains_key(&s), self.parent.as_ref()) {
             (true, _) => true,
@@ -99,6 +196,62 @@ fn test_table() {
 pub type TypeEnv<'a> = Table<'a, Ty>;
 pub type ValueEnv<'a> = Table<'a, EnvEntry>;
 
+pub fn base_tenv(symbol_table: &mut SymbolTable) -> TypeEnv<'static> {
+    let mut tenv = TypeEnv::new(None);
+    tenv.enter(symbol_table.symbol("int"), Rc::new(Ty::Int));
+    tenv.enter(symbol_table.symbol("string"), Rc::new(Ty::String));
+    tenv
+}
+
+// The standard library functions, all implemented by the runtime.
+pub fn builtin_functions() -> Vec<(&'static str, Vec<Ty>, Ty)> {
+    vec![
+        ("print", vec![Ty::String], Ty::Unit),
+        ("printi", vec![Ty::Int], Ty::Unit),
+        ("flush", vec![], Ty::Unit),
+        ("getchar", vec![], Ty::String),
+        ("ord", vec![Ty::String], Ty::Int),
+        ("chr", vec![Ty::Int], Ty::String),
+        ("size", vec![Ty::String], Ty::Int),
+        ("substring", vec![Ty::String, Ty::Int, Ty::Int], Ty::String),
+        ("concat", vec![Ty::String, Ty::String], Ty::String),
+        ("not", vec![Ty::Int], Ty::Int),
+        ("exit", vec![Ty::Int], Ty::Unit),
+    ]
+}
+
+pub fn base_venv(symbol_table: &mut SymbolTable) -> ValueEnv<'static> {
+    let mut venv = ValueEnv::new(None);
+    for (name, formals, result) in builtin_functions().into_iter() {
+        venv.enter(symbol_table.symbol(name), Rc::new(EnvEntry::FunEntry {
+            formals: formals.into_iter().map(Rc::new).collect(),
+            result: Rc::new(result),
+        }));
+    }
+    venv
+}
+
+#[test]
+fn test_recursive_type() {
+    // type list = {hd: int, tl: list}
+    let mut table = SymbolTable::new();
+    let list = table.symbol("list");
+    let name = Rc::new(Ty::Name(list, RefCell::new(None)));
+    let record = Rc::new(Ty::Record {
+        unique: 0,
+        fields: vec![(table.symbol("hd"), Rc::new(Ty::Int)),
+                     (table.symbol("tl"), name.clone())],
+    });
+    if let &Ty::Name(_, ref r) = name.as_ref() {
+        *r.borrow_mut() = Some(record.clone());
+    }
+
+    assert_eq!(Ty::actual(&name), record);
+    assert!(Ty::is_compatible(&name, &Rc::new(Ty::Nil)));
+    assert_eq!(record.name(&table), "{hd: int, tl: list}");
+    assert_eq!(format!("{:?}", record), "Record { unique: 0, fields: [(1, Int), (2, Name(0))] }");
+}
+
 #[test]
 fn test_table_refs() {
     //    let mut tenv = TypeEnv::new(None);
@@ -0,0 +1,596 @@
+use ast;
+use bytecode::{Builtin, Op, Program, LINK_SLOT, ENV_SLOT};
+use interp::{Control, RuntimeError};
+
+use std::cmp::Ordering;
+use std::io::{Read, Write};
+
+// The virtual machine running the bytecode of bytecode.rs. Locals and
+// operands share one stack of values; strings, records, arrays and the
+// environments of functions live on a heap with a mark and sweep collector,
+// whose roots are the stack and the string constants. A collection only
+// happens at the start of an instruction that allocates, while all the
+// values it uses are still on the stack.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Int(i32),
+    Nil,
+    Ref(u32),
+}
+
+enum Object {
+    Str(String),
+    // records and environments
+    Record(Vec<Value>),
+    Array(Vec<Value>),
+}
+
+impl Object {
+    // roughly in words, to decide when to collect
+    fn size(&self) -> usize {
+        match self {
+            &Object::Str(ref s) => 1 + s.len() / 8,
+            &Object::Record(ref fields) | &Object::Array(ref fields) => 1 + fields.len(),
+        }
+    }
+}
+
+const INITIAL_THRESHOLD: usize = 1 << 16;
+
+struct Heap {
+    objects: Vec<Option<Object>>,
+    marks: Vec<bool>,
+    free: Vec<u32>,
+    // the size allocated since the last collection, and the size that
+    // triggers the next one
+    allocated: usize,
+    threshold: usize,
+    collections: usize,
+}
+
+impl Heap {
+    fn new() -> Heap {
+        Heap {
+            objects: vec![],
+            marks: vec![],
+            free: vec![],
+            allocated: 0,
+            threshold: INITIAL_THRESHOLD,
+            collections: 0,
+        }
+    }
+
+    fn alloc(&mut self, object: Object) -> Value {
+        self.allocated += object.size();
+        match self.free.pop() {
+            Some(i) => {
+                self.objects[i as usize] = Some(object);
+                Value::Ref(i)
+            },
+            None => {
+                self.objects.push(Some(object));
+                self.marks.push(false);
+                Value::Ref(self.objects.len() as u32 - 1)
+            },
+        }
+    }
+
+    fn get(&self, value: Value) -> &Object {
+        match value {
+            Value::Ref(i) => self.objects[i as usize].as_ref().expect("dangling reference"),
+            _ => panic!("expected a reference"),
+        }
+    }
+
+    fn fields(&self, value: Value) -> &Vec<Value> {
+        match self.get(value) {
+            &Object::Record(ref fields) | &Object::Array(ref fields) => fields,
+            &Object::Str(_) => panic!("expected a record or an array"),
+        }
+    }
+
+    fn fields_mut(&mut self, value: Value) -> &mut Vec<Value> {
+        let i = match value {
+            Value::Ref(i) => i as usize,
+            _ => panic!("expected a reference"),
+        };
+        match self.objects[i].as_mut() {
+            Some(&mut Object::Record(ref mut fields)) | Some(&mut Object::Array(ref mut fields)) => fields,
+            _ => panic!("expected a record or an array"),
+        }
+    }
+
+    fn str(&self, value: Value) -> &str {
+        match self.get(value) {
+            &Object::Str(ref s) => s,
+            _ => panic!("expected a string"),
+        }
+    }
+
+    fn collect(&mut self, roots: &[&[Value]]) {
+        let mut work: Vec<u32> = vec![];
+        for values in roots.iter() {
+            for &v in values.iter() {
+                if let Value::Ref(i) = v {
+                    work.push(i);
+                }
+            }
+        }
+        while let Some(i) = work.pop() {
+            if self.marks[i as usize] {
+                continue;
+            }
+            self.marks[i as usize] = true;
+            match self.objects[i as usize] {
+                Some(Object::Record(ref fields)) | Some(Object::Array(ref fields)) => {
+                    for &v in fields.iter() {
+                        if let Value::Ref(j) = v {
+                            if !self.marks[j as usize] {
+                                work.push(j);
+                            }
+                        }
+                    }
+                },
+                _ => (),
+            }
+        }
+
+        let mut live = 0;
+        for (i, object) in self.objects.iter_mut().enumerate() {
+            if self.marks[i] {
+                self.marks[i] = false;
+                live += object.as_ref().unwrap().size();
+            } else if object.is_some() {
+                *object = None;
+                self.free.push(i as u32);
+            }
+        }
+        self.allocated = 0;
+        self.threshold = INITIAL_THRESHOLD.max(live);
+        self.collections += 1;
+    }
+}
+
+struct CallFrame {
+    function: usize,
+    pc: usize,
+    base: usize,
+}
+
+fn error<T>(message: String, pos: ast::Position) -> Result<T, Control> {
+    Err(Control::Error(RuntimeError { message: message, pos: pos }))
+}
+
+pub struct Vm<'p, 'io> {
+    program: &'p Program,
+    heap: Heap,
+    stack: Vec<Value>,
+    constants: Vec<Value>,
+    input: &'io mut dyn Read,
+    output: &'io mut dyn Write,
+}
+
+impl<'p, 'io> Vm<'p, 'io> {
+    pub fn new(program: &'p Program, input: &'io mut dyn Read, output: &'io mut dyn Write) -> Vm<'p, 'io> {
+        let mut heap = Heap::new();
+        let constants = program.strings.iter().map(|s| heap.alloc(Object::Str(s.clone()))).collect();
+        Vm {
+            program: program,
+            heap: heap,
+            stack: vec![],
+            constants: constants,
+            input: input,
+            output: output,
+        }
+    }
+
+    // the number of garbage collections so far
+    pub fn collections(&self) -> usize {
+        self.heap.collections
+    }
+
+    // the number of heap slots, live or free
+    pub fn heap_slots(&self) -> usize {
+        self.heap.objects.len()
+    }
+
+    // runs the main program; exit() and runtime errors are returned as
+    // Control, as in the interpreter
+    pub fn run(&mut self) -> Result<(), Control> {
+        let result = self.execute();
+        let _ = self.output.flush();
+        result
+    }
+
+    fn maybe_collect(&mut self) {
+        if self.heap.allocated >= self.heap.threshold {
+            self.heap.collect(&[&self.stack, &self.constants]);
+        }
+    }
+
+    fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("operand stack underflow")
+    }
+
+    fn pop_int(&mut self) -> i32 {
+        match self.pop() {
+            Value::Int(i) => i,
+            _ => panic!("expected an integer"),
+        }
+    }
+
+    fn new_string(&mut self, s: String) -> Value {
+        self.heap.alloc(Object::Str(s))
+    }
+
+    fn env(&self, base: usize, hops: u16) -> Value {
+        if hops == 0 {
+            return self.stack[base + ENV_SLOT as usize];
+        }
+        let mut env = self.stack[base + LINK_SLOT as usize];
+        for _ in 1..hops {
+            env = self.heap.fields(env)[0];
+        }
+        env
+    }
+
+    fn equal(&self, a: Value, b: Value) -> bool {
+        match (a, b) {
+            (Value::Ref(x), Value::Ref(y)) if x != y => match (self.heap.get(a), self.heap.get(b)) {
+                (&Object::Str(ref s), &Object::Str(ref t)) => s == t,
+                _ => false,
+            },
+            _ => a == b,
+        }
+    }
+
+    fn compare(&self, a: Value, b: Value) -> Ordering {
+        match (a, b) {
+            (Value::Int(x), Value::Int(y)) => x.cmp(&y),
+            _ => self.heap.str(a).cmp(self.heap.str(b)),
+        }
+    }
+
+    fn write(&mut self, s: &str, pos: ast::Position) -> Result<(), Control> {
+        match self.output.write_all(s.as_bytes()) {
+            Ok(()) => Ok(()),
+            Err(e) => error(format!("cannot write output: {}", e), pos),
+        }
+    }
+
+    fn call_builtin(&mut self, builtin: Builtin, pos: ast::Position) -> Result<Value, Control> {
+        self.maybe_collect();
+        let n = self.stack.len() - builtin.arity();
+        let args = self.stack.split_off(n);
+        Ok(match builtin {
+            Builtin::Print => {
+                let s = self.heap.str(args[0]).to_owned();
+                self.write(&s, pos)?;
+                Value::Int(0)
+            },
+            Builtin::PrintI => {
+                let s = match args[0] {
+                    Value::Int(i) => i.to_string(),
+                    _ => panic!("expected an integer"),
+                };
+                self.write(&s, pos)?;
+                Value::Int(0)
+            },
+            Builtin::Flush => {
+                let _ = self.output.flush();
+                Value::Int(0)
+            },
+            Builtin::GetChar => {
+                let mut buf = [0u8; 1];
+                let s = match self.input.read(&mut buf) {
+                    Ok(1) => (buf[0] as char).to_string(),
+                    _ => String::new(),
+                };
+                self.new_string(s)
+            },
+            Builtin::Ord => Value::Int(self.heap.str(args[0]).chars().next().map(|c| c as i32).unwrap_or(-1)),
+            Builtin::Chr => {
+                let i = match args[0] {
+                    Value::Int(i) => i,
+                    _ => panic!("expected an integer"),
+                };
+                if i < 0 || i > 255 {
+                    return error(format!("chr({}) is out of range", i), pos);
+                }
+                self.new_string((i as u8 as char).to_string())
+            },
+            Builtin::Size => Value::Int(self.heap.str(args[0]).chars().count() as i32),
+            Builtin::Substring => {
+                let (first, n) = match (args[1], args[2]) {
+                    (Value::Int(first), Value::Int(n)) => (first, n),
+                    _ => panic!("expected integers"),
+                };
+                let s = self.heap.str(args[0]).to_owned();
+                let size = s.chars().count() as i32;
+                if first < 0 || n < 0 || first > size - n {
+                    return error(format!("substring({:?}, {}, {}) is out of range", s, first, n), pos);
+                }
+                self.new_string(s.chars().skip(first as usize).take(n as usize).collect())
+            },
+            Builtin::Concat => {
+                let s = format!("{}{}", self.heap.str(args[0]), self.heap.str(args[1]));
+                self.new_string(s)
+            },
+            Builtin::Not => Value::Int(if args[0] == Value::Int(0) { 1 } else { 0 }),
+            Builtin::Exit => match args[0] {
+                Value::Int(i) => return Err(Control::Exit(i)),
+                _ => panic!("expected an integer"),
+            },
+        })
+    }
+
+    fn execute(&mut self) -> Result<(), Control> {
+        let program = self.program;
+        let mut frames: Vec<CallFrame> = vec![];
+        let mut current = 0;
+        let mut code = &program.functions[0].code;
+        let mut pc = 0;
+        let mut base = 0;
+        self.stack.resize(program.functions[0].locals, Value::Nil);
+
+        loop {
+            let op = code[pc];
+            pc += 1;
+            match op {
+                Op::Int(i) => self.push(Value::Int(i)),
+                Op::Nil => self.push(Value::Nil),
+                Op::Str(i) => {
+                    let s = self.constants[i as usize];
+                    self.push(s);
+                },
+                Op::Pop => {
+                    self.pop();
+                },
+                Op::GetLocal(i) => {
+                    let v = self.stack[base + i as usize];
+                    self.push(v);
+                },
+                Op::SetLocal(i) => {
+                    let v = self.pop();
+                    self.stack[base + i as usize] = v;
+                },
+                Op::GetEnv(hops, i) => {
+                    let env = self.env(base, hops);
+                    let v = self.heap.fields(env)[i as usize];
+                    self.push(v);
+                },
+                Op::SetEnv(hops, i) => {
+                    let v = self.pop();
+                    let env = self.env(base, hops);
+                    self.heap.fields_mut(env)[i as usize] = v;
+                },
+                Op::PushEnv(hops) => {
+                    let env = self.env(base, hops);
+                    self.push(env);
+                },
+                Op::MakeEnv(n) => {
+                    self.maybe_collect();
+                    let mut fields = vec![Value::Nil; n as usize + 1];
+                    fields[0] = self.stack[base + LINK_SLOT as usize];
+                    let env = self.heap.alloc(Object::Record(fields));
+                    self.stack[base + ENV_SLOT as usize] = env;
+                },
+                Op::Add | Op::Sub | Op::Mul | Op::Div(_) => {
+                    let b = self.pop_int();
+                    let a = self.pop_int();
+                    let v = match op {
+                        Op::Add => a.wrapping_add(b),
+                        Op::Sub => a.wrapping_sub(b),
+                        Op::Mul => a.wrapping_mul(b),
+                        Op::Div(pos) => {
+                            if b == 0 {
+                                return error(String::from("division by zero"), pos);
+                            }
+                            a.wrapping_div(b)
+                        },
+                        _ => unreachable!(),
+                    };
+                    self.push(Value::Int(v));
+                },
+                Op::Eq | Op::Ne => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    let equal = self.equal(a, b);
+                    self.push(Value::Int(if equal == (op == Op::Eq) { 1 } else { 0 }));
+                },
+                Op::Lt | Op::Le | Op::Gt | Op::Ge => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    let ordering = self.compare(a, b);
+                    let result = match op {
+                        Op::Lt => ordering == Ordering::Less,
+                        Op::Le => ordering != Ordering::Greater,
+                        Op::Gt => ordering == Ordering::Greater,
+                        _ => ordering != Ordering::Less,
+                    };
+                    self.push(Value::Int(if result { 1 } else { 0 }));
+                },
+                Op::Jump(target) => pc = target as usize,
+                Op::JumpIfFalse(target) => {
+                    if self.pop_int() == 0 {
+                        pc = target as usize;
+                    }
+                },
+                Op::Call(function, args) => {
+                    frames.push(CallFrame { function: current, pc: pc, base: base });
+                    current = function as usize;
+                    let f = &program.functions[current];
+                    code = &f.code;
+                    pc = 0;
+                    base = self.stack.len() - args as usize;
+                    self.stack.resize(base + f.locals, Value::Nil);
+                },
+                Op::CallBuiltin(builtin, pos) => {
+                    let v = self.call_builtin(builtin, pos)?;
+                    self.push(v);
+                },
+                Op::Return => {
+                    let v = self.pop();
+                    self.stack.truncate(base);
+                    match frames.pop() {
+                        Some(frame) => {
+                            current = frame.function;
+                            code = &program.functions[current].code;
+                            pc = frame.pc;
+                            base = frame.base;
+                            self.push(v);
+                        },
+                        None => return Ok(()),
+                    }
+                },
+                Op::Record(n) => {
+                    self.maybe_collect();
+                    let start = self.stack.len() - n as usize;
+                    let fields = self.stack.split_off(start);
+                    let r = self.heap.alloc(Object::Record(fields));
+                    self.push(r);
+                },
+                Op::GetField(i, pos) => {
+                    let r = self.pop();
+                    if r == Value::Nil {
+                        return error(String::from("nil record dereference"), pos);
+                    }
+                    let v = self.heap.fields(r)[i as usize];
+                    self.push(v);
+                },
+                Op::SetField(i, pos) => {
+                    let v = self.pop();
+                    let r = self.pop();
+                    if r == Value::Nil {
+                        return error(String::from("nil record dereference"), pos);
+                    }
+                    self.heap.fields_mut(r)[i as usize] = v;
+                },
+                Op::Array(pos) => {
+                    self.maybe_collect();
+                    let init = self.pop();
+                    let size = self.pop_int();
+                    if size < 0 {
+                        return error(format!("negative array size {}", size), pos);
+                    }
+                    let a = self.heap.alloc(Object::Array(vec![init; size as usize]));
+                    self.push(a);
+                },
+                Op::GetIndex(pos) => {
+                    let index = self.pop_int();
+                    let a = self.pop();
+                    let v = {
+                        let elements = self.heap.fields(a);
+                        if index < 0 || index as usize >= elements.len() {
+                            return error(format!("index {} out of bounds for array of size {}",
+                                                 index, elements.len()), pos);
+                        }
+                        elements[index as usize]
+                    };
+                    self.push(v);
+                },
+                Op::SetIndex(pos) => {
+                    let v = self.pop();
+                    let index = self.pop_int();
+                    let a = self.pop();
+                    let elements = self.heap.fields_mut(a);
+                    if index < 0 || index as usize >= elements.len() {
+                        return error(format!("index {} out of bounds for array of size {}",
+                                             index, elements.len()), pos);
+                    }
+                    elements[index as usize] = v;
+                },
+                Op::Nop => (),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_run_programs() {
+    use bytecode::compile;
+    use driver::front_end;
+
+    let run = |program: &str, input: &str| -> (Result<(), Control>, String, usize) {
+        let (exp, mut symbol_table, types) = front_end(program).unwrap();
+        let program = compile(&exp, &types, &mut symbol_table);
+        let mut input = input.as_bytes();
+        let mut output = vec![];
+        let (result, slots) = {
+            let mut vm = Vm::new(&program, &mut input, &mut output);
+            let result = vm.run();
+            (result, vm.heap_slots())
+        };
+        (result, String::from_utf8(output).unwrap(), slots)
+    };
+
+    let (result, output, _) = run("let function fact(n: int): int = if n = 0 then 1 else n * fact(n - 1) \
+                                   in printi(fact(10)); print(\"\\n\") end", "");
+    assert!(result.is_ok());
+    assert_eq!(output, "3628800\n");
+
+    // escaping variables reached through static links, and a break that
+    // leaves operands behind
+    let (_, output, _) = run("let var n := 0 \
+                                  function f(x: int) = \
+                                    let function g() = n := n + x in g() end \
+                              in for i := 1 to 10 do f(i); \
+                                 while 1 do (n := n + 1 + (if n > 60 then break; 0)); \
+                                 printi(n) end", "");
+    assert_eq!(output, "61");
+
+    let (_, output, _) = run("let var c := getchar() in \
+                                while c <> \"\" do (print(chr(ord(c) + 1)); c := getchar()) end", "HAL");
+    assert_eq!(output, "IBM");
+
+    // only a few records are live at a time, so the heap stays small
+    let (result, output, slots) = run("let type list = { hd: int, tl: list } \
+                                           var l: list := nil \
+                                       in for i := 1 to 200000 do \
+                                            (if i - i / 10 * 10 = 0 then l := nil; \
+                                             l := list { hd = i, tl = l }); \
+                                          printi(l.hd) end", "");
+    assert!(result.is_ok());
+    assert_eq!(output, "200000");
+    assert!(slots < 50000);
+
+    let (result, _, _) = run("let type a = array of int var v := a[2] of 0 in v[2] := 1 end", "");
+    assert_eq!(result, Err(Control::Error(RuntimeError {
+        message: String::from("index 2 out of bounds for array of size 2"),
+        pos: 48,
+    })));
+}
+
+// compares the bytecode machine with the interpreter on the eight queens,
+// leaving out the front end; run with cargo test -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_queens() {
+    use bytecode::compile;
+    use driver::front_end;
+    use interp::Interpreter;
+    use std::time::{Duration, Instant};
+
+    let (exp, mut symbol_table, types) = front_end(include_str!("../tests/appel/queens.tig")).unwrap();
+    let program = compile(&exp, &types, &mut symbol_table);
+    let mut time = |bytecode: bool| -> Duration {
+        let start = Instant::now();
+        for _ in 0..20 {
+            let mut output = vec![];
+            if bytecode {
+                Vm::new(&program, &mut "".as_bytes(), &mut output).run().unwrap();
+            } else {
+                Interpreter::new(&mut symbol_table, &mut "".as_bytes(), &mut output).run(&exp).unwrap();
+            }
+            assert_eq!(String::from_utf8(output).unwrap(), include_str!("../tests/appel/queens.out"));
+        }
+        start.elapsed() / 20
+    };
+    let (interp, vm) = (time(false), time(true));
+    println!("queens: {:?} interpreted, {:?} in the bytecode machine", interp, vm);
+}
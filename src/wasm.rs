@@ -0,0 +1,825 @@
+use canon::{linearize, basic_blocks};
+use frame::{Access, Frame, Fragment};
+use ir::{Exp, Stm, BinOp, RelOp, seq};
+use symbol::SymbolTable;
+use temp::{Temp, Label, TempGenerator};
+
+use std::collections::{BTreeSet, HashMap};
+
+// A back end emitting the WebAssembly text format, for running programs in
+// a browser. All values are i32. Non-escaping variables and temps are wasm
+// locals; escaping variables and static links live in frames on a shadow
+// stack in linear memory, below the global $sp. Records, arrays and
+// strings are allocated on a heap above the stack, which is never freed.
+//
+// The host provides three functions in the module "tiger":
+//   print(address, length) writes bytes of linear memory to the output,
+//   getchar() returns the next input byte, or -1 at end of input,
+//   exit(status) stops the program.
+// The program is exported as "main" and its memory as "memory".
+//
+// WebAssembly has no goto: the control flow of each function is rebuilt
+// from its basic blocks with structured blocks, loops and ifs, following
+// Ramsey's "Beyond Relooper" (ICFP 2022). The control flow graphs of Tiger
+// programs are always reducible.
+
+const WORD: i32 = 4;
+const PAGE_SIZE: i32 = 65536;
+const STACK_SIZE: i32 = 1 << 20;
+// linear memory below this address is left unused, so nil is never valid
+const DATA_START: i32 = 16;
+
+const FP: Temp = Temp(0);
+const RV: Temp = Temp(1);
+
+#[derive(Clone)]
+pub struct WasmFrame {
+    name: Label,
+    formals: Vec<Access>,
+    // the wasm parameters, which escaping formals are copied from
+    params: Vec<Temp>,
+    locals: i32,
+}
+
+impl WasmFrame {
+    pub fn frame_size(&self) -> i32 {
+        (self.locals * WORD + 7) / 8 * 8
+    }
+}
+
+impl Frame for WasmFrame {
+    fn new_frame(name: Label, formals_escape: Vec<bool>, gen: &mut TempGenerator) -> WasmFrame {
+        let mut frame = WasmFrame { name: name, formals: vec![], params: vec![], locals: 0 };
+        for &escape in formals_escape.iter() {
+            let param = gen.new_temp();
+            let access = if escape { frame.alloc_local(true, gen) } else { Access::InReg(param) };
+            frame.params.push(param);
+            frame.formals.push(access);
+        }
+        frame
+    }
+
+    fn name(&self) -> Label {
+        self.name
+    }
+
+    fn formals(&self) -> &[Access] {
+        &self.formals
+    }
+
+    fn alloc_local(&mut self, escape: bool, gen: &mut TempGenerator) -> Access {
+        if escape {
+            self.locals += 1;
+            Access::InFrame(-self.locals * WORD)
+        } else {
+            Access::InReg(gen.new_temp())
+        }
+    }
+
+    fn fp() -> Temp {
+        FP
+    }
+
+    fn rv() -> Temp {
+        RV
+    }
+
+    fn word_size() -> i32 {
+        WORD
+    }
+
+    fn proc_entry_exit1(&self, body: Stm, _: &mut TempGenerator) -> Stm {
+        let mut stms = vec![];
+        for (&access, &param) in self.formals.iter().zip(self.params.iter()) {
+            if let Access::InFrame(_) = access {
+                stms.push(Stm::Move(Box::new(WasmFrame::exp(access, Exp::Temp(FP))), Box::new(Exp::Temp(param))));
+            }
+        }
+        stms.push(body);
+        seq(stms)
+    }
+}
+
+// The runtime system, written in wat. Every function returns an i32 so
+// that calls can be compiled alike; the addresses of the strings it uses
+// are globals defined in emit_wat. The heap is never collected, so
+// allocRecord and initArray ignore the layouts passed for the garbage
+// collector of the native runtime.
+const RUNTIME: &'static str = r#"  (func $fail (param $message i32)
+    (drop (call $print (local.get $message)))
+    (call $host_exit (i32.const 1))
+    (unreachable))
+
+  (func $alloc (param $size i32) (result i32)
+    (local $p i32)
+    (local.set $p (global.get $heap))
+    (global.set $heap (i32.and (i32.add (i32.add (local.get $p) (local.get $size)) (i32.const 7))
+                               (i32.const -8)))
+    (if (i32.gt_u (global.get $heap) (i32.shl (memory.size) (i32.const 16)))
+      (then
+        (if (i32.lt_s (memory.grow (i32.sub (i32.shr_u (i32.add (global.get $heap) (i32.const 65535))
+                                                       (i32.const 16))
+                                            (memory.size)))
+                      (i32.const 0))
+          (then (call $fail (global.get $memory_message))))))
+    (local.get $p))
+
+  (func $allocRecord (param $size i32) (param $descriptor i32) (result i32)
+    (call $alloc (local.get $size)))
+
+  (func $initArray (param $size i32) (param $init i32) (param $pointers i32) (result i32)
+    (local $a i32)
+    (local $p i32)
+    (if (i32.lt_s (local.get $size) (i32.const 0))
+      (then (call $fail (global.get $initArray_message))))
+    (local.set $a (call $alloc (i32.shl (i32.add (local.get $size) (i32.const 1)) (i32.const 2))))
+    (i32.store (local.get $a) (local.get $size))
+    (local.set $p (local.get $a))
+    (block $done
+      (loop $next
+        (br_if $done (i32.eqz (local.get $size)))
+        (local.set $p (i32.add (local.get $p) (i32.const 4)))
+        (i32.store (local.get $p) (local.get $init))
+        (local.set $size (i32.sub (local.get $size) (i32.const 1)))
+        (br $next)))
+    (local.get $a))
+
+  (func $stringEqual (param $a i32) (param $b i32) (result i32)
+    (local $i i32)
+    (if (i32.ne (i32.load (local.get $a)) (i32.load (local.get $b)))
+      (then (return (i32.const 0))))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $i) (i32.load (local.get $a))))
+        (if (i32.ne (i32.load8_u offset=4 (i32.add (local.get $a) (local.get $i)))
+                    (i32.load8_u offset=4 (i32.add (local.get $b) (local.get $i))))
+          (then (return (i32.const 0))))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next)))
+    (i32.const 1))
+
+  (func $stringCompare (param $a i32) (param $b i32) (result i32)
+    (local $i i32)
+    (local $n i32)
+    (local $x i32)
+    (local $y i32)
+    (local.set $n (select (i32.load (local.get $a)) (i32.load (local.get $b))
+                          (i32.lt_u (i32.load (local.get $a)) (i32.load (local.get $b)))))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $i) (local.get $n)))
+        (local.set $x (i32.load8_u offset=4 (i32.add (local.get $a) (local.get $i))))
+        (local.set $y (i32.load8_u offset=4 (i32.add (local.get $b) (local.get $i))))
+        (if (i32.ne (local.get $x) (local.get $y))
+          (then (return (select (i32.const -1) (i32.const 1) (i32.lt_u (local.get $x) (local.get $y))))))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next)))
+    (i32.sub (i32.gt_u (i32.load (local.get $a)) (i32.load (local.get $b)))
+             (i32.lt_u (i32.load (local.get $a)) (i32.load (local.get $b)))))
+
+  (func $print (param $s i32) (result i32)
+    (call $host_print (i32.add (local.get $s) (i32.const 4)) (i32.load (local.get $s)))
+    (i32.const 0))
+
+  ;; the digits are written backwards from the end of the buffer
+  (func $printi (param $i i32) (result i32)
+    (local $n i32)
+    (local $p i32)
+    (local.set $p (i32.add (global.get $digits) (i32.const 12)))
+    (local.set $n (select (i32.sub (i32.const 0) (local.get $i)) (local.get $i)
+                          (i32.lt_s (local.get $i) (i32.const 0))))
+    (loop $next
+      (local.set $p (i32.sub (local.get $p) (i32.const 1)))
+      (i32.store8 (local.get $p) (i32.add (i32.const 48) (i32.rem_u (local.get $n) (i32.const 10))))
+      (local.set $n (i32.div_u (local.get $n) (i32.const 10)))
+      (br_if $next (local.get $n)))
+    (if (i32.lt_s (local.get $i) (i32.const 0))
+      (then
+        (local.set $p (i32.sub (local.get $p) (i32.const 1)))
+        (i32.store8 (local.get $p) (i32.const 45))))
+    (call $host_print (local.get $p) (i32.sub (i32.add (global.get $digits) (i32.const 12)) (local.get $p)))
+    (i32.const 0))
+
+  (func $flush (result i32)
+    (i32.const 0))
+
+  (func $getchar (result i32)
+    (local $c i32)
+    (local.set $c (call $host_getchar))
+    (if (result i32) (i32.lt_s (local.get $c) (i32.const 0))
+      (then (global.get $empty))
+      (else (call $chr (local.get $c)))))
+
+  (func $ord (param $s i32) (result i32)
+    (if (result i32) (i32.eqz (i32.load (local.get $s)))
+      (then (i32.const -1))
+      (else (i32.load8_u offset=4 (local.get $s)))))
+
+  (func $chr (param $i i32) (result i32)
+    (local $s i32)
+    (if (i32.gt_u (local.get $i) (i32.const 255))
+      (then (call $fail (global.get $chr_message))))
+    (local.set $s (call $alloc (i32.const 5)))
+    (i32.store (local.get $s) (i32.const 1))
+    (i32.store8 offset=4 (local.get $s) (local.get $i))
+    (local.get $s))
+
+  (func $size (param $s i32) (result i32)
+    (i32.load (local.get $s)))
+
+  (func $substring (param $s i32) (param $first i32) (param $n i32) (result i32)
+    (local $r i32)
+    (if (i32.or (i32.or (i32.lt_s (local.get $first) (i32.const 0)) (i32.lt_s (local.get $n) (i32.const 0)))
+                (i32.gt_s (local.get $first) (i32.sub (i32.load (local.get $s)) (local.get $n))))
+      (then (call $fail (global.get $substring_message))))
+    (local.set $r (call $alloc (i32.add (local.get $n) (i32.const 4))))
+    (i32.store (local.get $r) (local.get $n))
+    (memory.copy (i32.add (local.get $r) (i32.const 4))
+                 (i32.add (i32.add (local.get $s) (i32.const 4)) (local.get $first))
+                 (local.get $n))
+    (local.get $r))
+
+  (func $concat (param $a i32) (param $b i32) (result i32)
+    (local $r i32)
+    (local $n i32)
+    (local.set $n (i32.load (local.get $a)))
+    (local.set $r (call $alloc (i32.add (i32.add (local.get $n) (i32.load (local.get $b))) (i32.const 4))))
+    (i32.store (local.get $r) (i32.add (local.get $n) (i32.load (local.get $b))))
+    (memory.copy (i32.add (local.get $r) (i32.const 4)) (i32.add (local.get $a) (i32.const 4)) (local.get $n))
+    (memory.copy (i32.add (i32.add (local.get $r) (i32.const 4)) (local.get $n))
+                 (i32.add (local.get $b) (i32.const 4))
+                 (i32.load (local.get $b)))
+    (local.get $r))
+
+  (func $not (param $i i32) (result i32)
+    (i32.eqz (local.get $i)))
+
+  (func $exit (param $status i32) (result i32)
+    (call $host_exit (local.get $status))
+    (unreachable))
+
+  (func $nilError (param $pos i32) (result i32)
+    (drop (call $print (global.get $nilError_message)))
+    (drop (call $printi (local.get $pos)))
+    (call $fail (global.get $newline))
+    (unreachable))
+
+  (func $boundsError (param $pos i32) (param $index i32) (result i32)
+    (drop (call $print (global.get $boundsError_message)))
+    (drop (call $printi (local.get $index)))
+    (drop (call $print (global.get $outOfBounds_message)))
+    (drop (call $printi (local.get $pos)))
+    (call $fail (global.get $newline))
+    (unreachable))
+"#;
+
+const RUNTIME_STRINGS: [(&'static str, &'static str); 10] = [
+    ("empty", ""),
+    ("newline", "\n"),
+    ("memory_message", "out of memory\n"),
+    ("stack_message", "stack overflow\n"),
+    ("initArray_message", "negative array size\n"),
+    ("chr_message", "chr out of range\n"),
+    ("substring_message", "substring out of range\n"),
+    ("nilError_message", "nil record dereference at position "),
+    ("boundsError_message", "index "),
+    ("outOfBounds_message", " out of bounds at position "),
+];
+
+// a string as a data segment: its length followed by the characters
+fn data_literal(s: &str) -> String {
+    let length = s.chars().count() as u32;
+    let mut res = String::from("\"");
+    for i in 0..4 {
+        res.push_str(&format!("\\{:02x}", (length >> (8 * i)) & 0xff));
+    }
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => res.push_str(&format!("\\{:02x}", c as u32)),
+            c if c >= ' ' && c <= '~' => res.push(c),
+            _ => res.push_str(&format!("\\{:02x}", c as u32 & 0xff)),
+        }
+    }
+    res.push('"');
+    res
+}
+
+// appends the data segment for s at address, returning the address and
+// advancing it past the segment
+fn data_segment(data: &mut String, s: &str, address: &mut i32) -> i32 {
+    let start = *address;
+    data.push_str(&format!("  (data (i32.const {}) {})\n", start, data_literal(s)));
+    *address = (start + WORD + s.chars().count() as i32 + WORD - 1) / WORD * WORD;
+    start
+}
+
+fn local_name(t: Temp) -> String {
+    match t {
+        FP => String::from("$fp"),
+        RV => String::from("$rv"),
+        Temp(i) => format!("$t{}", i),
+    }
+}
+
+fn binop_instruction(op: BinOp) -> &'static str {
+    match op {
+        BinOp::Plus => "i32.add",
+        BinOp::Minus => "i32.sub",
+        BinOp::Mul => "i32.mul",
+        BinOp::Div => "i32.div_s",
+        BinOp::And => "i32.and",
+        BinOp::Or => "i32.or",
+        BinOp::LShift => "i32.shl",
+        BinOp::RShift => "i32.shr_u",
+        BinOp::ARShift => "i32.shr_s",
+        BinOp::Xor => "i32.xor",
+    }
+}
+
+fn relop_instruction(op: RelOp) -> &'static str {
+    match op {
+        RelOp::Eq => "i32.eq",
+        RelOp::Ne => "i32.ne",
+        RelOp::Lt => "i32.lt_s",
+        RelOp::Gt => "i32.gt_s",
+        RelOp::Le => "i32.le_s",
+        RelOp::Ge => "i32.ge_s",
+        RelOp::ULt => "i32.lt_u",
+        RelOp::ULe => "i32.le_u",
+        RelOp::UGt => "i32.gt_u",
+        RelOp::UGe => "i32.ge_u",
+    }
+}
+
+fn temps_exp(exp: &Exp, temps: &mut BTreeSet<Temp>) {
+    match exp {
+        &Exp::Temp(t) => {
+            temps.insert(t);
+        },
+        &Exp::BinOp(_, ref a, ref b) => {
+            temps_exp(a, temps);
+            temps_exp(b, temps);
+        },
+        &Exp::Mem(ref a) => temps_exp(a, temps),
+        &Exp::Call(ref f, ref args) => {
+            temps_exp(f, temps);
+            for arg in args.iter() {
+                temps_exp(arg, temps);
+            }
+        },
+        &Exp::ESeq(ref s, ref e) => {
+            temps_stm(s, temps);
+            temps_exp(e, temps);
+        },
+        &Exp::Const(_) | &Exp::Name(_) => (),
+    }
+}
+
+fn temps_stm(stm: &Stm, temps: &mut BTreeSet<Temp>) {
+    match stm {
+        &Stm::Move(ref a, ref b) | &Stm::CJump(_, ref a, ref b, _, _) => {
+            temps_exp(a, temps);
+            temps_exp(b, temps);
+        },
+        &Stm::Exp(ref e) | &Stm::Jump(ref e, _) => temps_exp(e, temps),
+        &Stm::Seq(ref a, ref b) => {
+            temps_stm(a, temps);
+            temps_stm(b, temps);
+        },
+        &Stm::Label(_) => (),
+    }
+}
+
+// the control flow graph of a function body, with the reachable basic
+// blocks numbered in reverse postorder
+struct Cfg {
+    blocks: Vec<Vec<Stm>>,
+    index: HashMap<Label, usize>,
+    // the label after the last block, where the function returns
+    exit: Label,
+    idom: Vec<usize>,
+    loop_header: Vec<bool>,
+    // nodes with more than one forward edge coming in
+    merge_node: Vec<bool>,
+}
+
+fn block_label(block: &Vec<Stm>) -> Label {
+    match block[0] {
+        Stm::Label(l) => l,
+        _ => panic!("basic block does not start with a label"),
+    }
+}
+
+fn block_successors(block: &Vec<Stm>) -> Vec<Label> {
+    match block.last() {
+        Some(&Stm::Jump(_, ref labels)) => labels.clone(),
+        Some(&Stm::CJump(_, _, _, t, f)) => vec![t, f],
+        _ => panic!("basic block does not end with a jump"),
+    }
+}
+
+impl Cfg {
+    fn new(blocks: Vec<Vec<Stm>>, exit: Label) -> Cfg {
+        let index: HashMap<Label, usize> = blocks.iter().enumerate().map(|(i, b)| (block_label(b), i)).collect();
+        let successors: Vec<Vec<usize>> = blocks.iter()
+            .map(|b| block_successors(b).into_iter().filter_map(|l| index.get(&l).cloned()).collect())
+            .collect();
+
+        // depth first search from the entry, without recursion
+        let mut visited = vec![false; blocks.len()];
+        let mut postorder = vec![];
+        let mut stack = vec![(0, 0)];
+        visited[0] = true;
+        while let Some((node, next)) = stack.pop() {
+            if next < successors[node].len() {
+                stack.push((node, next + 1));
+                let s = successors[node][next];
+                if !visited[s] {
+                    visited[s] = true;
+                    stack.push((s, 0));
+                }
+            } else {
+                postorder.push(node);
+            }
+        }
+        postorder.reverse();
+        let mut number = vec![None; blocks.len()];
+        for (n, &node) in postorder.iter().enumerate() {
+            number[node] = Some(n);
+        }
+
+        let successors: Vec<Vec<usize>> = postorder.iter()
+            .map(|&node| successors[node].iter().map(|&s| number[s].unwrap()).collect())
+            .collect();
+        let mut blocks: Vec<Option<Vec<Stm>>> = blocks.into_iter().map(Some).collect();
+        let blocks: Vec<Vec<Stm>> = postorder.iter().map(|&node| blocks[node].take().unwrap()).collect();
+        let index = blocks.iter().enumerate().map(|(i, b)| (block_label(b), i)).collect();
+
+        let n = blocks.len();
+        let mut predecessors = vec![vec![]; n];
+        let mut loop_header = vec![false; n];
+        let mut forward_edges = vec![0; n];
+        for (node, succs) in successors.iter().enumerate() {
+            for &s in succs.iter() {
+                predecessors[s].push(node);
+                if s <= node {
+                    loop_header[s] = true;
+                } else {
+                    forward_edges[s] += 1;
+                }
+            }
+        }
+
+        // Cooper, Harvey and Kennedy's iterative algorithm
+        let mut idom: Vec<Option<usize>> = vec![None; n];
+        idom[0] = Some(0);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for node in 1..n {
+                let mut new_idom: Option<usize> = None;
+                for &p in predecessors[node].iter() {
+                    if idom[p].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => p,
+                        Some(mut a) => {
+                            let mut b = p;
+                            while a != b {
+                                while a > b {
+                                    a = idom[a].unwrap();
+                                }
+                                while b > a {
+                                    b = idom[b].unwrap();
+                                }
+                            }
+                            a
+                        },
+                    });
+                }
+                if new_idom != idom[node] {
+                    idom[node] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+
+        Cfg {
+            blocks: blocks,
+            index: index,
+            exit: exit,
+            idom: idom.into_iter().map(|d| d.unwrap()).collect(),
+            loop_header: loop_header,
+            merge_node: forward_edges.into_iter().map(|e| e > 1).collect(),
+        }
+    }
+}
+
+struct Emitter<'a> {
+    symbol_table: &'a SymbolTable,
+    // the addresses of string literals
+    strings: &'a HashMap<Label, i32>,
+    out: String,
+    indent: usize,
+}
+
+impl<'a> Emitter<'a> {
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.indent {
+            self.out.push_str("  ");
+        }
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+
+    fn name(&self, label: Label) -> String {
+        format!("${}", label.name(self.symbol_table))
+    }
+
+    fn exp(&self, exp: &Exp) -> String {
+        match exp {
+            &Exp::Const(i) => format!("(i32.const {})", i),
+            &Exp::Name(l) => match self.strings.get(&l) {
+                Some(address) => format!("(i32.const {})", address),
+                None => panic!("{} is not a string", l.name(self.symbol_table)),
+            },
+            &Exp::Temp(t) => format!("(local.get {})", local_name(t)),
+            &Exp::BinOp(op, ref a, ref b) => format!("({} {} {})", binop_instruction(op), self.exp(a), self.exp(b)),
+            &Exp::Mem(ref a) => format!("(i32.load {})", self.exp(a)),
+            &Exp::Call(ref f, ref args) => {
+                let mut res = match **f {
+                    Exp::Name(l) => format!("(call {}", self.name(l)),
+                    _ => panic!("call of a computed address"),
+                };
+                for arg in args.iter() {
+                    res.push(' ');
+                    res.push_str(&self.exp(arg));
+                }
+                res.push(')');
+                res
+            },
+            &Exp::ESeq(_, _) => panic!("ESEQ in canonical tree"),
+        }
+    }
+
+    fn stm(&mut self, stm: &Stm) {
+        let line = match stm {
+            &Stm::Move(ref dst, ref src) => match **dst {
+                Exp::Temp(t) => format!("(local.set {} {})", local_name(t), self.exp(src)),
+                Exp::Mem(ref a) => format!("(i32.store {} {})", self.exp(a), self.exp(src)),
+                _ => panic!("move to a non-location"),
+            },
+            &Stm::Exp(ref e) => format!("(drop {})", self.exp(e)),
+            &Stm::Label(_) => return,
+            _ => panic!("jump in the middle of a basic block"),
+        };
+        self.line(&line);
+    }
+
+    // the code for node x and the nodes it immediately dominates, wrapped in
+    // a loop if x is a loop header
+    fn tree(&mut self, cfg: &Cfg, x: usize) {
+        let merge_children: Vec<usize> = (x + 1..cfg.blocks.len())
+            .filter(|&n| cfg.idom[n] == x && cfg.merge_node[n])
+            .collect();
+        if cfg.loop_header[x] {
+            let line = format!("(loop {}_loop", self.name(block_label(&cfg.blocks[x])));
+            self.line(&line);
+            self.indent += 1;
+            self.node_within(cfg, x, &merge_children);
+            self.indent -= 1;
+            self.line(")");
+        } else {
+            self.node_within(cfg, x, &merge_children);
+        }
+    }
+
+    // the code for node x inside one block for each of the merge nodes
+    // ys, ordered by reverse postorder; the code of each merge node follows
+    // its block, so that branching to it is leaving the block
+    fn node_within(&mut self, cfg: &Cfg, x: usize, ys: &[usize]) {
+        match ys.split_last() {
+            Some((&y, inner)) => {
+                let line = format!("(block {}", self.name(block_label(&cfg.blocks[y])));
+                self.line(&line);
+                self.indent += 1;
+                self.node_within(cfg, x, inner);
+                self.indent -= 1;
+                self.line(")");
+                self.tree(cfg, y);
+            },
+            None => {
+                let block = &cfg.blocks[x];
+                for stm in block[..block.len() - 1].iter() {
+                    self.stm(stm);
+                }
+                match block.last() {
+                    Some(&Stm::Jump(_, ref labels)) if labels.len() == 1 => self.branch(cfg, x, labels[0]),
+                    Some(&Stm::CJump(op, ref a, ref b, t, f)) => {
+                        let line = format!("(if ({} {} {})", relop_instruction(op), self.exp(a), self.exp(b));
+                        self.line(&line);
+                        self.indent += 1;
+                        self.line("(then");
+                        self.indent += 1;
+                        self.branch(cfg, x, t);
+                        self.indent -= 1;
+                        self.line(")");
+                        self.line("(else");
+                        self.indent += 1;
+                        self.branch(cfg, x, f);
+                        self.indent -= 1;
+                        self.line(")");
+                        self.indent -= 1;
+                        self.line(")");
+                    },
+                    _ => panic!("basic block does not end with a jump to a label"),
+                }
+            },
+        }
+    }
+
+    fn branch(&mut self, cfg: &Cfg, source: usize, target: Label) {
+        if target == cfg.exit {
+            let line = format!("(br {})", self.name(target));
+            return self.line(&line);
+        }
+        let t = cfg.index[&target];
+        if t <= source {
+            let line = format!("(br {}_loop)", self.name(target));
+            self.line(&line);
+        } else if cfg.merge_node[t] {
+            let line = format!("(br {})", self.name(target));
+            self.line(&line);
+        } else {
+            self.tree(cfg, t);
+        }
+    }
+
+    fn function(&mut self, frame: &WasmFrame, cfg: &Cfg) {
+        let mut temps = BTreeSet::new();
+        for block in cfg.blocks.iter() {
+            for stm in block.iter() {
+                temps_stm(stm, &mut temps);
+            }
+        }
+        let mut header = format!("(func {}", self.name(frame.name));
+        for &param in frame.params.iter() {
+            header.push_str(&format!(" (param {} i32)", local_name(param)));
+            temps.remove(&param);
+        }
+        header.push_str(" (result i32)");
+        self.line(&header);
+        self.indent += 1;
+        temps.insert(FP);
+        temps.insert(RV);
+        for &t in temps.iter() {
+            let line = format!("(local {} i32)", local_name(t));
+            self.line(&line);
+        }
+
+        self.line("(local.set $fp (global.get $sp))");
+        let line = format!("(global.set $sp (i32.sub (local.get $fp) (i32.const {})))", frame.frame_size());
+        self.line(&line);
+        self.line("(if (i32.lt_u (global.get $sp) (global.get $stack_limit))");
+        self.line("  (then (call $fail (global.get $stack_message))))");
+        let line = format!("(block {}", self.name(cfg.exit));
+        self.line(&line);
+        self.indent += 1;
+        if !cfg.blocks.is_empty() {
+            self.tree(cfg, 0);
+        }
+        self.indent -= 1;
+        self.line(")");
+        self.line("(global.set $sp (local.get $fp))");
+        self.line("(local.get $rv))");
+        self.indent -= 1;
+        self.out.push('\n');
+    }
+}
+
+// Emits a module for the fragments of a translated program.
+pub fn emit_wat(fragments: Vec<Fragment<WasmFrame>>, gen: &mut TempGenerator, symbol_table: &mut SymbolTable) -> String {
+    let mut data = String::new();
+    let mut globals = String::new();
+    let mut address = DATA_START;
+    for &(name, s) in RUNTIME_STRINGS.iter() {
+        let start = data_segment(&mut data, s, &mut address);
+        globals.push_str(&format!("  (global ${} i32 (i32.const {}))\n", name, start));
+    }
+    // the buffer for printi, long enough for -2147483648
+    globals.push_str(&format!("  (global $digits i32 (i32.const {}))\n", address));
+    address += 12;
+
+    let mut strings = HashMap::new();
+    let mut procs = vec![];
+    for fragment in fragments.into_iter() {
+        match fragment {
+            Fragment::String(label, s) => {
+                strings.insert(label, data_segment(&mut data, &s, &mut address));
+            },
+            Fragment::Proc { body, frame } => {
+                let stms = linearize(body, gen);
+                let (blocks, exit) = basic_blocks(stms, gen, symbol_table);
+                procs.push((frame, Cfg::new(blocks, exit)));
+            },
+        }
+    }
+
+    let stack_limit = (address + 15) / 16 * 16;
+    let heap_start = stack_limit + STACK_SIZE;
+    globals.push_str(&format!("  (global $stack_limit i32 (i32.const {}))\n", stack_limit));
+    globals.push_str(&format!("  (global $sp (mut i32) (i32.const {}))\n", heap_start));
+    globals.push_str(&format!("  (global $heap (mut i32) (i32.const {}))\n", heap_start));
+
+    let mut emitter = Emitter {
+        symbol_table: symbol_table,
+        strings: &strings,
+        out: String::new(),
+        indent: 1,
+    };
+    emitter.out.push_str("(module\n");
+    emitter.line("(import \"tiger\" \"print\" (func $host_print (param i32 i32)))");
+    emitter.line("(import \"tiger\" \"getchar\" (func $host_getchar (result i32)))");
+    emitter.line("(import \"tiger\" \"exit\" (func $host_exit (param i32)))");
+    let line = format!("(memory (export \"memory\") {})", (heap_start + PAGE_SIZE - 1) / PAGE_SIZE + 1);
+    emitter.line(&line);
+    emitter.line("(export \"main\" (func $tigermain))");
+    emitter.out.push('\n');
+    emitter.out.push_str(&globals);
+    emitter.out.push('\n');
+    emitter.out.push_str(&data);
+    emitter.out.push('\n');
+    emitter.out.push_str(RUNTIME);
+    emitter.out.push('\n');
+
+    for &(ref frame, ref cfg) in procs.iter() {
+        emitter.function(frame, cfg);
+    }
+    emitter.out.push_str(")\n");
+    emitter.out
+}
+
+// runs a module in the wasmi interpreter, returning its output and exit
+// status
+#[cfg(test)]
+pub fn run_wat(module: &str, input: &str) -> (String, i32) {
+    use wasmi::{Caller, Engine, Linker, Module, Store};
+    use wasmi::core::Trap;
+
+    struct Io {
+        input: Vec<u8>,
+        output: Vec<u8>,
+    }
+
+    let binary = wat::parse_str(module).unwrap();
+    let engine = Engine::default();
+    let module = Module::new(&engine, &binary[..]).unwrap();
+    let mut store = Store::new(&engine, Io { input: input.bytes().rev().collect(), output: vec![] });
+    let mut linker = <Linker<Io>>::new(&engine);
+    linker.func_wrap("tiger", "print", |mut caller: Caller<Io>, address: i32, length: i32| {
+        let memory = caller.get_export("memory").and_then(|e| e.into_memory()).unwrap();
+        let mut bytes = vec![0; length as usize];
+        memory.read(&caller, address as usize, &mut bytes).unwrap();
+        caller.data_mut().output.extend(bytes);
+    }).unwrap();
+    linker.func_wrap("tiger", "getchar", |mut caller: Caller<Io>| -> i32 {
+        caller.data_mut().input.pop().map(|c| c as i32).unwrap_or(-1)
+    }).unwrap();
+    linker.func_wrap("tiger", "exit", |status: i32| -> Result<(), Trap> {
+        Err(Trap::i32_exit(status))
+    }).unwrap();
+    let instance = linker.instantiate(&mut store, &module).unwrap().start(&mut store).unwrap();
+    let main = instance.get_typed_func::<(), i32>(&store, "main").unwrap();
+    let status = match main.call(&mut store, ()) {
+        Ok(_) => 0,
+        Err(e) => e.i32_exit_status().expect("trap"),
+    };
+    (String::from_utf8(store.into_data().output).unwrap(), status)
+}
+
+#[test]
+fn test_run_programs() {
+    use driver::{compile, Target};
+    use regalloc::Allocator;
+
+    let run = |program: &str, input: &str| run_wat(&compile(program, Target::Wasm, Allocator::Coloring).unwrap(), input);
+
+    assert_eq!(run("let function fact(n: int): int = if n = 0 then 1 else n * fact(n - 1) \
+                    in printi(fact(10)); print(\"\\n\") end", ""), (String::from("3628800\n"), 0));
+
+    // escaping variables and static links, loops and break
+    assert_eq!(run("let var sum := 0 \
+                        function add(i: int) = sum := sum + i \
+                        var i := 0 \
+                    in for j := 1 to 10 do add(j); \
+                       while 1 do (i := i + 1; if i > 5 then break; add(100)); \
+                       printi(sum) end", ""), (String::from("555"), 0));
+
+    assert_eq!(run("let var c := getchar() \
+                    in while c <> \"\" do (print(chr(ord(c) + 1)); c := getchar()) end", "HAL"),
+               (String::from("IBM"), 0));
+
+    assert_eq!(run("let type a = array of int var x := a[3] of 0 in printi(-17); x[3] := 1 end", ""),
+               (String::from("-17index 3 out of bounds at position 61\n"), 1));
+}
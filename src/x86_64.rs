@@ -0,0 +1,527 @@
+use assem::Instr;
+use codegen::{Codegen, ascii_literal};
+use frame::{Access, Frame, shift_view};
+use gc::{PointerMap, pointer_map_table};
+use ir::{Exp, Stm, BinOp, RelOp};
+use symbol::SymbolTable;
+use temp::{Temp, Label, TempGenerator};
+
+// The x86-64 back end, with the System V calling convention: the first six
+// arguments in registers, the rest on the stack, %rbp as the frame pointer.
+
+pub const RAX: Temp = Temp(0);
+pub const RBX: Temp = Temp(1);
+pub const RCX: Temp = Temp(2);
+pub const RDX: Temp = Temp(3);
+pub const RSI: Temp = Temp(4);
+pub const RDI: Temp = Temp(5);
+pub const RBP: Temp = Temp(6);
+pub const RSP: Temp = Temp(7);
+pub const R8: Temp = Temp(8);
+pub const R9: Temp = Temp(9);
+pub const R10: Temp = Temp(10);
+pub const R11: Temp = Temp(11);
+pub const R12: Temp = Temp(12);
+pub const R13: Temp = Temp(13);
+pub const R14: Temp = Temp(14);
+pub const R15: Temp = Temp(15);
+
+const REGISTER_NAMES: [&'static str; 16] = ["rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp",
+                                            "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"];
+
+pub const ARG_REGISTERS: [Temp; 6] = [RDI, RSI, RDX, RCX, R8, R9];
+pub const CALLEE_SAVES: [Temp; 5] = [RBX, R12, R13, R14, R15];
+pub const CALLER_SAVES: [Temp; 9] = [RAX, RCX, RDX, RSI, RDI, R8, R9, R10, R11];
+
+const WORD: i32 = 8;
+
+#[derive(Clone)]
+pub struct X86Frame {
+    name: Label,
+    formals: Vec<Access>,
+    locals: i32,
+}
+
+impl X86Frame {
+    // the size of the locals area, keeping %rsp 16-byte aligned
+    pub fn frame_size(&self) -> i32 {
+        (self.locals * WORD + 15) / 16 * 16
+    }
+}
+
+// arguments after the sixth are above the return address and saved %rbp
+fn stack_arg_offset(i: usize) -> i32 {
+    2 * WORD + (i - ARG_REGISTERS.len()) as i32 * WORD
+}
+
+impl Frame for X86Frame {
+    fn new_frame(name: Label, formals_escape: Vec<bool>, gen: &mut TempGenerator) -> X86Frame {
+        let mut frame = X86Frame { name: name, formals: vec![], locals: 0 };
+        for (i, &escape) in formals_escape.iter().enumerate() {
+            let access = if escape && i >= ARG_REGISTERS.len() {
+                Access::InFrame(stack_arg_offset(i))
+            } else {
+                frame.alloc_local(escape, gen)
+            };
+            frame.formals.push(access);
+        }
+        frame
+    }
+
+    fn name(&self) -> Label {
+        self.name
+    }
+
+    fn formals(&self) -> &[Access] {
+        &self.formals
+    }
+
+    fn alloc_local(&mut self, escape: bool, gen: &mut TempGenerator) -> Access {
+        if escape {
+            self.locals += 1;
+            Access::InFrame(-self.locals * WORD)
+        } else {
+            Access::InReg(gen.new_temp())
+        }
+    }
+
+    fn fp() -> Temp {
+        RBP
+    }
+
+    fn rv() -> Temp {
+        RAX
+    }
+
+    fn word_size() -> i32 {
+        WORD
+    }
+
+    // the runtime's C functions are prefixed, so that getchar and exit do
+    // not clash with the C library
+    fn external_name(name: &str) -> String {
+        format!("tig_{}", name)
+    }
+
+    fn proc_entry_exit1(&self, body: Stm, gen: &mut TempGenerator) -> Stm {
+        shift_view(self, body, &ARG_REGISTERS, &|i| Access::InFrame(stack_arg_offset(i)), &CALLEE_SAVES, gen)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Syntax {
+    Att,
+    Intel,
+}
+
+pub struct X86Codegen {
+    pub syntax: Syntax,
+}
+
+// An instruction operand, before it is written in one of the two syntaxes.
+enum Operand {
+    Src(usize),
+    Dst(usize),
+    Imm(i32),
+    // a word at a constant offset from the address in a source temp
+    Mem(usize, i32),
+    // the address of a label, relative to %rip
+    Address(String),
+    Cl,
+}
+
+fn condition(op: RelOp) -> &'static str {
+    match op {
+        RelOp::Eq => "e",
+        RelOp::Ne => "ne",
+        RelOp::Lt => "l",
+        RelOp::Gt => "g",
+        RelOp::Le => "le",
+        RelOp::Ge => "ge",
+        RelOp::ULt => "b",
+        RelOp::UGt => "a",
+        RelOp::ULe => "be",
+        RelOp::UGe => "ae",
+    }
+}
+
+impl X86Codegen {
+    fn operand(&self, op: &Operand) -> String {
+        match (self.syntax, op) {
+            (_, &Operand::Src(i)) => format!("`s{}", i),
+            (_, &Operand::Dst(i)) => format!("`d{}", i),
+            (Syntax::Att, &Operand::Imm(i)) => format!("${}", i),
+            (Syntax::Intel, &Operand::Imm(i)) => format!("{}", i),
+            (Syntax::Att, &Operand::Mem(base, 0)) => format!("(`s{})", base),
+            (Syntax::Att, &Operand::Mem(base, offset)) => format!("{}(`s{})", offset, base),
+            (Syntax::Intel, &Operand::Mem(base, 0)) => format!("QWORD PTR [`s{}]", base),
+            (Syntax::Intel, &Operand::Mem(base, offset)) => format!("QWORD PTR [`s{}{:+}]", base, offset),
+            (Syntax::Att, &Operand::Address(ref l)) => format!("{}(%rip)", l),
+            (Syntax::Intel, &Operand::Address(ref l)) => format!("[rip+{}]", l),
+            (Syntax::Att, &Operand::Cl) => String::from("%cl"),
+            (Syntax::Intel, &Operand::Cl) => String::from("cl"),
+        }
+    }
+
+    // an instruction on quadwords; the operands are in AT&T order (source
+    // first), and reversed for Intel syntax
+    fn ins(&self, mnemonic: &str, operands: &[Operand]) -> String {
+        let mut operands: Vec<String> = operands.iter().map(|o| self.operand(o)).collect();
+        match self.syntax {
+            Syntax::Att => format!("{}q {}", mnemonic, operands.join(", ")),
+            Syntax::Intel => {
+                operands.reverse();
+                format!("{} {}", mnemonic, operands.join(", "))
+            },
+        }
+    }
+}
+
+struct Muncher<'a> {
+    codegen: &'a X86Codegen,
+    gen: &'a mut TempGenerator,
+    symbol_table: &'a SymbolTable,
+    instrs: Vec<Instr>,
+}
+
+impl<'a> Muncher<'a> {
+    fn emit(&mut self, mnemonic: &str, operands: &[Operand], dst: Vec<Temp>, src: Vec<Temp>) {
+        let assem = self.codegen.ins(mnemonic, operands);
+        self.instrs.push(Instr::oper(assem, dst, src));
+    }
+
+    fn emit_move(&mut self, dst: Temp, src: Temp) {
+        let assem = self.codegen.ins("mov", &[Operand::Src(0), Operand::Dst(0)]);
+        self.instrs.push(Instr::Move { assem: assem, dst: dst, src: src });
+    }
+
+    fn label_name(&self, l: Label) -> String {
+        l.name(self.symbol_table).clone()
+    }
+
+    // the base temp and offset of a memory operand at address e
+    fn munch_address(&mut self, e: &Exp) -> (Temp, i32) {
+        match e {
+            &Exp::BinOp(BinOp::Plus, ref a, ref b) => match (a.as_ref(), b.as_ref()) {
+                (a, &Exp::Const(i)) | (&Exp::Const(i), a) => (self.munch_exp(a), i),
+                _ => (self.munch_exp(e), 0),
+            },
+            &Exp::BinOp(BinOp::Minus, ref a, ref b) => match b.as_ref() {
+                &Exp::Const(i) if i != i32::min_value() => (self.munch_exp(a), -i),
+                _ => (self.munch_exp(e), 0),
+            },
+            e => (self.munch_exp(e), 0),
+        }
+    }
+
+    fn munch_stm(&mut self, stm: &Stm) {
+        match stm {
+            &Stm::Move(ref dst, ref src) => match (dst.as_ref(), src.as_ref()) {
+                (&Exp::Mem(ref address), src) => {
+                    let (base, offset) = self.munch_address(address);
+                    if let &Exp::Const(i) = src {
+                        self.emit("mov", &[Operand::Imm(i), Operand::Mem(0, offset)], vec![], vec![base]);
+                    } else {
+                        let src = self.munch_exp(src);
+                        self.emit("mov", &[Operand::Src(0), Operand::Mem(1, offset)], vec![], vec![src, base]);
+                    }
+                },
+                (&Exp::Temp(t), &Exp::Const(i)) => self.emit("mov", &[Operand::Imm(i), Operand::Dst(0)], vec![t], vec![]),
+                (&Exp::Temp(t), &Exp::Mem(ref address)) => {
+                    let (base, offset) = self.munch_address(address);
+                    self.emit("mov", &[Operand::Mem(0, offset), Operand::Dst(0)], vec![t], vec![base]);
+                },
+                (&Exp::Temp(t), src) => {
+                    let src = self.munch_exp(src);
+                    self.emit_move(t, src);
+                },
+                _ => panic!("cannot move to {:?}", dst),
+            },
+            &Stm::Exp(ref e) => match e.as_ref() {
+                &Exp::Call(ref f, ref args) => self.munch_call(f, args),
+                e => {
+                    self.munch_exp(e);
+                },
+            },
+            &Stm::Jump(ref e, ref labels) => match e.as_ref() {
+                &Exp::Name(l) => {
+                    let assem = String::from("jmp `j0");
+                    self.instrs.push(Instr::Oper { assem: assem, dst: vec![], src: vec![], jump: Some(vec![l]) });
+                },
+                e => {
+                    let t = self.munch_exp(e);
+                    let assem = match self.codegen.syntax {
+                        Syntax::Att => String::from("jmp *`s0"),
+                        Syntax::Intel => String::from("jmp `s0"),
+                    };
+                    self.instrs.push(Instr::Oper { assem: assem, dst: vec![], src: vec![t], jump: Some(labels.clone()) });
+                },
+            },
+            &Stm::CJump(op, ref a, ref b, t, f) => {
+                match (a.as_ref(), b.as_ref()) {
+                    (a, &Exp::Const(i)) => {
+                        let a = self.munch_exp(a);
+                        self.emit("cmp", &[Operand::Imm(i), Operand::Src(0)], vec![], vec![a]);
+                        self.emit_jump(op, t, f);
+                    },
+                    (&Exp::Const(i), b) => {
+                        let b = self.munch_exp(b);
+                        self.emit("cmp", &[Operand::Imm(i), Operand::Src(0)], vec![], vec![b]);
+                        self.emit_jump(op.commute(), t, f);
+                    },
+                    (a, b) => {
+                        let a = self.munch_exp(a);
+                        let b = self.munch_exp(b);
+                        self.emit("cmp", &[Operand::Src(1), Operand::Src(0)], vec![], vec![a, b]);
+                        self.emit_jump(op, t, f);
+                    },
+                }
+            },
+            &Stm::Label(l) => {
+                let assem = format!("{}:", self.label_name(l));
+                self.instrs.push(Instr::Label { assem: assem, label: l });
+            },
+            &Stm::Seq(_, _) => panic!("SEQ in canonical IR"),
+        }
+    }
+
+    // a conditional jump to t, falling through to f otherwise
+    fn emit_jump(&mut self, op: RelOp, t: Label, f: Label) {
+        let assem = format!("j{} `j0", condition(op));
+        self.instrs.push(Instr::Oper { assem: assem, dst: vec![], src: vec![], jump: Some(vec![t, f]) });
+    }
+
+    fn munch_call(&mut self, f: &Exp, args: &[Exp]) {
+        let name = match f {
+            &Exp::Name(l) => self.label_name(l),
+            f => panic!("cannot call {:?}", f),
+        };
+
+        // all arguments are computed before any argument register is set,
+        // since computing one may use those registers
+        let temps: Vec<Temp> = args.iter().map(|a| self.munch_exp(a)).collect();
+        let in_registers = temps.len().min(ARG_REGISTERS.len());
+        let on_stack = temps.len() - in_registers;
+        if on_stack % 2 == 1 {
+            self.emit("sub", &[Operand::Imm(WORD), Operand::Dst(0)], vec![RSP], vec![RSP]);
+        }
+        for &t in temps[in_registers..].iter().rev() {
+            self.emit("push", &[Operand::Src(0)], vec![RSP], vec![t, RSP]);
+        }
+        for (&t, &r) in temps.iter().zip(ARG_REGISTERS.iter()) {
+            self.emit_move(r, t);
+        }
+
+        let assem = format!("call {}", name);
+        self.instrs.push(Instr::oper(assem, CALLER_SAVES.to_vec(), ARG_REGISTERS[..in_registers].to_vec()));
+        if on_stack > 0 {
+            let size = (on_stack + on_stack % 2) as i32 * WORD;
+            self.emit("add", &[Operand::Imm(size), Operand::Dst(0)], vec![RSP], vec![RSP]);
+        }
+    }
+
+    fn munch_exp(&mut self, exp: &Exp) -> Temp {
+        match exp {
+            &Exp::Const(i) => {
+                let r = self.gen.new_temp();
+                self.emit("mov", &[Operand::Imm(i), Operand::Dst(0)], vec![r], vec![]);
+                r
+            },
+            &Exp::Temp(t) => t,
+            &Exp::Name(l) => {
+                let r = self.gen.new_temp();
+                let name = self.label_name(l);
+                self.emit("lea", &[Operand::Address(name), Operand::Dst(0)], vec![r], vec![]);
+                r
+            },
+            &Exp::Mem(ref address) => {
+                let r = self.gen.new_temp();
+                let (base, offset) = self.munch_address(address);
+                self.emit("mov", &[Operand::Mem(0, offset), Operand::Dst(0)], vec![r], vec![base]);
+                r
+            },
+            &Exp::BinOp(BinOp::Div, ref a, ref b) => {
+                let a = self.munch_exp(a);
+                let b = self.munch_exp(b);
+                self.emit_move(RAX, a);
+                let cqto = match self.codegen.syntax {
+                    Syntax::Att => "cqto",
+                    Syntax::Intel => "cqo",
+                };
+                self.instrs.push(Instr::oper(String::from(cqto), vec![RDX], vec![RAX]));
+                self.emit("idiv", &[Operand::Src(0)], vec![RAX, RDX], vec![b, RAX, RDX]);
+                let r = self.gen.new_temp();
+                self.emit_move(r, RAX);
+                r
+            },
+            &Exp::BinOp(op, ref a, ref b) => {
+                let mnemonic = match op {
+                    BinOp::Plus => "add",
+                    BinOp::Minus => "sub",
+                    BinOp::Mul => "imul",
+                    BinOp::And => "and",
+                    BinOp::Or => "or",
+                    BinOp::Xor => "xor",
+                    BinOp::LShift => "sal",
+                    BinOp::RShift => "shr",
+                    BinOp::ARShift => "sar",
+                    BinOp::Div => unreachable!(),
+                };
+                let commutes = match op {
+                    BinOp::Plus | BinOp::Mul | BinOp::And | BinOp::Or | BinOp::Xor => true,
+                    _ => false,
+                };
+                // two-address instructions: r := a; r := r op b
+                let (a, b) = match (a.as_ref(), b.as_ref()) {
+                    (&Exp::Const(_), b) if commutes => (b, a.as_ref()),
+                    (a, b) => (a, b),
+                };
+                let r = self.gen.new_temp();
+                let a = self.munch_exp(a);
+                self.emit_move(r, a);
+                match b {
+                    &Exp::Const(i) => self.emit(mnemonic, &[Operand::Imm(i), Operand::Dst(0)], vec![r], vec![r]),
+                    b => {
+                        let b = self.munch_exp(b);
+                        match op {
+                            BinOp::LShift | BinOp::RShift | BinOp::ARShift => {
+                                self.emit_move(RCX, b);
+                                self.emit(mnemonic, &[Operand::Cl, Operand::Dst(0)], vec![r], vec![RCX, r]);
+                            },
+                            _ => self.emit(mnemonic, &[Operand::Src(0), Operand::Dst(0)], vec![r], vec![b, r]),
+                        }
+                    },
+                }
+                r
+            },
+            &Exp::Call(ref f, ref args) => {
+                self.munch_call(f, args);
+                let r = self.gen.new_temp();
+                self.emit_move(r, RAX);
+                r
+            },
+            &Exp::ESeq(_, _) => panic!("ESEQ in canonical IR"),
+        }
+    }
+}
+
+impl Codegen for X86Codegen {
+    type Frame = X86Frame;
+
+    fn codegen(&self, _: &X86Frame, stm: Stm, gen: &mut TempGenerator, symbol_table: &SymbolTable) -> Vec<Instr> {
+        let mut muncher = Muncher {
+            codegen: self,
+            gen: gen,
+            symbol_table: symbol_table,
+            instrs: vec![],
+        };
+        muncher.munch_stm(&stm);
+        muncher.instrs
+    }
+
+    fn proc_entry_exit2(&self, _: &X86Frame, mut body: Vec<Instr>) -> Vec<Instr> {
+        let mut live = vec![RAX, RSP, RBP];
+        live.extend(CALLEE_SAVES.iter());
+        body.push(Instr::oper(String::new(), vec![], live));
+        body
+    }
+
+    fn proc_entry_exit3(&self, frame: &X86Frame, symbol_table: &SymbolTable) -> (String, String) {
+        let name = frame.name.name(symbol_table);
+        let mut prologue = format!("\t.globl {}\n{}:\n", name, name);
+        for instr in [Instr::oper(self.ins("push", &[Operand::Src(0)]), vec![RSP], vec![RBP, RSP]),
+                      Instr::Move { assem: self.ins("mov", &[Operand::Src(0), Operand::Dst(0)]), dst: RBP, src: RSP },
+                      Instr::oper(self.ins("sub", &[Operand::Imm(frame.frame_size()), Operand::Dst(0)]),
+                                  vec![RSP], vec![RSP])].iter() {
+            let text = instr.format(&|t| self.register_name(t), &|l| l.name(symbol_table).clone());
+            prologue.push_str(&format!("\t{}\n", text));
+        }
+        (prologue, String::from("\tleave\n\tret\n"))
+    }
+
+    fn file_header(&self) -> String {
+        match self.syntax {
+            Syntax::Att => String::from("\t.section .note.GNU-stack,\"\",@progbits\n\t.text\n"),
+            Syntax::Intel => String::from("\t.intel_syntax noprefix\n\t.section .note.GNU-stack,\"\",@progbits\n\t.text\n"),
+        }
+    }
+
+    fn data_section(&self) -> String {
+        String::from("\t.data\n")
+    }
+
+    // strings are a length word followed by the characters
+    fn string(&self, label: Label, s: &str, symbol_table: &SymbolTable) -> String {
+        format!("\t.balign 8\n{}:\n\t.quad {}\n\t.ascii {}\n",
+                label.name(symbol_table), s.chars().count(), ascii_literal(s))
+    }
+
+    fn register_name(&self, t: Temp) -> String {
+        match (self.syntax, REGISTER_NAMES.get(t.0 as usize)) {
+            (Syntax::Att, Some(name)) => format!("%{}", name),
+            (Syntax::Intel, Some(name)) => name.to_string(),
+            (_, None) => format!("t{}", t.0),
+        }
+    }
+
+    fn load(&self, dst: Temp, offset: i32) -> Vec<Instr> {
+        vec![Instr::oper(self.ins("mov", &[Operand::Mem(0, offset), Operand::Dst(0)]), vec![dst], vec![RBP])]
+    }
+
+    fn store(&self, src: Temp, offset: i32) -> Vec<Instr> {
+        vec![Instr::oper(self.ins("mov", &[Operand::Src(0), Operand::Mem(1, offset)]), vec![], vec![src, RBP])]
+    }
+
+    fn registers(&self) -> Vec<Temp> {
+        CALLER_SAVES.iter().chain(CALLEE_SAVES.iter()).cloned().collect()
+    }
+
+    fn is_call(&self, instr: &Instr) -> bool {
+        match instr {
+            &Instr::Oper { ref assem, .. } => assem.starts_with("call "),
+            _ => false,
+        }
+    }
+
+    fn pointer_map_table(&self, maps: &[PointerMap], symbol_table: &SymbolTable) -> String {
+        format!("\t.balign 8\n{}", pointer_map_table(maps, ".quad", symbol_table))
+    }
+}
+
+#[test]
+fn test_munch() {
+    let mut table = SymbolTable::new();
+    let frame = X86Frame::new_frame(Label(table.symbol("f")), vec![], &mut TempGenerator::new());
+    let (a, b, t) = (Temp(200), Temp(201), Temp(202));
+    let (yes, no) = (Label(table.symbol("yes")), Label(table.symbol("no")));
+    let temp = |t: Temp| Box::new(Exp::Temp(t));
+
+    // MOVE(TEMP t, BINOP(DIV, TEMP a, TEMP b)): the dividend goes through
+    // %rax, sign-extended into %rdx, and idiv clobbers both
+    let div = Stm::Move(temp(t), Box::new(Exp::BinOp(BinOp::Div, temp(a), temp(b))));
+    // CJUMP(LT, TEMP a, TEMP b) compares a with b, whatever the syntax writes
+    // first; with the constant on the left the condition is swapped instead
+    let lt = Stm::CJump(RelOp::Lt, temp(a), temp(b), yes, no);
+    let lt_const = Stm::CJump(RelOp::Lt, Box::new(Exp::Const(3)), temp(a), yes, no);
+    for &(syntax, expected) in [
+        (Syntax::Att, [&["movq t200, %rax", "cqto", "idivq t201", "movq %rax, t100", "movq t100, t202"][..],
+                       &["cmpq t201, t200", "jl yes"][..], &["cmpq $3, t200", "jg yes"][..]]),
+        (Syntax::Intel, [&["mov rax, t200", "cqo", "idiv t201", "mov t100, rax", "mov t202, t100"][..],
+                         &["cmp t200, t201", "jl yes"][..], &["cmp t200, 3", "jg yes"][..]]),
+    ].iter() {
+        let codegen = X86Codegen { syntax: syntax };
+        let name = |t: Temp| codegen.register_name(t);
+        let label = |l: Label| l.name(&table).clone();
+        for (stm, &expected) in [&div, &lt, &lt_const].iter().zip(expected.iter()) {
+            let instrs = codegen.codegen(&frame, (*stm).clone(), &mut TempGenerator::new(), &table);
+            let assem: Vec<String> = instrs.iter().map(|i| i.format(&name, &label)).collect();
+            assert_eq!(assem, expected);
+        }
+
+        let instrs = codegen.codegen(&frame, div.clone(), &mut TempGenerator::new(), &table);
+        assert_eq!((instrs[1].defs(), instrs[1].uses()), (vec![RDX], vec![RAX]));
+        assert_eq!((instrs[2].defs(), instrs[2].uses()), (vec![RAX, RDX], vec![b, RAX, RDX]));
+        let instrs = codegen.codegen(&frame, lt.clone(), &mut TempGenerator::new(), &table);
+        assert_eq!(instrs[1].jumps(), Some(&vec![yes, no]));
+    }
+}